@@ -1,5 +1,5 @@
 
-use raytracer_lib::{RayTracer, stats::Stats};
+use raytracer_lib::{ApertureShape, Exposure, ExposureMode, KeyValue, Matrix, OctTreeConfig, Projection, RayTracer, RenderSettings, SceneObject, SensorFit, StereoMode, TriangleTest, Vec3, stats::Stats};
 
 use std::{
     sync::mpsc::{channel, Receiver, Sender},
@@ -7,19 +7,40 @@ use std::{
     time::Duration,
 };
 
-use clap::{Arg, Command};
-use minifb::{Key, Window, WindowOptions};
+use clap::{Arg, ArgAction, Command};
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
 
 const DEFAULT_WIDTH: usize = 1024;
 const DEFAULT_HEIGHT: usize = 768;
 const DEFAULT_COLLADA_FILE: &str = "./data/thai2.dae";
+const DEFAULT_FPS: f32 = 24.0;
+const DEFAULT_SAMPLES_PER_FRAME: usize = 16;
+const DEFAULT_OUTPUT_PREFIX: &str = "frame";
 
+#[derive(Clone)]
 struct CmdArgs {
-    max_triangles: usize,
+    octtree_config: OctTreeConfig,
+    rebuild_accel: bool,
+    accel_stats: bool,
     frame_iterations: Option<usize>,
     collada_filename: String,
     width: usize,
     height: usize,
+    exposure_mode: ExposureMode,
+    aperture: Option<(f32, ApertureShape)>,
+    focus_distance: Option<f32>,
+    sensor_fit: SensorFit,
+    projection: Option<Projection>,
+    stereo: Option<StereoMode>,
+    interocular_distance: f32,
+    convergence_distance: f32,
+    camera: Option<String>,
+    shutter: Option<(f32, f32)>,
+    frames: Option<(u32, u32)>,
+    fps: f32,
+    samples: usize,
+    output_prefix: String,
+    save_scene: Option<String>,
 }
 
 impl CmdArgs {
@@ -30,8 +51,8 @@ impl CmdArgs {
         .arg(Arg::new("collada_file")
             .short('f')
             .long("file")
-            .value_name("COLLADA_FILENAME")
-            .help("what collada (.dae) file to load for rendering")
+            .value_name("FILENAME")
+            .help("what collada (.dae) or scene (.toml) file to load for rendering. a scene file also sets the resolution, samples and other render settings")
         )
         .arg(Arg::new("max_triangles")
             .short('m')
@@ -39,6 +60,31 @@ impl CmdArgs {
             .value_name("MAX_TRIS")
             .help(&format!("sets maximum number of triangles per leaf in octtree. defaults to {} if omitted",raytracer_lib::DEFAULT_TRIANGLES_PER_LEAF))
         )
+        .arg(Arg::new("max_depth")
+            .long("max_depth")
+            .value_name("DEPTH")
+            .help(format!("sets maximum depth of the octtree. defaults to {} if omitted", raytracer_lib::DEFAULT_MAX_DEPTH))
+        )
+        .arg(Arg::new("loose_factor")
+            .long("loose_factor")
+            .value_name("FACTOR")
+            .help(format!("grows the octtree cubes by this factor, so triangles fit in fewer of them. defaults to {} if omitted", raytracer_lib::DEFAULT_LOOSE_FACTOR))
+        )
+        .arg(Arg::new("watertight")
+            .long("watertight")
+            .action(ArgAction::SetTrue)
+            .help("uses watertight ray-triangle intersection, so no rays slip through the edges between triangles")
+        )
+        .arg(Arg::new("accel_stats")
+            .long("accel_stats")
+            .action(ArgAction::SetTrue)
+            .help("prints statistics of the octtree when loaded and of the rays traced through it when done")
+        )
+        .arg(Arg::new("rebuild_accel")
+            .long("rebuild_accel")
+            .action(ArgAction::SetTrue)
+            .help("rebuilds the octtree instead of loading it from the cache next to the collada file")
+        )
         .arg(Arg::new("frame_iterations")
             .short('i')
             .long("frame_iterations")
@@ -55,6 +101,94 @@ impl CmdArgs {
             .value_name("HEIGHT")
             .help("sets height of output")
         )
+        .arg(Arg::new("exposure")
+            .short('e')
+            .long("exposure")
+            .value_name("STOPS")
+            .help("sets a fixed exposure in stops, disabling auto exposure")
+        )
+        .arg(Arg::new("exposure_percentile")
+            .long("exposure_percentile")
+            .value_name("PERCENTILE")
+            .help("auto exposure keys on this luminance percentile [0..1] instead of the average")
+        )
+        .arg(Arg::new("aperture")
+            .long("aperture")
+            .value_name("RADIUS")
+            .help("sets lens aperture radius for depth of field. defaults to 0 (pinhole) if omitted")
+        )
+        .arg(Arg::new("aperture_blades")
+            .long("aperture_blades")
+            .value_name("BLADES")
+            .help("gives the aperture a polygonal shape with this many blades, instead of a circle")
+        )
+        .arg(Arg::new("focus_distance")
+            .long("focus_distance")
+            .value_name("DISTANCE")
+            .help("sets focus distance, overriding the one in the collada file")
+        )
+        .arg(Arg::new("sensor_fit")
+            .long("sensor_fit")
+            .value_name("auto|horizontal|vertical")
+            .help("which image axis keeps the camera field of view. defaults to auto (the larger one) if omitted")
+        )
+        .arg(Arg::new("projection")
+            .long("projection")
+            .value_name("fisheye[:FOV]|equirectangular")
+            .help("overrides the camera projection from the collada file. fisheye fov defaults to 180 degrees")
+        )
+        .arg(Arg::new("stereo")
+            .long("stereo")
+            .value_name("sbs|anaglyph")
+            .help("renders a stereo pair, side by side or as a red/cyan anaglyph")
+        )
+        .arg(Arg::new("interocular")
+            .long("interocular")
+            .value_name("DISTANCE")
+            .help(format!("sets distance between the eyes for stereo. defaults to {} if omitted", raytracer_lib::DEFAULT_INTEROCULAR_DISTANCE))
+        )
+        .arg(Arg::new("convergence")
+            .long("convergence")
+            .value_name("DISTANCE")
+            .help(format!("sets stereo convergence distance, where the eyes agree. defaults to {} if omitted", raytracer_lib::DEFAULT_CONVERGENCE_DISTANCE))
+        )
+        .arg(Arg::new("camera")
+            .short('c')
+            .long("camera")
+            .value_name("NAME|INDEX")
+            .help("what camera in the collada file to render from, by id or index. defaults to the first camera")
+        )
+        .arg(Arg::new("shutter")
+            .long("shutter")
+            .value_name("[OPEN:]CLOSE")
+            .help("enables motion blur, spreading rays over the shutter interval in seconds. open defaults to 0")
+        )
+        .arg(Arg::new("frames")
+            .long("frames")
+            .value_name("START..END")
+            .help("renders the frames START to END (inclusive) of the animation to numbered png files, without opening a window")
+        )
+        .arg(Arg::new("fps")
+            .long("fps")
+            .value_name("FPS")
+            .help(format!("frames per second of the animation. defaults to {} if omitted", DEFAULT_FPS))
+        )
+        .arg(Arg::new("samples")
+            .long("samples")
+            .value_name("SAMPLES")
+            .help(format!("samples per pixel for each rendered frame. defaults to {} if omitted", DEFAULT_SAMPLES_PER_FRAME))
+        )
+        .arg(Arg::new("output")
+            .short('o')
+            .long("output")
+            .value_name("PREFIX")
+            .help(format!("file name prefix of rendered frames, frame 7 is written to PREFIX_0007.png. defaults to {} if omitted", DEFAULT_OUTPUT_PREFIX))
+        )
+        .arg(Arg::new("save_scene")
+            .long("save_scene")
+            .value_name("SCENE_FILENAME")
+            .help("writes the loaded scene and render settings to a scene (.toml) file")
+        )
         .get_matches();
 
         let max_triangles = match matches.get_one::<String>("max_triangles") {
@@ -65,6 +199,39 @@ impl CmdArgs {
         };
         println!("max triangles per leaf: {}", max_triangles);
 
+        let max_depth = match matches.get_one::<String>("max_depth") {
+            Some(max_depth) => max_depth.parse::<usize>().unwrap_or(raytracer_lib::DEFAULT_MAX_DEPTH),
+            None => raytracer_lib::DEFAULT_MAX_DEPTH,
+        };
+
+        let loose_factor = match matches.get_one::<String>("loose_factor") {
+            Some(loose_factor) => match loose_factor.parse::<f32>() {
+                Ok(loose_factor) if loose_factor >= 1.0 => loose_factor,
+                _ => {
+                    println!("loose factor must be at least 1, using {}", raytracer_lib::DEFAULT_LOOSE_FACTOR);
+                    raytracer_lib::DEFAULT_LOOSE_FACTOR
+                }
+            },
+            None => raytracer_lib::DEFAULT_LOOSE_FACTOR,
+        };
+
+        let triangle_test = if matches.get_flag("watertight") {
+            TriangleTest::Watertight
+        } else {
+            TriangleTest::MollerTrumbore
+        };
+
+        let octtree_config = OctTreeConfig {
+            triangles_per_leaf: max_triangles,
+            max_depth,
+            loose_factor,
+            triangle_test,
+        };
+
+        let accel_stats = matches.get_flag("accel_stats");
+
+        let rebuild_accel = matches.get_flag("rebuild_accel");
+
         let frame_iterations = match matches.get_one::<String>("frame_iterations") {
             Some(frame_iterations) => frame_iterations.parse::<usize>().ok(),
             None => None,
@@ -89,12 +256,145 @@ impl CmdArgs {
         }
         .to_string();
 
+        let exposure_percentile = match matches.get_one::<String>("exposure_percentile") {
+            Some(percentile) => percentile.parse::<f32>().ok(),
+            None => None,
+        };
+
+        let exposure_mode = match matches.get_one::<String>("exposure") {
+            Some(stops) => match stops.parse::<f32>() {
+                Ok(stops) => ExposureMode::Fixed(stops),
+                Err(_) => ExposureMode::Auto(KeyValue::Average),
+            },
+            None => match exposure_percentile {
+                Some(percentile) => ExposureMode::Auto(KeyValue::Percentile(percentile)),
+                None => ExposureMode::Auto(KeyValue::Average),
+            },
+        };
+        println!("exposure: {:?}", exposure_mode);
+
+        let aperture_shape = match matches.get_one::<String>("aperture_blades") {
+            Some(blades) => match blades.parse::<u32>() {
+                Ok(blades) => ApertureShape::Polygon(blades),
+                Err(_) => ApertureShape::Circle,
+            },
+            None => ApertureShape::Circle,
+        };
+
+        let aperture = match matches.get_one::<String>("aperture") {
+            Some(radius) => radius.parse::<f32>().ok().map(|radius| (radius, aperture_shape)),
+            None => None,
+        };
+
+        let focus_distance = match matches.get_one::<String>("focus_distance") {
+            Some(focus_distance) => focus_distance.parse::<f32>().ok(),
+            None => None,
+        };
+
+        let sensor_fit = match matches.get_one::<String>("sensor_fit").map(|s| s.as_str()) {
+            Some("horizontal") => SensorFit::Horizontal,
+            Some("vertical") => SensorFit::Vertical,
+            _ => SensorFit::Auto,
+        };
+
+        let projection = match matches.get_one::<String>("projection") {
+            Some(projection) => match projection.split(':').collect::<Vec<_>>()[..] {
+                ["fisheye"] => Some(Projection::Fisheye { fov_deg: 180.0 }),
+                ["fisheye", fov_deg] => fov_deg
+                    .parse::<f32>()
+                    .ok()
+                    .map(|fov_deg| Projection::Fisheye { fov_deg }),
+                ["equirectangular"] => Some(Projection::Equirectangular),
+                _ => None,
+            },
+            None => None,
+        };
+
+        let stereo = match matches.get_one::<String>("stereo").map(|s| s.as_str()) {
+            Some("sbs") => Some(StereoMode::SideBySide),
+            Some("anaglyph") => Some(StereoMode::Anaglyph),
+            _ => None,
+        };
+
+        let interocular_distance = match matches.get_one::<String>("interocular") {
+            Some(distance) => distance
+                .parse::<f32>()
+                .unwrap_or(raytracer_lib::DEFAULT_INTEROCULAR_DISTANCE),
+            None => raytracer_lib::DEFAULT_INTEROCULAR_DISTANCE,
+        };
+
+        let convergence_distance = match matches.get_one::<String>("convergence") {
+            Some(distance) => distance
+                .parse::<f32>()
+                .unwrap_or(raytracer_lib::DEFAULT_CONVERGENCE_DISTANCE),
+            None => raytracer_lib::DEFAULT_CONVERGENCE_DISTANCE,
+        };
+
+        let camera = matches.get_one::<String>("camera").cloned();
+
+        let shutter = match matches.get_one::<String>("shutter") {
+            Some(shutter) => match shutter.split(':').collect::<Vec<_>>()[..] {
+                [close] => close.parse::<f32>().ok().map(|close| (0.0, close)),
+                [open, close] => match (open.parse::<f32>(), close.parse::<f32>()) {
+                    (Ok(open), Ok(close)) => Some((open, close)),
+                    _ => None,
+                },
+                _ => None,
+            },
+            None => None,
+        };
+
+        let frames = match matches.get_one::<String>("frames") {
+            Some(frames) => match frames.split_once("..") {
+                Some((start, end)) => match (start.parse::<u32>(), end.parse::<u32>()) {
+                    (Ok(start), Ok(end)) => Some((start, end.max(start))),
+                    _ => None,
+                },
+                None => frames.parse::<u32>().ok().map(|frame| (frame, frame)),
+            },
+            None => None,
+        };
+
+        let fps = match matches.get_one::<String>("fps") {
+            Some(fps) => fps.parse::<f32>().unwrap_or(DEFAULT_FPS),
+            None => DEFAULT_FPS,
+        };
+
+        let samples = match matches.get_one::<String>("samples") {
+            Some(samples) => samples.parse::<usize>().unwrap_or(DEFAULT_SAMPLES_PER_FRAME),
+            None => DEFAULT_SAMPLES_PER_FRAME,
+        };
+
+        let output_prefix = match matches.get_one::<String>("output") {
+            Some(output_prefix) => output_prefix.clone(),
+            None => DEFAULT_OUTPUT_PREFIX.to_string(),
+        };
+
+        let save_scene = matches.get_one::<String>("save_scene").cloned();
+
         CmdArgs {
-            max_triangles,
+            octtree_config,
+            rebuild_accel,
+            accel_stats,
             frame_iterations,
             collada_filename,
             width,
             height,
+            exposure_mode,
+            aperture,
+            focus_distance,
+            sensor_fit,
+            projection,
+            stereo,
+            interocular_distance,
+            convergence_distance,
+            camera,
+            shutter,
+            frames,
+            fps,
+            samples,
+            output_prefix,
+            save_scene,
         }
     }
 }
@@ -102,6 +402,7 @@ impl CmdArgs {
 #[derive(Debug, Clone, Copy)]
 enum Event {
     KeyDown(Key),
+    KeyPressed(Key),
 }
 
 fn generate_events(window: &Window) -> Vec<Event> {
@@ -109,6 +410,9 @@ fn generate_events(window: &Window) -> Vec<Event> {
     for key in window.get_keys() {
         events.push(Event::KeyDown(key));
     }
+    for key in window.get_keys_pressed(KeyRepeat::No) {
+        events.push(Event::KeyPressed(key));
+    }
 
     events
 }
@@ -116,6 +420,8 @@ fn generate_events(window: &Window) -> Vec<Event> {
 fn handle_events(
     raytracer: &mut RayTracer,
     events_receiver: &Receiver<Vec<Event>>,
+    cmd_args: &CmdArgs,
+    selected_object: &mut Option<SceneObject>,
 ) {
     for events in events_receiver.try_iter() {
         for event in events {
@@ -123,43 +429,74 @@ fn handle_events(
                 Event::KeyDown(key) => match key {
                     Key::Left => {
                         raytracer.camera.move_rel(0.1, 0.0, 0.0);
-                        raytracer.film.clear();
+                        raytracer.clear_film();
                     }
                     Key::Right => {
                         raytracer.camera.move_rel(-0.1, 0.0, 0.0);
-                        raytracer.film.clear();
+                        raytracer.clear_film();
                     }
                     Key::Up => {
                         raytracer.camera.move_rel(0.0, 0.1, 0.0);
-                        raytracer.film.clear();
+                        raytracer.clear_film();
                     }
                     Key::Down => {
                         raytracer.camera.move_rel(0.0, -0.1, 0.0);
-                        raytracer.film.clear();
+                        raytracer.clear_film();
                     }
                     Key::Comma => {
                         raytracer.camera.move_rel(0.0, 0.0, 0.1);
-                        raytracer.film.clear();
+                        raytracer.clear_film();
                     }
                     Key::Period => {
                         raytracer.camera.move_rel(0.0, 0.0, -0.1);
-                        raytracer.film.clear();
+                        raytracer.clear_film();
                     }
                     Key::A => {
                         raytracer.camera.add_y_angle(0.01);
-                        raytracer.film.clear();
+                        raytracer.clear_film();
                     }
                     Key::D => {
                         raytracer.camera.add_y_angle(-0.01);
-                        raytracer.film.clear();
+                        raytracer.clear_film();
                     }
                     Key::W => {
                         raytracer.camera.add_x_angle(0.01);
-                        raytracer.film.clear();
+                        raytracer.clear_film();
                     }
                     Key::S => {
                         raytracer.camera.add_x_angle(-0.01);
-                        raytracer.film.clear();
+                        raytracer.clear_film();
+                    }
+                    Key::J => move_object(raytracer, *selected_object, Vec3::new(-0.1, 0.0, 0.0), cmd_args),
+                    Key::L => move_object(raytracer, *selected_object, Vec3::new(0.1, 0.0, 0.0), cmd_args),
+                    Key::Y => move_object(raytracer, *selected_object, Vec3::new(0.0, 0.1, 0.0), cmd_args),
+                    Key::H => move_object(raytracer, *selected_object, Vec3::new(0.0, -0.1, 0.0), cmd_args),
+                    Key::I => move_object(raytracer, *selected_object, Vec3::new(0.0, 0.0, 0.1), cmd_args),
+                    Key::K => move_object(raytracer, *selected_object, Vec3::new(0.0, 0.0, -0.1), cmd_args),
+                    _ => (),
+                },
+                Event::KeyPressed(key) => match key {
+                    Key::N => {
+                        let index = (raytracer.camera_index() + 1) % raytracer.num_cameras();
+                        select_camera(raytracer, index, cmd_args);
+                    }
+                    Key::P => {
+                        let num_cameras = raytracer.num_cameras();
+                        let index = (raytracer.camera_index() + num_cameras - 1) % num_cameras;
+                        select_camera(raytracer, index, cmd_args);
+                    }
+                    Key::O => {
+                        // cycles through the objects, then back to none selected
+                        let objects = raytracer.objects();
+                        let next = match *selected_object {
+                            None => 0,
+                            Some(object) => objects
+                                .iter()
+                                .position(|o| *o == object)
+                                .map_or(0, |idx| idx + 1),
+                        };
+                        *selected_object = objects.get(next).copied();
+                        println!("selected object: {:?}", selected_object);
                     }
                     _ => (),
                 },
@@ -168,23 +505,114 @@ fn handle_events(
     }
 }
 
+// moves the object in world space
+fn move_object(raytracer: &mut RayTracer, object: Option<SceneObject>, offset: Vec3, cmd_args: &CmdArgs) {
+    if let Some(object) = object {
+        if let Some(transform) = raytracer.object_transform(object) {
+            raytracer.set_object_transform(object, &(transform * Matrix::translate(&offset)));
+            if cmd_args.accel_stats {
+                println!("accel quality: {:.2}", raytracer.accel_quality());
+            }
+        }
+    }
+}
+
+// switches camera, keeping the camera settings given on the command line
+fn select_camera(raytracer: &mut RayTracer, index: usize, cmd_args: &CmdArgs) {
+    raytracer.select_camera(index);
+    raytracer.camera.set_sensor_fit(cmd_args.sensor_fit);
+    if let Some(projection) = cmd_args.projection {
+        raytracer.camera.set_projection(projection);
+    }
+    raytracer
+        .camera
+        .set_stereo(cmd_args.interocular_distance, cmd_args.convergence_distance);
+    if let Some((radius, shape)) = cmd_args.aperture {
+        raytracer.camera.set_aperture(radius, shape);
+    }
+    if let Some(focus_distance) = cmd_args.focus_distance {
+        raytracer.camera.set_focus_distance(focus_distance);
+    }
+    if let Some((open, close)) = cmd_args.shutter {
+        raytracer.camera.set_shutter(open, close);
+    }
+    println!("camera {}: {}", index, raytracer.camera.name());
+}
+
+// renders each frame of the animation to its own png file
+fn render_frames(raytracer: &mut RayTracer, cmd_args: &CmdArgs, start: u32, end: u32) -> Result<(), String> {
+    let mut stats = Stats::new();
+    for frame in start..=end {
+        raytracer.set_time(frame as f32 / cmd_args.fps);
+        let num_primary_rays = raytracer.trace_samples_per_pixel(cmd_args.samples);
+        let filename = format!("{}_{:04}.png", cmd_args.output_prefix, frame);
+        raytracer.save_tonemapped_pixels(&filename)?;
+        println!("{}: {}", filename, stats.stats(num_primary_rays));
+    }
+    println!("{}\n\n", stats.mean_stats());
+    if cmd_args.accel_stats {
+        println!("{}", raytracer.accel_stats());
+    }
+    Ok(())
+}
 
 fn main() -> Result<(), String> {
-    let cmd_args = CmdArgs::get_cmd_args();
+    let mut cmd_args = CmdArgs::get_cmd_args();
 
     // setup
+    let (mut raytracer, settings) = if cmd_args.collada_filename.ends_with(".toml") {
+        let (raytracer, settings) = raytracer_lib::create_raytracer_from_scene_file(
+            cmd_args.collada_filename.clone(),
+            cmd_args.octtree_config,
+            cmd_args.rebuild_accel)?;
+        cmd_args.width = settings.width;
+        cmd_args.height = settings.height;
+        cmd_args.samples = settings.samples_per_pixel;
+        (raytracer, settings)
+    } else {
+        let raytracer = raytracer_lib::create_raytracer_from_file(
+            cmd_args.collada_filename.clone(), 
+            cmd_args.octtree_config, 
+            cmd_args.width, 
+            cmd_args.height,
+            cmd_args.rebuild_accel)?;
+        let settings = RenderSettings {
+            width: cmd_args.width,
+            height: cmd_args.height,
+            samples_per_pixel: cmd_args.samples,
+            ..RenderSettings::default()
+        };
+        (raytracer, settings)
+    };
     let (width, height) = (cmd_args.width, cmd_args.height);
+    if let Some(scene_filename) = &cmd_args.save_scene {
+        raytracer_lib::save_scene_file(&raytracer, &settings, scene_filename)?;
+        println!("scene written to {}", scene_filename);
+    }
+    raytracer.exposure = Exposure::new(cmd_args.exposure_mode);
+    raytracer.set_stereo(cmd_args.stereo);
+    let camera_index = match &cmd_args.camera {
+        Some(camera) => raytracer
+            .find_camera(camera)
+            .ok_or(format!("no camera named {} in {}", camera, cmd_args.collada_filename))?,
+        None => 0,
+    };
+    select_camera(&mut raytracer, camera_index, &cmd_args);
+    if cmd_args.accel_stats {
+        println!("{}", raytracer.accel_stats());
+    }
+
+    if let Some((start, end)) = cmd_args.frames {
+        return render_frames(&mut raytracer, &cmd_args, start, end);
+    }
+    raytracer.set_time(0.0);
+
     let mut window = Window::new("raytracer-rs", width, height, WindowOptions::default())
         .map_err(|e| e.to_string())?;
     let frame = Arc::new(RwLock::new(vec![0u32; width*height]));
     let (events_sender, events_receiver): (Sender<Vec<Event>>, Receiver<Vec<Event>>) = channel();
     let mut stats = Stats::new();
     let mut current_iteration = 0;
-    let mut raytracer = raytracer_lib::create_raytracer_from_file(
-        cmd_args.collada_filename, 
-        cmd_args.max_triangles, 
-        width, 
-        height)?;
 
     let (frame_ready_signaler, frame_ready_listener) = waithandle::new();
     let (copied_frame_signaler, copied_frame_listener) = waithandle::new();
@@ -193,7 +621,9 @@ fn main() -> Result<(), String> {
     // raytracer loop
     let raytracer_thread = std::thread::spawn({
         let frame = Arc::clone(&frame);
+        let cmd_args = cmd_args.clone();
         move || {
+            let mut selected_object = None;
             while !shutdown_listener.check().expect("shutdown_listener failed") {
 
                 // render
@@ -208,12 +638,15 @@ fn main() -> Result<(), String> {
                 frame_ready_signaler.signal().expect("frame_ready_signaler failed");
                 copied_frame_listener.wait(Duration::from_millis(10000)).expect("copied_frame_listener failed");
 
-                handle_events(&mut raytracer, &events_receiver);
+                handle_events(&mut raytracer, &events_receiver, &cmd_args, &mut selected_object);
 
                 println!("{}", stats.stats(num_primary_rays));
             }
 
             println!("{}\n\n", stats.mean_stats());
+            if cmd_args.accel_stats {
+                println!("{}", raytracer.accel_stats());
+            }
         }
     });
 
@@ -1,43 +1,130 @@
 mod raytracer;
 mod scene;
+mod simd;
 mod vecmath;
 
 pub mod stats;
-pub use raytracer::RayTracer;
-pub use raytracer::accel_intersect::oct_tree_intersector::DEFAULT_TRIANGLES_PER_LEAF;
+pub use raytracer::{RayTracer, StereoMode, TriangleTest};
+pub use raytracer::exposure::{Exposure, ExposureMode, KeyValue};
+pub use raytracer::settings::{Integrator, RenderSettings};
+pub use raytracer::tonemap::Tonemapper;
+pub use scene::builder::{SceneBuildError, SceneBuilder};
+pub use scene::camera::{
+    ApertureShape, Camera, Eye, FieldOfView, Magnification, Projection, SensorFit,
+    DEFAULT_CONVERGENCE_DISTANCE, DEFAULT_INTEROCULAR_DISTANCE,
+};
+pub use scene::color::{Diffuse, RGB};
+pub use scene::motion::{Keyframe, Motion};
+pub use scene::shape::{Shape, ShapeKind};
+pub use scene::texture::{ColorSpace, Texture};
+pub use scene::{Bump, Geometry, Instance, Light, Material, Scene, SceneObject};
+pub use vecmath::{Matrix, Vec3};
+pub use raytracer::accel_intersect::accel_stats::AccelStats;
+pub use raytracer::accel_intersect::oct_tree_intersector::{
+    OctTreeConfig, DEFAULT_LOOSE_FACTOR, DEFAULT_MAX_DEPTH, DEFAULT_TRIANGLES_PER_LEAF,
+};
 
 
+use raytracer::accel_intersect::{cache, Intersector, TwoLevelIntersector};
 #[allow(unused_imports)]
-use scene::loaders::{colladaloader::ColladaLoader, SceneLoader};
+use scene::loaders::{colladaloader::ColladaLoader, tomlloader::TomlLoader, SceneLoader};
 
-use scene::Scene;
 
 pub fn create_raytracer(collada_doc: &str, triangles_per_leaf: usize, width: usize, height: usize) -> Result<RayTracer, String> {
     let scene = ColladaLoader::from_str(collada_doc, None, width, height)
         .map_err(|e| e.to_string())?;
 
-    build_raytracer(scene, triangles_per_leaf, width, height)
+    let accel = TwoLevelIntersector::with_triangles_per_leaf(&scene, triangles_per_leaf);
+    println!("accel build time: {:.3}s", accel.build_time().as_secs_f32());
+    build_raytracer(scene, accel, width, height)
 }
 
-pub fn create_raytracer_from_file(collada_filename: String, triangles_per_leaf: usize, width: usize, height: usize) -> Result<RayTracer, String> {
-    let scene = ColladaLoader::from_file(collada_filename, width, height)
+// the accel is cached next to the collada file, it's rebuilt if the cache is missing or outdated,
+// or if rebuild_accel is set
+pub fn create_raytracer_from_file(collada_filename: String, config: OctTreeConfig, width: usize, height: usize, rebuild_accel: bool) -> Result<RayTracer, String> {
+    let scene = ColladaLoader::from_file(&collada_filename, width, height)
         .map_err(|e| e.to_string())?;
 
-    build_raytracer(scene, triangles_per_leaf, width, height)
+    let accel = cached_accel(&scene, &config, &collada_filename, rebuild_accel);
+    build_raytracer(scene, accel, width, height)
 }
 
-fn build_raytracer(scene: Scene, triangles_per_leaf: usize, width: usize, height: usize) -> Result<RayTracer, String> {
-    let octtree = raytracer::accel_intersect::OctTreeIntersector::with_triangles_per_leaf(
-        &scene,
-        triangles_per_leaf,
-    );
-    
+// The scene file holds the resolution and the other render settings too, they are applied to
+// the raytracer and returned for the rest, like samples per pixel. The accel is cached next to
+// the scene file, as for collada files.
+pub fn create_raytracer_from_scene_file(scene_filename: String, config: OctTreeConfig, rebuild_accel: bool) -> Result<(RayTracer, RenderSettings), String> {
+    let (scene, settings) = TomlLoader::from_file_with_settings(&scene_filename)
+        .map_err(|e| e.to_string())?;
+
+    let accel = cached_accel(&scene, &config, &scene_filename, rebuild_accel);
+    let mut raytracer = build_raytracer(scene, accel, settings.width, settings.height)?;
+    raytracer.apply_settings(&settings);
+    Ok((raytracer, settings))
+}
+
+// writes the scene as rendered right now, files it refers to are made relative to the scene file
+pub fn save_scene_file(raytracer: &RayTracer, settings: &RenderSettings, scene_filename: &str) -> Result<(), String> {
+    let data_dir = std::path::Path::new(scene_filename).parent();
+    let doc = TomlLoader::to_string(raytracer.scene(), settings, data_dir)
+        .map_err(|e| e.to_string())?;
+    std::fs::write(scene_filename, doc).map_err(|e| e.to_string())
+}
+
+// for scenes made in code, e.g. with SceneBuilder. Cameras are set to the given resolution.
+pub fn create_raytracer_from_scene(mut scene: Scene, config: OctTreeConfig, width: usize, height: usize) -> Result<RayTracer, String> {
+    for camera in scene.cameras.iter_mut() {
+        camera.set_resolution(width, height);
+    }
+    let accel = TwoLevelIntersector::with_config(&scene, &config);
+    println!("accel build time: {:.3}s", accel.build_time().as_secs_f32());
+    build_raytracer(scene, accel, width, height)
+}
+
+// the accel cached next to the scene file, built and cached if missing or outdated, or if
+// rebuild_accel is set
+fn cached_accel(scene: &Scene, config: &OctTreeConfig, scene_filename: &str, rebuild_accel: bool) -> TwoLevelIntersector {
+    let cache_filename = format!("{}.accel", scene_filename);
+    let cache_key = cache::cache_key(scene, config);
+    let cached = if rebuild_accel {
+        None
+    } else {
+        match cache::load::<TwoLevelIntersector, _>(&cache_filename, cache_key) {
+            Ok(accel) => Some(accel),
+            Err(cache::CacheError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => {
+                println!("not using {}: {}", cache_filename, e);
+                None
+            }
+        }
+    };
+    match cached {
+        Some(accel) => {
+            println!("accel loaded from {}", cache_filename);
+            accel
+        }
+        None => {
+            let accel = TwoLevelIntersector::with_config(scene, config);
+            println!("accel build time: {:.3}s", accel.build_time().as_secs_f32());
+            if let Err(e) = cache::save(&cache_filename, &accel, cache_key) {
+                println!("could not write {}: {}", cache_filename, e);
+            }
+            accel
+        }
+    }
+}
+
+fn build_raytracer(mut scene: Scene, accel: TwoLevelIntersector, width: usize, height: usize) -> Result<RayTracer, String> {
+    if scene.cameras.is_empty() {
+        let bounds = scene.bounds();
+        scene.cameras.push(Camera::framing_bounds(width, height, bounds));
+    }
+
     Ok(
         RayTracer::new_with_intersector(
             width,
             height,
             scene.cameras[0].clone(),
-            octtree,
+            accel,
             scene,
         )
     )
@@ -1,13 +1,94 @@
+use std::time::Duration;
+
+use super::intersect;
 use super::Hit;
 use crate::scene::Scene;
 use crate::vecmath::Ray;
 
+pub mod accel_stats;
+use accel_stats::AccelStats;
+
+pub mod cache;
+
 pub mod no_acceleration_intersector;
 
 pub mod oct_tree_intersector;
 pub use oct_tree_intersector::OctTreeIntersector;
 
+pub mod triangle_buffer;
+
+pub mod two_level_intersector;
+pub use two_level_intersector::TwoLevelIntersector;
+
+// rays intersected together by intersect_packet
+pub const PACKET_SIZE: usize = 4;
+
 pub trait Intersector {
     fn new(scene: &Scene) -> Self;
     fn intersect_ray(&self, scene: &Scene, ray: &Ray) -> Option<Hit>;
+
+    // same hits as intersecting the rays one by one. Coherent rays, like camera rays through
+    // neighbouring pixels, share most of their traversal and are faster to intersect together.
+    fn intersect_packet(
+        &self,
+        scene: &Scene,
+        rays: &[Ray; PACKET_SIZE],
+    ) -> [Option<Hit>; PACKET_SIZE] {
+        rays.each_ref().map(|ray| self.intersect_ray(scene, ray))
+    }
+
+    // called after the scene geometry changed
+    fn rebuild(&mut self, scene: &Scene)
+    where
+        Self: Sized,
+    {
+        *self = Self::new(scene);
+    }
+
+    // updates the bounds after geometry moved, without rebuilding. The scene must have the same
+    // triangles as when built. Returns the quality relative to when it was built, 1.0 is as fast
+    // and lower means a rebuild is worthwhile.
+    fn refit(&mut self, scene: &Scene) -> f32
+    where
+        Self: Sized,
+    {
+        self.rebuild(scene);
+        1.0
+    }
+
+    // time taken by the last build
+    fn build_time(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    // shape of the structure and the traversal work of all rays intersected so far
+    fn stats(&self) -> AccelStats {
+        AccelStats::default()
+    }
+}
+
+// keeps the hit on the scene shape if it is closer
+fn intersect_shape(scene: &Scene, shape_idx: usize, ray: &Ray, closest_hit: &mut Option<Hit>) {
+    if let Some(hit_info) = intersect::intersect_shape(ray, &scene.shapes[shape_idx].kind) {
+        let closer = match closest_hit {
+            None => true,
+            Some(hit) => hit_info.t < hit.hit_info.t,
+        };
+        if closer {
+            *closest_hit = Some(Hit::on_shape(hit_info, shape_idx));
+        }
+    }
+}
+
+// runs f, timing it. std::time::Instant isn't available on wasm32, there it takes no time.
+#[cfg(not(target_arch = "wasm32"))]
+fn timed<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+    let start = std::time::Instant::now();
+    let result = f();
+    (result, start.elapsed())
+}
+
+#[cfg(target_arch = "wasm32")]
+fn timed<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+    (f(), Duration::ZERO)
 }
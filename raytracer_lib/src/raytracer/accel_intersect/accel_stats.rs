@@ -0,0 +1,117 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Shape of an acceleration structure and how much work tracing through it has taken, for tuning
+// the build parameters per scene.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AccelStats {
+    pub nodes: usize,
+    pub leaves: usize,
+    pub triangles: usize,
+    // triangles in all the leaves, counting a triangle once for every leaf it is in
+    pub triangle_references: usize,
+    // number of leaves at each depth, the trunk is at depth 0
+    pub leaf_depths: Vec<usize>,
+    pub memory_bytes: usize,
+    pub rays: u64,
+    pub nodes_visited: u64,
+    pub triangles_tested: u64,
+}
+
+impl AccelStats {
+    pub fn duplication_factor(&self) -> f32 {
+        ratio(self.triangle_references as f32, self.triangles as f32)
+    }
+
+    pub fn nodes_per_ray(&self) -> f32 {
+        ratio(self.nodes_visited as f32, self.rays as f32)
+    }
+
+    pub fn triangles_per_ray(&self) -> f32 {
+        ratio(self.triangles_tested as f32, self.rays as f32)
+    }
+
+    // adds the structure of a part, e.g. the octtree of a mesh
+    pub fn add_structure(&mut self, part: &AccelStats) {
+        self.nodes += part.nodes;
+        self.leaves += part.leaves;
+        self.triangles += part.triangles;
+        self.triangle_references += part.triangle_references;
+        self.memory_bytes += part.memory_bytes;
+        for (depth, leaves) in part.leaf_depths.iter().enumerate() {
+            self.add_leaf_depth(depth, *leaves);
+        }
+    }
+
+    pub fn add_leaf_depth(&mut self, depth: usize, leaves: usize) {
+        if self.leaf_depths.len() <= depth {
+            self.leaf_depths.resize(depth + 1, 0);
+        }
+        self.leaf_depths[depth] += leaves;
+    }
+}
+
+fn ratio(a: f32, b: f32) -> f32 {
+    if b > 0.0 {
+        a / b
+    } else {
+        0.0
+    }
+}
+
+impl fmt::Display for AccelStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "nodes: {}, leaves: {}", self.nodes, self.leaves)?;
+        writeln!(
+            f,
+            "triangles: {}, in leaves: {} (duplication {:.2})",
+            self.triangles,
+            self.triangle_references,
+            self.duplication_factor()
+        )?;
+        writeln!(f, "memory: {:.1} MB", self.memory_bytes as f32 / 1e6)?;
+        writeln!(f, "leaves per depth:")?;
+        for (depth, leaves) in self.leaf_depths.iter().enumerate() {
+            writeln!(f, "  {:2}: {}", depth, leaves)?;
+        }
+        write!(
+            f,
+            "rays: {}, per ray nodes visited: {:.1}, triangles tested: {:.1}",
+            self.rays,
+            self.nodes_per_ray(),
+            self.triangles_per_ray()
+        )
+    }
+}
+
+// work done tracing a single ray
+#[derive(Default)]
+pub struct RayCounters {
+    pub nodes_visited: u64,
+    pub triangles_tested: u64,
+}
+
+// work done by all rays so far. Rays count locally and add up here once they are done, so the
+// threads tracing rays rarely touch the shared counters.
+#[derive(Default)]
+pub struct TraversalCounters {
+    rays: AtomicU64,
+    nodes_visited: AtomicU64,
+    triangles_tested: AtomicU64,
+}
+
+impl TraversalCounters {
+    pub fn add(&self, ray_counters: &RayCounters) {
+        self.rays.fetch_add(1, Ordering::Relaxed);
+        self.nodes_visited
+            .fetch_add(ray_counters.nodes_visited, Ordering::Relaxed);
+        self.triangles_tested
+            .fetch_add(ray_counters.triangles_tested, Ordering::Relaxed);
+    }
+
+    pub fn add_to(&self, stats: &mut AccelStats) {
+        stats.rays += self.rays.load(Ordering::Relaxed);
+        stats.nodes_visited += self.nodes_visited.load(Ordering::Relaxed);
+        stats.triangles_tested += self.triangles_tested.load(Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,345 @@
+use std::convert::TryFrom;
+use std::{error, fmt, fs, io, path};
+
+use super::oct_tree_intersector::OctTreeConfig;
+use super::Intersector;
+use crate::scene::motion::Interpolation;
+use crate::scene::shape::ShapeKind;
+use crate::scene::{Geometry, Scene};
+use crate::vecmath::{Matrix, Vec3};
+
+// Built acceleration structures can be written to disk and read back, so large scenes don't need
+// a rebuild on every launch. A cache file holds a header and the intersector's own data:
+//   magic, format version, kind of intersector, key of the scene and build parameters
+// all numbers little endian.
+const MAGIC: &[u8; 8] = b"RTACCEL\0";
+const VERSION: u32 = 5;
+
+pub trait CachedIntersector: Intersector + Sized {
+    // tells intersectors apart, a cache of one kind is never read as another
+    const CACHE_KIND: &'static str;
+
+    fn write_cache(&self, writer: &mut CacheWriter);
+    fn read_cache(reader: &mut CacheReader) -> Result<Self, CacheError>;
+}
+
+#[derive(Debug)]
+pub enum CacheError {
+    Io(io::Error),
+    NotACache,
+    // made by another version, for another intersector or for another scene
+    Outdated,
+    Corrupt(String),
+}
+
+impl From<io::Error> for CacheError {
+    fn from(e: io::Error) -> Self {
+        CacheError::Io(e)
+    }
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CacheError::Io(e) => write!(f, "{}", e),
+            CacheError::NotACache => write!(f, "not an accel cache file"),
+            CacheError::Outdated => write!(f, "accel cache is outdated"),
+            CacheError::Corrupt(what) => write!(f, "accel cache is corrupt: {}", what),
+        }
+    }
+}
+
+impl error::Error for CacheError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            CacheError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+pub fn save<I: CachedIntersector, P: AsRef<path::Path>>(
+    path: P,
+    accel: &I,
+    key: u64,
+) -> Result<(), CacheError> {
+    let mut writer = CacheWriter::new();
+    writer.bytes(MAGIC);
+    writer.u32(VERSION);
+    writer.string(I::CACHE_KIND);
+    writer.u64(key);
+    accel.write_cache(&mut writer);
+    fs::write(path, writer.data)?;
+    Ok(())
+}
+
+pub fn load<I: CachedIntersector, P: AsRef<path::Path>>(
+    path: P,
+    key: u64,
+) -> Result<I, CacheError> {
+    let data = fs::read(path)?;
+    let mut reader = CacheReader::new(&data);
+    if reader.bytes(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        return Err(CacheError::NotACache);
+    }
+    if reader.u32()? != VERSION || reader.string()? != I::CACHE_KIND || reader.u64()? != key {
+        return Err(CacheError::Outdated);
+    }
+    let accel = I::read_cache(&mut reader)?;
+    if !reader.is_at_end() {
+        return Err(CacheError::Corrupt("trailing data".to_string()));
+    }
+    Ok(accel)
+}
+
+// identifies the scene geometry and the build parameters a cache was made from
+pub fn cache_key(scene: &Scene, config: &OctTreeConfig) -> u64 {
+    let mut hasher = Fnv1a::new();
+    hasher.u64(config.triangles_per_leaf as u64);
+    hasher.u64(config.max_depth as u64);
+    hasher.f32(config.loose_factor);
+    hasher.u64(config.triangle_test as u64);
+    hasher.f32(scene.time_range.0);
+    hasher.f32(scene.time_range.1);
+    for geometries in [&scene.geometries, &scene.meshes].iter() {
+        hasher.u64(geometries.len() as u64);
+        for geometry in geometries.iter() {
+            hash_geometry(&mut hasher, geometry);
+        }
+    }
+    hasher.u64(scene.instances.len() as u64);
+    for instance in &scene.instances {
+        hasher.u64(instance.mesh_index as u64);
+        hasher.matrix(instance.matrix());
+    }
+    hasher.u64(scene.shapes.len() as u64);
+    for shape in &scene.shapes {
+        hash_shape(&mut hasher, &shape.kind);
+    }
+    hasher.hash
+}
+
+fn hash_shape(hasher: &mut Fnv1a, shape: &ShapeKind) {
+    match shape {
+        ShapeKind::Sphere { center, radius } => {
+            hasher.u64(0);
+            hasher.vec3(center);
+            hasher.f32(*radius);
+        }
+        ShapeKind::Plane { point, normal } => {
+            hasher.u64(1);
+            hasher.vec3(point);
+            hasher.vec3(normal);
+        }
+        ShapeKind::Disk {
+            center,
+            normal,
+            radius,
+        } => {
+            hasher.u64(2);
+            hasher.vec3(center);
+            hasher.vec3(normal);
+            hasher.f32(*radius);
+        }
+        ShapeKind::Quad {
+            corner,
+            edge_u,
+            edge_v,
+        } => {
+            hasher.u64(3);
+            hasher.vec3(corner);
+            hasher.vec3(edge_u);
+            hasher.vec3(edge_v);
+        }
+    }
+}
+
+fn hash_geometry(hasher: &mut Fnv1a, geometry: &Geometry) {
+    hasher.u64(geometry.vertices.len() as u64);
+    for v in geometry
+        .vertices
+        .iter()
+        .chain(&geometry.transformed_vertices)
+    {
+        hasher.vec3(v);
+    }
+    hasher.matrix(geometry.transform());
+    let keyframes = match &geometry.motion {
+        Some(motion) => motion.keyframes(),
+        None => &[],
+    };
+    hasher.u64(keyframes.len() as u64);
+    for keyframe in keyframes {
+        hasher.f32(keyframe.time);
+        hasher.matrix(&keyframe.matrix);
+        hasher.u64(match keyframe.interpolation {
+            Interpolation::Linear => 0,
+            Interpolation::Step => 1,
+        });
+    }
+}
+
+// 64 bit FNV-1a, unlike std's hasher it stays the same between rust versions
+struct Fnv1a {
+    hash: u64,
+}
+impl Fnv1a {
+    fn new() -> Self {
+        Fnv1a {
+            hash: 0xcbf2_9ce4_8422_2325,
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.hash ^= *byte as u64;
+            self.hash = self.hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    fn f32(&mut self, v: f32) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    fn vec3(&mut self, v: &Vec3) {
+        self.f32(v.x);
+        self.f32(v.y);
+        self.f32(v.z);
+    }
+
+    fn matrix(&mut self, m: &Matrix) {
+        for e in m.elements() {
+            self.f32(*e);
+        }
+    }
+}
+
+pub struct CacheWriter {
+    data: Vec<u8>,
+}
+impl CacheWriter {
+    fn new() -> Self {
+        CacheWriter { data: vec![] }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn usize(&mut self, v: usize) {
+        self.u64(v as u64);
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.data.push(v as u8);
+    }
+
+    pub fn f32(&mut self, v: f32) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn vec3(&mut self, v: &Vec3) {
+        self.f32(v.x);
+        self.f32(v.y);
+        self.f32(v.z);
+    }
+
+    pub fn string(&mut self, s: &str) {
+        self.usize(s.len());
+        self.bytes(s.as_bytes());
+    }
+}
+
+pub struct CacheReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+impl<'a> CacheReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        CacheReader { data, pos: 0 }
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], CacheError> {
+        if len > self.data.len() - self.pos {
+            return Err(CacheError::Corrupt("unexpected end of file".to_string()));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], CacheError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Ok(array)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, CacheError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, CacheError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn usize(&mut self) -> Result<usize, CacheError> {
+        usize::try_from(self.u64()?).map_err(|_| CacheError::Corrupt("index too large".to_string()))
+    }
+
+    // an index into something with len elements
+    pub fn index(&mut self, len: usize) -> Result<usize, CacheError> {
+        let idx = self.usize()?;
+        if idx >= len {
+            return Err(CacheError::Corrupt(format!("index {} out of {}", idx, len)));
+        }
+        Ok(idx)
+    }
+
+    // number of elements that follow, each at least min_size bytes. Checked against the
+    // remaining data so a corrupt length doesn't allocate everything.
+    pub fn count(&mut self, min_size: usize) -> Result<usize, CacheError> {
+        let len = self.usize()?;
+        if len.saturating_mul(min_size) > self.data.len() - self.pos {
+            return Err(CacheError::Corrupt("length beyond end of file".to_string()));
+        }
+        Ok(len)
+    }
+
+    pub fn bool(&mut self) -> Result<bool, CacheError> {
+        match self.bytes(1)?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(CacheError::Corrupt("invalid bool".to_string())),
+        }
+    }
+
+    pub fn f32(&mut self) -> Result<f32, CacheError> {
+        Ok(f32::from_le_bytes(self.array()?))
+    }
+
+    pub fn vec3(&mut self) -> Result<Vec3, CacheError> {
+        Ok(Vec3::new(self.f32()?, self.f32()?, self.f32()?))
+    }
+
+    pub fn string(&mut self) -> Result<String, CacheError> {
+        let len = self.count(1)?;
+        String::from_utf8(self.bytes(len)?.to_vec())
+            .map_err(|_| CacheError::Corrupt("invalid string".to_string()))
+    }
+}
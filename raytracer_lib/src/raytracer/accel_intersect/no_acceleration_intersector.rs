@@ -1,9 +1,10 @@
-use super::Intersector;
+use super::cache::{CacheError, CacheReader, CacheWriter, CachedIntersector};
+use super::{intersect_shape, Intersector};
 use crate::raytracer::{intersect, Hit};
 use crate::scene::Scene;
 use crate::vecmath::Ray;
 
-// no acceleration for intersections, just iterates through all the geometries' triangles.
+// no acceleration for intersections, just iterates through all the geometries' and instances' triangles and the shapes.
 pub struct NoAccelerationIntersector {}
 
 impl Intersector for NoAccelerationIntersector {
@@ -14,10 +15,9 @@ impl Intersector for NoAccelerationIntersector {
         let mut closest_hit = None;
 
         for (geom_idx, geom) in scene.geometries.iter().enumerate() {
-            let intersections: Vec<Option<intersect::HitInfo>> = geom
-                .transformed_vertices
-                .chunks(3)
-                .map(|tri_vertices| {
+            let intersections: Vec<Option<intersect::HitInfo>> = (0..geom.vertices.len() / 3)
+                .map(|tri_idx| {
+                    let tri_vertices = geom.triangle_at(tri_idx * 3, ray.time);
                     intersect::intersect(ray, &tri_vertices[0], &tri_vertices[1], &tri_vertices[2])
                 })
                 .collect();
@@ -37,6 +37,46 @@ impl Intersector for NoAccelerationIntersector {
                 }
             }
         }
+
+        for (instance_idx, instance) in scene.instances.iter().enumerate() {
+            let object_ray = instance.ray_to_object(ray);
+            let mesh = &scene.meshes[instance.mesh_index];
+            for tri_idx in 0..mesh.vertices.len() / 3 {
+                let tri_vertices = mesh.triangle_at(tri_idx * 3, ray.time);
+                let intersection = intersect::intersect(
+                    &object_ray,
+                    &tri_vertices[0],
+                    &tri_vertices[1],
+                    &tri_vertices[2],
+                );
+                if let Some(hit_info) = intersection {
+                    let closer = match &closest_hit {
+                        None => true,
+                        Some(hit) => hit_info.t < hit.hit_info.t,
+                    };
+                    if closer {
+                        let mut hit = Hit::new(hit_info, instance.mesh_index, tri_idx * 3);
+                        hit.instance_index = Some(instance_idx);
+                        closest_hit = Some(hit);
+                    }
+                }
+            }
+        }
+
+        for shape_idx in 0..scene.shapes.len() {
+            intersect_shape(scene, shape_idx, ray, &mut closest_hit);
+        }
         closest_hit
     }
 }
+
+// nothing to cache, but it can stand in wherever a cached intersector is expected
+impl CachedIntersector for NoAccelerationIntersector {
+    const CACHE_KIND: &'static str = "no acceleration";
+
+    fn write_cache(&self, _writer: &mut CacheWriter) {}
+
+    fn read_cache(_reader: &mut CacheReader) -> Result<Self, CacheError> {
+        Ok(NoAccelerationIntersector {})
+    }
+}
@@ -1,36 +1,97 @@
-use super::Intersector;
-use crate::raytracer::{intersect, Hit};
-use crate::scene::Scene;
+use std::ops::Range;
+use std::time::Duration;
+
+use super::accel_stats::{AccelStats, RayCounters, TraversalCounters};
+use super::cache::{CacheError, CacheReader, CacheWriter, CachedIntersector};
+use super::triangle_buffer::{TriangleBuffer, TriangleIndex};
+use super::{intersect_shape, timed, Intersector, PACKET_SIZE};
+use crate::raytracer::intersect::{self, HitInfo, TriangleRay, TriangleTest};
+use crate::raytracer::Hit;
+use crate::scene::{Geometry, Scene};
+use crate::simd::{F32x4, Vec3x4};
 use crate::vecmath::{cross, dot, Ray, Vec3};
 
 pub struct OctTreeIntersector {
     cubes: Vec<Cube>,
     nodes: Vec<OctNode>,
+    triangles: TriangleBuffer,
     trunk: usize,
+    config: OctTreeConfig,
+    // distinct triangles, a triangle can be in several leaves
+    num_triangles: usize,
+    // after a refit, cubes bound the triangles of their leaves instead of splitting space
+    refitted: bool,
+    built_cost: f32,
+    build_time: Duration,
+    counters: TraversalCounters,
 }
 
 pub const DEFAULT_TRIANGLES_PER_LEAF: usize = 70;
+pub const DEFAULT_MAX_DEPTH: usize = 9;
+pub const DEFAULT_LOOSE_FACTOR: f32 = 1.0;
 
-#[derive(Clone, Copy)]
-struct TriangleIndex {
-    geom_idx: usize,
-    tri_idx: usize,
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OctTreeConfig {
+    // nodes with more triangles than this are split
+    pub triangles_per_leaf: usize,
+    // nodes this deep are leaves however many triangles they hold, the trunk is at depth 0
+    pub max_depth: usize,
+    // child cubes are grown by this factor around their centers, and out to their parent's grown
+    // cube. Above 1.0 the cubes overlap, and a triangle that fits in one of them is kept only
+    // there instead of in every cube it touches.
+    pub loose_factor: f32,
+    pub triangle_test: TriangleTest,
 }
-impl TriangleIndex {
-    pub fn new(geom_idx: usize, tri_idx: usize) -> Self {
-        TriangleIndex { geom_idx, tri_idx }
+
+impl Default for OctTreeConfig {
+    fn default() -> Self {
+        OctTreeConfig {
+            triangles_per_leaf: DEFAULT_TRIANGLES_PER_LEAF,
+            max_depth: DEFAULT_MAX_DEPTH,
+            loose_factor: DEFAULT_LOOSE_FACTOR,
+            triangle_test: TriangleTest::MollerTrumbore,
+        }
     }
 }
+
+// nodes at least this large get their children built on separate threads, down to PARALLEL_LEVELS
+const PARALLEL_BUILD_MIN_TRIANGLES: usize = 4096;
+const PARALLEL_LEVELS: usize = 2;
+
+// the nodes, cubes and triangles of a subtree, with its root first
+type Subtree = (Vec<OctNode>, Vec<Cube>, TriangleBuffer);
+
 #[derive(Clone)]
 pub struct Cube {
-    min: Vec3,
-    max: Vec3,
+    pub min: Vec3,
+    pub max: Vec3,
 }
 impl Cube {
-    fn new(min: Vec3, max: Vec3) -> Self {
+    pub fn new(min: Vec3, max: Vec3) -> Self {
         Cube { min, max }
     }
 
+    // smallest cube containing both
+    pub fn union(&self, other: &Cube) -> Cube {
+        Cube::new(
+            Vec3::new(
+                self.min.x.min(other.min.x),
+                self.min.y.min(other.min.y),
+                self.min.z.min(other.min.z),
+            ),
+            Vec3::new(
+                self.max.x.max(other.max.x),
+                self.max.y.max(other.max.y),
+                self.max.z.max(other.max.z),
+            ),
+        )
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let extent = self.max - self.min;
+        2.0 * (extent.x * extent.y + extent.y * extent.z + extent.z * extent.x)
+    }
+
     fn contains(&self, v: &Vec3) -> bool {
         if v.x < self.min.x
             || v.x > self.max.x
@@ -47,13 +108,16 @@ impl Cube {
 
 struct Leaf {
     cube_index: usize,
-    triangle_indices: Vec<TriangleIndex>,
+    // range in the triangle buffer, the triangles from moving_start on are moving
+    triangles: Range<usize>,
+    moving_start: usize,
 }
 impl Leaf {
-    pub fn new(cube_index: usize, triangle_indices: Vec<TriangleIndex>) -> Self {
+    pub fn new(cube_index: usize, triangles: Range<usize>, moving_start: usize) -> Self {
         Leaf {
             cube_index,
-            triangle_indices,
+            triangles,
+            moving_start,
         }
     }
 }
@@ -61,108 +125,345 @@ enum OctNode {
     Leaf(Leaf),
     Node([usize; 8]), //indices to nodes & cubes vec
 }
+impl OctNode {
+    // the node with its indices moved, for when its subtree is appended to another
+    fn offset(self, offset: usize, triangle_offset: usize) -> Self {
+        match self {
+            OctNode::Leaf(leaf) => OctNode::Leaf(Leaf::new(
+                leaf.cube_index + offset,
+                leaf.triangles.start + triangle_offset..leaf.triangles.end + triangle_offset,
+                leaf.moving_start + triangle_offset,
+            )),
+            OctNode::Node(child_indices) => OctNode::Node(child_indices.map(|idx| idx + offset)),
+        }
+    }
+}
 
 impl OctTreeIntersector {
-    pub fn with_triangles_per_leaf(scene: &Scene, triangles_per_leaf: usize) -> Self {
-        let trunk_cube = calc_extents(&scene);
-        let all_triangle_indices = all_triangle_indices(&scene);
-        let trunk = 0;
-        let leaf = Leaf::new(trunk, all_triangle_indices);
-        let nodes = vec![OctNode::Leaf(leaf)];
+    pub fn with_config(scene: &Scene, config: &OctTreeConfig) -> Self {
+        Self::from_geometries(&scene.geometries, scene.time_range, config)
+    }
+
+    // octtree over the given geometries only, e.g. a single mesh in its object space
+    pub fn from_geometries(
+        geometries: &[Geometry],
+        time_range: (f32, f32),
+        config: &OctTreeConfig,
+    ) -> Self {
+        let trunk_cube = calc_extents(geometries, time_range);
+        let all_triangle_indices = all_triangle_indices(geometries);
+        let num_triangles = all_triangle_indices.len();
+        let ((nodes, cubes, triangles), build_time) = timed(|| {
+            Self::build_subtree(
+                trunk_cube.clone(),
+                loosen(&trunk_cube, config.loose_factor),
+                all_triangle_indices,
+                config,
+                geometries,
+                time_range,
+                0,
+            )
+        });
         let mut octtree = Self {
-            cubes: vec![trunk_cube],
+            cubes,
             nodes,
-            trunk,
+            triangles,
+            trunk: 0,
+            config: *config,
+            num_triangles,
+            refitted: false,
+            built_cost: 0.0,
+            build_time,
+            counters: TraversalCounters::default(),
         };
 
-        octtree.split(triangles_per_leaf, scene);
-        //octtree.print_debug_info();
+        let bounds = octtree.triangle_bounds(geometries, time_range);
+        octtree.built_cost = octtree.cost(&bounds, geometries, time_range);
         octtree
     }
 
-    fn split(&mut self, num_triangles: usize, scene: &Scene) {
-        Self::split_node(
-            self.trunk,
-            &mut self.nodes,
-            &mut self.cubes,
-            num_triangles,
-            scene,
-            0,
-        );
+    // refit over the given geometries, which must have the same triangles as when built
+    pub fn refit_geometries(&mut self, geometries: &[Geometry], time_range: (f32, f32)) -> f32 {
+        let bounds = self.triangle_bounds(geometries, time_range);
+        for (cube, node_bounds) in self.cubes.iter_mut().zip(bounds.iter()) {
+            if let Some(node_bounds) = node_bounds {
+                *cube = node_bounds.clone();
+            }
+        }
+        self.refitted = true;
+        self.triangles.update(geometries, self.config.triangle_test);
+
+        let cost = self.cost(&bounds, geometries, time_range);
+        if cost > 0.0 {
+            self.built_cost / cost
+        } else {
+            1.0
+        }
+    }
+
+    // tight bounds of each node's triangles, None for nodes without triangles
+    fn triangle_bounds(
+        &self,
+        geometries: &[Geometry],
+        time_range: (f32, f32),
+    ) -> Vec<Option<Cube>> {
+        let mut bounds = vec![None; self.nodes.len()];
+        self.node_triangle_bounds(self.trunk, geometries, time_range, &mut bounds);
+        bounds
     }
 
-    fn split_node(
+    fn node_triangle_bounds(
+        &self,
         node_idx: usize,
-        nodes: &mut Vec<OctNode>,
-        cubes: &mut Vec<Cube>,
-        num_triangles: usize,
-        scene: &Scene,
-        recurse_level: usize,
-    ) {
-        let mut new_nodes = Vec::new();
-        let mut new_child_list = [0; 8];
+        geometries: &[Geometry],
+        time_range: (f32, f32),
+        bounds: &mut Vec<Option<Cube>>,
+    ) -> Option<Cube> {
+        let node_bounds = match &self.nodes[node_idx] {
+            OctNode::Leaf(leaf) => leaf
+                .triangles
+                .clone()
+                .map(|i| {
+                    let index = self.triangles.index(i);
+                    let (min, max) =
+                        geometries[index.geom_idx].swept_triangle_bounds(index.tri_idx, time_range);
+                    Cube::new(min, max)
+                })
+                .reduce(|a, b| a.union(&b)),
+            OctNode::Node(child_indices) => child_indices
+                .iter()
+                .filter_map(|child_idx| {
+                    self.node_triangle_bounds(*child_idx, geometries, time_range, bounds)
+                })
+                .reduce(|a, b| a.union(&b)),
+        };
+        bounds[node_idx] = node_bounds.clone();
+        node_bounds
+    }
 
-        match nodes[node_idx] {
-            OctNode::Node(_) => return,
-            OctNode::Leaf(ref leaf) => {
-                if leaf.triangle_indices.len() <= num_triangles || recurse_level > 8 {
-                    return;
+    // surface area heuristic: the leaf areas weighted by their triangles, relative to the area
+    // of the triangles themselves. Grows as moved triangles stretch the leaves they are in.
+    fn cost(
+        &self,
+        bounds: &[Option<Cube>],
+        geometries: &[Geometry],
+        time_range: (f32, f32),
+    ) -> f32 {
+        let triangles_area: f32 = all_triangle_indices(geometries)
+            .iter()
+            .map(|index| {
+                let (min, max) =
+                    geometries[index.geom_idx].swept_triangle_bounds(index.tri_idx, time_range);
+                Cube::new(min, max).surface_area()
+            })
+            .sum();
+        if triangles_area <= 0.0 {
+            return 0.0;
+        }
+        self.nodes
+            .iter()
+            .zip(bounds)
+            .map(|(node, node_bounds)| match (node, node_bounds) {
+                (OctNode::Leaf(leaf), Some(cube)) => {
+                    cube.surface_area() * leaf.triangles.len() as f32
                 }
+                _ => 0.0,
+            })
+            .sum::<f32>()
+            / triangles_area
+    }
 
-                // println!(
-                //     "splitting node {} containing {} triangles",
-                //     node_idx,
-                //     leaf.triangle_indices.len()
-                // );
-
-                let cube = &cubes[leaf.cube_index];
-                let child_cubes = generate_child_cubes(cube);
-                for i in 0..8 {
-                    let triangles_inside = triangles_intersecting_cube(
-                        &child_cubes[i],
-                        &leaf.triangle_indices,
-                        &scene,
-                    );
-                    cubes.push(child_cubes[i].clone());
-                    let child_idx = cubes.len() - 1;
-                    new_nodes.push(OctNode::Leaf(Leaf::new(child_idx, triangles_inside)));
-                    new_child_list[i] = child_idx;
+    // geometries must be the ones the octtree was built from. The work done is added to counters.
+    pub fn intersect_geometries(
+        &self,
+        geometries: &[Geometry],
+        ray: &Ray,
+        counters: &mut RayCounters,
+    ) -> Option<Hit> {
+        let inv_ray = Ray::new(
+            ray.pos,
+            Vec3::new(1.0 / ray.dir.x, 1.0 / ray.dir.y, 1.0 / ray.dir.z),
+        );
+        let triangle_ray = TriangleRay::new(ray, self.config.triangle_test);
+        self.intersect_node(geometries, &triangle_ray, &inv_ray, self.trunk, counters)
+    }
+
+    // intersect_geometries for each ray, the rays traverse the tree together
+    pub fn intersect_geometries_packet(
+        &self,
+        geometries: &[Geometry],
+        rays: &[Ray; PACKET_SIZE],
+        counters: &mut [RayCounters; PACKET_SIZE],
+    ) -> [Option<Hit>; PACKET_SIZE] {
+        // there is no packet version of the watertight test
+        if self.config.triangle_test != TriangleTest::MollerTrumbore {
+            return std::array::from_fn(|lane| {
+                self.intersect_geometries(geometries, &rays[lane], &mut counters[lane])
+            });
+        }
+        let packet = RayPacket::new(rays);
+        let mut hits = Default::default();
+        self.intersect_node_packet(
+            geometries, rays, &packet, self.trunk, ALL_LANES, &mut hits, counters,
+        );
+        hits
+    }
+
+    // refitted or loose cubes overlap, so the first hit found isn't necessarily the closest
+    fn overlapping(&self) -> bool {
+        self.refitted || self.config.loose_factor > 1.0
+    }
+
+    // the shape of the tree, without any traversal counts
+    pub fn structure_stats(&self) -> AccelStats {
+        let mut stats = AccelStats {
+            nodes: self.nodes.len(),
+            triangles: self.num_triangles,
+            memory_bytes: std::mem::size_of::<Self>()
+                + self.nodes.capacity() * std::mem::size_of::<OctNode>()
+                + self.cubes.capacity() * std::mem::size_of::<Cube>()
+                + self.triangles.memory_bytes(),
+            ..AccelStats::default()
+        };
+        self.add_node_stats(self.trunk, 0, &mut stats);
+        stats
+    }
+
+    fn add_node_stats(&self, node_idx: usize, depth: usize, stats: &mut AccelStats) {
+        match &self.nodes[node_idx] {
+            OctNode::Leaf(leaf) => {
+                stats.leaves += 1;
+                stats.triangle_references += leaf.triangles.len();
+                stats.add_leaf_depth(depth, 1);
+            }
+            OctNode::Node(child_indices) => {
+                for child_idx in child_indices {
+                    self.add_node_stats(*child_idx, depth + 1, stats);
                 }
             }
         }
-        nodes[node_idx] = OctNode::Node(new_child_list);
-        let start_idx_new_nodes = nodes.len();
-        nodes.append(&mut new_nodes);
-        for child_node_idx in start_idx_new_nodes..start_idx_new_nodes + 8 {
-            Self::split_node(
-                child_node_idx,
-                nodes,
-                cubes,
-                num_triangles,
-                &scene,
+    }
+
+    pub fn build_time(&self) -> Duration {
+        self.build_time
+    }
+
+    // builds the cube's subtree into its own node, cube and triangle vecs, with the subtree's
+    // root first. The triangles are the ones given to the cube, they lie within its loose cube,
+    // which is the cube itself unless the tree is loose
+    fn build_subtree(
+        cube: Cube,
+        loose_cube: Cube,
+        triangle_indices: Vec<TriangleIndex>,
+        config: &OctTreeConfig,
+        geometries: &[Geometry],
+        time_range: (f32, f32),
+        recurse_level: usize,
+    ) -> Subtree {
+        if triangle_indices.len() <= config.triangles_per_leaf || recurse_level >= config.max_depth
+        {
+            let (moving, fixed): (Vec<_>, Vec<_>) = triangle_indices
+                .into_iter()
+                .partition(|index| geometries[index.geom_idx].motion.is_some());
+            let moving_start = fixed.len();
+            let mut triangles = TriangleBuffer::default();
+            for index in fixed.into_iter().chain(moving) {
+                triangles.push(index, geometries, config.triangle_test);
+            }
+            return (
+                vec![OctNode::Leaf(Leaf::new(
+                    0,
+                    0..triangles.len(),
+                    moving_start,
+                ))],
+                vec![loose_cube],
+                triangles,
+            );
+        }
+
+        let child_cubes = generate_child_cubes(&cube);
+        let loose_child_cubes = loose_child_cubes(&child_cubes, &loose_cube, config.loose_factor);
+        let build_child = |child_idx: usize| {
+            let triangles_inside = if config.loose_factor > 1.0 {
+                triangles_in_loose_child(
+                    &loose_child_cubes,
+                    &cube,
+                    child_idx,
+                    &triangle_indices,
+                    geometries,
+                    time_range,
+                )
+            } else {
+                triangles_intersecting_cube(
+                    &child_cubes[child_idx],
+                    &triangle_indices,
+                    geometries,
+                    time_range,
+                )
+            };
+            Self::build_subtree(
+                child_cubes[child_idx].clone(),
+                loose_child_cubes[child_idx].clone(),
+                triangles_inside,
+                config,
+                geometries,
+                time_range,
                 recurse_level + 1,
+            )
+        };
+        let subtrees = if recurse_level < PARALLEL_LEVELS
+            && triangle_indices.len() >= PARALLEL_BUILD_MIN_TRIANGLES
+        {
+            build_in_parallel(build_child)
+        } else {
+            (0..8).map(build_child).collect()
+        };
+
+        let mut nodes = vec![OctNode::Node([0; 8])];
+        let mut cubes = vec![loose_cube];
+        let mut triangles = TriangleBuffer::default();
+        let mut child_indices = [0; 8];
+        for (child_index, (child_nodes, child_cubes, child_triangles)) in
+            child_indices.iter_mut().zip(subtrees)
+        {
+            let offset = nodes.len();
+            let triangle_offset = triangles.len();
+            *child_index = offset;
+            nodes.extend(
+                child_nodes
+                    .into_iter()
+                    .map(|node| node.offset(offset, triangle_offset)),
             );
+            cubes.extend(child_cubes);
+            triangles.append(child_triangles);
         }
+        nodes[0] = OctNode::Node(child_indices);
+        (nodes, cubes, triangles)
     }
 
     fn intersect_node(
         &self,
-        scene: &Scene,
-        ray: &Ray,
+        geometries: &[Geometry],
+        triangle_ray: &TriangleRay,
         inv_ray: &Ray,
         node_idx: usize,
+        counters: &mut RayCounters,
     ) -> Option<Hit> {
+        counters.nodes_visited += 1;
         match self.nodes[node_idx] {
             OctNode::Leaf(ref leaf) => {
-                match intersect_leaf_triangles(scene, ray, leaf) {
+                counters.triangles_tested += leaf.triangles.len() as u64;
+                match intersect_leaf_triangles(geometries, &self.triangles, triangle_ray, leaf) {
                     None => None,
                     Some(hit) => {
                         // Since we arent splitting triangles in the octtree, the hit point on the triangle
                         // might actually lay outside of this cube, in some other cube to be intersected later.
                         // In that case, there might be another triangle closer in the next cube, so we get a faulty result.
                         // We need to check that the hit point actually is in this cube, or return None.
+                        // Overlapping cubes are searched for the closest hit anyway, so there is nothing to check.
+                        let ray = triangle_ray.ray();
                         let hit_point = ray.pos + ray.dir * hit.hit_info.t;
-                        if self.cubes[node_idx].contains(&hit_point) {
+                        if self.overlapping() || self.cubes[leaf.cube_index].contains(&hit_point) {
                             Some(hit)
                         } else {
                             None
@@ -181,17 +482,30 @@ impl OctTreeIntersector {
                     }
                 }
                 distances.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
-                let ordered_child_indices =
-                    distances.iter().map(|(idx, _t)| *idx).collect::<Vec<_>>();
 
-                // recurse
-                for child_idx in ordered_child_indices {
-                    if let Some(hit) = self.intersect_node(scene, ray, inv_ray, child_idx) {
-                        return Some(hit);
+                // recurse. Overlapping cubes can have a later child hold a closer hit if the ray
+                // enters it before the hit found so far.
+                let overlapping = self.overlapping();
+                let mut closest_hit: Option<Hit> = None;
+                for (child_idx, t) in distances {
+                    if let Some(hit) = &closest_hit {
+                        if !overlapping || hit.hit_info.t < t {
+                            break;
+                        }
+                    }
+                    if let Some(hit) =
+                        self.intersect_node(geometries, triangle_ray, inv_ray, child_idx, counters)
+                    {
+                        let closer = match &closest_hit {
+                            None => true,
+                            Some(closest_hit) => hit.hit_info.t < closest_hit.hit_info.t,
+                        };
+                        if closer {
+                            closest_hit = Some(hit);
+                        }
                     }
                 }
-
-                None
+                closest_hit
             }
         }
 
@@ -205,70 +519,374 @@ impl OctTreeIntersector {
         // }
     }
 
-    #[allow(dead_code)]
-    fn print_debug_info(&self) {
-        self.print_rec(self.trunk, 0)
-    }
+    // Only the lanes in active traverse the node. Each lane keeps the closest hit it has found so
+    // far, and skips the children it enters after that hit. The children are visited in the order
+    // the packet enters them, which isn't every lane's order, so a lane's first hit isn't
+    // necessarily its closest one even in a tree that doesn't overlap.
+    #[allow(clippy::too_many_arguments)]
+    fn intersect_node_packet(
+        &self,
+        geometries: &[Geometry],
+        rays: &[Ray; PACKET_SIZE],
+        packet: &RayPacket,
+        node_idx: usize,
+        active: u32,
+        hits: &mut [Option<Hit>; PACKET_SIZE],
+        counters: &mut [RayCounters; PACKET_SIZE],
+    ) {
+        for lane in lanes(active) {
+            counters[lane].nodes_visited += 1;
+        }
+        match self.nodes[node_idx] {
+            OctNode::Leaf(ref leaf) => {
+                let mut leaf_hits = intersect_leaf_triangles_packet(
+                    geometries,
+                    &self.triangles,
+                    rays,
+                    packet,
+                    leaf,
+                );
+                let cube = &self.cubes[leaf.cube_index];
+                for lane in lanes(active) {
+                    counters[lane].triangles_tested += leaf.triangles.len() as u64;
+                    if let Some(hit) = leaf_hits[lane].take() {
+                        // same check as intersect_node, the hit might be in a cube further along
+                        let ray = &rays[lane];
+                        let hit_point = ray.pos + ray.dir * hit.hit_info.t;
+                        if (self.overlapping() || cube.contains(&hit_point))
+                            && closer(&hit, &hits[lane])
+                        {
+                            hits[lane] = Some(hit);
+                        }
+                    }
+                }
+            }
 
-    fn print_rec(&self, node_idx: usize, recurse_level: usize) {
-        let node = &self.nodes[node_idx];
-        match node {
-            OctNode::Node(child_indices) => {
-                (0..recurse_level).for_each(|_| print!("    "));
-                println!("node {}", node_idx);
-                for child_idx in child_indices {
-                    self.print_rec(*child_idx, recurse_level + 1);
+            OctNode::Node(ref child_indices) => {
+                let mut distances = Vec::new();
+                for child_index in child_indices {
+                    let (t, entered) = intersect_cube_packet(packet, &self.cubes[*child_index]);
+                    let entered = entered & active;
+                    if entered != 0 {
+                        let first_t = lanes(entered).map(|lane| t[lane]).fold(f32::MAX, f32::min);
+                        distances.push((*child_index, t, entered, first_t));
+                    }
+                }
+                distances.sort_by(|a, b| a.3.partial_cmp(&b.3).unwrap());
+
+                for (child_idx, t, entered, _) in distances {
+                    let mut child_active = entered;
+                    for lane in lanes(entered) {
+                        if let Some(hit) = &hits[lane] {
+                            if hit.hit_info.t < t[lane] {
+                                child_active &= !(1 << lane);
+                            }
+                        }
+                    }
+                    if child_active != 0 {
+                        self.intersect_node_packet(
+                            geometries,
+                            rays,
+                            packet,
+                            child_idx,
+                            child_active,
+                            hits,
+                            counters,
+                        );
+                    }
                 }
             }
-            OctNode::Leaf(leaf) => {
-                (0..recurse_level).for_each(|_| print!("    "));
-                println!(
-                    "leaf {} contains {} triangles",
-                    node_idx,
-                    leaf.triangle_indices.len()
-                );
+        }
+    }
+}
+
+impl CachedIntersector for OctTreeIntersector {
+    const CACHE_KIND: &'static str = "octtree";
+
+    fn write_cache(&self, writer: &mut CacheWriter) {
+        self.triangles.write_cache(writer);
+        writer.usize(self.cubes.len());
+        for cube in &self.cubes {
+            writer.vec3(&cube.min);
+            writer.vec3(&cube.max);
+        }
+        writer.usize(self.nodes.len());
+        for node in &self.nodes {
+            match node {
+                OctNode::Leaf(leaf) => {
+                    writer.u32(0);
+                    writer.usize(leaf.cube_index);
+                    writer.usize(leaf.triangles.start);
+                    writer.usize(leaf.moving_start);
+                    writer.usize(leaf.triangles.end);
+                }
+                OctNode::Node(child_indices) => {
+                    writer.u32(1);
+                    for idx in child_indices {
+                        writer.usize(*idx);
+                    }
+                }
             }
         }
+        writer.usize(self.trunk);
+        write_config(writer, &self.config);
+        writer.usize(self.num_triangles);
+        writer.bool(self.refitted);
+        writer.f32(self.built_cost);
+    }
+
+    // triangle indices aren't checked against the scene, the cache key ties them to it
+    fn read_cache(reader: &mut CacheReader) -> Result<Self, CacheError> {
+        let triangles = TriangleBuffer::read_cache(reader)?;
+        let num_cubes = reader.count(24)?;
+        let cubes = (0..num_cubes)
+            .map(|_| Ok(Cube::new(reader.vec3()?, reader.vec3()?)))
+            .collect::<Result<Vec<_>, CacheError>>()?;
+        let num_nodes = reader.count(12)?;
+        let mut nodes = Vec::with_capacity(num_nodes);
+        for node_idx in 0..num_nodes {
+            let node = match reader.u32()? {
+                0 => {
+                    let cube_index = reader.index(num_cubes)?;
+                    let start = reader.usize()?;
+                    let moving_start = reader.usize()?;
+                    let end = reader.usize()?;
+                    if start > moving_start || moving_start > end || end > triangles.len() {
+                        return Err(CacheError::Corrupt("octtree leaf triangles".to_string()));
+                    }
+                    OctNode::Leaf(Leaf::new(cube_index, start..end, moving_start))
+                }
+                1 => {
+                    let mut child_indices = [0; 8];
+                    for idx in child_indices.iter_mut() {
+                        *idx = reader.index(num_nodes.min(num_cubes))?;
+                        // children come after their parent, so traversal always ends
+                        if *idx <= node_idx {
+                            return Err(CacheError::Corrupt("octtree node order".to_string()));
+                        }
+                    }
+                    OctNode::Node(child_indices)
+                }
+                _ => return Err(CacheError::Corrupt("octtree node kind".to_string())),
+            };
+            nodes.push(node);
+        }
+        let trunk = reader.index(num_nodes.min(num_cubes))?;
+        Ok(OctTreeIntersector {
+            cubes,
+            nodes,
+            triangles,
+            trunk,
+            config: read_config(reader)?,
+            num_triangles: reader.usize()?,
+            refitted: reader.bool()?,
+            built_cost: reader.f32()?,
+            build_time: Duration::ZERO,
+            counters: TraversalCounters::default(),
+        })
     }
 }
 
+pub fn write_config(writer: &mut CacheWriter, config: &OctTreeConfig) {
+    writer.usize(config.triangles_per_leaf);
+    writer.usize(config.max_depth);
+    writer.f32(config.loose_factor);
+    writer.bool(config.triangle_test == TriangleTest::Watertight);
+}
+
+pub fn read_config(reader: &mut CacheReader) -> Result<OctTreeConfig, CacheError> {
+    Ok(OctTreeConfig {
+        triangles_per_leaf: reader.usize()?,
+        max_depth: reader.usize()?,
+        loose_factor: reader.f32()?,
+        triangle_test: if reader.bool()? {
+            TriangleTest::Watertight
+        } else {
+            TriangleTest::MollerTrumbore
+        },
+    })
+}
+
 impl Intersector for OctTreeIntersector {
     fn new(scene: &Scene) -> Self {
-        OctTreeIntersector::with_triangles_per_leaf(scene, DEFAULT_TRIANGLES_PER_LEAF)
+        OctTreeIntersector::with_config(scene, &OctTreeConfig::default())
+    }
+
+    fn rebuild(&mut self, scene: &Scene) {
+        *self = OctTreeIntersector::with_config(scene, &self.config);
+    }
+
+    fn refit(&mut self, scene: &Scene) -> f32 {
+        self.refit_geometries(&scene.geometries, scene.time_range)
+    }
+
+    fn build_time(&self) -> Duration {
+        self.build_time
+    }
+
+    fn stats(&self) -> AccelStats {
+        let mut stats = self.structure_stats();
+        self.counters.add_to(&mut stats);
+        stats
     }
 
+    // the octtree only holds the geometries, the scene's shapes are tested one by one
     fn intersect_ray(&self, scene: &Scene, ray: &Ray) -> Option<Hit> {
-        let inv_ray = Ray::new(
-            ray.pos,
-            Vec3::new(1.0 / ray.dir.x, 1.0 / ray.dir.y, 1.0 / ray.dir.z),
-        );
-        self.intersect_node(scene, ray, &inv_ray, self.trunk)
+        let mut counters = RayCounters::default();
+        let mut hit = self.intersect_geometries(&scene.geometries, ray, &mut counters);
+        for shape_idx in 0..scene.shapes.len() {
+            intersect_shape(scene, shape_idx, ray, &mut hit);
+        }
+        self.counters.add(&counters);
+        hit
+    }
+
+    fn intersect_packet(
+        &self,
+        scene: &Scene,
+        rays: &[Ray; PACKET_SIZE],
+    ) -> [Option<Hit>; PACKET_SIZE] {
+        let mut counters = Default::default();
+        let mut hits = self.intersect_geometries_packet(&scene.geometries, rays, &mut counters);
+        for ((ray, hit), ray_counters) in rays.iter().zip(hits.iter_mut()).zip(counters.iter()) {
+            for shape_idx in 0..scene.shapes.len() {
+                intersect_shape(scene, shape_idx, ray, hit);
+            }
+            self.counters.add(ray_counters);
+        }
+        hits
     }
 }
 
-fn intersect_leaf_triangles(scene: &Scene, ray: &Ray, leaf: &Leaf) -> Option<Hit> {
+fn intersect_leaf_triangles(
+    geometries: &[Geometry],
+    triangles: &TriangleBuffer,
+    triangle_ray: &TriangleRay,
+    leaf: &Leaf,
+) -> Option<Hit> {
     let mut closest_hit = None;
-
-    for index in &leaf.triangle_indices {
-        let tri_vertices = &scene.geometries[index.geom_idx].transformed_vertices
-            [index.tri_idx..index.tri_idx + 3];
-        let hit_info =
-            intersect::intersect(ray, &tri_vertices[0], &tri_vertices[1], &tri_vertices[2]);
-
-        match (&closest_hit, &hit_info) {
-            (None, None) => (),
-            (Some(_), None) => (),
-            (None, Some(hit_info)) => {
-                closest_hit = Some(Hit::new(hit_info.clone(), index.geom_idx, index.tri_idx))
+    let mut keep_closest = |hit_info: Option<HitInfo>, index: &TriangleIndex| {
+        if let Some(hit_info) = hit_info {
+            let hit = Hit::new(hit_info, index.geom_idx, index.tri_idx);
+            if closer(&hit, &closest_hit) {
+                closest_hit = Some(hit);
             }
-            (Some(hit), Some(hit_info)) => {
-                if hit_info.t < hit.hit_info.t {
-                    closest_hit = Some(Hit::new(hit_info.clone(), index.geom_idx, index.tri_idx))
+        }
+    };
+
+    for i in leaf.triangles.start..leaf.moving_start {
+        keep_closest(
+            triangle_ray.intersect_prepared(&triangles.corners(i)),
+            triangles.index(i),
+        );
+    }
+    for i in leaf.moving_start..leaf.triangles.end {
+        let index = triangles.index(i);
+        let tri_vertices =
+            geometries[index.geom_idx].triangle_at(index.tri_idx, triangle_ray.ray().time);
+        keep_closest(
+            triangle_ray.intersect(&tri_vertices[0], &tri_vertices[1], &tri_vertices[2]),
+            index,
+        );
+    }
+    closest_hit
+}
+
+// the closest hit of each ray in the leaf, wherever it is
+fn intersect_leaf_triangles_packet(
+    geometries: &[Geometry],
+    triangles: &TriangleBuffer,
+    rays: &[Ray; PACKET_SIZE],
+    packet: &RayPacket,
+    leaf: &Leaf,
+) -> [Option<Hit>; PACKET_SIZE] {
+    let mut closest_hits: [Option<Hit>; PACKET_SIZE] = Default::default();
+
+    for i in leaf.triangles.clone() {
+        let index = triangles.index(i);
+        let [v0, v0v1, v0v2] = if i < leaf.moving_start {
+            triangles.corners(i).map(|corner| Vec3x4::splat(&corner))
+        } else {
+            // moving triangles are where they are at the time of each ray
+            let geometry = &geometries[index.geom_idx];
+            let vertices = rays
+                .each_ref()
+                .map(|ray| geometry.triangle_at(index.tri_idx, ray.time));
+            let [v0, v1, v2] = [0, 1, 2]
+                .map(|corner| Vec3x4::from_lanes(vertices.each_ref().map(|tri| &tri[corner])));
+            [v0, v1.sub(&v0), v2.sub(&v0)]
+        };
+        let hit_infos = intersect::intersect_packet(&packet.pos, &packet.dir, &v0, &v0v1, &v0v2);
+
+        for (hit_info, closest_hit) in hit_infos.iter().zip(closest_hits.iter_mut()) {
+            if let Some(hit_info) = hit_info {
+                let hit = Hit::new(hit_info.clone(), index.geom_idx, index.tri_idx);
+                if closer(&hit, closest_hit) {
+                    *closest_hit = Some(hit);
                 }
             }
         }
     }
-    closest_hit
+    closest_hits
+}
+
+fn closer(hit: &Hit, closest_hit: &Option<Hit>) -> bool {
+    match closest_hit {
+        None => true,
+        Some(closest_hit) => hit.hit_info.t < closest_hit.hit_info.t,
+    }
+}
+
+const ALL_LANES: u32 = (1 << PACKET_SIZE) - 1;
+
+// the lanes set in a lane mask
+fn lanes(mask: u32) -> impl Iterator<Item = usize> {
+    (0..PACKET_SIZE).filter(move |lane| mask & (1 << lane) != 0)
+}
+
+// rays in simd lanes, for testing them against a cube or triangle at once
+struct RayPacket {
+    pos: Vec3x4,
+    dir: Vec3x4,
+    inv_dir: Vec3x4,
+}
+
+impl RayPacket {
+    fn new(rays: &[Ray; PACKET_SIZE]) -> Self {
+        let dir = Vec3x4::from_lanes(rays.each_ref().map(|ray| &ray.dir));
+        let one = F32x4::splat(1.0);
+        RayPacket {
+            pos: Vec3x4::from_lanes(rays.each_ref().map(|ray| &ray.pos)),
+            dir,
+            inv_dir: Vec3x4 {
+                x: one.div(dir.x),
+                y: one.div(dir.y),
+                z: one.div(dir.z),
+            },
+        }
+    }
+}
+
+// intersect_cube_inverse_ray for each lane, returns the entry ts and the mask of the lanes that hit
+fn intersect_cube_packet(packet: &RayPacket, cube: &Cube) -> ([f32; PACKET_SIZE], u32) {
+    let slab = |min: f32, max: f32, pos: F32x4, inv_dir: F32x4| {
+        let t1 = F32x4::splat(min).sub(pos).mul(inv_dir);
+        let t2 = F32x4::splat(max).sub(pos).mul(inv_dir);
+        (t1.min(t2), t1.max(t2))
+    };
+    let (tmin, tmax) = slab(cube.min.x, cube.max.x, packet.pos.x, packet.inv_dir.x);
+
+    // like f32::max and min, a NaN t (a ray in the plane of a face) doesn't narrow the range
+    let (ty_min, ty_max) = slab(cube.min.y, cube.max.y, packet.pos.y, packet.inv_dir.y);
+    let tmin = ty_min.max(tmin);
+    let tmax = ty_max.min(tmax);
+
+    let (tz_min, tz_max) = slab(cube.min.z, cube.max.z, packet.pos.z, packet.inv_dir.z);
+    let tmin = tz_min.max(tmin);
+    let tmax = tz_max.min(tmax);
+
+    let hit = tmin.le(tmax).and(F32x4::splat(0.0).lt(tmax));
+    (tmin.to_array(), hit.bits())
 }
 
 fn generate_child_cubes(cube: &Cube) -> [Cube; 8] {
@@ -312,26 +930,23 @@ fn generate_child_cubes(cube: &Cube) -> [Cube; 8] {
     ]
 }
 
-fn calc_extents(scene: &Scene) -> Cube {
-    let mut min = Vec3::new(std::f32::MAX, std::f32::MAX, std::f32::MAX);
-    let mut max = Vec3::new(std::f32::MIN, std::f32::MIN, std::f32::MIN);
-
-    for geom in &scene.geometries {
-        for vtx in &geom.transformed_vertices {
-            min.x = min.x.min(vtx.x);
-            min.y = min.y.min(vtx.y);
-            min.z = min.z.min(vtx.z);
-            max.x = max.x.max(vtx.x);
-            max.y = max.y.max(vtx.y);
-            max.z = max.z.max(vtx.z);
+fn calc_extents(geometries: &[Geometry], time_range: (f32, f32)) -> Cube {
+    // includes moving geometry at all times
+    match crate::scene::geometries_bounds(geometries, time_range) {
+        Some((min, max)) => {
+            // padded, so flat geometry (e.g. a planar mesh in object space) doesn't get a zero
+            // thickness cube that rounding puts the hit points outside of
+            let extent = max - min;
+            let pad = 1e-4 * extent.x.max(extent.y).max(extent.z) + f32::EPSILON;
+            let pad = Vec3::new(pad, pad, pad);
+            Cube::new(min - pad, max + pad)
         }
+        None => Cube::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 0.0)),
     }
-    Cube { min, max }
 }
 
-fn all_triangle_indices(scene: &Scene) -> Vec<TriangleIndex> {
-    scene
-        .geometries
+fn all_triangle_indices(geometries: &[Geometry]) -> Vec<TriangleIndex> {
+    geometries
         .iter()
         .enumerate()
         .flat_map(|(geom_idx, geom)| {
@@ -371,23 +986,147 @@ pub fn intersect_cube_inverse_ray(inv_ray: &Ray, cube: &Cube) -> Option<f32> {
     }
 }
 
+// builds the subtrees of the eight children on a thread each
+#[cfg(not(target_arch = "wasm32"))]
+fn build_in_parallel<F>(build_child: F) -> Vec<Subtree>
+where
+    F: Fn(usize) -> Subtree + Sync,
+{
+    let build_child = &build_child;
+    std::thread::scope(|scope| {
+        let handles = (0..8)
+            .map(|child_idx| scope.spawn(move || build_child(child_idx)))
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("octtree build thread panicked"))
+            .collect()
+    })
+}
+
+// there are no threads on wasm32, the subtrees are built in order
+#[cfg(target_arch = "wasm32")]
+fn build_in_parallel<F>(build_child: F) -> Vec<Subtree>
+where
+    F: Fn(usize) -> Subtree + Sync,
+{
+    (0..8).map(build_child).collect()
+}
+
 fn triangles_intersecting_cube(
     cube: &Cube,
     triangle_indices: &[TriangleIndex],
-    scene: &Scene,
+    geometries: &[Geometry],
+    time_range: (f32, f32),
 ) -> Vec<TriangleIndex> {
-    let mut insiders = Vec::new();
-    for indices in triangle_indices {
-        if triangle_cube_intersection(
-            cube,
-            &scene.geometries[indices.geom_idx].transformed_vertices
-                [indices.tri_idx..indices.tri_idx + 3],
-        ) {
-            insiders.push(indices.clone());
-            continue;
+    triangle_indices
+        .iter()
+        .filter(|index| triangle_intersects_cube(cube, index, geometries, time_range))
+        .copied()
+        .collect()
+}
+
+fn triangle_intersects_cube(
+    cube: &Cube,
+    index: &TriangleIndex,
+    geometries: &[Geometry],
+    time_range: (f32, f32),
+) -> bool {
+    let geom = &geometries[index.geom_idx];
+    match geom.motion {
+        // moving triangles are kept in every cube their sweep might touch
+        Some(_) => {
+            let (min, max) = geom.swept_triangle_bounds(index.tri_idx, time_range);
+            cube_overlaps_bounds(cube, &min, &max)
+        }
+        None => {
+            // the separating axis test is only needed when the triangle's bounds overlap
+            let tri_vertices = &geom.transformed_vertices[index.tri_idx..index.tri_idx + 3];
+            let (min, max) = triangle_bounds(tri_vertices);
+            cube_overlaps_bounds(cube, &min, &max) && triangle_cube_intersection(cube, tri_vertices)
         }
     }
-    insiders
+}
+
+// A triangle goes to the child its center is in, if it fits in that child's loose cube. Larger
+// triangles go to every loose cube they intersect, like in a regular octtree.
+fn triangles_in_loose_child(
+    loose_cubes: &[Cube; 8],
+    parent_cube: &Cube,
+    child_idx: usize,
+    triangle_indices: &[TriangleIndex],
+    geometries: &[Geometry],
+    time_range: (f32, f32),
+) -> Vec<TriangleIndex> {
+    // children are ordered with x, y and z in the bits of their index, see generate_child_cubes
+    let mid = 0.5 * (parent_cube.min + parent_cube.max);
+    triangle_indices
+        .iter()
+        .filter(|index| {
+            let (min, max) =
+                geometries[index.geom_idx].swept_triangle_bounds(index.tri_idx, time_range);
+            let center = 0.5 * (min + max);
+            let home_idx = (center.x > mid.x) as usize
+                + 2 * (center.y > mid.y) as usize
+                + 4 * (center.z > mid.z) as usize;
+            let home_cube = &loose_cubes[home_idx];
+            if home_cube.contains(&min) && home_cube.contains(&max) {
+                home_idx == child_idx
+            } else {
+                triangle_intersects_cube(&loose_cubes[child_idx], index, geometries, time_range)
+            }
+        })
+        .copied()
+        .collect()
+}
+
+// The children's cubes grown by factor, and on the parent's outer sides out to the parent's loose
+// cube. Triangles in the parent's margin may stick out of every child's own loose cube, so
+// together the children have to cover all of the parent's loose cube.
+fn loose_child_cubes(child_cubes: &[Cube; 8], parent_loose_cube: &Cube, factor: f32) -> [Cube; 8] {
+    let mid = child_cubes[7].min;
+    std::array::from_fn(|child_idx| {
+        let side = |bit: usize, min: f32, max: f32| if child_idx & bit == 0 { min } else { max };
+        let outer_corner = Vec3::new(
+            side(1, parent_loose_cube.min.x, parent_loose_cube.max.x),
+            side(2, parent_loose_cube.min.y, parent_loose_cube.max.y),
+            side(4, parent_loose_cube.min.z, parent_loose_cube.max.z),
+        );
+        loosen(&child_cubes[child_idx], factor)
+            .union(&Cube::new(outer_corner, outer_corner))
+            .union(&Cube::new(mid, mid))
+    })
+}
+
+// the cube grown by factor around its center
+fn loosen(cube: &Cube, factor: f32) -> Cube {
+    let growth = (cube.max - cube.min) * (0.5 * (factor - 1.0));
+    Cube::new(cube.min - growth, cube.max + growth)
+}
+
+fn triangle_bounds(tri_vertices: &[Vec3]) -> (Vec3, Vec3) {
+    let (v0, v1, v2) = (&tri_vertices[0], &tri_vertices[1], &tri_vertices[2]);
+    (
+        Vec3::new(
+            v0.x.min(v1.x).min(v2.x),
+            v0.y.min(v1.y).min(v2.y),
+            v0.z.min(v1.z).min(v2.z),
+        ),
+        Vec3::new(
+            v0.x.max(v1.x).max(v2.x),
+            v0.y.max(v1.y).max(v2.y),
+            v0.z.max(v1.z).max(v2.z),
+        ),
+    )
+}
+
+fn cube_overlaps_bounds(cube: &Cube, min: &Vec3, max: &Vec3) -> bool {
+    !(max.x < cube.min.x
+        || min.x > cube.max.x
+        || max.y < cube.min.y
+        || min.y > cube.max.y
+        || max.z < cube.min.z
+        || min.z > cube.max.z)
 }
 
 fn triangle_cube_intersection(cube: &Cube, tri_vertices: &[Vec3]) -> bool {
@@ -510,4 +1249,316 @@ mod tests {
         let t = intersect_cube_inverse_ray(&ray, &cube);
         assert!(t.is_none());
     }
+
+    #[test]
+    fn test_refit_follows_moved_geometry() {
+        use crate::scene::{Geometry, Material};
+        use crate::vecmath::Matrix;
+
+        let triangle = |x: f32| {
+            Geometry::new(
+                vec![
+                    Vec3::new(x - 1.0, -1.0, 5.0),
+                    Vec3::new(x + 1.0, -1.0, 5.0),
+                    Vec3::new(x, 1.0, 5.0),
+                ],
+                Material::default(),
+            )
+        };
+        let mut scene = Scene {
+            geometries: (0..16).map(|i| triangle(3.0 * i as f32)).collect(),
+            meshes: vec![],
+            instances: vec![],
+            shapes: vec![],
+            lights: vec![],
+            cameras: vec![],
+            textures: vec![],
+            time_range: crate::scene::ALL_TIME,
+        };
+        let mut octtree = OctTreeIntersector::with_config(&scene, &config(4));
+        assert_eq!(octtree.refit(&scene), 1.0);
+
+        let dir = Vec3::new(0.0, 0.0, 1.0);
+        let old_pos = Ray::new(Vec3::new(0.0, 0.0, 0.0), dir);
+        let new_pos = Ray::new(Vec3::new(0.0, 20.0, 0.0), dir);
+        scene.geometries[0].set_transform(&Matrix::translate(&Vec3::new(0.0, 20.0, 0.0)));
+        let quality = octtree.refit(&scene);
+
+        // the leaf the triangle moved out of has stretched
+        assert!(quality < 1.0);
+        assert!(octtree.intersect_ray(&scene, &old_pos).is_none());
+        assert!(octtree.intersect_ray(&scene, &new_pos).is_some());
+        let next = Ray::new(Vec3::new(3.0, 0.0, 0.0), dir);
+        assert!(octtree.intersect_ray(&scene, &next).is_some());
+    }
+
+    fn config(triangles_per_leaf: usize) -> OctTreeConfig {
+        OctTreeConfig {
+            triangles_per_leaf,
+            ..OctTreeConfig::default()
+        }
+    }
+
+    // a size x size grid of quads at slightly different depths
+    fn grid_scene(size: usize) -> Scene {
+        use crate::scene::{Geometry, Material};
+
+        let vertices = (0..size * size)
+            .flat_map(|i| {
+                let (x, y) = ((i % size) as f32, (i / size) as f32);
+                let z = 5.0 + 0.1 * ((x + y) % 3.0);
+                vec![
+                    Vec3::new(x, y, z),
+                    Vec3::new(x + 1.0, y, z),
+                    Vec3::new(x, y + 1.0, z),
+                    Vec3::new(x + 1.0, y, z),
+                    Vec3::new(x + 1.0, y + 1.0, z),
+                    Vec3::new(x, y + 1.0, z),
+                ]
+            })
+            .collect();
+        Scene {
+            geometries: vec![Geometry::new(vertices, Material::default())],
+            meshes: vec![],
+            instances: vec![],
+            shapes: vec![],
+            lights: vec![],
+            cameras: vec![],
+            textures: vec![],
+            time_range: crate::scene::ALL_TIME,
+        }
+    }
+
+    fn assert_matches_no_acceleration(scene: &Scene, octtree: &OctTreeIntersector, size: usize) {
+        use crate::raytracer::accel_intersect::no_acceleration_intersector::NoAccelerationIntersector;
+
+        let no_accel = NoAccelerationIntersector::new(scene);
+        for i in 0..200 {
+            let pos = Vec3::new(
+                0.31 * i as f32 % size as f32,
+                0.17 * i as f32 % size as f32,
+                0.0,
+            );
+            let ray = Ray::new(pos, Vec3::new(0.0, 0.0, 1.0));
+            let expected = no_accel
+                .intersect_ray(scene, &ray)
+                .map(|hit| hit.hit_info.t);
+            let t = octtree.intersect_ray(scene, &ray).map(|hit| hit.hit_info.t);
+            match (t, expected) {
+                (Some(t), Some(expected)) => assert!((t - expected).abs() < 1e-4),
+                (t, expected) => assert_eq!(t.is_some(), expected.is_some()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parallel_build_matches_no_acceleration() {
+        // enough triangles for the top levels to be built in parallel
+        let size = 64;
+        let scene = grid_scene(size);
+        assert!(2 * size * size >= PARALLEL_BUILD_MIN_TRIANGLES);
+        let octtree = OctTreeIntersector::with_config(&scene, &config(8));
+        assert_matches_no_acceleration(&scene, &octtree, size);
+    }
+
+    #[test]
+    fn test_loose_octtree_matches_no_acceleration() {
+        let size = 16;
+        let scene = grid_scene(size);
+        let tight = OctTreeIntersector::with_config(&scene, &config(4));
+        let loose_config = OctTreeConfig {
+            loose_factor: 1.5,
+            max_depth: 4,
+            ..config(4)
+        };
+        let loose = OctTreeIntersector::with_config(&scene, &loose_config);
+        assert_matches_no_acceleration(&scene, &loose, size);
+
+        let tight_stats = tight.stats();
+        let loose_stats = loose.stats();
+        assert_eq!(loose_stats.triangles, 2 * size * size);
+        assert!(loose_stats.leaf_depths.len() <= loose_config.max_depth + 1);
+        assert!(loose_stats.duplication_factor() < tight_stats.duplication_factor());
+        assert_eq!(loose_stats.rays, 200);
+        assert!(loose_stats.triangles_per_ray() > 0.0);
+        assert_eq!(tight_stats.rays, 0);
+        assert_eq!(
+            tight_stats.leaves,
+            tight_stats.leaf_depths.iter().sum::<usize>()
+        );
+    }
+
+    #[test]
+    fn test_loose_octtree_with_scattered_triangles() {
+        use crate::scene::{Geometry, Material};
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        // triangles at any angle and offset, many of them reaching into the margins of the cubes
+        let size = 16;
+        let mut rng = StdRng::seed_from_u64(3);
+        let vertices = (0..400)
+            .flat_map(|_| {
+                let center = Vec3::new(
+                    rng.random_range(0.0..size as f32),
+                    rng.random_range(0.0..size as f32),
+                    rng.random_range(3.0..7.0),
+                );
+                (0..3)
+                    .map(|_| {
+                        let offset = Vec3::new(
+                            rng.random_range(-1.0..1.0),
+                            rng.random_range(-1.0..1.0),
+                            rng.random_range(-1.0..1.0),
+                        );
+                        center + offset
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        let scene = Scene {
+            geometries: vec![Geometry::new(vertices, Material::default())],
+            ..grid_scene(0)
+        };
+        for loose_factor in [1.25, 1.5, 2.0].iter() {
+            let loose_config = OctTreeConfig {
+                loose_factor: *loose_factor,
+                max_depth: 5,
+                ..config(4)
+            };
+            let loose = OctTreeIntersector::with_config(&scene, &loose_config);
+            assert!(loose.stats().leaf_depths.len() > 3);
+            assert_matches_no_acceleration(&scene, &loose, size);
+        }
+    }
+
+    #[test]
+    fn test_watertight_octtree_matches_no_acceleration() {
+        let size = 16;
+        let scene = grid_scene(size);
+        let watertight_config = OctTreeConfig {
+            triangle_test: TriangleTest::Watertight,
+            ..config(4)
+        };
+        let octtree = OctTreeIntersector::with_config(&scene, &watertight_config);
+        assert_matches_no_acceleration(&scene, &octtree, size);
+    }
+
+    #[test]
+    fn test_packet_matches_single_rays() {
+        let size = 16;
+        let scene = grid_scene(size);
+        let loose_config = OctTreeConfig {
+            loose_factor: 1.5,
+            ..config(4)
+        };
+        for config in [config(4), loose_config].iter() {
+            let octtree = OctTreeIntersector::with_config(&scene, config);
+            // diverging like camera rays, some of them miss the grid
+            for i in 0..100 {
+                let rays = [0, 1, 2, 3].map(|lane| {
+                    let (x, y) = ((i % 10) as f32, (i / 10 * 4 + lane) as f32 / 4.0);
+                    let dir = Vec3::new(0.37 * x - 1.0, 0.41 * y - 1.0, 1.0);
+                    Ray::new(Vec3::new(4.0, 4.0, 0.0), dir)
+                });
+                let hits = octtree.intersect_packet(&scene, &rays);
+                for (ray, hit) in rays.iter().zip(hits.iter()) {
+                    let expected = octtree.intersect_ray(&scene, ray);
+                    assert_eq!(
+                        hit.as_ref().map(|hit| hit.hit_info.t),
+                        expected.map(|hit| hit.hit_info.t)
+                    );
+                }
+            }
+            let stats = octtree.stats();
+            assert_eq!(stats.rays, 800);
+            assert!(stats.nodes_visited > 0);
+        }
+    }
+
+    #[test]
+    fn test_moving_triangle_hit_at_ray_time() {
+        use crate::scene::motion::{Keyframe, Motion};
+        use crate::scene::{Geometry, Material};
+        use crate::vecmath::Matrix;
+
+        let mut geom = Geometry::new(
+            vec![
+                Vec3::new(-1.0, -1.0, 5.0),
+                Vec3::new(1.0, -1.0, 5.0),
+                Vec3::new(0.0, 1.0, 5.0),
+            ],
+            Material::default(),
+        );
+        geom.set_motion(Some(Motion::new(vec![
+            Keyframe::new(0.0, Matrix::ident()),
+            Keyframe::new(1.0, Matrix::translate(&Vec3::new(10.0, 0.0, 0.0))),
+        ])));
+        let scene = Scene {
+            geometries: vec![geom],
+            lights: vec![],
+            meshes: vec![],
+            instances: vec![],
+            shapes: vec![],
+            cameras: vec![],
+            textures: vec![],
+            time_range: crate::scene::ALL_TIME,
+        };
+        let octtree = OctTreeIntersector::with_config(&scene, &config(1));
+
+        let dir = Vec3::new(0.0, 0.0, 1.0);
+        let at_start = Ray::with_time(Vec3::new(0.0, 0.0, 0.0), dir, 0.0);
+        let at_end = Ray::with_time(Vec3::new(10.0, 0.0, 0.0), dir, 1.0);
+        assert!(octtree.intersect_ray(&scene, &at_start).is_some());
+        assert!(octtree.intersect_ray(&scene, &at_end).is_some());
+        let missed = Ray::with_time(Vec3::new(10.0, 0.0, 0.0), dir, 0.0);
+        assert!(octtree.intersect_ray(&scene, &missed).is_none());
+    }
+
+    #[test]
+    fn test_turning_triangle_hit_mid_shutter() {
+        use crate::raytracer::accel_intersect::no_acceleration_intersector::NoAccelerationIntersector;
+        use crate::scene::motion::{Keyframe, Motion};
+        use crate::scene::{Geometry, Material};
+        use crate::vecmath::Matrix;
+
+        // half a turn around y, from around (4, 0, 0) through (0, 0, +-4) to (-4, 0, 0)
+        let mut turning = Geometry::new(
+            vec![
+                Vec3::new(4.0, -1.0, -1.0),
+                Vec3::new(4.0, -1.0, 1.0),
+                Vec3::new(4.0, 1.0, 0.0),
+            ],
+            Material::default(),
+        );
+        turning.set_motion(Some(Motion::new(vec![
+            Keyframe::new(0.0, Matrix::ident()),
+            Keyframe::new(1.0, Matrix::rot_y(std::f32::consts::PI)),
+        ])));
+        let scene = Scene {
+            geometries: vec![turning, grid_scene(4).geometries.remove(0)],
+            time_range: (0.0, 1.0),
+            ..grid_scene(0)
+        };
+        let octtree = OctTreeIntersector::with_config(&scene, &config(1));
+        let no_accel = NoAccelerationIntersector::new(&scene);
+
+        let mut hits = 0;
+        for i in 0..=20 {
+            let time = i as f32 / 20.0;
+            for z in [-1.0, 1.0].iter() {
+                // toward the center from beyond where the triangle passes, along z
+                let ray =
+                    Ray::with_time(Vec3::new(0.0, 0.0, 10.0 * z), Vec3::new(0.0, 0.0, -z), time);
+                let expected = no_accel
+                    .intersect_ray(&scene, &ray)
+                    .map(|hit| hit.hit_info.t);
+                let t = octtree
+                    .intersect_ray(&scene, &ray)
+                    .map(|hit| hit.hit_info.t);
+                assert_eq!(t, expected);
+                hits += expected.is_some() as usize;
+            }
+        }
+        assert!(hits > 0);
+    }
 }
@@ -0,0 +1,123 @@
+use super::cache::{CacheError, CacheReader, CacheWriter};
+use crate::raytracer::intersect::{self, TriangleTest};
+use crate::scene::Geometry;
+use crate::vecmath::Vec3;
+
+// a triangle of a geometry, by the index of its first vertex
+#[derive(Clone, Copy)]
+pub struct TriangleIndex {
+    pub geom_idx: usize,
+    pub tri_idx: usize,
+}
+impl TriangleIndex {
+    pub fn new(geom_idx: usize, tri_idx: usize) -> Self {
+        TriangleIndex { geom_idx, tri_idx }
+    }
+}
+
+// The triangles of an acceleration structure in the order its leaves use them, so each leaf's
+// triangles are a range, and testing them reads straight through memory. The corners are stored
+// as the triangle test uses them (see intersect::prepare_triangle), one array per coordinate.
+// Moving triangles are stored posed as their geometry is, the test has to use them at the ray's
+// time instead.
+#[derive(Default)]
+pub struct TriangleBuffer {
+    indices: Vec<TriangleIndex>,
+    // corners[corner][axis][triangle]
+    corners: [[Vec<f32>; 3]; 3],
+}
+
+impl TriangleBuffer {
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    pub fn index(&self, i: usize) -> &TriangleIndex {
+        &self.indices[i]
+    }
+
+    pub fn corners(&self, i: usize) -> [Vec3; 3] {
+        let corner = |c: usize| {
+            Vec3::new(
+                self.corners[c][0][i],
+                self.corners[c][1][i],
+                self.corners[c][2][i],
+            )
+        };
+        [corner(0), corner(1), corner(2)]
+    }
+
+    pub fn push(&mut self, index: TriangleIndex, geometries: &[Geometry], test: TriangleTest) {
+        self.indices.push(index);
+        for corners in self.corners.iter_mut() {
+            for axis in corners.iter_mut() {
+                axis.push(0.0);
+            }
+        }
+        self.prepare(self.len() - 1, geometries, test);
+    }
+
+    pub fn append(&mut self, other: TriangleBuffer) {
+        self.indices.extend(other.indices);
+        for (corners, other_corners) in self.corners.iter_mut().zip(other.corners) {
+            for (axis, other_axis) in corners.iter_mut().zip(other_corners) {
+                axis.extend(other_axis);
+            }
+        }
+    }
+
+    // after the geometries moved, the triangles must be the ones the buffer was made from
+    pub fn update(&mut self, geometries: &[Geometry], test: TriangleTest) {
+        for i in 0..self.len() {
+            self.prepare(i, geometries, test);
+        }
+    }
+
+    fn prepare(&mut self, i: usize, geometries: &[Geometry], test: TriangleTest) {
+        let index = self.indices[i];
+        let vertices = &geometries[index.geom_idx].transformed_vertices[index.tri_idx..];
+        let prepared = intersect::prepare_triangle(test, &vertices[0], &vertices[1], &vertices[2]);
+        for (corners, corner) in self.corners.iter_mut().zip(prepared.iter()) {
+            corners[0][i] = corner.x;
+            corners[1][i] = corner.y;
+            corners[2][i] = corner.z;
+        }
+    }
+
+    pub fn memory_bytes(&self) -> usize {
+        self.indices.capacity() * std::mem::size_of::<TriangleIndex>()
+            + self
+                .corners
+                .iter()
+                .flatten()
+                .map(|axis| axis.capacity() * std::mem::size_of::<f32>())
+                .sum::<usize>()
+    }
+
+    pub fn write_cache(&self, writer: &mut CacheWriter) {
+        writer.usize(self.len());
+        for i in 0..self.len() {
+            writer.usize(self.indices[i].geom_idx);
+            writer.usize(self.indices[i].tri_idx);
+            for corner in self.corners(i).iter() {
+                writer.vec3(corner);
+            }
+        }
+    }
+
+    pub fn read_cache(reader: &mut CacheReader) -> Result<Self, CacheError> {
+        let mut buffer = TriangleBuffer::default();
+        for _ in 0..reader.count(52)? {
+            buffer
+                .indices
+                .push(TriangleIndex::new(reader.usize()?, reader.usize()?));
+            for corners in buffer.corners.iter_mut() {
+                let corner = reader.vec3()?;
+                corners[0].push(corner.x);
+                corners[1].push(corner.y);
+                corners[2].push(corner.z);
+            }
+        }
+        Ok(buffer)
+    }
+}
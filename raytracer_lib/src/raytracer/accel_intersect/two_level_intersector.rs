@@ -0,0 +1,625 @@
+use std::time::Duration;
+
+use super::accel_stats::{AccelStats, RayCounters, TraversalCounters};
+use super::cache::{CacheError, CacheReader, CacheWriter, CachedIntersector};
+use super::oct_tree_intersector::{
+    intersect_cube_inverse_ray, read_config, write_config, Cube, OctTreeConfig,
+};
+use super::{intersect_shape, timed, Intersector, OctTreeIntersector, PACKET_SIZE};
+use crate::raytracer::Hit;
+use crate::scene::Scene;
+use crate::vecmath::{Ray, Vec3};
+
+// Instances are found through a bounding volume hierarchy (the top level), each instanced mesh has
+// its own octtree in object space (the bottom level) that is shared by all instances of the mesh.
+// Geometry that isn't instanced goes into a regular octtree. Shapes are in the top level next to
+// the instances, except for infinite planes which every ray is tested against.
+pub struct TwoLevelIntersector {
+    geometries: OctTreeIntersector,
+    meshes: Vec<OctTreeIntersector>,
+    nodes: Vec<TopLevelNode>,
+    unbounded_shapes: Vec<usize>,
+    config: OctTreeConfig,
+    built_cost: f32,
+    build_time: Duration,
+    counters: TraversalCounters,
+}
+
+struct TopLevelNode {
+    cube: Cube,
+    kind: TopLevelNodeKind,
+}
+
+#[derive(Clone, Copy)]
+enum TopLevelNodeKind {
+    Instance(usize),
+    Shape(usize),
+    Node([usize; 2]), //indices to nodes vec
+}
+
+impl TwoLevelIntersector {
+    pub fn with_triangles_per_leaf(scene: &Scene, triangles_per_leaf: usize) -> Self {
+        let config = OctTreeConfig {
+            triangles_per_leaf,
+            ..OctTreeConfig::default()
+        };
+        Self::with_config(scene, &config)
+    }
+
+    pub fn with_config(scene: &Scene, config: &OctTreeConfig) -> Self {
+        let geometries = OctTreeIntersector::with_config(scene, config);
+        let meshes: Vec<OctTreeIntersector> = scene
+            .meshes
+            .iter()
+            .map(|mesh| {
+                OctTreeIntersector::from_geometries(
+                    std::slice::from_ref(mesh),
+                    scene.time_range,
+                    config,
+                )
+            })
+            .collect();
+
+        let (nodes, top_level_build_time) = timed(|| {
+            let instance_bounds =
+                scene
+                    .instances
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(idx, instance)| {
+                        let (min, max) = scene.instance_bounds(instance)?;
+                        Some((TopLevelNodeKind::Instance(idx), Cube::new(min, max)))
+                    });
+            let shape_bounds = scene.shapes.iter().enumerate().filter_map(|(idx, shape)| {
+                let (min, max) = shape.bounds()?;
+                Some((TopLevelNodeKind::Shape(idx), Cube::new(min, max)))
+            });
+            let mut leaf_bounds = instance_bounds.chain(shape_bounds).collect::<Vec<_>>();
+            let mut nodes = vec![];
+            if !leaf_bounds.is_empty() {
+                build_node(&mut leaf_bounds, &mut nodes);
+            }
+            nodes
+        });
+        let unbounded_shapes = (0..scene.shapes.len())
+            .filter(|idx| scene.shapes[*idx].bounds().is_none())
+            .collect();
+        let build_time = meshes
+            .iter()
+            .map(|octtree| octtree.build_time())
+            .sum::<Duration>()
+            + geometries.build_time()
+            + top_level_build_time;
+
+        let mut two_level = TwoLevelIntersector {
+            geometries,
+            meshes,
+            nodes,
+            unbounded_shapes,
+            config: *config,
+            built_cost: 0.0,
+            build_time,
+            counters: TraversalCounters::default(),
+        };
+        two_level.built_cost = two_level.cost();
+        two_level
+    }
+
+    // sum of the node surface areas relative to the area of the instances and shapes themselves,
+    // grows as moved instances stretch the nodes they are in
+    fn cost(&self) -> f32 {
+        let instances_area: f32 = self
+            .nodes
+            .iter()
+            .filter(|node| !matches!(node.kind, TopLevelNodeKind::Node(_)))
+            .map(|node| node.cube.surface_area())
+            .sum();
+        if instances_area <= 0.0 {
+            return 0.0;
+        }
+        self.nodes
+            .iter()
+            .map(|node| node.cube.surface_area())
+            .sum::<f32>()
+            / instances_area
+    }
+
+    // sets the node bounds to the current instance bounds, returns the bounds of the node
+    fn refit_node(&mut self, scene: &Scene, node_idx: usize) -> Cube {
+        let cube = match self.nodes[node_idx].kind {
+            TopLevelNodeKind::Instance(instance_idx) => {
+                match scene.instance_bounds(&scene.instances[instance_idx]) {
+                    Some((min, max)) => Cube::new(min, max),
+                    None => self.nodes[node_idx].cube.clone(),
+                }
+            }
+            TopLevelNodeKind::Shape(shape_idx) => match scene.shapes[shape_idx].bounds() {
+                Some((min, max)) => Cube::new(min, max),
+                None => self.nodes[node_idx].cube.clone(),
+            },
+            TopLevelNodeKind::Node([left, right]) => {
+                let left = self.refit_node(scene, left);
+                left.union(&self.refit_node(scene, right))
+            }
+        };
+        self.nodes[node_idx].cube = cube.clone();
+        cube
+    }
+
+    // the instances, shapes and planes
+    fn intersect_top_level(
+        &self,
+        scene: &Scene,
+        ray: &Ray,
+        closest_hit: &mut Option<Hit>,
+        counters: &mut RayCounters,
+    ) {
+        for shape_idx in &self.unbounded_shapes {
+            intersect_shape(scene, *shape_idx, ray, closest_hit);
+        }
+        if let Some(trunk) = self.nodes.first() {
+            let inv_ray = Ray::new(
+                ray.pos,
+                Vec3::new(1.0 / ray.dir.x, 1.0 / ray.dir.y, 1.0 / ray.dir.z),
+            );
+            if intersect_cube_inverse_ray(&inv_ray, &trunk.cube).is_some() {
+                self.intersect_node(scene, ray, &inv_ray, 0, closest_hit, counters);
+            }
+        }
+    }
+
+    fn intersect_node(
+        &self,
+        scene: &Scene,
+        ray: &Ray,
+        inv_ray: &Ray,
+        node_idx: usize,
+        closest_hit: &mut Option<Hit>,
+        counters: &mut RayCounters,
+    ) {
+        counters.nodes_visited += 1;
+        match self.nodes[node_idx].kind {
+            TopLevelNodeKind::Instance(instance_idx) => {
+                let instance = &scene.instances[instance_idx];
+                let object_ray = instance.ray_to_object(ray);
+                let mesh = std::slice::from_ref(&scene.meshes[instance.mesh_index]);
+                if let Some(mut hit) = self.meshes[instance.mesh_index].intersect_geometries(
+                    mesh,
+                    &object_ray,
+                    counters,
+                ) {
+                    if closer(&hit, closest_hit) {
+                        hit.geometry_index = instance.mesh_index;
+                        hit.instance_index = Some(instance_idx);
+                        *closest_hit = Some(hit);
+                    }
+                }
+            }
+            TopLevelNodeKind::Shape(shape_idx) => {
+                intersect_shape(scene, shape_idx, ray, closest_hit);
+            }
+            TopLevelNodeKind::Node(child_indices) => {
+                let mut distances = child_indices
+                    .iter()
+                    .filter_map(|idx| {
+                        intersect_cube_inverse_ray(inv_ray, &self.nodes[*idx].cube)
+                            .map(|t| (*idx, t))
+                    })
+                    .collect::<Vec<_>>();
+                distances.sort_by(|a, b| a.1.total_cmp(&b.1));
+                for (child_idx, t) in distances {
+                    // a hit closer than the box entry can't be beaten by anything inside it, nor
+                    // by the children after it, which are entered even further along the ray
+                    if let Some(hit) = closest_hit {
+                        if hit.hit_info.t < t {
+                            break;
+                        }
+                    }
+                    self.intersect_node(scene, ray, inv_ray, child_idx, closest_hit, counters);
+                }
+            }
+        }
+    }
+}
+
+impl Intersector for TwoLevelIntersector {
+    fn new(scene: &Scene) -> Self {
+        TwoLevelIntersector::with_config(scene, &OctTreeConfig::default())
+    }
+
+    fn rebuild(&mut self, scene: &Scene) {
+        *self = TwoLevelIntersector::with_config(scene, &self.config);
+    }
+
+    fn build_time(&self) -> Duration {
+        self.build_time
+    }
+
+    // meshes are counted once however many instances they have, top level nodes count as nodes
+    fn stats(&self) -> AccelStats {
+        let mut stats = self.geometries.structure_stats();
+        for octtree in &self.meshes {
+            stats.add_structure(&octtree.structure_stats());
+        }
+        stats.nodes += self.nodes.len();
+        stats.memory_bytes += self.nodes.capacity() * std::mem::size_of::<TopLevelNode>();
+        self.counters.add_to(&mut stats);
+        stats
+    }
+
+    // the worst quality of the refitted parts, meshes only change in object space if they move
+    fn refit(&mut self, scene: &Scene) -> f32 {
+        let mut quality = self.geometries.refit(scene);
+        for (octtree, mesh) in self.meshes.iter_mut().zip(scene.meshes.iter()) {
+            if mesh.motion.is_some() {
+                let mesh = std::slice::from_ref(mesh);
+                quality = quality.min(octtree.refit_geometries(mesh, scene.time_range));
+            }
+        }
+        if !self.nodes.is_empty() {
+            self.refit_node(scene, 0);
+            let cost = self.cost();
+            if cost > 0.0 {
+                quality = quality.min(self.built_cost / cost);
+            }
+        }
+        quality
+    }
+
+    fn intersect_ray(&self, scene: &Scene, ray: &Ray) -> Option<Hit> {
+        let mut counters = RayCounters::default();
+        let mut closest_hit =
+            self.geometries
+                .intersect_geometries(&scene.geometries, ray, &mut counters);
+        self.intersect_top_level(scene, ray, &mut closest_hit, &mut counters);
+        self.counters.add(&counters);
+        closest_hit
+    }
+
+    // the geometries are intersected as a packet, the top level ray by ray since each ray has its
+    // own path through it
+    fn intersect_packet(
+        &self,
+        scene: &Scene,
+        rays: &[Ray; PACKET_SIZE],
+    ) -> [Option<Hit>; PACKET_SIZE] {
+        let mut counters: [RayCounters; PACKET_SIZE] = Default::default();
+        let mut hits =
+            self.geometries
+                .intersect_geometries_packet(&scene.geometries, rays, &mut counters);
+        for ((ray, closest_hit), ray_counters) in
+            rays.iter().zip(hits.iter_mut()).zip(counters.iter_mut())
+        {
+            self.intersect_top_level(scene, ray, closest_hit, ray_counters);
+            self.counters.add(ray_counters);
+        }
+        hits
+    }
+}
+
+impl CachedIntersector for TwoLevelIntersector {
+    const CACHE_KIND: &'static str = "two level";
+
+    fn write_cache(&self, writer: &mut CacheWriter) {
+        self.geometries.write_cache(writer);
+        writer.usize(self.meshes.len());
+        for octtree in &self.meshes {
+            octtree.write_cache(writer);
+        }
+        writer.usize(self.nodes.len());
+        for node in &self.nodes {
+            writer.vec3(&node.cube.min);
+            writer.vec3(&node.cube.max);
+            match node.kind {
+                TopLevelNodeKind::Instance(instance_idx) => {
+                    writer.u32(0);
+                    writer.usize(instance_idx);
+                }
+                TopLevelNodeKind::Shape(shape_idx) => {
+                    writer.u32(2);
+                    writer.usize(shape_idx);
+                }
+                TopLevelNodeKind::Node([left, right]) => {
+                    writer.u32(1);
+                    writer.usize(left);
+                    writer.usize(right);
+                }
+            }
+        }
+        writer.usize(self.unbounded_shapes.len());
+        for shape_idx in &self.unbounded_shapes {
+            writer.usize(*shape_idx);
+        }
+        write_config(writer, &self.config);
+        writer.f32(self.built_cost);
+    }
+
+    fn read_cache(reader: &mut CacheReader) -> Result<Self, CacheError> {
+        let geometries = OctTreeIntersector::read_cache(reader)?;
+        let num_meshes = reader.count(1)?;
+        let meshes = (0..num_meshes)
+            .map(|_| OctTreeIntersector::read_cache(reader))
+            .collect::<Result<Vec<_>, CacheError>>()?;
+        let num_nodes = reader.count(36)?;
+        let mut nodes = Vec::with_capacity(num_nodes);
+        for node_idx in 0..num_nodes {
+            let cube = Cube::new(reader.vec3()?, reader.vec3()?);
+            let kind = match reader.u32()? {
+                0 => TopLevelNodeKind::Instance(reader.usize()?),
+                1 => {
+                    let child_indices = [reader.index(num_nodes)?, reader.index(num_nodes)?];
+                    // children come after their parent, so traversal always ends
+                    if child_indices.iter().any(|idx| *idx <= node_idx) {
+                        return Err(CacheError::Corrupt("top level node order".to_string()));
+                    }
+                    TopLevelNodeKind::Node(child_indices)
+                }
+                2 => TopLevelNodeKind::Shape(reader.usize()?),
+                _ => return Err(CacheError::Corrupt("top level node kind".to_string())),
+            };
+            nodes.push(TopLevelNode { cube, kind });
+        }
+        let num_unbounded_shapes = reader.count(8)?;
+        let unbounded_shapes = (0..num_unbounded_shapes)
+            .map(|_| reader.usize())
+            .collect::<Result<Vec<_>, CacheError>>()?;
+        Ok(TwoLevelIntersector {
+            geometries,
+            meshes,
+            nodes,
+            unbounded_shapes,
+            config: read_config(reader)?,
+            built_cost: reader.f32()?,
+            build_time: Duration::ZERO,
+            counters: TraversalCounters::default(),
+        })
+    }
+}
+
+fn closer(hit: &Hit, closest_hit: &Option<Hit>) -> bool {
+    match closest_hit {
+        None => true,
+        Some(closest_hit) => hit.hit_info.t < closest_hit.hit_info.t,
+    }
+}
+
+// splits the instances and shapes at the median of the longest axis until each node holds one,
+// returns the index of the created node
+fn build_node(
+    leaf_bounds: &mut [(TopLevelNodeKind, Cube)],
+    nodes: &mut Vec<TopLevelNode>,
+) -> usize {
+    let cube = leaf_bounds
+        .iter()
+        .skip(1)
+        .fold(leaf_bounds[0].1.clone(), |cube, (_, other)| {
+            cube.union(other)
+        });
+
+    let node_idx = nodes.len();
+    if let [(kind, _)] = leaf_bounds {
+        nodes.push(TopLevelNode { cube, kind: *kind });
+        return node_idx;
+    }
+    nodes.push(TopLevelNode {
+        cube: cube.clone(),
+        kind: TopLevelNodeKind::Node([0; 2]),
+    });
+
+    let extent = cube.max - cube.min;
+    let axis = |v: &Vec3| {
+        if extent.x >= extent.y && extent.x >= extent.z {
+            v.x
+        } else if extent.y >= extent.z {
+            v.y
+        } else {
+            v.z
+        }
+    };
+    leaf_bounds.sort_by(|a, b| axis(&(a.1.min + a.1.max)).total_cmp(&axis(&(b.1.min + b.1.max))));
+    let (left, right) = leaf_bounds.split_at_mut(leaf_bounds.len() / 2);
+    let child_indices = [build_node(left, nodes), build_node(right, nodes)];
+    nodes[node_idx].kind = TopLevelNodeKind::Node(child_indices);
+    node_idx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raytracer::accel_intersect::no_acceleration_intersector::NoAccelerationIntersector;
+    use crate::scene::{Geometry, Instance, Material};
+    use crate::vecmath::Matrix;
+
+    // ten instances of a triangle, the i:th centered at x = 3 * i
+    fn instanced_scene() -> Scene {
+        let mesh = Geometry::new(
+            vec![
+                Vec3::new(-1.0, -1.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0),
+                Vec3::new(1.0, -1.0, 0.0),
+            ],
+            Material::default(),
+        );
+        let instances = (0..10)
+            .map(|i| {
+                let offset = Vec3::new(3.0 * i as f32, 0.0, 5.0 + i as f32);
+                Instance::new(
+                    0,
+                    Matrix::rot_y(0.1 * i as f32) * Matrix::translate(&offset),
+                )
+                .unwrap()
+            })
+            .collect();
+        Scene {
+            geometries: vec![],
+            meshes: vec![mesh],
+            instances,
+            shapes: vec![],
+            lights: vec![],
+            cameras: vec![],
+            textures: vec![],
+            time_range: crate::scene::ALL_TIME,
+        }
+    }
+
+    #[test]
+    fn test_instances_match_no_acceleration() {
+        let scene = instanced_scene();
+        let two_level = TwoLevelIntersector::with_triangles_per_leaf(&scene, 1);
+        let no_accel = NoAccelerationIntersector::new(&scene);
+
+        for i in 0..10 {
+            let ray = Ray::new(
+                Vec3::new(3.0 * i as f32, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 1.0),
+            );
+            let expected = no_accel.intersect_ray(&scene, &ray).unwrap();
+            let hit = two_level.intersect_ray(&scene, &ray).unwrap();
+            assert_eq!(hit.instance_index, Some(i));
+            assert_eq!(hit.instance_index, expected.instance_index);
+            assert!((hit.hit_info.t - expected.hit_info.t).abs() < 1e-4);
+        }
+        let missed = Ray::new(Vec3::new(-5.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0));
+        assert!(two_level.intersect_ray(&scene, &missed).is_none());
+    }
+
+    #[test]
+    fn test_packet_matches_single_rays() {
+        let mut scene = instanced_scene();
+        let mut geometry = scene.meshes[0].clone();
+        geometry.set_transform(&Matrix::translate(&Vec3::new(6.0, 0.0, 2.0)));
+        scene.geometries.push(geometry);
+        let two_level = TwoLevelIntersector::with_triangles_per_leaf(&scene, 1);
+
+        for i in 0..4 {
+            let rays = [0, 1, 2, 3].map(|lane| {
+                let x = 3.0 * (i * 4 + lane) as f32 / 1.5 - 2.0;
+                Ray::new(
+                    Vec3::new(x, 0.1 * lane as f32, 0.0),
+                    Vec3::new(0.0, 0.0, 1.0),
+                )
+            });
+            let hits = two_level.intersect_packet(&scene, &rays);
+            for (ray, hit) in rays.iter().zip(hits.iter()) {
+                let expected = two_level.intersect_ray(&scene, ray);
+                let hit = hit.as_ref().map(|hit| (hit.hit_info.t, hit.instance_index));
+                let expected = expected.map(|hit| (hit.hit_info.t, hit.instance_index));
+                assert_eq!(hit, expected);
+            }
+        }
+        assert_eq!(two_level.stats().rays, 32);
+    }
+
+    #[test]
+    fn test_shapes_match_no_acceleration() {
+        use crate::scene::shape::Shape;
+
+        let mut scene = instanced_scene();
+        let material = Material::default();
+        scene.shapes = vec![
+            Shape::sphere(Vec3::new(6.0, 0.0, 6.0), 1.5, material.clone()),
+            Shape::plane(
+                Vec3::new(0.0, 0.0, 20.0),
+                Vec3::new(0.0, 0.0, -1.0),
+                material.clone(),
+            ),
+            Shape::disk(
+                Vec3::new(12.0, 0.5, 3.0),
+                Vec3::new(0.0, 1.0, -1.0),
+                1.0,
+                material.clone(),
+            ),
+            Shape::quad(
+                Vec3::new(19.8, -1.0, 4.0),
+                Vec3::new(4.0, 0.0, 0.0),
+                Vec3::new(0.0, 2.0, 0.0),
+                material,
+            ),
+        ];
+        let two_level = TwoLevelIntersector::with_triangles_per_leaf(&scene, 1);
+        let no_accel = NoAccelerationIntersector::new(&scene);
+
+        let mut shape_hits = 0;
+        for i in 0..60 {
+            let ray = Ray::new(
+                Vec3::new(0.5 * i as f32 - 1.0, 0.3, 0.0),
+                Vec3::new(0.0, 0.0, 1.0),
+            );
+            let expected = no_accel.intersect_ray(&scene, &ray).unwrap();
+            let hit = two_level.intersect_ray(&scene, &ray).unwrap();
+            assert_eq!(hit.hit_info.t, expected.hit_info.t);
+            assert_eq!(hit.shape_index, expected.shape_index);
+            assert_eq!(hit.instance_index, expected.instance_index);
+            if hit.shape_index.is_some() {
+                shape_hits += 1;
+            }
+        }
+        assert!(shape_hits > 30);
+    }
+
+    #[test]
+    fn test_refit_moved_instance() {
+        let mut scene = instanced_scene();
+        let mut two_level = TwoLevelIntersector::with_triangles_per_leaf(&scene, 1);
+        let offset = Matrix::translate(&Vec3::new(0.0, 50.0, 0.0));
+        assert!(scene.set_object_transform(crate::scene::SceneObject::Instance(0), &offset));
+        assert!(two_level.refit(&scene) < 1.0);
+
+        let dir = Vec3::new(0.0, 0.0, 1.0);
+        let old_pos = Ray::new(Vec3::new(0.0, 0.0, 0.0), dir);
+        let new_pos = Ray::new(Vec3::new(0.0, 50.0, 0.0), dir);
+        assert!(two_level.intersect_ray(&scene, &old_pos).is_none());
+        assert_eq!(
+            two_level
+                .intersect_ray(&scene, &new_pos)
+                .unwrap()
+                .instance_index,
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn test_cache_round_trip() {
+        use crate::raytracer::accel_intersect::cache;
+
+        let mut scene = instanced_scene();
+        scene.geometries.push(scene.meshes[0].clone());
+        let two_level = TwoLevelIntersector::with_triangles_per_leaf(&scene, 1);
+        let key = cache::cache_key(&scene, &two_level.config);
+        let path = std::env::temp_dir().join(format!("test_cache_{}.accel", std::process::id()));
+        cache::save(&path, &two_level, key).unwrap();
+
+        let loaded: TwoLevelIntersector = cache::load(&path, key).unwrap();
+        for i in -1..10 {
+            let ray = Ray::new(
+                Vec3::new(3.0 * i as f32, 0.0, -1.0),
+                Vec3::new(0.0, 0.0, 1.0),
+            );
+            let hit = loaded
+                .intersect_ray(&scene, &ray)
+                .map(|hit| hit.instance_index);
+            let expected = two_level
+                .intersect_ray(&scene, &ray)
+                .map(|hit| hit.instance_index);
+            assert_eq!(hit, expected);
+        }
+
+        let other_config = OctTreeConfig {
+            triangles_per_leaf: 2,
+            ..two_level.config
+        };
+        let other_key = cache::cache_key(&scene, &other_config);
+        assert_ne!(key, other_key);
+        assert!(matches!(
+            cache::load::<TwoLevelIntersector, _>(&path, other_key),
+            Err(cache::CacheError::Outdated)
+        ));
+        let data = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &data[..data.len() - 3]).unwrap();
+        assert!(matches!(
+            cache::load::<TwoLevelIntersector, _>(&path, key),
+            Err(cache::CacheError::Corrupt(_))
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
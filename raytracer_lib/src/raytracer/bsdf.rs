@@ -0,0 +1,262 @@
+use std::f32::consts::PI;
+
+use rand::Rng;
+
+use super::sample_generator::SampleGenerator;
+use super::tonemap::luminance;
+use crate::scene::{color::RGB, Material};
+use crate::vecmath::{cross, dot, Vec3};
+
+// reflectance of dielectrics at normal incidence, about 4% for glass and most plastics
+const DIELECTRIC_F0: f32 = 0.04;
+
+// smoother than this, GGX lobes are too narrow to evaluate in f32
+const MIN_ROUGHNESS: f32 = 0.03;
+
+// A lambertian diffuse layer under a GGX (Trowbridge-Reitz) microfacet specular, with Smith
+// height correlated masking-shadowing and Schlick's Fresnel. Parametrized by metallic and
+// roughness as in glTF: metals have no diffuse and specular tinted by the base color, dielectrics
+// reflect DIELECTRIC_F0 specular, tinted by the material's specular color, over their base color.
+// A material's reflective color replaces the dielectric's reflectance at normal incidence.
+//
+// Directions point away from the surface, normal is on the side of wo.
+pub struct Bsdf {
+    diffuse: RGB,
+    // specular reflectance at normal and grazing incidence
+    f0: RGB,
+    f90: RGB,
+    // GGX width, roughness squared
+    alpha: f32,
+}
+
+impl Bsdf {
+    pub fn new(base_color: &RGB, material: &Material) -> Self {
+        let metallic = material.metallic.clamp(0.0, 1.0);
+        let roughness = material.roughness.clamp(MIN_ROUGHNESS, 1.0);
+        let (dielectric_f0, dielectric_f90) = match &material.reflective {
+            Some(reflective) => (*reflective, RGB::white()),
+            None => (material.specular * DIELECTRIC_F0, material.specular),
+        };
+        let lerp = |dielectric: RGB, metal: RGB| dielectric * (1.0 - metallic) + metal * metallic;
+        Bsdf {
+            diffuse: base_color * (1.0 - metallic),
+            f0: lerp(dielectric_f0, *base_color),
+            f90: lerp(dielectric_f90, RGB::white()),
+            alpha: roughness * roughness,
+        }
+    }
+
+    // f(wo, wi), without the cosine
+    pub fn eval(&self, normal: &Vec3, wo: &Vec3, wi: &Vec3) -> RGB {
+        let frame = Frame::new(normal);
+        let (wo, wi) = (frame.to_local(wo), frame.to_local(wi));
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return RGB::black();
+        }
+        self.diffuse * (1.0 / PI) + self.specular(&wo, &wi)
+    }
+
+    // A direction wi to continue along, and the weight f(wo, wi) * cos / pdf(wi) of what is found
+    // there. Picks the specular lobe, sampled by its visible normals, or the cosine distributed
+    // diffuse lobe, in proportion to how much they reflect. None if wi ends up below the surface.
+    pub fn sample(
+        &self,
+        normal: &Vec3,
+        wo: &Vec3,
+        sample_generator: &mut SampleGenerator,
+        mut rng: impl Rng,
+    ) -> Option<(Vec3, RGB)> {
+        let frame = Frame::new(normal);
+        let wo_local = frame.to_local(wo);
+        if wo_local.z <= 0.0 {
+            return None;
+        }
+
+        let specular_weight = luminance(&self.fresnel(wo_local.z));
+        let diffuse_weight = luminance(&self.diffuse);
+        if specular_weight + diffuse_weight <= 0.0 {
+            return None;
+        }
+        let specular_probability = specular_weight / (specular_weight + diffuse_weight);
+
+        let wi = if rng.random::<f32>() < specular_probability {
+            let h = self.sample_visible_normal(&wo_local, rng.random(), rng.random());
+            frame.to_world(&reflect(&wo_local, &h))
+        } else {
+            // a uniform direction on the unit sphere, moved out along the normal, is cosine
+            // distributed around it
+            let mut dir = normal + sample_generator.normalized_vec_pseudo(&mut rng);
+            while dot(&dir, &dir) < 1e-6 {
+                dir = normal + sample_generator.normalized_vec_lookup();
+            }
+            dir.normalized()
+        };
+
+        let wi_local = frame.to_local(&wi);
+        if wi_local.z <= 0.0 {
+            return None;
+        }
+        let pdf = specular_probability * self.specular_pdf(&wo_local, &wi_local)
+            + (1.0 - specular_probability) * wi_local.z / PI;
+        if pdf <= 0.0 {
+            return None;
+        }
+        let f = self.diffuse * (1.0 / PI) + self.specular(&wo_local, &wi_local);
+        Some((wi, f * (wi_local.z / pdf)))
+    }
+
+    fn specular(&self, wo: &Vec3, wi: &Vec3) -> RGB {
+        let h = (wo + wi).normalized();
+        let d = self.distribution(&h);
+        let g = 1.0 / (1.0 + self.lambda(wo) + self.lambda(wi));
+        self.fresnel(dot(wi, &h)) * (d * g / (4.0 * wo.z * wi.z))
+    }
+
+    // Schlick's approximation
+    fn fresnel(&self, cos: f32) -> RGB {
+        let t = (1.0 - cos.clamp(0.0, 1.0)).powi(5);
+        self.f0 * (1.0 - t) + self.f90 * t
+    }
+
+    // pdf of wi when sampling visible normals, D(h) G1(wo) / (4 wo.z)
+    fn specular_pdf(&self, wo: &Vec3, wi: &Vec3) -> f32 {
+        let h = (wo + wi).normalized();
+        self.distribution(&h) / ((1.0 + self.lambda(wo)) * 4.0 * wo.z)
+    }
+
+    // GGX normal distribution D(h)
+    fn distribution(&self, h: &Vec3) -> f32 {
+        if h.z <= 0.0 {
+            return 0.0;
+        }
+        let alpha2 = self.alpha * self.alpha;
+        let denom = h.z * h.z * (alpha2 - 1.0) + 1.0;
+        alpha2 / (PI * denom * denom)
+    }
+
+    // Smith's auxiliary function, G1(w) = 1 / (1 + lambda(w))
+    fn lambda(&self, w: &Vec3) -> f32 {
+        let cos2 = w.z * w.z;
+        let tan2 = (1.0 - cos2).max(0.0) / cos2;
+        ((1.0 + self.alpha * self.alpha * tan2).sqrt() - 1.0) * 0.5
+    }
+
+    // a microfacet normal, distributed as the normals seen from wo. Heitz, "Sampling the GGX
+    // Distribution of Visible Normals", JCGT 2018.
+    fn sample_visible_normal(&self, wo: &Vec3, u1: f32, u2: f32) -> Vec3 {
+        // stretch to the hemisphere configuration
+        let v = Vec3::new(self.alpha * wo.x, self.alpha * wo.y, wo.z).normalized();
+
+        let len2 = v.x * v.x + v.y * v.y;
+        let t1 = if len2 > 0.0 {
+            Vec3::new(-v.y, v.x, 0.0) * (1.0 / len2.sqrt())
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let t2 = cross(&v, &t1);
+
+        // a point on the projected disk, squeezed toward the visible half
+        let r = u1.sqrt();
+        let phi = 2.0 * PI * u2;
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + v.z);
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+        let n = t1 * p1 + t2 * p2 + v * (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+
+        // unstretch
+        Vec3::new(self.alpha * n.x, self.alpha * n.y, n.z.max(0.0)).normalized()
+    }
+}
+
+fn reflect(w: &Vec3, h: &Vec3) -> Vec3 {
+    2.0 * dot(w, h) * h - w
+}
+
+// an orthonormal basis with the normal as z. Duff et al., "Building an Orthonormal Basis,
+// Revisited", JCGT 2017.
+struct Frame {
+    tangent: Vec3,
+    bitangent: Vec3,
+    normal: Vec3,
+}
+
+impl Frame {
+    fn new(normal: &Vec3) -> Self {
+        let sign = 1.0f32.copysign(normal.z);
+        let a = -1.0 / (sign + normal.z);
+        let b = normal.x * normal.y * a;
+        Frame {
+            tangent: Vec3::new(
+                1.0 + sign * normal.x * normal.x * a,
+                sign * b,
+                -sign * normal.x,
+            ),
+            bitangent: Vec3::new(b, sign + normal.y * normal.y * a, -normal.y),
+            normal: *normal,
+        }
+    }
+
+    fn to_local(&self, v: &Vec3) -> Vec3 {
+        Vec3::new(
+            dot(v, &self.tangent),
+            dot(v, &self.bitangent),
+            dot(v, &self.normal),
+        )
+    }
+
+    fn to_world(&self, v: &Vec3) -> Vec3 {
+        self.tangent * v.x + self.bitangent * v.y + self.normal * v.z
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn test_frame() {
+        for normal in [
+            Vec3::new(0.0, 0.0, 1.0),
+            Vec3::new(0.0, 0.0, -1.0),
+            Vec3::new(1.0, 2.0, 3.0).normalized(),
+        ] {
+            let frame = Frame::new(&normal);
+            let v = Vec3::new(0.3, -0.5, 0.8);
+            let back = frame.to_world(&frame.to_local(&v));
+            assert!((back - v).x.abs() < 1e-5);
+            assert!((back - v).y.abs() < 1e-5);
+            assert!((back - v).z.abs() < 1e-5);
+            assert!(dot(&frame.tangent, &normal).abs() < 1e-5);
+            assert!(dot(&frame.bitangent, &normal).abs() < 1e-5);
+        }
+    }
+
+    // the sampled weights average to the albedo, about 1 for white materials. A little more for
+    // dielectrics, whose diffuse isn't dimmed by what the specular reflects.
+    #[test]
+    fn test_sampled_albedo() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut sample_generator = SampleGenerator::with_rng(&mut rng);
+        let normal = Vec3::new(0.0, 1.0, 0.0);
+        let wo = Vec3::new(0.5, 1.0, 0.0).normalized();
+        for (metallic, roughness) in [(0.0, 1.0), (1.0, 0.5), (0.0, 0.2)] {
+            let material = Material {
+                metallic,
+                roughness,
+                ..Material::default()
+            };
+            let bsdf = Bsdf::new(&RGB::white(), &material);
+            const NUM_SAMPLES: usize = 20000;
+            let albedo = (0..NUM_SAMPLES)
+                .filter_map(|_| bsdf.sample(&normal, &wo, &mut sample_generator, &mut rng))
+                .map(|(wi, weight)| {
+                    assert!(dot(&wi, &normal) > 0.0);
+                    weight.g
+                })
+                .sum::<f32>()
+                / NUM_SAMPLES as f32;
+            assert!(albedo > 0.8 && albedo < 1.1, "albedo {}", albedo);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::cmp::Ordering;
+
+use crate::scene::color::RGBA;
+use crate::vecmath::{cross, dot, Vec3};
+
+// The frame textures perturb the normal in: the tangent follows dp/du, the bitangent points up
+// the image, toward decreasing v, both made perpendicular to the normal. Mirrored texture
+// coordinates flip the bitangent.
+pub struct TangentFrame {
+    tangent: Vec3,
+    bitangent: Vec3,
+    normal: Vec3,
+}
+
+impl TangentFrame {
+    // None where the texture coordinates give no direction along the surface, or a NaN one
+    pub fn new(normal: &Vec3, dpdu: &Vec3, dpdv: &Vec3) -> Option<Self> {
+        let tangent = dpdu - normal * dot(normal, dpdu);
+        let length = dot(&tangent, &tangent).sqrt();
+        let min_length = 1e-6 * dot(dpdu, dpdu).sqrt();
+        if length.partial_cmp(&min_length) != Some(Ordering::Greater) {
+            return None;
+        }
+        let tangent = tangent * (1.0 / length);
+        let bitangent = cross(normal, &tangent);
+        let bitangent = if dot(&bitangent, dpdv) > 0.0 {
+            -bitangent
+        } else {
+            bitangent
+        };
+        Some(TangentFrame {
+            tangent,
+            bitangent,
+            normal: *normal,
+        })
+    }
+
+    // the world space normal of a tangent space one, normalized
+    pub fn to_world(&self, v: &Vec3) -> Vec3 {
+        (self.tangent * v.x + self.bitangent * v.y + self.normal * v.z).normalized()
+    }
+}
+
+// the tangent space normal stored in a normal map texel
+pub fn normal_map_normal(texel: &RGBA) -> Vec3 {
+    Vec3::new(
+        2.0 * texel.r - 1.0,
+        2.0 * texel.g - 1.0,
+        2.0 * texel.b - 1.0,
+    )
+}
+
+// The tangent space normal of a height map, from how much the height rises over a texel along x
+// and y. The surface tilts away from where it rises.
+pub fn height_map_normal(rise_x: f32, rise_y: f32, scale: f32) -> Vec3 {
+    Vec3::new(-rise_x * scale, -rise_y * scale, 1.0)
+}
+
+// a height map texel, grey images store the height in all channels
+pub fn height(texel: &RGBA) -> f32 {
+    (texel.r + texel.g + texel.b) / 3.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(a: &Vec3, b: &Vec3) {
+        let d = a - b;
+        assert!(dot(&d, &d) < 1e-10, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn test_tangent_frame() {
+        // u along x, the image upright, so v runs down along -y
+        let normal = Vec3::new(0.0, 0.0, 1.0);
+        let frame = TangentFrame::new(
+            &normal,
+            &Vec3::new(2.0, 0.0, 0.5),
+            &Vec3::new(0.0, -3.0, 0.0),
+        )
+        .unwrap();
+        assert_near(
+            &frame.to_world(&Vec3::new(1.0, 0.0, 0.0)),
+            &Vec3::new(1.0, 0.0, 0.0),
+        );
+        assert_near(
+            &frame.to_world(&Vec3::new(0.0, 1.0, 0.0)),
+            &Vec3::new(0.0, 1.0, 0.0),
+        );
+
+        // mirrored along v
+        let frame = TangentFrame::new(
+            &normal,
+            &Vec3::new(1.0, 0.0, 0.0),
+            &Vec3::new(0.0, 1.0, 0.0),
+        )
+        .unwrap();
+        assert_near(
+            &frame.to_world(&Vec3::new(0.0, 1.0, 0.0)),
+            &Vec3::new(0.0, -1.0, 0.0),
+        );
+
+        let zero = Vec3::new(0.0, 0.0, 0.0);
+        assert!(TangentFrame::new(&normal, &zero, &zero).is_none());
+        assert!(TangentFrame::new(&normal, &normal, &zero).is_none());
+        let nan = Vec3::new(f32::NAN, 0.0, 0.0);
+        assert!(TangentFrame::new(&normal, &nan, &zero).is_none());
+    }
+
+    #[test]
+    fn test_maps() {
+        // the flat normal map color
+        let flat = normal_map_normal(&RGBA::new(0.5, 0.5, 1.0, 1.0));
+        assert_near(&flat, &Vec3::new(0.0, 0.0, 1.0));
+
+        // rising by one over a texel along x tilts 45 degrees toward -x
+        let tilted = height_map_normal(1.0, 0.0, 1.0).normalized();
+        assert_near(&tilted, &Vec3::new(-1.0, 0.0, 1.0).normalized());
+        assert_eq!(height(&RGBA::new(0.5, 0.5, 0.5, 1.0)), 0.5);
+    }
+}
@@ -0,0 +1,203 @@
+use super::tonemap::luminance;
+use crate::scene::color::RGB;
+
+const NUM_BINS: usize = 128;
+const MIN_LOG_LUMINANCE: f32 = -16.0;
+const MAX_LOG_LUMINANCE: f32 = 16.0;
+
+// luminance the scene key is mapped to, "middle grey"
+pub const DEFAULT_KEY: f32 = 0.18;
+// fraction of the distance to the target exposure covered per update
+pub const DEFAULT_ADAPTATION_RATE: f32 = 0.1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyValue {
+    // geometric mean of the luminance
+    Average,
+    // luminance at the given percentile [0..1]
+    Percentile(f32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExposureMode {
+    Auto(KeyValue),
+    // exposure in stops, 0.0 leaves the radiance untouched
+    Fixed(f32),
+}
+
+#[derive(Debug, Clone)]
+pub struct Exposure {
+    pub mode: ExposureMode,
+    pub key: f32,
+    pub adaptation_rate: f32,
+    adapted: Option<f32>,
+}
+
+impl Default for Exposure {
+    fn default() -> Self {
+        Exposure::new(ExposureMode::Auto(KeyValue::Average))
+    }
+}
+
+impl Exposure {
+    pub fn new(mode: ExposureMode) -> Self {
+        Exposure {
+            mode,
+            key: DEFAULT_KEY,
+            adaptation_rate: DEFAULT_ADAPTATION_RATE,
+            adapted: None,
+        }
+    }
+
+    pub fn fixed(stops: f32) -> Self {
+        Exposure::new(ExposureMode::Fixed(stops))
+    }
+
+    // current scale factor to apply to radiance before tonemapping
+    pub fn value(&self) -> f32 {
+        match self.mode {
+            ExposureMode::Fixed(stops) => stops.exp2(),
+            ExposureMode::Auto(_) => self.adapted.unwrap_or(1.0),
+        }
+    }
+
+    // forget the adapted state, next update will jump straight to the target exposure
+    pub fn reset(&mut self) {
+        self.adapted = None;
+    }
+
+    // moves the exposure towards the target of the given frame, returns the new scale factor
+    pub fn update(&mut self, hdr_frame: &[RGB]) -> f32 {
+        let key_value = match self.mode {
+            ExposureMode::Fixed(_) => return self.value(),
+            ExposureMode::Auto(key_value) => key_value,
+        };
+
+        let histogram = Histogram::from_pixels(hdr_frame);
+        let scene_luminance = match key_value {
+            KeyValue::Average => histogram.geometric_mean(),
+            KeyValue::Percentile(percentile) => histogram.percentile(percentile),
+        };
+        let scene_luminance = match scene_luminance {
+            Some(scene_luminance) => scene_luminance,
+            None => return self.value(), // nothing sampled yet, keep what we have
+        };
+
+        let target = self.key / scene_luminance;
+        let adapted = match self.adapted {
+            None => target,
+            Some(adapted) => {
+                // adapt in log space, so brightening and darkening feel equally fast
+                let log_adapted = adapted.log2();
+                (log_adapted + (target.log2() - log_adapted) * self.adaptation_rate).exp2()
+            }
+        };
+        self.adapted = Some(adapted);
+        adapted
+    }
+}
+
+struct Histogram {
+    bins: [u32; NUM_BINS],
+    count: u32,
+    log_sum: f32,
+}
+
+impl Histogram {
+    fn from_pixels(pixels: &[RGB]) -> Self {
+        let mut bins = [0; NUM_BINS];
+        let mut count = 0;
+        let mut log_sum = 0.0;
+        for pixel in pixels {
+            let lum = luminance(pixel);
+            // pixels without samples are NaN, black pixels carry no information about exposure
+            if !lum.is_finite() || lum <= 0.0 {
+                continue;
+            }
+            let log_lum = lum.log2();
+            bins[Self::bin_index(log_lum)] += 1;
+            count += 1;
+            log_sum += log_lum;
+        }
+        Histogram {
+            bins,
+            count,
+            log_sum,
+        }
+    }
+
+    fn bin_index(log_lum: f32) -> usize {
+        let t = (log_lum - MIN_LOG_LUMINANCE) / (MAX_LOG_LUMINANCE - MIN_LOG_LUMINANCE);
+        ((t * NUM_BINS as f32) as isize).clamp(0, NUM_BINS as isize - 1) as usize
+    }
+
+    fn bin_center(idx: usize) -> f32 {
+        MIN_LOG_LUMINANCE
+            + (idx as f32 + 0.5) * (MAX_LOG_LUMINANCE - MIN_LOG_LUMINANCE) / NUM_BINS as f32
+    }
+
+    fn geometric_mean(&self) -> Option<f32> {
+        if self.count == 0 {
+            return None;
+        }
+        Some((self.log_sum / self.count as f32).exp2())
+    }
+
+    fn percentile(&self, percentile: f32) -> Option<f32> {
+        if self.count == 0 {
+            return None;
+        }
+        let wanted = (percentile.clamp(0.0, 1.0) * self.count as f32).ceil().max(1.0) as u32;
+        let mut accum = 0;
+        for (idx, num) in self.bins.iter().enumerate() {
+            accum += num;
+            if accum >= wanted {
+                return Some(Self::bin_center(idx).exp2());
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grey_frame(lum: f32) -> Vec<RGB> {
+        vec![RGB::new(lum, lum, lum); 100]
+    }
+
+    #[test]
+    fn test_auto_exposure_maps_average_to_key() {
+        let mut exposure = Exposure::default();
+        let value = exposure.update(&grey_frame(4.0));
+        let mapped = 4.0 * value;
+        assert!((mapped - DEFAULT_KEY).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_auto_exposure_adapts_gradually() {
+        let mut exposure = Exposure::default();
+        let dark = exposure.update(&grey_frame(0.1));
+        let after_one = exposure.update(&grey_frame(10.0));
+        assert!(after_one < dark);
+        assert!(after_one > DEFAULT_KEY / 10.0);
+    }
+
+    #[test]
+    fn test_percentile_ignores_unsampled_pixels() {
+        let mut frame = grey_frame(1.0);
+        frame.push(RGB::new(f32::NAN, f32::NAN, f32::NAN));
+        frame.push(RGB::black());
+        let histogram = Histogram::from_pixels(&frame);
+        assert_eq!(histogram.count, 100);
+        let lum = histogram.percentile(0.5).unwrap();
+        assert!((lum - 1.0).abs() < 0.2);
+    }
+
+    #[test]
+    fn test_fixed_exposure() {
+        let mut exposure = Exposure::fixed(1.0);
+        assert_eq!(exposure.update(&grey_frame(100.0)), 2.0);
+    }
+}
@@ -1,4 +1,7 @@
+use crate::scene::shape::{self, ShapeKind};
 use crate::scene::{Ray, Vertex};
+use crate::simd::Vec3x4;
+use crate::vecmath::{cross, dot, Vec3};
 
 #[derive(Debug, Clone)]
 pub struct HitInfo {
@@ -16,12 +19,352 @@ pub fn intersect(ray: &Ray, v0: &Vertex, v1: &Vertex, v2: &Vertex) -> Option<Hit
     moller_trumbore::intersect_late_out(ray, v0, v1, v2)
 }
 
+// four rays against four triangles given by a corner and the edges from it, lane by lane, with
+// the same results as intersect
+pub fn intersect_packet(
+    pos: &Vec3x4,
+    dir: &Vec3x4,
+    v0: &Vec3x4,
+    v0v1: &Vec3x4,
+    v0v2: &Vec3x4,
+) -> [Option<HitInfo>; 4] {
+    moller_trumbore::intersect_packet(pos, dir, v0, v0v1, v0v2)
+}
+
+// u, v of the hit are the shape's texture coordinates
+pub fn intersect_shape(ray: &Ray, shape: &ShapeKind) -> Option<HitInfo> {
+    use std::f32::consts::PI;
+
+    match shape {
+        ShapeKind::Sphere { center, radius } => {
+            // the nearer root of |pos + t dir - center| = radius in front of the ray
+            let oc = ray.pos - *center;
+            let a = dot(&ray.dir, &ray.dir);
+            let half_b = dot(&oc, &ray.dir);
+            let c = dot(&oc, &oc) - radius * radius;
+            let discriminant = half_b * half_b - a * c;
+            if discriminant < 0.0 || a == 0.0 {
+                return None;
+            }
+            let root = discriminant.sqrt();
+            let t = if -half_b - root >= 0.0 {
+                (-half_b - root) / a
+            } else {
+                (-half_b + root) / a
+            };
+            if t < 0.0 {
+                return None;
+            }
+            // longitude around y and latitude from the bottom
+            let n = (ray.pos + ray.dir * t - *center).normalized();
+            let u = 0.5 + n.z.atan2(n.x) / (2.0 * PI);
+            let v = 0.5 + n.y.clamp(-1.0, 1.0).asin() / PI;
+            Some(HitInfo::new(t, u, v))
+        }
+        ShapeKind::Plane { point, normal } => {
+            let t = intersect_plane(ray, point, normal)?;
+            let (tangent, bitangent) = shape::tangents(normal);
+            let offset = ray.pos + ray.dir * t - *point;
+            Some(HitInfo::new(
+                t,
+                dot(&offset, &tangent).rem_euclid(1.0),
+                dot(&offset, &bitangent).rem_euclid(1.0),
+            ))
+        }
+        ShapeKind::Disk {
+            center,
+            normal,
+            radius,
+        } => {
+            let t = intersect_plane(ray, center, normal)?;
+            let offset = ray.pos + ray.dir * t - *center;
+            let distance = dot(&offset, &offset).sqrt();
+            if distance > *radius {
+                return None;
+            }
+            // angle around the center and distance from it
+            let (tangent, bitangent) = shape::tangents(normal);
+            let angle = dot(&offset, &bitangent).atan2(dot(&offset, &tangent));
+            Some(HitInfo::new(t, 0.5 + angle / (2.0 * PI), distance / radius))
+        }
+        ShapeKind::Quad {
+            corner,
+            edge_u,
+            edge_v,
+        } => {
+            let normal = cross(edge_u, edge_v);
+            let t = intersect_plane(ray, corner, &normal)?;
+            // coordinates along the edges, w is the normal scaled so they come out in [0, 1]
+            let offset = ray.pos + ray.dir * t - *corner;
+            let w = normal * (1.0 / dot(&normal, &normal));
+            let u = dot(&w, &cross(&offset, edge_v));
+            let v = dot(&w, &cross(edge_u, &offset));
+            if !(0.0..=1.0).contains(&u) || !(0.0..=1.0).contains(&v) {
+                return None;
+            }
+            Some(HitInfo::new(t, u, v))
+        }
+    }
+}
+
+// t where the ray meets the plane through the point, None if it is parallel or behind
+fn intersect_plane(ray: &Ray, point: &Vec3, normal: &Vec3) -> Option<f32> {
+    let denominator = dot(&ray.dir, normal);
+    if denominator.abs() < f32::EPSILON * dot(normal, normal).sqrt() {
+        return None;
+    }
+    let t = dot(&(*point - ray.pos), normal) / denominator;
+    if t < 0.0 {
+        return None;
+    }
+    Some(t)
+}
+
+// how rays are tested against triangles
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TriangleTest {
+    MollerTrumbore,
+    // never lets a ray slip through the edge shared by two triangles, somewhat slower
+    Watertight,
+}
+
+// a ray prepared for testing against many triangles
+pub enum TriangleRay<'a> {
+    MollerTrumbore(&'a Ray),
+    Watertight(&'a Ray, watertight::ShearedRay),
+}
+
+impl<'a> TriangleRay<'a> {
+    pub fn new(ray: &'a Ray, test: TriangleTest) -> Self {
+        match test {
+            TriangleTest::MollerTrumbore => TriangleRay::MollerTrumbore(ray),
+            TriangleTest::Watertight => {
+                TriangleRay::Watertight(ray, watertight::ShearedRay::new(ray))
+            }
+        }
+    }
+
+    pub fn ray(&self) -> &Ray {
+        match self {
+            TriangleRay::MollerTrumbore(ray) => ray,
+            TriangleRay::Watertight(ray, _) => ray,
+        }
+    }
+
+    pub fn intersect(&self, v0: &Vertex, v1: &Vertex, v2: &Vertex) -> Option<HitInfo> {
+        match self {
+            TriangleRay::MollerTrumbore(ray) => intersect(ray, v0, v1, v2),
+            TriangleRay::Watertight(ray, sheared) => {
+                watertight::intersect(ray, sheared, v0, v1, v2)
+            }
+        }
+    }
+
+    // the corners must be prepared for the same test, by prepare_triangle
+    pub fn intersect_prepared(&self, corners: &[Vertex; 3]) -> Option<HitInfo> {
+        match self {
+            TriangleRay::MollerTrumbore(ray) => moller_trumbore::intersect_late_out_edges(
+                ray,
+                &corners[0],
+                &corners[1],
+                &corners[2],
+            ),
+            TriangleRay::Watertight(ray, sheared) => {
+                watertight::intersect(ray, sheared, &corners[0], &corners[1], &corners[2])
+            }
+        }
+    }
+}
+
+// The corners of a triangle as its test uses them, for storing with the triangle. Möller-Trumbore
+// uses the edges from the first corner. The watertight test needs the corners themselves, edges
+// added back to a corner would round differently for the two triangles sharing them.
+pub fn prepare_triangle(test: TriangleTest, v0: &Vertex, v1: &Vertex, v2: &Vertex) -> [Vertex; 3] {
+    match test {
+        TriangleTest::MollerTrumbore => [*v0, v1 - v0, v2 - v0],
+        TriangleTest::Watertight => [*v0, *v1, *v2],
+    }
+}
+
+// Moves a point on a surface off it, to the side dir points to, so a ray starting there doesn't hit
+// the surface it starts on. The offset is a fixed number of float steps in each coordinate, so it
+// grows with the rounding error of the point instead of being a fixed distance that is too small
+// for large scenes and too large for small ones. Wächter & Binder, "A Fast and Robust Method for
+// Avoiding Self-Intersection", Ray Tracing Gems 2019.
+pub fn offset_ray_origin(pos: &Vec3, geometric_normal: &Vec3, dir: &Vec3) -> Vec3 {
+    // close to the origin float steps are tiny, there a small fixed offset is used instead
+    const ORIGIN: f32 = 1.0 / 32.0;
+    const FLOAT_SCALE: f32 = 1.0 / 65536.0;
+    const INT_SCALE: f32 = 256.0;
+
+    let normal = if crate::vecmath::dot(geometric_normal, dir) < 0.0 {
+        -*geometric_normal
+    } else {
+        *geometric_normal
+    };
+    let offset = |p: f32, n: f32| {
+        if p.abs() < ORIGIN {
+            return p + FLOAT_SCALE * n;
+        }
+        let steps = (INT_SCALE * n) as i32;
+        let steps = if p < 0.0 { -steps } else { steps };
+        f32::from_bits((p.to_bits() as i32).wrapping_add(steps) as u32)
+    };
+    Vec3::new(
+        offset(pos.x, normal.x),
+        offset(pos.y, normal.y),
+        offset(pos.z, normal.z),
+    )
+}
+
+// Watertight ray-triangle intersection, Woop, Benthin & Wald, "Watertight Ray/Triangle
+// Intersection", JCGT 2013. The triangle is sheared into a space where the ray points along z from
+// the origin, and the hit is decided by the signs of 2d edge functions, which agree for the edge
+// two triangles share.
+mod watertight {
+    use super::HitInfo;
+    use crate::scene::{Ray, Vertex};
+
+    pub struct ShearedRay {
+        // axes of the sheared space, z along the ray's largest direction component
+        kx: usize,
+        ky: usize,
+        kz: usize,
+        shear_x: f32,
+        shear_y: f32,
+        shear_z: f32,
+    }
+
+    impl ShearedRay {
+        pub fn new(ray: &Ray) -> Self {
+            let dir = [ray.dir.x, ray.dir.y, ray.dir.z];
+            let kz = if dir[0].abs() > dir[1].abs() {
+                if dir[0].abs() > dir[2].abs() {
+                    0
+                } else {
+                    2
+                }
+            } else if dir[1].abs() > dir[2].abs() {
+                1
+            } else {
+                2
+            };
+            let mut kx = (kz + 1) % 3;
+            let mut ky = (kx + 1) % 3;
+            // keeps the triangle winding
+            if dir[kz] < 0.0 {
+                std::mem::swap(&mut kx, &mut ky);
+            }
+            ShearedRay {
+                kx,
+                ky,
+                kz,
+                shear_x: dir[kx] / dir[kz],
+                shear_y: dir[ky] / dir[kz],
+                shear_z: 1.0 / dir[kz],
+            }
+        }
+    }
+
+    pub fn intersect(
+        ray: &Ray,
+        sheared: &ShearedRay,
+        v0: &Vertex,
+        v1: &Vertex,
+        v2: &Vertex,
+    ) -> Option<HitInfo> {
+        let to_array = |v: &Vertex| {
+            let v = v - ray.pos;
+            [v.x, v.y, v.z]
+        };
+        let (a, b, c) = (to_array(v0), to_array(v1), to_array(v2));
+        let (kx, ky, kz) = (sheared.kx, sheared.ky, sheared.kz);
+
+        let ax = a[kx] - sheared.shear_x * a[kz];
+        let ay = a[ky] - sheared.shear_y * a[kz];
+        let bx = b[kx] - sheared.shear_x * b[kz];
+        let by = b[ky] - sheared.shear_y * b[kz];
+        let cx = c[kx] - sheared.shear_x * c[kz];
+        let cy = c[ky] - sheared.shear_y * c[kz];
+
+        let mut u = cx * by - cy * bx;
+        let mut v = ax * cy - ay * cx;
+        let mut w = bx * ay - by * ax;
+        // exactly on an edge in f32, decide it in f64 so neighbours agree on which one is hit
+        if u == 0.0 || v == 0.0 || w == 0.0 {
+            u = (cx as f64 * by as f64 - cy as f64 * bx as f64) as f32;
+            v = (ax as f64 * cy as f64 - ay as f64 * cx as f64) as f32;
+            w = (bx as f64 * ay as f64 - by as f64 * ax as f64) as f32;
+        }
+        if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+            return None;
+        }
+        let det = u + v + w;
+        if det == 0.0 {
+            return None;
+        }
+
+        let az = sheared.shear_z * a[kz];
+        let bz = sheared.shear_z * b[kz];
+        let cz = sheared.shear_z * c[kz];
+        let t = (u * az + v * bz + w * cz) / det;
+        if t < 0.0 {
+            return None;
+        }
+        // v and w weigh v1 and v2, like u and v from Möller-Trumbore
+        Some(HitInfo::new(t, v / det, w / det))
+    }
+}
+
 mod moller_trumbore {
 
     use super::HitInfo;
     use crate::scene::{Ray, Vertex};
+    use crate::simd::{F32x4, Vec3x4};
     use crate::vecmath::{cross, dot};
 
+    // intersect_late_out on four lanes, the operations are in the same order so the results are
+    // bit for bit the same
+    pub fn intersect_packet(
+        pos: &Vec3x4,
+        dir: &Vec3x4,
+        v0: &Vec3x4,
+        v0v1: &Vec3x4,
+        v0v2: &Vec3x4,
+    ) -> [Option<HitInfo>; 4] {
+        let pvec = dir.cross(v0v2);
+        let det = v0v1.dot(&pvec);
+        let inv_det = F32x4::splat(1.0).div(det);
+
+        let tvec = pos.sub(v0);
+        let u = tvec.dot(&pvec).mul(inv_det);
+        let qvec = tvec.cross(v0v1);
+        let v = dir.dot(&qvec).mul(inv_det);
+        let t = v0v2.dot(&qvec).mul(inv_det);
+
+        let zero = F32x4::splat(0.0);
+        let one = F32x4::splat(1.0);
+        let hits = F32x4::splat(f32::EPSILON)
+            .le(det.abs())
+            .and(zero.le(u))
+            .and(u.le(one))
+            .and(zero.le(v))
+            .and(u.add(v).le(one))
+            .and(zero.le(t))
+            .bits();
+        if hits == 0 {
+            return [None, None, None, None];
+        }
+        let (t, u, v) = (t.to_array(), u.to_array(), v.to_array());
+        let mut hit_infos = [None, None, None, None];
+        for (lane, hit_info) in hit_infos.iter_mut().enumerate() {
+            if hits & (1 << lane) != 0 {
+                *hit_info = Some(HitInfo::new(t[lane], u[lane], v[lane]));
+            }
+        }
+        hit_infos
+    }
+
     #[allow(dead_code)]
     pub fn intersect(ray: &Ray, v0: &Vertex, v1: &Vertex, v2: &Vertex) -> Option<HitInfo> {
         // Möller-Trumbore algo
@@ -60,12 +403,20 @@ mod moller_trumbore {
 
     #[allow(dead_code)]
     pub fn intersect_late_out(ray: &Ray, v0: &Vertex, v1: &Vertex, v2: &Vertex) -> Option<HitInfo> {
+        intersect_late_out_edges(ray, v0, &(v1 - v0), &(v2 - v0))
+    }
+
+    // with the edges from v0 precomputed
+    pub fn intersect_late_out_edges(
+        ray: &Ray,
+        v0: &Vertex,
+        v0v1: &Vertex,
+        v0v2: &Vertex,
+    ) -> Option<HitInfo> {
         // Möller-Trumbore algo
 
-        let v0v1 = v1 - v0;
-        let v0v2 = v2 - v0;
-        let pvec = cross(&ray.dir, &v0v2);
-        let det = dot(&v0v1, &pvec);
+        let pvec = cross(&ray.dir, v0v2);
+        let det = dot(v0v1, &pvec);
         // ray and triangle are parallel if det is close to 0
         if det.abs() < std::f32::EPSILON {
             // switch to "if det < std::f32::EPSILON { return None };" for backface culling
@@ -77,11 +428,11 @@ mod moller_trumbore {
         let tvec = ray.pos - v0;
         let u = dot(&tvec, &pvec) * inv_det;
 
-        let qvec = cross(&tvec, &v0v1);
+        let qvec = cross(&tvec, v0v1);
         let v = dot(&ray.dir, &qvec) * inv_det;
 
         // u,v are coords in tri, return if needed
-        let t = dot(&v0v2, &qvec) * inv_det;
+        let t = dot(v0v2, &qvec) * inv_det;
 
         // dont merge re-order or break apart these if-clauses - it has a major performance impact!
         if u < 0.0 || u > 1.0 {
@@ -142,3 +493,112 @@ mod moller_trumbore {
         Some(HitInfo::new(t, u, v))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vecmath::Vec3;
+
+    #[test]
+    fn test_watertight_matches_moller_trumbore() {
+        let (v0, v1, v2) = (
+            Vec3::new(-1.0, -1.0, 5.0),
+            Vec3::new(2.0, -1.0, 4.0),
+            Vec3::new(0.0, 1.5, 6.0),
+        );
+        for (x, y) in [(0.0, 0.0), (0.5, -0.5), (-0.9, -0.9), (1.5, 1.5)].iter() {
+            let ray = Ray::new(Vec3::new(0.1, 0.2, 0.0), Vec3::new(*x, *y, 5.0));
+            let expected = intersect(&ray, &v0, &v1, &v2);
+            let hit = TriangleRay::new(&ray, TriangleTest::Watertight).intersect(&v0, &v1, &v2);
+            match (hit, expected) {
+                (Some(hit), Some(expected)) => {
+                    assert!((hit.t - expected.t).abs() < 1e-5);
+                    assert!((hit.u - expected.u).abs() < 1e-5);
+                    assert!((hit.v - expected.v).abs() < 1e-5);
+                }
+                (hit, expected) => assert_eq!(hit.is_some(), expected.is_some()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_watertight_shared_edge() {
+        // two triangles sharing the diagonal of a quad, rays along the diagonal hit one of them
+        let (a, b, c, d) = (
+            Vec3::new(0.0, 0.0, 1.0),
+            Vec3::new(1.0, 0.0, 1.0),
+            Vec3::new(1.0, 1.0, 1.0),
+            Vec3::new(0.0, 1.0, 1.0),
+        );
+        for i in 0..100 {
+            let s = 0.01 * i as f32 + 0.001;
+            let ray = Ray::new(Vec3::new(0.3, 0.7, 0.0), Vec3::new(s - 0.3, s - 0.7, 1.0));
+            let triangle_ray = TriangleRay::new(&ray, TriangleTest::Watertight);
+            let hits = triangle_ray.intersect(&a, &b, &c).is_some() as u32
+                + triangle_ray.intersect(&a, &c, &d).is_some() as u32;
+            assert!(hits >= 1);
+        }
+    }
+
+    #[test]
+    fn test_offset_ray_origin_leaves_surface() {
+        let normal = Vec3::new(0.0, 0.0, 1.0);
+        for scale in [1e-3, 1.0, 1e3, 1e5].iter() {
+            let pos = Vec3::new(0.3 * scale, -0.7 * scale, 1.0 * scale);
+            let up = offset_ray_origin(&pos, &normal, &Vec3::new(0.0, 0.0, 1.0));
+            let down = offset_ray_origin(&pos, &normal, &Vec3::new(0.0, 0.0, -1.0));
+            assert!(up.z > pos.z && down.z < pos.z);
+            assert_eq!((up.x, up.y), (pos.x, pos.y));
+            // tiny relative to the position
+            assert!((up.z - pos.z) < 1e-3 * scale.max(1.0));
+        }
+    }
+
+    #[test]
+    fn test_intersect_shapes() {
+        let z = Vec3::new(0.0, 0.0, 1.0);
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), z);
+        let t = |shape: &ShapeKind| intersect_shape(&ray, shape).map(|hit| hit.t);
+
+        let sphere = ShapeKind::Sphere {
+            center: Vec3::new(0.0, 0.0, 5.0),
+            radius: 2.0,
+        };
+        assert_eq!(t(&sphere), Some(3.0));
+        // from inside, the far side
+        let inside = Ray::new(Vec3::new(0.0, 0.0, 5.0), z);
+        assert_eq!(
+            intersect_shape(&inside, &sphere).map(|hit| hit.t),
+            Some(2.0)
+        );
+
+        let plane = ShapeKind::Plane {
+            point: Vec3::new(0.0, 7.0, 4.0),
+            normal: Vec3::new(0.0, 0.0, -1.0),
+        };
+        assert_eq!(t(&plane), Some(4.0));
+        let parallel = ShapeKind::Plane {
+            point: Vec3::new(0.0, 1.0, 0.0),
+            normal: Vec3::new(0.0, 1.0, 0.0),
+        };
+        assert_eq!(t(&parallel), None);
+
+        let disk = |x: f32| ShapeKind::Disk {
+            center: Vec3::new(x, 0.0, 2.0),
+            normal: z,
+            radius: 1.0,
+        };
+        assert_eq!(t(&disk(0.5)), Some(2.0));
+        assert_eq!(t(&disk(1.5)), None);
+
+        let quad = ShapeKind::Quad {
+            corner: Vec3::new(-1.0, -0.5, 3.0),
+            edge_u: Vec3::new(4.0, 0.0, 0.0),
+            edge_v: Vec3::new(0.0, 2.0, 0.0),
+        };
+        let hit = intersect_shape(&ray, &quad).unwrap();
+        assert_eq!((hit.t, hit.u, hit.v), (3.0, 0.25, 0.25));
+        let behind = Ray::new(Vec3::new(0.0, 0.0, 4.0), z);
+        assert!(intersect_shape(&behind, &quad).is_none());
+    }
+}
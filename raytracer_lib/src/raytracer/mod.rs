@@ -1,23 +1,36 @@
 pub mod accel_intersect;
+mod bsdf;
+mod bump;
+pub mod exposure;
 mod film;
 mod intersect;
 mod sample_generator;
-mod tonemap;
+pub mod settings;
+pub mod tonemap;
 
 use rand::{SeedableRng, rngs::StdRng};
 
-use super::scene::{camera::Camera, color::Diffuse, color::{RGB, RGBA}, Ray, Scene};
+use super::scene::{camera::{Camera, Eye}, color::Diffuse, color::{RGB, RGBA}, motion::Motion, shape::Shape, texture::UvDerivatives, Bump, Geometry, Material, Matrix, Ray, RayDifferential, Scene, SceneObject};
 use super::vecmath::{cross, dot, Vec3};
 
 use accel_intersect::*;
+use bsdf::Bsdf;
+use exposure::Exposure;
 use film::Film;
 use intersect::HitInfo;
+pub use intersect::TriangleTest;
 use sample_generator::SampleGenerator;
+use settings::{Integrator, RenderSettings};
+use tonemap::Tonemapper;
 
 pub struct Hit {
     hit_info: HitInfo,
+    // index into the scene meshes for instance hits, into the scene geometries otherwise
     geometry_index: usize,
     vertex_index: usize,
+    instance_index: Option<usize>,
+    // set for hits on analytic shapes, the other indices don't apply to them
+    shape_index: Option<usize>,
 }
 impl Hit {
     fn new(hit_info: HitInfo, geometry_index: usize, vertex_index: usize) -> Self {
@@ -25,21 +38,58 @@ impl Hit {
             hit_info,
             geometry_index,
             vertex_index,
+            instance_index: None,
+            shape_index: None,
         }
     }
+
+    fn on_shape(hit_info: HitInfo, shape_index: usize) -> Self {
+        Hit {
+            shape_index: Some(shape_index),
+            ..Hit::new(hit_info, 0, 0)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StereoMode {
+    // left eye in the left half of the film, right eye in the right half
+    SideBySide,
+    // red/cyan composite of both eyes
+    Anaglyph,
 }
 
-pub struct RayTracer<Accel = OctTreeIntersector>
+// rows traced by each call to trace_frame_additive
+const ROWS_PER_TRACE: usize = 50;
+
+// secondary rays per bounce left, for each hit
+const SUB_SPREAD: u32 = 1;
+
+// surfaces a ray is refracted through, on top of the diffuse bounces
+const MAX_TRANSMISSIONS: u8 = 8;
+
+// refitted accels below this quality are rebuilt
+const MIN_REFIT_QUALITY: f32 = 0.5;
+
+pub struct RayTracer<Accel = TwoLevelIntersector>
 where
     Accel: Intersector,
 {
     width: usize,
     height: usize,
     pub camera: Camera,
+    camera_index: usize,
 
     sample_generator: sample_generator::SampleGenerator,
     pub film: Film,
+    film_right: Film, // only used for anaglyph stereo
+    pub exposure: Exposure,
+    pub tonemapper: Tonemapper,
+    pub integrator: Integrator,
+    rng: StdRng,
+    stereo: Option<StereoMode>,
     accel: Accel,
+    accel_quality: f32,
 
     current_row: usize,
 
@@ -56,9 +106,17 @@ where
             width,
             height,
             camera,
+            camera_index: 0,
             sample_generator: SampleGenerator::new(),
             film: Film::new(width * height),
+            film_right: Film::new(0),
+            exposure: Exposure::default(),
+            tonemapper: Tonemapper::default(),
+            integrator: Integrator::default(),
+            rng: StdRng::from_os_rng(),
+            stereo: None,
             accel: Intersector::new(&scene),
+            accel_quality: 1.0,
             current_row: 0,
             scene,
         }
@@ -69,193 +127,822 @@ where
             width,
             height,
             camera,
+            camera_index: 0,
             sample_generator: SampleGenerator::new(),
             film: Film::new(width * height),
+            film_right: Film::new(0),
+            exposure: Exposure::default(),
+            tonemapper: Tonemapper::default(),
+            integrator: Integrator::default(),
+            rng: StdRng::from_os_rng(),
+            stereo: None,
             accel,
+            accel_quality: 1.0,
             current_row: 0,
             scene,
         }
     }
 
-    pub fn trace_frame_additive(&mut self) -> u32 {
-        const RECURSIONS: u8 = 2;
-        const SUB_SPREAD: u32 = 1;
+    // the same seed traces the same rays, for renders that can be reproduced exactly
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+        self.sample_generator = SampleGenerator::with_rng(&mut self.rng);
+    }
+
+    // the resolution is the one the raytracer was made with
+    pub fn apply_settings(&mut self, settings: &RenderSettings) {
+        self.integrator = settings.integrator;
+        self.tonemapper = settings.tonemapper;
+        if let Some(seed) = settings.seed {
+            self.set_seed(seed);
+        }
+    }
+
+    pub fn set_stereo(&mut self, stereo: Option<StereoMode>) {
+        self.stereo = stereo;
+        self.film_right = match stereo {
+            Some(StereoMode::Anaglyph) => Film::new(self.width * self.height),
+            _ => Film::new(0),
+        };
+        self.film.clear();
+    }
+
+    pub fn scene(&self) -> &Scene {
+        &self.scene
+    }
+
+    pub fn num_cameras(&self) -> usize {
+        self.scene.cameras.len()
+    }
 
-        let mut rng = StdRng::from_os_rng();
+    pub fn camera_index(&self) -> usize {
+        self.camera_index
+    }
+
+    // index of the scene camera with the given name, or the given index as a number
+    pub fn find_camera(&self, name_or_index: &str) -> Option<usize> {
+        self.scene.find_camera(name_or_index)
+    }
+
+    // switches to one of the scene cameras, discarding any changes made to the current camera
+    pub fn select_camera(&mut self, index: usize) {
+        if let Some(camera) = self.scene.cameras.get(index) {
+            let time = self.camera.time();
+            self.camera = camera.clone();
+            self.camera.set_resolution(self.width, self.height);
+            self.camera.set_time(time);
+            self.camera_index = index;
+            self.clear_film();
+        }
+    }
+
+    // animates one of the scene geometries, None makes it static again
+    pub fn set_geometry_motion(&mut self, geometry_index: usize, motion: Option<Motion>) {
+        if let Some(geom) = self.scene.geometries.get_mut(geometry_index) {
+            geom.set_motion(motion);
+            self.update_accel();
+            self.clear_film();
+        }
+    }
+
+    // adds an analytic shape to the scene, returns its index
+    pub fn add_shape(&mut self, shape: Shape) -> usize {
+        self.scene.shapes.push(shape);
+        self.accel.rebuild(&self.scene);
+        self.accel_quality = 1.0;
+        self.clear_film();
+        self.scene.shapes.len() - 1
+    }
+
+    // the geometries and instances that can be moved with set_object_transform
+    pub fn objects(&self) -> Vec<SceneObject> {
+        self.scene.objects()
+    }
+
+    pub fn object_transform(&self, object: SceneObject) -> Option<Matrix> {
+        self.scene.object_transform(object).copied()
+    }
+
+    // places the object with the transform on top of its pose as loaded, false if it can't be
+    pub fn set_object_transform(&mut self, object: SceneObject, transform: &Matrix) -> bool {
+        if !self.scene.set_object_transform(object, transform) {
+            return false;
+        }
+        self.update_accel();
+        self.clear_film();
+        true
+    }
+
+    // sets the transform of every object in the scene
+    pub fn apply_transform(&mut self, transform: &Matrix) {
+        self.scene.apply_transform(transform);
+        self.update_accel();
+        self.clear_film();
+    }
+
+    // moves the scene and camera to the given time, e.g. the start of an animation frame
+    pub fn set_time(&mut self, time: f32) {
+        self.camera.set_time(time);
+        let (open, close) = self.camera.shutter_interval();
+        self.scene.set_time_range(open, close);
+        if self
+            .scene
+            .geometries
+            .iter()
+            .chain(self.scene.meshes.iter())
+            .any(|geom| geom.motion.is_some())
+        {
+            self.update_accel();
+        }
+        self.clear_film();
+    }
+
+    // adds the given number of samples to every pixel
+    pub fn trace_samples_per_pixel(&mut self, samples: usize) -> u32 {
+        self.current_row = 0;
+        let mut num_primary_rays = 0;
+        for _ in 0..(samples * self.height).div_ceil(ROWS_PER_TRACE) {
+            num_primary_rays += self.trace_frame_additive();
+        }
+        num_primary_rays
+    }
+
+    pub fn save_tonemapped_pixels<P: AsRef<std::path::Path>>(
+        &mut self,
+        path: P,
+    ) -> Result<(), String> {
+        let pixels = self.get_tonemapped_pixels();
+        let mut img = image::RgbImage::new(self.width as u32, self.height as u32);
+        for (img_pixel, pixel) in img.pixels_mut().zip(pixels) {
+            *img_pixel = image::Rgb([(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8]);
+        }
+        img.save(path).map_err(|e| e.to_string())
+    }
+
+    pub fn accel_build_time(&self) -> std::time::Duration {
+        self.accel.build_time()
+    }
+
+    pub fn accel_stats(&self) -> accel_intersect::accel_stats::AccelStats {
+        self.accel.stats()
+    }
+
+    // quality of the accel after the last refit, 1.0 is as good as a rebuild
+    pub fn accel_quality(&self) -> f32 {
+        self.accel_quality
+    }
+
+    // refits the accel to moved geometry, or rebuilds it once refitting has degraded it too much
+    fn update_accel(&mut self) {
+        self.accel_quality = self.accel.refit(&self.scene);
+        if self.accel_quality < MIN_REFIT_QUALITY {
+            self.accel.rebuild(&self.scene);
+            self.accel_quality = 1.0;
+        }
+    }
+
+    pub fn clear_film(&mut self) {
+        self.film.clear();
+        self.film_right.clear();
+    }
+
+    pub fn trace_frame_additive(&mut self) -> u32 {
+        let mut rng = StdRng::from_rng(&mut self.rng);
+        let bounces = self.integrator.bounces();
+
+        // side by side renders each eye at half width
+        let half_width = self.width / 2;
+        let (left_camera, right_camera) = match self.stereo {
+            None => (self.camera.clone(), self.camera.clone()),
+            Some(StereoMode::Anaglyph) => {
+                (self.camera.eye(Eye::Left), self.camera.eye(Eye::Right))
+            }
+            Some(StereoMode::SideBySide) => {
+                let mut left_camera = self.camera.eye(Eye::Left);
+                let mut right_camera = self.camera.eye(Eye::Right);
+                left_camera.set_resolution(half_width, self.height);
+                right_camera.set_resolution(self.width - half_width, self.height);
+                (left_camera, right_camera)
+            }
+        };
 
         let mut num_primary_rays = 0;
-        for _ in 0..50 {
-            for (i, pixel_data) in self.film.pixel_datas
-                [self.current_row * self.width..(self.current_row + 1) * self.width]
-                .iter_mut()
-                .enumerate()
-            {
-                let idx = self.current_row * self.width + i;
-                let ray = self
-                    .camera
-                    .get_ray(idx % self.width, idx / self.height, &mut rng);
-
-                let hit = self.accel.intersect_ray(&self.scene, &ray);
-                let color = match hit {
-                    None => RGB::black(),
-                    Some(ref hit) => compute_radiance(
-                        &self.accel,
-                        &self.scene,
-                        &ray,
-                        hit,
-                        &mut self.sample_generator,
-                        RECURSIONS,
-                        SUB_SPREAD,
-                    ),
-                };
+        for _ in 0..ROWS_PER_TRACE {
+            let row = self.current_row * self.width..(self.current_row + 1) * self.width;
+            let (rays, differentials): (Vec<_>, Vec<_>) = (0..self.width)
+                .map(|i| match self.stereo {
+                    Some(StereoMode::SideBySide) if i >= half_width => right_camera
+                        .get_ray_differential(i - half_width, self.current_row, &mut rng),
+                    _ => left_camera.get_ray_differential(i, self.current_row, &mut rng),
+                })
+                .unzip();
+            let colors = trace_primary_rays(
+                &self.accel,
+                &self.scene,
+                &rays,
+                &differentials,
+                &mut self.sample_generator,
+                &mut rng,
+                bounces,
+            );
+            for (pixel_data, color) in self.film.pixel_datas[row.clone()].iter_mut().zip(colors) {
                 pixel_data.add_sample(color);
             }
             num_primary_rays += self.width as u32;
+
+            if let Some(StereoMode::Anaglyph) = self.stereo {
+                let (rays, differentials): (Vec<_>, Vec<_>) = (0..self.width)
+                    .map(|i| right_camera.get_ray_differential(i, self.current_row, &mut rng))
+                    .unzip();
+                let colors = trace_primary_rays(
+                    &self.accel,
+                    &self.scene,
+                    &rays,
+                    &differentials,
+                    &mut self.sample_generator,
+                    &mut rng,
+                    bounces,
+                );
+                for (pixel_data, color) in self.film_right.pixel_datas[row].iter_mut().zip(colors) {
+                    pixel_data.add_sample(color);
+                }
+                num_primary_rays += self.width as u32;
+            }
+
             self.current_row = (self.current_row + 1) % self.height;
         }
         num_primary_rays
     }
 
-
-    pub fn get_tonemapped_pixels(&self) -> Vec<u32> {
+    pub fn get_tonemapped_pixels(&mut self) -> Vec<u32> {
         let hdr_frame = self.film.get_pixels();
+        let exposure = self.exposure.update(&hdr_frame);
+        let tonemapper = self.tonemapper;
         let ldr_frame = hdr_frame
             .iter()
-            .map(|pix| tonemap::simple_map(pix))
-            .map(|pix| RGBA::from_rgb(pix, 1.0).to_u32())
-            .collect();
+            .map(|pix| tonemapper.map(&(pix * exposure)));
+
+        if let Some(StereoMode::Anaglyph) = self.stereo {
+            // red from the left eye, green and blue from the right
+            let hdr_frame_right = self.film_right.get_pixels();
+            let ldr_frame_right = hdr_frame_right
+                .iter()
+                .map(|pix| tonemapper.map(&(pix * exposure)));
+            return ldr_frame
+                .zip(ldr_frame_right)
+                .map(|(left, right)| RGB::new(left.r, right.g, right.b))
+                .map(|pix| RGBA::from_rgb(pix, 1.0).to_u32())
+                .collect();
+        }
+
         ldr_frame
+            .map(|pix| RGBA::from_rgb(pix, 1.0).to_u32())
+            .collect()
     }
+}
 
+// camera rays through neighbouring pixels are coherent, so they are intersected as packets
+fn trace_primary_rays<Accel>(
+    accel: &Accel,
+    scene: &Scene,
+    rays: &[Ray],
+    differentials: &[RayDifferential],
+    sample_generator: &mut SampleGenerator,
+    rng: &mut StdRng,
+    bounces: u8,
+) -> Vec<RGB>
+where
+    Accel: Intersector,
+{
+    let mut colors = Vec::with_capacity(rays.len());
+    for chunk in rays.chunks(PACKET_SIZE) {
+        // the last packet is padded with copies of its first ray
+        let packet: [Ray; PACKET_SIZE] =
+            std::array::from_fn(|lane| chunk.get(lane).unwrap_or(&chunk[0]).clone());
+        let hits = accel.intersect_packet(scene, &packet);
+        let chunk_differentials = &differentials[colors.len()..colors.len() + chunk.len()];
+        for ((ray, differential), hit) in chunk.iter().zip(chunk_differentials).zip(hits.iter()) {
+            colors.push(match hit {
+                None => RGB::black(),
+                Some(hit) => compute_radiance(
+                    accel,
+                    scene,
+                    ray,
+                    Some(differential),
+                    hit,
+                    sample_generator,
+                    rng,
+                    bounces,
+                    MAX_TRANSMISSIONS,
+                ),
+            });
+        }
+    }
+    colors
 }
 
+// the differential, when known, sets how blurry textures are looked up
+#[allow(clippy::too_many_arguments)]
 fn compute_radiance<Accel>(
     accel: &Accel,
     scene: &Scene,
     ray: &Ray,
+    differential: Option<&RayDifferential>,
     hit: &Hit,
     sample_generator: &mut SampleGenerator,
+    rng: &mut StdRng,
     recursions: u8,
-    spread: u32,
+    transmissions: u8,
 ) -> RGB
 where
     Accel: Intersector,
 {
-    let normal = calc_normal(scene, &hit);
-    let radiance = shade(accel, scene, ray, hit, &normal);
+    let material = hit_material(scene, hit);
+    let mut surface = hit_surface(scene, ray, hit);
+    let (bsdf, shading_normal) = hit_bsdf(scene, ray, differential, hit, &surface);
+    surface.shading_normal = shading_normal;
+    // what isn't let through is reflected by the bsdf, all of it once nothing more is let through
+    let opacity = if transmissions > 0 {
+        1.0 - material.transparency.clamp(0.0, 1.0)
+    } else {
+        1.0
+    };
+    let refracted = refracted_radiance(
+        accel,
+        scene,
+        ray,
+        &surface,
+        material,
+        opacity,
+        sample_generator,
+        rng,
+        recursions,
+        transmissions,
+    );
+    let radiance = material.emissive + shade(accel, scene, ray, &surface, &bsdf) * opacity + refracted;
     if recursions < 1 {
         return radiance;
     }
 
-    let num_sub_rays = spread * recursions as u32;
-
-    let mut rng = StdRng::from_os_rng(); // TODO move this to new
+    let num_sub_rays = SUB_SPREAD * recursions as u32;
+    let wo = -ray.dir.normalized();
 
     let sub_radiance = (0..num_sub_rays)
         .map(|_| {
-            let sub_ray = randomize_reflection_ray(sample_generator, hit, ray, &normal, &mut rng);
+            let sample = bsdf.sample(&surface.shading_normal, &wo, sample_generator, &mut *rng);
+            let (dir, weight) = match sample {
+                // a bumped normal can send it into the surface
+                Some(sample) if dot(&sample.0, &surface.normal) > 0.0 => sample,
+                _ => return RGB::black(),
+            };
+            let sub_ray = spawn_ray(&surface, dir, ray.time);
 
             let sub_hit = accel.intersect_ray(&scene, &sub_ray);
 
             match sub_hit {
-                Some(sub_hit) => compute_radiance(
-                    accel,
-                    scene,
-                    &sub_ray,
-                    &sub_hit,
-                    sample_generator,
-                    recursions - 1,
-                    spread,
-                ),
+                Some(sub_hit) => {
+                    weight
+                        * compute_radiance(
+                            accel,
+                            scene,
+                            &sub_ray,
+                            None,
+                            &sub_hit,
+                            sample_generator,
+                            rng,
+                            recursions - 1,
+                            transmissions,
+                        )
+                }
                 None => RGB::black(),
             }
         })
         .fold(RGB::black(), |sum, x| sum + x)
         * (1.0f32 / num_sub_rays as f32);
-    radiance + sub_radiance
+
+    radiance + sub_radiance * opacity
 }
 
-fn randomize_reflection_ray(
-    sample_generator: &mut SampleGenerator,
-    hit: &Hit,
+// The light let through the surface, on its own budget so that transparent surfaces stay
+// transparent whatever the number of diffuse bounces left.
+#[allow(clippy::too_many_arguments)]
+fn refracted_radiance<Accel>(
+    accel: &Accel,
+    scene: &Scene,
     ray: &Ray,
-    normal: &Vec3,
-    rng: impl rand::Rng,
-) -> Ray {
-    // get random direction on hemisphere
-    let mut random_dir = sample_generator.normalized_vec_pseudo(rng);
-    while dot(&random_dir, normal) <= 0.0 {
-        random_dir = sample_generator.normalized_vec_lookup();
+    surface: &SurfacePoint,
+    material: &Material,
+    opacity: f32,
+    sample_generator: &mut SampleGenerator,
+    rng: &mut StdRng,
+    recursions: u8,
+    transmissions: u8,
+) -> RGB
+where
+    Accel: Intersector,
+{
+    if opacity >= 1.0 {
+        return RGB::black();
+    }
+    // the index is relative to what the surface normal points into
+    let eta = if surface.front_face {
+        1.0 / material.index_of_refraction
+    } else {
+        material.index_of_refraction
+    };
+    let wo = -ray.dir.normalized();
+    let dir = refract(&wo, &surface.shading_normal, eta);
+    let refracted_ray = spawn_ray(surface, dir, ray.time);
+    match accel.intersect_ray(scene, &refracted_ray) {
+        Some(refracted_hit) => {
+            compute_radiance(
+                accel,
+                scene,
+                &refracted_ray,
+                None,
+                &refracted_hit,
+                sample_generator,
+                rng,
+                recursions,
+                transmissions - 1,
+            ) * (1.0 - opacity)
+        }
+        None => RGB::black(),
+    }
+}
+
+// wo bent through the surface by Snell's law, eta being the ratio of the indices of refraction on
+// wo's side and the other. Reflected at angles past total internal reflection.
+fn refract(wo: &Vec3, normal: &Vec3, eta: f32) -> Vec3 {
+    let cos_i = dot(wo, normal);
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i).max(0.0);
+    if sin2_t >= 1.0 {
+        return 2.0 * cos_i * normal - wo;
     }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    (-eta * wo + (eta * cos_i - cos_t) * normal).normalized()
+}
+
+// where a ray hit, with the geometric normal of the triangle or shape, turned toward the ray
+struct SurfacePoint {
+    pos: Vec3,
+    normal: Vec3,
+    // the normal as perturbed by the material, to shade with
+    shading_normal: Vec3,
+    // whether the ray hit the side the normal originally pointed out of
+    front_face: bool,
+    // world space edges from the first corner of the triangle hit, None for shapes
+    edges: Option<(Vec3, Vec3)>,
+}
+
+// a ray leaving the surface. Its origin is moved off the surface so it can't hit the triangle it
+// starts on, whatever the scale of the scene.
+fn spawn_ray(surface: &SurfacePoint, dir: Vec3, time: f32) -> Ray {
+    let pos = intersect::offset_ray_origin(&surface.pos, &surface.normal, &dir);
+    Ray::with_time(pos, dir, time)
+}
 
-    // calc pos and offset slightly
-    let hit_point = ray.pos + hit.hit_info.t * ray.dir;
-    let hit_point = hit_point + 0.00001 * &random_dir;
+// the geometry that was hit, either placed directly in the scene or an instanced mesh
+fn hit_geometry<'a>(scene: &'a Scene, hit: &Hit) -> &'a Geometry {
+    match hit.instance_index {
+        None => &scene.geometries[hit.geometry_index],
+        Some(_) => &scene.meshes[hit.geometry_index],
+    }
+}
 
-    Ray::new(hit_point, random_dir)
+fn hit_material<'a>(scene: &'a Scene, hit: &Hit) -> &'a Material {
+    match hit.shape_index {
+        Some(shape_index) => &scene.shapes[shape_index].material,
+        None => &hit_geometry(scene, hit).material,
+    }
 }
 
-fn calc_normal(scene: &Scene, hit: &Hit) -> Vec3 {
-    let geom_vertices = &scene.geometries[hit.geometry_index].vertices;
-    let normal = cross(
-        &(geom_vertices[hit.vertex_index + 1] - geom_vertices[hit.vertex_index]),
-        &(geom_vertices[hit.vertex_index + 2] - geom_vertices[hit.vertex_index]),
+// The position is interpolated from the triangle's vertices rather than followed along the ray,
+// which keeps its error small however far the ray travelled. Shapes have no vertices, there it
+// is followed along the ray.
+fn hit_surface(scene: &Scene, ray: &Ray, hit: &Hit) -> SurfacePoint {
+    let surface_point = |pos: Vec3, normal: Vec3, edges| {
+        let front_face = dot(&normal, &ray.dir) <= 0.0;
+        let normal = if front_face { normal } else { -normal };
+        SurfacePoint {
+            pos,
+            normal,
+            shading_normal: normal,
+            front_face,
+            edges,
+        }
+    };
+    if let Some(shape_index) = hit.shape_index {
+        let pos = ray.pos + ray.dir * hit.hit_info.t;
+        return surface_point(pos, scene.shapes[shape_index].normal_at(&pos), None);
+    }
+    let mut geom_vertices = hit_geometry(scene, hit).triangle_at(hit.vertex_index, ray.time);
+    if let Some(instance_index) = hit.instance_index {
+        let instance = &scene.instances[instance_index];
+        geom_vertices = geom_vertices.map(|vtx| instance.to_world(&vtx));
+    }
+    let edges = (
+        geom_vertices[1] - geom_vertices[0],
+        geom_vertices[2] - geom_vertices[0],
     );
-    normal.normalized()
+    let normal = cross(&edges.0, &edges.1);
+    let (u, v) = (hit.hit_info.u, hit.hit_info.v);
+    surface_point(
+        (1.0 - u - v) * geom_vertices[0] + u * geom_vertices[1] + v * geom_vertices[2],
+        normal.normalized(),
+        Some(edges),
+    )
+}
+
+// the material of the hit with its textures looked up, and the normal to shade with
+fn hit_bsdf(
+    scene: &Scene,
+    ray: &Ray,
+    differential: Option<&RayDifferential>,
+    hit: &Hit,
+    surface: &SurfacePoint,
+) -> (Bsdf, Vec3) {
+    let material = hit_material(scene, hit);
+    if let (Diffuse::Color(rgb), None) = (&material.diffuse, &material.bump) {
+        return (Bsdf::new(rgb, material), surface.normal);
+    }
+
+    let (u, v, derivatives) = hit_uv(scene, ray, differential, hit, surface);
+    let sample = |texture_id: usize, u: f32, v: f32| {
+        material
+            .sampler
+            .sample(&scene.textures[texture_id], u, v, derivatives.as_ref())
+    };
+    let base_color = match &material.diffuse {
+        Diffuse::Color(rgb) => *rgb,
+        Diffuse::TextureId(tex_id) => RGB::from(sample(*tex_id, u, v)),
+    };
+
+    let tangent_normal = match material.bump {
+        None => None,
+        Some(Bump::NormalMap(texture_id)) => {
+            Some(bump::normal_map_normal(&sample(texture_id, u, v)))
+        }
+        Some(Bump::HeightMap { texture_id, scale }) => {
+            // central differences, a texel of the full size image apart
+            let texture = &scene.textures[texture_id];
+            let (du, dv) = (1.0 / texture.width() as f32, 1.0 / texture.height() as f32);
+            let height = |u, v| bump::height(&sample(texture_id, u, v));
+            let rise_x = (height(u + du, v) - height(u - du, v)) * 0.5;
+            // up the image is toward decreasing v
+            let rise_y = (height(u, v - dv) - height(u, v + dv)) * 0.5;
+            Some(bump::height_map_normal(rise_x, rise_y, scale))
+        }
+    };
+    let shading_normal = tangent_normal
+        .and_then(|tangent_normal| {
+            let (dpdu, dpdv) = hit_tangents(scene, ray, hit, surface)?;
+            let frame = bump::TangentFrame::new(&surface.normal, &dpdu, &dpdv)?;
+            Some(frame.to_world(&tangent_normal))
+        })
+        // normals facing away from the ray would leave the surface black
+        .filter(|normal| dot(normal, &ray.dir) < 0.0)
+        .unwrap_or(surface.normal);
+    (Bsdf::new(&base_color, material), shading_normal)
+}
+
+// World space (dp/du, dp/dv) of the triangle hit, from the geometry's tangents or else its edges,
+// which are the tangents of the barycentric coordinates. None for shapes.
+fn hit_tangents(
+    scene: &Scene,
+    ray: &Ray,
+    hit: &Hit,
+    surface: &SurfacePoint,
+) -> Option<(Vec3, Vec3)> {
+    if hit.shape_index.is_some() {
+        return None;
+    }
+    let tangents = hit_geometry(scene, hit).triangle_tangents_at(hit.vertex_index, ray.time);
+    match (tangents, hit.instance_index) {
+        (None, _) => surface.edges,
+        (Some(tangents), None) => Some(tangents),
+        (Some((dpdu, dpdv)), Some(instance_index)) => {
+            let instance = &scene.instances[instance_index];
+            Some((instance.direction_to_world(&dpdu), instance.direction_to_world(&dpdv)))
+        }
+    }
 }
 
-fn shade<Accel>(accel: &Accel, scene: &Scene, ray: &Ray, hit: &Hit, normal: &Vec3) -> RGB
+// Texture coordinates of the hit, from the geometry's uvs or else the barycentric coordinates,
+// or those of the shape. For triangle hits by camera rays, also how they change from pixel to
+// pixel: the differential rays are followed to the plane of the triangle, and the offsets there
+// turned into offsets in barycentric coordinates, then in texture coordinates.
+fn hit_uv(
+    scene: &Scene,
+    ray: &Ray,
+    differential: Option<&RayDifferential>,
+    hit: &Hit,
+    surface: &SurfacePoint,
+) -> (f32, f32, Option<UvDerivatives>) {
+    let (b1, b2) = (hit.hit_info.u, hit.hit_info.v);
+    let uvs = match hit.shape_index {
+        Some(_) => None,
+        None => hit_geometry(scene, hit).triangle_uvs(hit.vertex_index),
+    };
+    let (u, v) = match &uvs {
+        None => (b1, b2),
+        Some([uv0, uv1, uv2]) => (
+            (1.0 - b1 - b2) * uv0.0 + b1 * uv1.0 + b2 * uv2.0,
+            (1.0 - b1 - b2) * uv0.1 + b1 * uv1.1 + b2 * uv2.1,
+        ),
+    };
+    let (differential, (e1, e2)) = match (differential, surface.edges) {
+        (Some(differential), Some(edges)) => (differential, edges),
+        _ => return (u, v, None),
+    };
+
+    // where the offset ray meets the plane of the triangle, relative to the hit
+    let plane_offset = |dpos: &Vec3, ddir: &Vec3| {
+        let pos = ray.pos + dpos;
+        let dir = ray.dir + ddir;
+        let denom = dot(&surface.normal, &dir);
+        if denom.abs() < 1e-12 {
+            return None;
+        }
+        let t = dot(&surface.normal, &(surface.pos - pos)) / denom;
+        Some(pos + dir * t - surface.pos)
+    };
+    // the barycentric coordinates of the offset, solved in the plane by least squares
+    let (a, b, c) = (dot(&e1, &e1), dot(&e1, &e2), dot(&e2, &e2));
+    let det = a * c - b * b;
+    if det.abs() < 1e-20 {
+        return (u, v, None);
+    }
+    let uv_offset = |offset: Vec3| {
+        let (p1, p2) = (dot(&offset, &e1), dot(&offset, &e2));
+        let (db1, db2) = ((c * p1 - b * p2) / det, (a * p2 - b * p1) / det);
+        match &uvs {
+            None => (db1, db2),
+            Some([uv0, uv1, uv2]) => (
+                db1 * (uv1.0 - uv0.0) + db2 * (uv2.0 - uv0.0),
+                db1 * (uv1.1 - uv0.1) + db2 * (uv2.1 - uv0.1),
+            ),
+        }
+    };
+    let derivatives = match (
+        plane_offset(&differential.dpos_dx, &differential.ddir_dx),
+        plane_offset(&differential.dpos_dy, &differential.ddir_dy),
+    ) {
+        (Some(offset_x), Some(offset_y)) => Some(UvDerivatives {
+            duv_dx: uv_offset(offset_x),
+            duv_dy: uv_offset(offset_y),
+        }),
+        _ => None,
+    };
+    (u, v, derivatives)
+}
+
+// Lights are points, scaled so a white lambertian surface facing one reflects its color
+fn shade<Accel>(accel: &Accel, scene: &Scene, ray: &Ray, surface: &SurfacePoint, bsdf: &Bsdf) -> RGB
 where
     Accel: Intersector,
 {
     let mut accum_color = RGB::black();
-    let hit_point = ray.pos + hit.hit_info.t * ray.dir;
+    let normal = &surface.shading_normal;
+    let wo = -ray.dir.normalized();
 
     for light in &scene.lights {
-        let ray_to_light = Ray::new(hit_point, light.pos - hit_point);
-        let dot_light_normal = dot(&normal, &ray_to_light.dir.normalized());
+        // reaches the light at t = 1
+        let ray_to_light = spawn_ray(surface, light.pos - surface.pos, ray.time);
+        let wi = ray_to_light.dir.normalized();
+        let dot_light_normal = dot(normal, &wi);
 
-        if dot_light_normal < 0.0 {
+        if dot_light_normal < 0.0 || dot(&surface.normal, &wi) < 0.0 {
             continue; // triangle is facing away from light
         }
 
         //is light blocked by geometry?
         let mut blocked = false;
-        let ray_to_light_offseted =
-            Ray::new(ray_to_light.pos + ray_to_light.dir * 0.01, ray_to_light.dir);
-        if let Some(hit) = accel.intersect_ray(&scene, &ray_to_light_offseted) {
-            if hit.hit_info.t > 0.01 && hit.hit_info.t < 1.0 {
+        if let Some(hit) = accel.intersect_ray(scene, &ray_to_light) {
+            if hit.hit_info.t < 1.0 {
                 blocked = true;
             }
         }
 
         if !blocked {
-            //lambertian / diffuse
-            // accum_color += dot_light_normal
-            //     * light.color
-            //     * scene.geometries[hit.geometry_index].material.diffuse;
-
-            // phong
-            {
-                const SPECULAR: RGB = RGB::white();
-                let diffuse = &scene.geometries[hit.geometry_index].material.diffuse;
-                let diffuse_rgb = match diffuse {
-                    Diffuse::Color(rgb) => rgb,
-                    Diffuse::TextureId(tex_id) => {
-                        let texture = &scene.textures[*tex_id];
-                        texture.get_texel(hit.hit_info.u, hit.hit_info.v)
-                    }
-                };
-
-                const SHININESS: f32 = 32.0;
-                let view_ray = ray.dir.normalized();
-                let reflected_light =
-                    2.0 * dot_light_normal * normal - ray_to_light.dir.normalized();
-                accum_color += (diffuse_rgb * dot_light_normal
-                    + SPECULAR * dot(&view_ray, &reflected_light).powf(SHININESS))
-                    * light.color;
-            }
+            accum_color += bsdf.eval(normal, &wo, &wi)
+                * (dot_light_normal * std::f32::consts::PI)
+                * light.color;
         }
     }
     accum_color
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::builder::SceneBuilder;
+    use crate::scene::camera::{FieldOfView, Projection};
+    use crate::scene::shape::ShapeKind;
+    use accel_intersect::no_acceleration_intersector::NoAccelerationIntersector;
+
+    // straight into a lit wall, through a window when there is one
+    fn wall_radiance(window_transparency: Option<f32>) -> RGB {
+        let mut builder = SceneBuilder::new();
+        let wall = builder.add_material(Material::default());
+        let quad = |z: f32| ShapeKind::Quad {
+            corner: Vec3::new(-2.0, -2.0, z),
+            edge_u: Vec3::new(4.0, 0.0, 0.0),
+            edge_v: Vec3::new(0.0, 4.0, 0.0),
+        };
+        builder.add_shape(quad(3.0), wall);
+        if let Some(transparency) = window_transparency {
+            let window = builder.add_material(Material {
+                transparency,
+                ..Material::default()
+            });
+            builder.add_shape(quad(1.0), window);
+        }
+        // between the window and the wall
+        builder.add_light(Vec3::new(0.5, 0.5, 2.0), RGB::white());
+        let scene = builder.build().unwrap();
+
+        let accel = NoAccelerationIntersector::new(&scene);
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0));
+        let hit = accel.intersect_ray(&scene, &ray).unwrap();
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut sample_generator = SampleGenerator::with_rng(&mut rng);
+        let direct = Integrator::Direct.bounces();
+        compute_radiance(
+            &accel,
+            &scene,
+            &ray,
+            None,
+            &hit,
+            &mut sample_generator,
+            &mut rng,
+            direct,
+            MAX_TRANSMISSIONS,
+        )
+    }
+
+    #[test]
+    fn test_transparent_window_under_direct_light() {
+        let wall = wall_radiance(None);
+        assert!(wall.r > 0.0);
+        let through_window = wall_radiance(Some(1.0));
+        assert!((through_window.r - wall.r).abs() < 1e-5);
+        let through_half = wall_radiance(Some(0.5));
+        assert!((through_half.r - 0.5 * wall.r).abs() < 1e-5);
+    }
+
+    // (r, g, b) of each pixel of a 2x1 image, with a red wall in front of the left eye and a
+    // cyan one in front of the right
+    fn stereo_pixels(stereo: StereoMode) -> Vec<(u32, u32, u32)> {
+        let mut builder = SceneBuilder::new();
+        let red = builder.add_material(Material::new(Diffuse::Color(RGB::new(1.0, 0.0, 0.0))));
+        let cyan = builder.add_material(Material::new(Diffuse::Color(RGB::new(0.0, 1.0, 1.0))));
+        let wall = |x: f32| ShapeKind::Quad {
+            corner: Vec3::new(x, -4.0, 5.0),
+            edge_u: Vec3::new(4.0, 0.0, 0.0),
+            edge_v: Vec3::new(0.0, 8.0, 0.0),
+        };
+        builder.add_shape(wall(-4.0), red);
+        builder.add_shape(wall(0.0), cyan);
+        builder.add_light(Vec3::new(0.0, 0.0, 1.0), RGB::white());
+        let scene = builder.build().unwrap();
+
+        // eyes 4 apart looking straight ahead, each only sees the wall in front of it
+        let mut camera = Camera::from_orientation_matrix(
+            2,
+            1,
+            &Matrix::ident(),
+            Projection::Perspective(FieldOfView::horizontal(10.0)),
+        );
+        camera.set_stereo(4.0, 1e6);
+        let accel = NoAccelerationIntersector::new(&scene);
+        let mut raytracer = RayTracer::new_with_intersector(2, 1, camera, accel, scene);
+        raytracer.set_seed(1);
+        raytracer.integrator = Integrator::Direct;
+        raytracer.set_stereo(Some(stereo));
+        raytracer.trace_frame_additive();
+        raytracer
+            .get_tonemapped_pixels()
+            .into_iter()
+            .map(|pix| ((pix >> 16) & 0xff, (pix >> 8) & 0xff, pix & 0xff))
+            .collect()
+    }
+
+    #[test]
+    fn test_stereo_eyes() {
+        // the tonemapper keeps black channels a little above 0
+        let lit = |channel: u32| channel > 10;
+
+        // left eye in the left half, right eye in the right half
+        let side_by_side = stereo_pixels(StereoMode::SideBySide);
+        let (r, g, b) = side_by_side[0];
+        assert!(lit(r) && !lit(g) && !lit(b), "{:?}", side_by_side);
+        let (r, g, b) = side_by_side[1];
+        assert!(!lit(r) && lit(g) && lit(b), "{:?}", side_by_side);
+
+        // red from the left eye, green and blue from the right, in every pixel
+        for (r, g, b) in stereo_pixels(StereoMode::Anaglyph) {
+            assert!(lit(r) && lit(g) && lit(b));
+        }
+    }
+}
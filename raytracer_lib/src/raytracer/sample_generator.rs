@@ -13,8 +13,13 @@ pub struct SampleGenerator {
 
 impl SampleGenerator {
     pub fn new() -> Self {
+        SampleGenerator::with_rng(&mut rand::rng())
+    }
+
+    // the same rng state gives the same samples
+    pub fn with_rng(rng: &mut impl Rng) -> Self {
         let normalized_vecs: Vec<Vec3> = (0..NUM_SAMPLES)
-            .map(|_| Self::generate_normalized_vec3())
+            .map(|_| Self::generate_normalized_vec3(&mut *rng))
             .collect();
 
         SampleGenerator {
@@ -33,9 +38,7 @@ impl SampleGenerator {
         self.normalized_vecs[self.sample_idx as usize]
     }
 
-    fn generate_normalized_vec3() -> Vec3 {
-        let mut rng = rand::rng();
-
+    fn generate_normalized_vec3(mut rng: impl Rng) -> Vec3 {
         // randomize in box, until inside unit sphere, then normalize
         let dir = loop {
             let dir = Vec3::new(
@@ -0,0 +1,50 @@
+use super::tonemap::Tonemapper;
+
+// how the light arriving at a surface is gathered
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Integrator {
+    // straight from the lights only
+    Direct,
+    // also light bounced off diffuse surfaces, up to the given number of bounces
+    Diffuse { bounces: u8 },
+}
+
+impl Default for Integrator {
+    fn default() -> Self {
+        Integrator::Diffuse { bounces: 2 }
+    }
+}
+
+impl Integrator {
+    pub fn bounces(&self) -> u8 {
+        match self {
+            Integrator::Direct => 0,
+            Integrator::Diffuse { bounces } => *bounces,
+        }
+    }
+}
+
+// everything besides the scene that decides how a render turns out
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderSettings {
+    pub width: usize,
+    pub height: usize,
+    pub samples_per_pixel: usize,
+    pub integrator: Integrator,
+    pub tonemapper: Tonemapper,
+    // the same seed renders the same noise, None seeds from the os
+    pub seed: Option<u64>,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        RenderSettings {
+            width: 1024,
+            height: 768,
+            samples_per_pixel: 16,
+            integrator: Integrator::default(),
+            tonemapper: Tonemapper::default(),
+            seed: None,
+        }
+    }
+}
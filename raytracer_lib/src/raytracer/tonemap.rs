@@ -1,6 +1,27 @@
 use crate::scene::color::RGB;
 
-#[allow(dead_code)]
+// how exposed radiance is compressed into the displayable range
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Tonemapper {
+    // per channel, desaturates bright colors
+    #[default]
+    Simple,
+    // on the luminance only, keeps the hue of bright colors
+    Luminance,
+    // gamma compressed luminance, keeps more contrast in the highlights
+    Gamma,
+}
+
+impl Tonemapper {
+    pub fn map(&self, color: &RGB) -> RGB {
+        match self {
+            Tonemapper::Simple => simple_map(color),
+            Tonemapper::Luminance => luminance_simple_map(color),
+            Tonemapper::Gamma => gamma_map(color),
+        }
+    }
+}
+
 pub fn simple_map(color: &RGB) -> RGB {
     RGB::new(
         color.r / (1.0 + color.r),
@@ -9,7 +30,6 @@ pub fn simple_map(color: &RGB) -> RGB {
     )
 }
 
-#[allow(dead_code)]
 pub fn luminance_simple_map(color: &RGB) -> RGB {
     let mut xyz = to_xyz(color);
 
@@ -19,7 +39,6 @@ pub fn luminance_simple_map(color: &RGB) -> RGB {
     to_rgb(&xyz)
 }
 
-#[allow(dead_code)]
 pub fn gamma_map(color: &RGB) -> RGB {
     const A: f32 = 0.5; // [0..inf)
     const GAMMA: f32 = 0.5; // [0..1]
@@ -32,6 +51,10 @@ pub fn gamma_map(color: &RGB) -> RGB {
     to_rgb(&xyz)
 }
 
+pub fn luminance(color: &RGB) -> f32 {
+    to_xyz(color).g
+}
+
 type XYZ = RGB;
 
 fn to_xyz(color: &RGB) -> XYZ {
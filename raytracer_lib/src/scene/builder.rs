@@ -0,0 +1,269 @@
+use super::camera::Camera;
+use super::color::{Diffuse, RGB};
+use super::shape::{Shape, ShapeKind};
+use super::texture::{ColorSpace, Texture, TextureLoadError, TextureLoader};
+use super::{Geometry, Instance, Light, Material, Matrix, Scene, Vertex, ALL_TIME};
+
+use std::{error, fmt};
+
+// Puts a scene together from code. The add functions return the index of what they added, which
+// is its index in the built scene too. Materials are shared by index, and checked along with
+// everything else that refers to something by index when the scene is built.
+#[derive(Default)]
+pub struct SceneBuilder {
+    materials: Vec<Material>,
+    textures: Vec<Texture>,
+    // (vertices, material index)
+    geometries: Vec<(Vec<Vertex>, usize)>,
+    meshes: Vec<(Vec<Vertex>, usize)>,
+    // (mesh index, placement)
+    instances: Vec<(usize, Matrix)>,
+    shapes: Vec<(ShapeKind, usize)>,
+    lights: Vec<Light>,
+    cameras: Vec<Camera>,
+}
+
+impl SceneBuilder {
+    pub fn new() -> Self {
+        SceneBuilder::default()
+    }
+
+    pub fn add_material(&mut self, material: Material) -> usize {
+        self.materials.push(material);
+        self.materials.len() - 1
+    }
+
+    // the index is the one to use in Diffuse::TextureId
+    pub fn add_texture(&mut self, texture: Texture) -> usize {
+        self.textures.push(texture);
+        self.textures.len() - 1
+    }
+
+    pub fn add_texture_file<P: AsRef<std::path::Path>>(
+        &mut self,
+        path: P,
+        color_space: ColorSpace,
+    ) -> Result<usize, TextureLoadError> {
+        Ok(self.add_texture(Texture::from_file(path, color_space)?))
+    }
+
+    // world space triangles, three vertices each
+    pub fn add_geometry(&mut self, vertices: Vec<Vertex>, material: usize) -> usize {
+        self.geometries.push((vertices, material));
+        self.geometries.len() - 1
+    }
+
+    // object space triangles, three vertices each, only drawn where instances place them
+    pub fn add_mesh(&mut self, vertices: Vec<Vertex>, material: usize) -> usize {
+        self.meshes.push((vertices, material));
+        self.meshes.len() - 1
+    }
+
+    pub fn add_instance(&mut self, mesh: usize, placement: Matrix) -> usize {
+        self.instances.push((mesh, placement));
+        self.instances.len() - 1
+    }
+
+    pub fn add_shape(&mut self, kind: ShapeKind, material: usize) -> usize {
+        self.shapes.push((kind, material));
+        self.shapes.len() - 1
+    }
+
+    pub fn add_light(&mut self, pos: Vertex, color: RGB) -> usize {
+        self.lights.push(Light::new(pos, color));
+        self.lights.len() - 1
+    }
+
+    // without cameras, the raytracer makes one that frames the whole scene
+    pub fn add_camera(&mut self, camera: Camera) -> usize {
+        self.cameras.push(camera);
+        self.cameras.len() - 1
+    }
+
+    pub fn build(self) -> Result<Scene, SceneBuildError> {
+        for (idx, material) in self.materials.iter().enumerate() {
+            if let Diffuse::TextureId(texture) = material.diffuse {
+                if texture >= self.textures.len() {
+                    return Err(SceneBuildError::Material(format!(
+                        "material {} uses texture {}, there are {}",
+                        idx,
+                        texture,
+                        self.textures.len()
+                    )));
+                }
+            }
+        }
+
+        let materials = &self.materials;
+        let material = |what: &str, idx: usize, material: usize| {
+            materials.get(material).cloned().ok_or_else(|| {
+                SceneBuildError::Material(format!(
+                    "{} {} uses material {}, there are {}",
+                    what,
+                    idx,
+                    material,
+                    materials.len()
+                ))
+            })
+        };
+        let geometry = |what: &str, idx: usize, (vertices, mat): (Vec<Vertex>, usize)| {
+            if vertices.len() % 3 != 0 {
+                return Err(SceneBuildError::Geometry(format!(
+                    "{} {} has {} vertices, not three per triangle",
+                    what,
+                    idx,
+                    vertices.len()
+                )));
+            }
+            Ok(Geometry::new(vertices, material(what, idx, mat)?))
+        };
+
+        let geometries = self
+            .geometries
+            .into_iter()
+            .enumerate()
+            .map(|(idx, geom)| geometry("geometry", idx, geom))
+            .collect::<Result<Vec<_>, _>>()?;
+        let meshes = self
+            .meshes
+            .into_iter()
+            .enumerate()
+            .map(|(idx, mesh)| geometry("mesh", idx, mesh))
+            .collect::<Result<Vec<_>, _>>()?;
+        let instances = self
+            .instances
+            .into_iter()
+            .enumerate()
+            .map(|(idx, (mesh, placement))| {
+                if mesh >= meshes.len() {
+                    return Err(SceneBuildError::Instance(format!(
+                        "instance {} uses mesh {}, there are {}",
+                        idx,
+                        mesh,
+                        meshes.len()
+                    )));
+                }
+                Instance::new(mesh, placement).ok_or_else(|| {
+                    SceneBuildError::Instance(format!(
+                        "instance {} has a placement that can't be inverted",
+                        idx
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let shapes = self
+            .shapes
+            .into_iter()
+            .enumerate()
+            .map(|(idx, (kind, mat))| {
+                Ok(Shape {
+                    kind,
+                    material: material("shape", idx, mat)?,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Scene {
+            geometries,
+            meshes,
+            instances,
+            shapes,
+            lights: self.lights,
+            cameras: self.cameras,
+            textures: self.textures,
+            time_range: ALL_TIME,
+        })
+    }
+}
+
+// -- Error Handling ----------------------------------------------------------
+
+#[derive(Debug)]
+pub enum SceneBuildError {
+    Geometry(String),
+    Instance(String),
+    Material(String),
+}
+
+impl fmt::Display for SceneBuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneBuildError::Geometry(e) => write!(f, "{}", e),
+            SceneBuildError::Instance(e) => write!(f, "{}", e),
+            SceneBuildError::Material(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for SceneBuildError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::Vec3;
+
+    fn triangle() -> Vec<Vertex> {
+        vec![
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+        ]
+    }
+
+    #[test]
+    fn test_build() {
+        let mut builder = SceneBuilder::new();
+        let red = builder.add_material(Material::new(Diffuse::Color(RGB::new(1.0, 0.0, 0.0))));
+        let textured = builder.add_material(Material::new(Diffuse::TextureId(0)));
+        builder.add_texture(Texture::new(1, 1, vec![RGB::white()]).unwrap());
+        assert_eq!(builder.add_geometry(triangle(), red), 0);
+        let mesh = builder.add_mesh(triangle(), textured);
+        assert_eq!(builder.add_instance(mesh, Matrix::ident()), 0);
+        assert_eq!(
+            builder.add_instance(mesh, Matrix::translate(&Vec3::new(0.0, 0.0, 2.0))),
+            1
+        );
+        builder.add_shape(
+            ShapeKind::Sphere {
+                center: Vec3::new(0.0, 0.0, 5.0),
+                radius: 1.0,
+            },
+            red,
+        );
+        builder.add_light(Vec3::new(0.0, 10.0, 0.0), RGB::white());
+
+        let scene = builder.build().unwrap();
+        assert_eq!(scene.geometries.len(), 1);
+        assert_eq!(scene.instances.len(), 2);
+        assert_eq!(scene.shapes.len(), 1);
+        assert_eq!(scene.lights.len(), 1);
+        assert!(matches!(
+            scene.meshes[0].material.diffuse,
+            Diffuse::TextureId(0)
+        ));
+        assert_eq!(
+            scene.bounds(),
+            Some((Vec3::new(-1.0, -1.0, 0.0), Vec3::new(1.0, 1.0, 6.0)))
+        );
+    }
+
+    #[test]
+    fn test_build_errors() {
+        let mut builder = SceneBuilder::new();
+        builder.add_geometry(triangle(), 0);
+        assert!(matches!(builder.build(), Err(SceneBuildError::Material(_))));
+
+        let mut builder = SceneBuilder::new();
+        let material = builder.add_material(Material::default());
+        builder.add_geometry(triangle()[..2].to_vec(), material);
+        assert!(matches!(builder.build(), Err(SceneBuildError::Geometry(_))));
+
+        let mut builder = SceneBuilder::new();
+        builder.add_instance(0, Matrix::ident());
+        assert!(matches!(builder.build(), Err(SceneBuildError::Instance(_))));
+
+        let mut builder = SceneBuilder::new();
+        builder.add_material(Material::new(Diffuse::TextureId(0)));
+        assert!(matches!(builder.build(), Err(SceneBuildError::Material(_))));
+    }
+}
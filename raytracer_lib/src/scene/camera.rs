@@ -1,20 +1,151 @@
 use rand::Rng;
 
-use crate::vecmath::{Matrix, Ray, Vec3, Vec4};
+use super::motion::Motion;
+use crate::vecmath::{cross, dot, Matrix, Ray, RayDifferential, Vec3, Vec4};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ApertureShape {
+    Circle,
+    // regular polygon with the given number of blades, gives polygonal bokeh
+    Polygon(u32),
+}
+
+// Which image axis the field of view is kept along, when the image aspect ratio
+// differs from the one the camera was authored with. Same semantics as blender.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SensorFit {
+    // fit along the larger image dimension
+    Auto,
+    Horizontal,
+    Vertical,
+}
+
+// Field of view as given by collada; xfov and/or yfov, optionally with an aspect ratio (xfov / yfov).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FieldOfView {
+    pub xfov_deg: Option<f32>,
+    pub yfov_deg: Option<f32>,
+    pub aspect_ratio: Option<f32>,
+}
+
+impl FieldOfView {
+    pub fn horizontal(xfov_deg: f32) -> Self {
+        FieldOfView {
+            xfov_deg: Some(xfov_deg),
+            yfov_deg: None,
+            aspect_ratio: None,
+        }
+    }
+
+    pub fn vertical(yfov_deg: f32) -> Self {
+        FieldOfView {
+            xfov_deg: None,
+            yfov_deg: Some(yfov_deg),
+            aspect_ratio: None,
+        }
+    }
+}
+
+// Orthographic view size as given by collada; half width and/or half height of the view,
+// optionally with an aspect ratio (xmag / ymag).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Magnification {
+    pub xmag: Option<f32>,
+    pub ymag: Option<f32>,
+    pub aspect_ratio: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Projection {
+    Perspective(FieldOfView),
+    Orthographic(Magnification),
+    // equidistant fisheye, the angle from the view direction grows linearly with the distance from the image center
+    Fisheye { fov_deg: f32 },
+    // full 360 x 180 degree panorama
+    Equirectangular,
+}
+
+impl Projection {
+    // half extents of the image along x and y, in image plane units (tan for perspective,
+    // world units for orthographic, radians for fisheye), plus the authored aspect ratio.
+    fn extents(&self) -> (Option<f32>, Option<f32>, Option<f32>) {
+        match self {
+            Projection::Perspective(fov) => (
+                fov.xfov_deg.map(half_tan),
+                fov.yfov_deg.map(half_tan),
+                fov.aspect_ratio,
+            ),
+            Projection::Orthographic(mag) => (mag.xmag, mag.ymag, mag.aspect_ratio),
+            Projection::Fisheye { fov_deg } => (Some(0.5 * fov_deg.to_radians()), None, None),
+            Projection::Equirectangular => (
+                Some(std::f32::consts::PI),
+                Some(0.5 * std::f32::consts::PI),
+                None,
+            ),
+        }
+    }
+}
+
+fn half_tan(fov_deg: f32) -> f32 {
+    (0.5 * fov_deg.to_radians()).tan()
+}
+
+// extent along one axis, derived from the other through an aspect ratio (x / y) if missing
+fn extent_x(extents: (Option<f32>, Option<f32>, Option<f32>), image_aspect: f32) -> f32 {
+    match extents {
+        (Some(x), _, _) => x,
+        (None, Some(y), aspect_ratio) => y * aspect_ratio.unwrap_or(image_aspect),
+        (None, None, _) => 1.0,
+    }
+}
+
+fn extent_y(extents: (Option<f32>, Option<f32>, Option<f32>), image_aspect: f32) -> f32 {
+    match extents {
+        (_, Some(y), _) => y,
+        (Some(x), None, aspect_ratio) => x / aspect_ratio.unwrap_or(image_aspect),
+        (None, None, _) => 1.0,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Eye {
+    Left,
+    Right,
+}
+
+pub const DEFAULT_INTEROCULAR_DISTANCE: f32 = 0.064;
+pub const DEFAULT_CONVERGENCE_DISTANCE: f32 = 10.0;
+// horizontal field of view of the fallback camera, used when a scene has no cameras
+pub const DEFAULT_FOV_DEG: f32 = 60.0;
 
 #[derive(Debug, Clone)]
 pub struct Camera {
+    name: String,
     x_angle_radians: f32,
     y_angle_radians: f32,
     pos: Vec3,
     width: usize,
     height: usize,
     base_orientation_matrix: Matrix,
-    base_rotation_matrix: Matrix,
     orientation_matrix: Matrix,
     rotation_matrix: Matrix,
+    projection: Projection,
+    sensor_fit: SensorFit,
     max_x: f32,
     max_y: f32,
+    aperture_radius: f32,
+    aperture_shape: ApertureShape,
+    focus_distance: f32,
+    interocular_distance: f32,
+    convergence_distance: f32,
+    // camera space x offset of the eye, 0.0 for a mono camera
+    eye_offset: f32,
+    // replaces the base orientation matrix, for an animated camera
+    motion: Option<Motion>,
+    // scene time of the frame, the shutter interval is relative to it
+    time: f32,
+    shutter_open: f32,
+    shutter_close: f32,
 }
 
 impl Camera {
@@ -23,43 +154,126 @@ impl Camera {
         width: usize,
         height: usize,
         orientation_matrix: &Matrix,
-        fov_deg: f32,
+        projection: Projection,
     ) -> Self {
-        let rotation_matrix = {
-            let mut rotation_matrix = *orientation_matrix;
-            rotation_matrix[3] = 0.0;
-            rotation_matrix[7] = 0.0;
-            rotation_matrix[11] = 0.0;
-
-            rotation_matrix[12] = 0.0;
-            rotation_matrix[13] = 0.0;
-            rotation_matrix[14] = 0.0;
-            rotation_matrix[15] = 1.0;
-            rotation_matrix
-        };
-
-        let fov = fov_deg * std::f32::consts::PI / 180.0;
-        let half_fov = 0.5 * fov;
-        let max_x = 1.0 * half_fov.tan();
-        let max_y = 1.0 * half_fov.tan();
-
         let mut cam = Camera {
+            name: String::new(),
             x_angle_radians: 0.0,
             y_angle_radians: 0.0,
             pos: Vec3::new(0.0, 0.0, 0.0),
             width,
             height,
             base_orientation_matrix: *orientation_matrix,
-            base_rotation_matrix: rotation_matrix,
             orientation_matrix: Matrix::ident(),
             rotation_matrix: Matrix::ident(),
-            max_x,
-            max_y,
+            projection,
+            sensor_fit: SensorFit::Auto,
+            max_x: 1.0,
+            max_y: 1.0,
+            aperture_radius: 0.0,
+            aperture_shape: ApertureShape::Circle,
+            focus_distance: 1.0,
+            interocular_distance: DEFAULT_INTEROCULAR_DISTANCE,
+            convergence_distance: DEFAULT_CONVERGENCE_DISTANCE,
+            eye_offset: 0.0,
+            motion: None,
+            time: 0.0,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
         };
         cam.update_matrices();
+        cam.update_projection();
+        cam
+    }
+
+    // perspective camera looking down +z at the given bounds (min, max), far enough back to see all of it
+    pub fn framing_bounds(width: usize, height: usize, bounds: Option<(Vec3, Vec3)>) -> Self {
+        let (min, max) = bounds.unwrap_or((Vec3::new(-1.0, -1.0, -1.0), Vec3::new(1.0, 1.0, 1.0)));
+        let center = 0.5 * (min + max);
+        let diagonal = max - min;
+        let radius = (0.5 * dot(&diagonal, &diagonal).sqrt()).max(f32::EPSILON);
+
+        // fit the bounding sphere inside the narrower of the two image axes
+        let narrow_ratio = width.min(height) as f32 / width.max(height).max(1) as f32;
+        let half_angle = (half_tan(DEFAULT_FOV_DEG) * narrow_ratio).atan();
+        let distance = radius / half_angle.sin();
+
+        let pos = Vec3::new(center.x, center.y, center.z - distance);
+        let mut cam = Camera::from_orientation_matrix(
+            width,
+            height,
+            &Matrix::translate(&pos),
+            Projection::Perspective(FieldOfView::horizontal(DEFAULT_FOV_DEG)),
+        );
+        cam.set_name("default");
+        cam.set_focus_distance(distance);
+        cam
+    }
+
+    // perspective camera at pos looking at target, with the image's up as close to up as it
+    // can be, focused at the target
+    pub fn look_at(
+        width: usize,
+        height: usize,
+        pos: &Vec3,
+        target: &Vec3,
+        up: &Vec3,
+        fov: FieldOfView,
+    ) -> Self {
+        let forward = (target - pos).normalized();
+        let right = cross(up, &forward).normalized();
+        let up = cross(&forward, &right);
+        // columns are camera space x, y and z (the view direction) in world space
+        #[rustfmt::skip]
+        let orientation_matrix = Matrix::new(&[
+            right.x, right.y, right.z, 0.0,
+            up.x, up.y, up.z, 0.0,
+            forward.x, forward.y, forward.z, 0.0,
+            pos.x, pos.y, pos.z, 1.0,
+        ]);
+        let mut cam = Camera::from_orientation_matrix(
+            width,
+            height,
+            &orientation_matrix,
+            Projection::Perspective(fov),
+        );
+        let to_target = target - pos;
+        cam.set_focus_distance(dot(&to_target, &to_target).sqrt());
         cam
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+
+    pub fn set_projection(&mut self, projection: Projection) {
+        self.projection = projection;
+        self.update_projection();
+    }
+
+    pub fn projection(&self) -> &Projection {
+        &self.projection
+    }
+
+    pub fn sensor_fit(&self) -> SensorFit {
+        self.sensor_fit
+    }
+
+    pub fn set_sensor_fit(&mut self, sensor_fit: SensorFit) {
+        self.sensor_fit = sensor_fit;
+        self.update_projection();
+    }
+
+    pub fn set_resolution(&mut self, width: usize, height: usize) {
+        self.width = width;
+        self.height = height;
+        self.update_projection();
+    }
+
     pub fn add_x_angle(&mut self, radians: f32) {
         self.x_angle_radians += radians;
         self.update_matrices();
@@ -77,23 +291,542 @@ impl Camera {
         self.update_matrices();
     }
 
-    pub fn get_ray(&self, u: usize, v: usize, mut rng: impl Rng) -> Ray {
-        let dir_x = -self.max_x
-            + 2.0 * self.max_x * ((u as f32 + rng.random_range(0.0..1.0)) / self.width as f32);
-        let dir_y = -self.max_y
-            + 2.0 * self.max_y * ((v as f32 + rng.random_range(0.0..1.0)) / self.height as f32);
-        let dir = Vec4::new(dir_x, -dir_y, 1.0, 1.0);
-        let dir = self.rotation_matrix * dir;
+    // the placement the camera was made with, without the moves and turns added since
+    pub fn base_orientation_matrix(&self) -> &Matrix {
+        &self.base_orientation_matrix
+    }
+
+    // (radius, shape)
+    pub fn aperture(&self) -> (f32, ApertureShape) {
+        (self.aperture_radius, self.aperture_shape)
+    }
+
+    // radius 0.0 gives a pinhole camera, with everything in focus
+    pub fn set_aperture(&mut self, radius: f32, shape: ApertureShape) {
+        self.aperture_radius = radius.max(0.0);
+        self.aperture_shape = shape;
+    }
+
+    pub fn focus_distance(&self) -> f32 {
+        self.focus_distance
+    }
+
+    // distance along the view direction to the plane in perfect focus
+    pub fn set_focus_distance(&mut self, distance: f32) {
+        self.focus_distance = distance.max(f32::EPSILON);
+    }
+
+    // (interocular distance, convergence distance)
+    pub fn stereo(&self) -> (f32, f32) {
+        (self.interocular_distance, self.convergence_distance)
+    }
+
+    // objects at the convergence distance end up at the same place in both eyes' images
+    pub fn set_stereo(&mut self, interocular_distance: f32, convergence_distance: f32) {
+        self.interocular_distance = interocular_distance;
+        self.convergence_distance = convergence_distance.max(f32::EPSILON);
+    }
+
+    // copy of this camera as seen from one eye, using parallel view directions with
+    // off-axis frustums (no toe-in), so there is no vertical parallax
+    pub fn eye(&self, eye: Eye) -> Camera {
+        let mut cam = self.clone();
+        cam.eye_offset = match eye {
+            Eye::Left => -0.5 * self.interocular_distance,
+            Eye::Right => 0.5 * self.interocular_distance,
+        };
+        cam
+    }
+
+    pub fn motion(&self) -> Option<&Motion> {
+        self.motion.as_ref()
+    }
+
+    pub fn set_motion(&mut self, motion: Option<Motion>) {
+        self.motion = motion;
+    }
 
-        let pos = self.orientation_matrix * Vec4::new(0.0, 0.0, 0.0, 1.0);
-        Ray::new(pos.into(), dir.into())
+    pub fn set_time(&mut self, time: f32) {
+        self.time = time;
+    }
+
+    pub fn time(&self) -> f32 {
+        self.time
+    }
+
+    // time interval, relative to the camera time, rays are spread over. open == close disables motion blur
+    pub fn set_shutter(&mut self, open: f32, close: f32) {
+        self.shutter_open = open;
+        self.shutter_close = close.max(open);
+    }
+
+    // (open, close) relative to the camera time
+    pub fn shutter(&self) -> (f32, f32) {
+        (self.shutter_open, self.shutter_close)
+    }
+
+    // (open, close) in scene time
+    pub fn shutter_interval(&self) -> (f32, f32) {
+        (
+            self.time + self.shutter_open,
+            self.time + self.shutter_close,
+        )
+    }
+
+    pub fn get_ray(&self, u: usize, v: usize, rng: impl Rng) -> Ray {
+        let (x, y, time, lens) = self.sample_pixel(u, v, rng);
+        self.ray_at(x, y, time, lens)
+    }
+
+    // the ray as get_ray, and how it changes toward the next pixel to the right and down
+    pub fn get_ray_differential(
+        &self,
+        u: usize,
+        v: usize,
+        rng: impl Rng,
+    ) -> (Ray, RayDifferential) {
+        let (x, y, time, lens) = self.sample_pixel(u, v, rng);
+        let ray = self.ray_at(x, y, time, lens);
+        let ray_x = self.ray_at(x + 1.0, y, time, lens);
+        let ray_y = self.ray_at(x, y + 1.0, time, lens);
+        let differential = RayDifferential {
+            dpos_dx: ray_x.pos - ray.pos,
+            ddir_dx: ray_x.dir - ray.dir,
+            dpos_dy: ray_y.pos - ray.pos,
+            ddir_dy: ray_y.dir - ray.dir,
+        };
+        (ray, differential)
+    }
+
+    // (x, y) jittered within the pixel, the time and the point on the lens to trace a ray from
+    fn sample_pixel(&self, u: usize, v: usize, mut rng: impl Rng) -> (f32, f32, f32, (f32, f32)) {
+        let x = u as f32 + rng.random_range(0.0..1.0);
+        let y = v as f32 + rng.random_range(0.0..1.0);
+        let (open, close) = self.shutter_interval();
+        let time = if close > open {
+            rng.random_range(open..close)
+        } else {
+            open
+        };
+        let lens = match self.projection {
+            Projection::Perspective(_) if self.aperture_radius > 0.0 => {
+                let (x, y) = sample_aperture(self.aperture_shape, &mut rng);
+                (x * self.aperture_radius, y * self.aperture_radius)
+            }
+            _ => (0.0, 0.0),
+        };
+        (x, y, time, lens)
+    }
+
+    // the ray through continuous pixel coords x, y
+    fn ray_at(&self, x: f32, y: f32, time: f32, lens: (f32, f32)) -> Ray {
+        let (x, y) = self.image_plane_pos(x, y);
+        let (pos, dir) = match self.projection {
+            Projection::Perspective(_) => self.thin_lens_ray(x, y, lens),
+            Projection::Orthographic(_) => (
+                Vec4::new(x + self.eye_offset, -y, 0.0, 1.0),
+                Vec4::new(0.0, 0.0, 1.0, 1.0),
+            ),
+            Projection::Fisheye { .. } => {
+                // x, y are angles here, distance from center is angle from view direction
+                let theta = (x * x + y * y).sqrt();
+                let phi = (-y).atan2(x);
+                (
+                    Vec4::new(self.eye_offset, 0.0, 0.0, 1.0),
+                    Vec4::new(
+                        theta.sin() * phi.cos(),
+                        theta.sin() * phi.sin(),
+                        theta.cos(),
+                        1.0,
+                    ),
+                )
+            }
+            Projection::Equirectangular => {
+                // x is longitude, y is latitude (downwards)
+                let (longitude, latitude) = (x, -y);
+                // omnidirectional stereo; the eyes sit on a circle, offset sideways to the looking direction
+                (
+                    Vec4::new(
+                        self.eye_offset * longitude.cos(),
+                        0.0,
+                        -self.eye_offset * longitude.sin(),
+                        1.0,
+                    ),
+                    Vec4::new(
+                        latitude.cos() * longitude.sin(),
+                        latitude.sin(),
+                        latitude.cos() * longitude.cos(),
+                        1.0,
+                    ),
+                )
+            }
+        };
+
+        let (rotation_matrix, orientation_matrix) = match &self.motion {
+            None => (self.rotation_matrix, self.orientation_matrix),
+            Some(motion) => self.matrices(&motion.matrix_at(time)),
+        };
+        let dir = rotation_matrix * dir;
+        let pos = orientation_matrix * pos;
+        Ray::with_time(pos.into(), dir.into(), time)
+    }
+
+    // camera space ray through image plane pos x, y, from the given point on the lens. Pinhole
+    // if aperture radius is 0.0
+    fn thin_lens_ray(&self, x: f32, y: f32, (lens_x, lens_y): (f32, f32)) -> (Vec4, Vec4) {
+        // for stereo, the eye looks at the point the center eye sees at the convergence distance
+        let eye_x = self.eye_offset;
+        let focus_x = eye_x + (x - eye_x / self.convergence_distance) * self.focus_distance;
+        let focus_y = -y * self.focus_distance;
+
+        // rays from all over the aperture converge on the focus plane (z = focus_distance in camera space)
+        (
+            Vec4::new(eye_x + lens_x, lens_y, 0.0, 1.0),
+            Vec4::new(
+                focus_x - eye_x - lens_x,
+                focus_y - lens_y,
+                self.focus_distance,
+                1.0,
+            ),
+        )
+    }
+
+    // position on the image plane, from continuous pixel coords. y grows downwards.
+    // For perspective this is the plane at z = 1.0, for the other projections see Projection::extents
+    fn image_plane_pos(&self, x: f32, y: f32) -> (f32, f32) {
+        let dir_x = -self.max_x + 2.0 * self.max_x * (x / self.width as f32);
+        let dir_y = -self.max_y + 2.0 * self.max_y * (y / self.height as f32);
+        (dir_x, dir_y)
+    }
+
+    fn update_projection(&mut self) {
+        // pixels are square, so one axis follows from the other through the image aspect ratio
+        let image_aspect = self.width as f32 / self.height as f32;
+        let fit_horizontal = match self.sensor_fit {
+            SensorFit::Auto => self.width >= self.height,
+            SensorFit::Horizontal => true,
+            SensorFit::Vertical => false,
+        };
+        let extents = self.projection.extents();
+        if let Projection::Equirectangular = self.projection {
+            // always covers the full sphere, regardless of image aspect
+            self.max_x = extent_x(extents, image_aspect);
+            self.max_y = extent_y(extents, image_aspect);
+        } else if fit_horizontal {
+            self.max_x = extent_x(extents, image_aspect);
+            self.max_y = self.max_x / image_aspect;
+        } else {
+            self.max_y = extent_y(extents, image_aspect);
+            self.max_x = self.max_y * image_aspect;
+        }
     }
 
     fn update_matrices(&mut self) {
-        self.rotation_matrix = Matrix::rot_x(self.x_angle_radians)
+        (self.rotation_matrix, self.orientation_matrix) =
+            self.matrices(&self.base_orientation_matrix);
+    }
+
+    // (rotation, orientation) matrices for a camera placed by the given base orientation matrix
+    fn matrices(&self, base_orientation_matrix: &Matrix) -> (Matrix, Matrix) {
+        let rotation_matrix = Matrix::rot_x(self.x_angle_radians)
             * Matrix::rot_y(self.y_angle_radians)
-            * self.base_rotation_matrix;
-        self.orientation_matrix =
-            self.rotation_matrix * Matrix::translate(&self.pos) * self.base_orientation_matrix;
+            * rotation_part(base_orientation_matrix);
+        let orientation_matrix =
+            rotation_matrix * Matrix::translate(&self.pos) * base_orientation_matrix;
+        (rotation_matrix, orientation_matrix)
+    }
+}
+
+fn rotation_part(orientation_matrix: &Matrix) -> Matrix {
+    let mut rotation_matrix = *orientation_matrix;
+    rotation_matrix[3] = 0.0;
+    rotation_matrix[7] = 0.0;
+    rotation_matrix[11] = 0.0;
+
+    rotation_matrix[12] = 0.0;
+    rotation_matrix[13] = 0.0;
+    rotation_matrix[14] = 0.0;
+    rotation_matrix[15] = 1.0;
+    rotation_matrix
+}
+
+// uniform sample on the unit aperture
+fn sample_aperture(shape: ApertureShape, mut rng: impl Rng) -> (f32, f32) {
+    match shape {
+        ApertureShape::Circle => {
+            let r = rng.random_range(0.0f32..1.0).sqrt();
+            let phi = rng.random_range(0.0..2.0 * std::f32::consts::PI);
+            (r * phi.cos(), r * phi.sin())
+        }
+        ApertureShape::Polygon(blades) => {
+            // pick one of the triangles fanning out from the center, then sample inside it
+            let blades = blades.max(3);
+            let blade = rng.random_range(0..blades);
+            let angle = 2.0 * std::f32::consts::PI / blades as f32;
+            let (x0, y0) = ((blade as f32 * angle).cos(), (blade as f32 * angle).sin());
+            let (x1, y1) = (
+                ((blade + 1) as f32 * angle).cos(),
+                ((blade + 1) as f32 * angle).sin(),
+            );
+
+            let mut a = rng.random_range(0.0f32..1.0);
+            let mut b = rng.random_range(0.0f32..1.0);
+            if a + b > 1.0 {
+                a = 1.0 - a;
+                b = 1.0 - b;
+            }
+            (a * x0 + b * x1, a * y0 + b * y1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vecmath::dot;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn test_thin_lens_rays_converge_on_focus_plane() {
+        let mut cam = Camera::from_orientation_matrix(
+            64,
+            64,
+            &Matrix::ident(),
+            Projection::Perspective(FieldOfView::horizontal(90.0)),
+        );
+        cam.set_aperture(0.5, ApertureShape::Polygon(6));
+        cam.set_focus_distance(4.0);
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let ray = cam.get_ray(32, 32, &mut rng);
+            let t = (4.0 - ray.pos.z) / ray.dir.z;
+            let focus_point = ray.pos + ray.dir * t;
+            // pixel 32 of 64 with a jitter of at most one pixel, so the focus point is close to the axis
+            assert!(dot(&focus_point, &focus_point) < 4.0 * 4.0 + 0.5);
+            assert!(focus_point.x.abs() < 4.0 * 2.0 / 64.0 + 1e-4);
+            assert!(ray.pos.x.abs() <= 0.5 && ray.pos.y.abs() <= 0.5);
+        }
+    }
+
+    fn assert_near(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-5, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_horizontal_fov_keeps_pixels_square() {
+        let cam = Camera::from_orientation_matrix(
+            1024,
+            768,
+            &Matrix::ident(),
+            Projection::Perspective(FieldOfView::horizontal(90.0)),
+        );
+        assert_near(cam.max_x, 1.0);
+        assert_near(cam.max_y, 0.75);
+
+        let (x, y) = cam.image_plane_pos(0.0, 0.0);
+        assert_near(x, -1.0);
+        assert_near(y, -0.75);
+        let (x, y) = cam.image_plane_pos(1024.0, 768.0);
+        assert_near(x, 1.0);
+        assert_near(y, 0.75);
+        let (x, y) = cam.image_plane_pos(512.0, 384.0);
+        assert_near(x, 0.0);
+        assert_near(y, 0.0);
+    }
+
+    #[test]
+    fn test_vertical_sensor_fit_keeps_yfov() {
+        let mut cam = Camera::from_orientation_matrix(
+            1024,
+            768,
+            &Matrix::ident(),
+            Projection::Perspective(FieldOfView::vertical(90.0)),
+        );
+        cam.set_sensor_fit(SensorFit::Vertical);
+        assert_near(cam.max_y, 1.0);
+        assert_near(cam.max_x, 1024.0 / 768.0);
+    }
+
+    #[test]
+    fn test_xfov_and_aspect_ratio_gives_yfov() {
+        let fov = FieldOfView {
+            xfov_deg: Some(90.0),
+            yfov_deg: None,
+            aspect_ratio: Some(2.0),
+        };
+        let mut cam = Camera::from_orientation_matrix(
+            100,
+            100,
+            &Matrix::ident(),
+            Projection::Perspective(fov),
+        );
+        cam.set_sensor_fit(SensorFit::Vertical);
+        assert_near(cam.max_y, 0.5);
+        assert_near(cam.max_x, 0.5);
+    }
+
+    #[test]
+    fn test_auto_sensor_fit_on_portrait_output() {
+        let fov = FieldOfView {
+            xfov_deg: Some(90.0),
+            yfov_deg: Some(90.0),
+            aspect_ratio: None,
+        };
+        let cam = Camera::from_orientation_matrix(
+            50,
+            100,
+            &Matrix::ident(),
+            Projection::Perspective(fov),
+        );
+        assert_near(cam.max_y, 1.0);
+        assert_near(cam.max_x, 0.5);
+    }
+
+    #[test]
+    fn test_ray_differential_spans_one_pixel() {
+        let cam = Camera::from_orientation_matrix(
+            1024,
+            768,
+            &Matrix::ident(),
+            Projection::Perspective(FieldOfView::horizontal(90.0)),
+        );
+        let (ray, differential) = cam.get_ray_differential(512, 384, &mut StdRng::seed_from_u64(1));
+        assert_eq!(ray, cam.get_ray(512, 384, &mut StdRng::seed_from_u64(1)));
+
+        // pixels are 2 / 1024 apart on the image plane at distance 1
+        assert_near((differential.ddir_dx.x / ray.dir.z).abs(), 2.0 / 1024.0);
+        assert_near((differential.ddir_dy.y / ray.dir.z).abs(), 2.0 / 1024.0);
+        assert_near(differential.ddir_dx.y, 0.0);
+        assert_near(differential.ddir_dy.x, 0.0);
+        assert_eq!(differential.dpos_dx, Vec3::new(0.0, 0.0, 0.0));
+    }
+
+    fn center_ray(cam: &Camera, x: f32, y: f32) -> Ray {
+        // get_ray jitters within the pixel, so aim at the pixel that has (x, y) as its upper left corner
+        // and accept up to one pixel of error
+        let mut rng = StdRng::seed_from_u64(1);
+        cam.get_ray(x as usize, y as usize, &mut rng)
+    }
+
+    #[test]
+    fn test_orthographic_rays_are_parallel() {
+        let mag = Magnification {
+            xmag: Some(2.0),
+            ymag: None,
+            aspect_ratio: None,
+        };
+        let cam = Camera::from_orientation_matrix(
+            200,
+            100,
+            &Matrix::ident(),
+            Projection::Orthographic(mag),
+        );
+        assert_near(cam.max_x, 2.0);
+        assert_near(cam.max_y, 1.0);
+
+        let ray = center_ray(&cam, 0.0, 0.0);
+        assert_eq!(ray.dir, Vec3::new(0.0, 0.0, 1.0));
+        assert!((ray.pos.x + 2.0).abs() < 0.03);
+        assert!((ray.pos.y - 1.0).abs() < 0.03);
+    }
+
+    #[test]
+    fn test_fisheye_edge_angle() {
+        let cam = Camera::from_orientation_matrix(
+            100,
+            100,
+            &Matrix::ident(),
+            Projection::Fisheye { fov_deg: 180.0 },
+        );
+        // the right edge of a 180 degree fisheye looks sideways
+        let ray = center_ray(&cam, 99.0, 50.0);
+        let dir = ray.dir.normalized();
+        assert!(dir.x > 0.99);
+        assert!(dir.z.abs() < 0.05);
+    }
+
+    #[test]
+    fn test_equirectangular_covers_sphere() {
+        let cam = Camera::from_orientation_matrix(
+            400,
+            200,
+            &Matrix::ident(),
+            Projection::Equirectangular,
+        );
+        assert_near(cam.max_x, std::f32::consts::PI);
+        assert_near(cam.max_y, 0.5 * std::f32::consts::PI);
+
+        // center looks forward, horizontal edges look backwards, top looks up
+        let forward = center_ray(&cam, 200.0, 100.0).dir.normalized();
+        assert!(forward.z > 0.99);
+        let backward = center_ray(&cam, 0.0, 100.0).dir.normalized();
+        assert!(backward.z < -0.99);
+        let up = center_ray(&cam, 200.0, 0.0).dir.normalized();
+        assert!(up.y > 0.99);
+    }
+
+    #[test]
+    fn test_stereo_eyes_converge() {
+        let mut cam = Camera::from_orientation_matrix(
+            101,
+            101,
+            &Matrix::ident(),
+            Projection::Perspective(FieldOfView::horizontal(90.0)),
+        );
+        cam.set_stereo(0.2, 5.0);
+        let left = center_ray(&cam.eye(Eye::Left), 50.0, 50.0);
+        let right = center_ray(&cam.eye(Eye::Right), 50.0, 50.0);
+        assert_near(left.pos.x, -0.1);
+        assert_near(right.pos.x, 0.1);
+
+        // both eyes see (close to) the same point at the convergence distance
+        let left_point = left.pos + left.dir * ((5.0 - left.pos.z) / left.dir.z);
+        let right_point = right.pos + right.dir * ((5.0 - right.pos.z) / right.dir.z);
+        assert!((left_point.x - right_point.x).abs() < 2.0 * 5.0 * 2.0 / 101.0);
+    }
+
+    #[test]
+    fn test_framing_camera_sees_whole_bounds() {
+        let bounds = (Vec3::new(1.0, 1.0, 1.0), Vec3::new(3.0, 5.0, 3.0));
+        let cam = Camera::framing_bounds(100, 50, Some(bounds));
+        assert_eq!(cam.name(), "default");
+
+        // center pixel looks at the center of the bounds
+        let ray = center_ray(&cam, 50.0, 25.0);
+        let t = (2.0 - ray.pos.z) / ray.dir.z;
+        let center = ray.pos + ray.dir * t;
+        assert!((center.x - 2.0).abs() < 0.2 && (center.y - 3.0).abs() < 0.2);
+
+        // the top edge of the image passes above the bounding sphere
+        let top = center_ray(&cam, 50.0, 0.0);
+        let t = (2.0 - top.pos.z) / top.dir.z;
+        let radius = 0.5 * (4.0f32 + 16.0 + 4.0).sqrt();
+        assert!((top.pos + top.dir * t).y > 3.0 + radius);
+    }
+
+    #[test]
+    fn test_look_at() {
+        let pos = Vec3::new(1.0, 2.0, 3.0);
+        let target = Vec3::new(-3.0, 2.0, 3.0);
+        let cam = Camera::look_at(
+            101,
+            101,
+            &pos,
+            &target,
+            &Vec3::new(0.0, 1.0, 0.0),
+            FieldOfView::horizontal(90.0),
+        );
+
+        // center pixel looks at the target, the top of the image is up
+        let ray = center_ray(&cam, 50.0, 50.0);
+        let dir = ray.dir.normalized();
+        assert_near(ray.pos.x, 1.0);
+        assert!(dir.x < -0.999);
+        let top = center_ray(&cam, 50.0, 0.0);
+        assert!(top.dir.y > 0.5 * dot(&top.dir, &top.dir).sqrt());
+        // and the right of the image is towards +z when looking along -x
+        let right = center_ray(&cam, 100.0, 50.0);
+        assert!(right.dir.z > 0.5 * dot(&right.dir, &right.dir).sqrt());
     }
 }
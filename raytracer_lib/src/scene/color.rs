@@ -86,6 +86,10 @@ impl RGBA {
         RGBA::new(rgb.r, rgb.g, rgb.b, a)
     }
 
+    pub fn transparent() -> Self {
+        RGBA::new(0.0, 0.0, 0.0, 0.0)
+    }
+
     pub fn to_u32(&self) -> u32 {
         let r = (self.r.min(1.0).max(0.0) * 255.0) as u8;
         let g = (self.g.min(1.0).max(0.0) * 255.0) as u8;
@@ -95,6 +99,30 @@ impl RGBA {
     }
 }
 
+impl std::ops::AddAssign for RGBA {
+    fn add_assign(&mut self, other: Self) {
+        self.r += other.r;
+        self.g += other.g;
+        self.b += other.b;
+        self.a += other.a;
+    }
+}
+
+impl std::ops::Add<RGBA> for RGBA {
+    type Output = RGBA;
+    fn add(self, other: Self) -> RGBA {
+        RGBA::new(
+            self.r + other.r,
+            self.g + other.g,
+            self.b + other.b,
+            self.a + other.a,
+        )
+    }
+}
+
+#[rustfmt::skip] impl std::ops::Mul<f32> for &RGBA { type Output = RGBA; fn mul(self, other: f32) -> RGBA { RGBA::new(self.r * other, self.g * other, self.b * other, self.a * other) }}
+#[rustfmt::skip] impl std::ops::Mul<f32> for  RGBA { type Output = RGBA; fn mul(self, other: f32) -> RGBA { RGBA::new(self.r * other, self.g * other, self.b * other, self.a * other) }}
+
 #[derive(Debug, Clone)]
 pub enum Diffuse {
     Color(RGB),
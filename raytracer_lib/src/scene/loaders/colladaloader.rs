@@ -1,20 +1,29 @@
-use std::{error::Error, fmt, fs::File, io::prelude::*, path};
+use std::{collections::HashMap, error::Error, fmt, fs::File, io::prelude::*, path};
 
 use parseval::{parsers::*, xml};
 
 use crate::scene::{
-    camera::Camera,
+    camera::{Camera, FieldOfView, Magnification, Projection},
     color::{Diffuse, RGB, RGBA},
-    texture::{Texture, TextureLoader},
-    Geometry, Light, Material, Scene, Vec3, Vertex,
+    motion::{Keyframe, Motion},
+    texture::{ColorSpace, Texture, TextureFilter, TextureLoader, TextureSampler, WrapMode},
+    Bump, Geometry, Instance, Light, Material, Scene, Vec3, Vertex, ALL_TIME,
 };
 
 mod collada_types;
 use collada_types::{
-    ColladaCamera, ColladaDiffuseOrTexImageId, ColladaEffect, ColladaGeometry, ColladaImage,
-    ColladaLight, ColladaMaterial, ColladaMatrix, ColladaVisualScene, ColladaVisualSceneNode,
+    ColladaAnimationChannel, ColladaBump, ColladaCamera, ColladaDiffuseOrTexImageId, ColladaEffect,
+    ColladaGeometry, ColladaImage, ColladaInterpolation, ColladaLight, ColladaMaterial,
+    ColladaTransform, ColladaTransformKind, ColladaVisualScene, ColladaVisualSceneNode,
 };
 
+// keyframes per second generated between linear animation keys. The channels interpolate the
+// node's own values, e.g. a rotation angle, which can turn further between two keys than Motion
+// can follow from one matrix to the next, so they are sampled densely to keep their shape.
+const ANIMATION_SAMPLE_RATE: f32 = 60.0;
+// keeps keys far apart in time from generating huge numbers of keyframes
+const MAX_ANIMATION_SAMPLES: usize = 1024;
+
 pub use super::{SceneLoadError, SceneLoader};
 
 pub struct ColladaLoader;
@@ -52,6 +61,7 @@ pub struct Collada {
     images: Vec<ColladaImage>,
     materials: Vec<ColladaMaterial>,
     geometries: Vec<ColladaGeometry>,
+    animation_channels: Vec<ColladaAnimationChannel>,
     visual_scenes: Vec<ColladaVisualScene>,
 }
 
@@ -72,6 +82,10 @@ impl Collada {
             .parse(remaining)
             .map_err(ColladaError::AssetParsing)?;
 
+        // animations are optional, and exporters put them either first or after the geometries
+        let (remaining, animations_element_first) =
+            optional_element("library_animations", remaining);
+
         let (remaining, cameras_element) = xml::element_with_name("library_cameras".to_string())
             .parse(remaining)
             .map_err(ColladaError::LibraryCamerasParsing)?;
@@ -98,6 +112,9 @@ impl Collada {
                 .parse(remaining)
                 .map_err(ColladaError::LibraryGeometriesParsing)?;
 
+        let (remaining, animations_element_after_geometries) =
+            optional_element("library_animations", remaining);
+
         let (remaining, visual_scenes_element) =
             xml::element_with_name("library_visual_scenes".to_string())
                 .parse(remaining)
@@ -121,6 +138,11 @@ impl Collada {
         let images = to_images(&images_element)?;
         let materials = to_materials(&materials_element)?;
         let geometries = to_geometries(&geometries_element)?;
+        let animation_channels =
+            match animations_element_first.or(animations_element_after_geometries) {
+                Some(animations_element) => to_animation_channels(&animations_element)?,
+                None => vec![],
+            };
         let visual_scenes = to_visual_scenes(&visual_scenes_element)?;
 
         Ok(Collada {
@@ -130,10 +152,147 @@ impl Collada {
             images,
             materials,
             geometries,
+            animation_channels,
             visual_scenes,
         })
     }
 
+    // keyframes for the node's animation, None if it is not animated
+    fn node_motion(&self, node: &ColladaVisualSceneNode) -> Option<Motion> {
+        let channels = self
+            .animation_channels
+            .iter()
+            .filter(|channel| channel.node_id == node.node_id)
+            .collect::<Vec<_>>();
+        if channels.is_empty() {
+            return None;
+        }
+
+        let mut key_times = channels
+            .iter()
+            .flat_map(|channel| channel.times.iter().copied())
+            .collect::<Vec<_>>();
+        key_times.sort_by(f32::total_cmp);
+        key_times.dedup();
+
+        let matrix_at = |time| node.matrix_at(&channels, time).to_vecmath_matrix();
+        let mut keyframes = vec![];
+        for (start, end) in key_times.iter().zip(key_times.iter().skip(1)) {
+            if channels.iter().all(|channel| channel.holds_after(*start)) {
+                keyframes.push(Keyframe::step(*start, matrix_at(*start)));
+                continue;
+            }
+            let num_samples = ((end - start) * ANIMATION_SAMPLE_RATE)
+                .ceil()
+                .clamp(1.0, MAX_ANIMATION_SAMPLES as f32) as usize;
+            for sample in 0..num_samples {
+                let time = start + (end - start) * sample as f32 / num_samples as f32;
+                keyframes.push(Keyframe::new(time, matrix_at(time)));
+            }
+        }
+        if let Some(last) = key_times.last() {
+            keyframes.push(Keyframe::new(*last, matrix_at(*last)));
+        }
+        Some(Motion::new(keyframes))
+    }
+
+    // material of the geometry, the default material if it has none
+    fn geometry_material(&self, geometry: &ColladaGeometry) -> Result<Material, ColladaError> {
+        let material = match self.materials.iter().find(|m| m.id == geometry.material_id) {
+            None => Material::default(),
+            Some(collada_material) => match self
+                .effects
+                .iter()
+                .find(|eff| eff.id == collada_material.effect_url)
+            {
+                None => Material::default(),
+                Some(collada_effect) => {
+                    let diffuse = match &collada_effect.diffuse_or_tex {
+                        ColladaDiffuseOrTexImageId::Diffuse(rgba) => Diffuse::Color((*rgba).into()),
+                        ColladaDiffuseOrTexImageId::TexImageId(image_id) => {
+                            Diffuse::TextureId(self.image_index(image_id)?)
+                        }
+                    };
+                    let bump = match &collada_effect.bump {
+                        None => None,
+                        Some(bump) if bump.normal_map => {
+                            Some(Bump::NormalMap(self.image_index(&bump.image_id)?))
+                        }
+                        // FCOLLADA has no bump amount
+                        Some(bump) => Some(Bump::HeightMap {
+                            texture_id: self.image_index(&bump.image_id)?,
+                            scale: 1.0,
+                        }),
+                    };
+                    // a material has one sampler, the diffuse texture's if there is one
+                    let sampler = match (&collada_effect.diffuse_or_tex, &collada_effect.bump) {
+                        (ColladaDiffuseOrTexImageId::Diffuse(_), Some(bump)) => bump.sampler,
+                        _ => collada_effect.sampler,
+                    };
+
+                    // reflectivity scales the reflective color, without it there's no mirror
+                    let reflective = collada_effect.reflective.map(|reflective| {
+                        RGB::from(reflective) * collada_effect.reflectivity.unwrap_or(1.0)
+                    });
+
+                    Material {
+                        diffuse,
+                        emissive: collada_effect.emission.into(),
+                        specular: collada_effect.specular.map_or(RGB::white(), RGB::from),
+                        reflective,
+                        transparency: collada_effect.transparency,
+                        index_of_refraction: collada_effect.index_of_refraction,
+                        metallic: 0.0,
+                        roughness: collada_effect
+                            .shininess
+                            .map_or(1.0, Material::roughness_from_shininess),
+                        sampler,
+                        bump,
+                    }
+                }
+            },
+        };
+        Ok(material)
+    }
+
+    fn image_index(&self, image_id: &str) -> Result<usize, ColladaError> {
+        self.images
+            .iter()
+            .position(|img| img.id == image_id)
+            .ok_or_else(|| ColladaError::MaterialsConversion("can't find texture name".to_string()))
+    }
+
+    // images are colors, unless they're only used as bump maps
+    fn image_color_space(&self, image_id: &str) -> ColorSpace {
+        let used_as_color = self
+            .effects
+            .iter()
+            .any(|effect| match &effect.diffuse_or_tex {
+                ColladaDiffuseOrTexImageId::TexImageId(id) => id == image_id,
+                ColladaDiffuseOrTexImageId::Diffuse(_) => false,
+            });
+        let used_as_bump = self
+            .effects
+            .iter()
+            .any(|effect| matches!(&effect.bump, Some(bump) if bump.image_id == image_id));
+        if used_as_bump && !used_as_color {
+            ColorSpace::Linear
+        } else {
+            ColorSpace::Srgb
+        }
+    }
+
+    // the geometry with its material and texture coordinates, at the given vertices
+    fn to_geometry(
+        &self,
+        geometry: &ColladaGeometry,
+        vertices: Vec<Vertex>,
+    ) -> Result<Geometry, ColladaError> {
+        let mut geom = Geometry::new(vertices, self.geometry_material(geometry)?);
+        geom.set_uvs(triangle_uvs(geometry)?);
+        Ok(geom)
+    }
+
     pub fn to_scene_flatten(
         &self,
         data_dir: Option<&path::Path>,
@@ -141,6 +300,9 @@ impl Collada {
         height: usize,
     ) -> Result<Scene, SceneLoadError> {
         let mut geometries = Vec::new();
+        let mut meshes = Vec::new();
+        let mut instances = Vec::new();
+        let mut mesh_indices = HashMap::new();
         let mut lights = Vec::new();
         let mut cameras = Vec::new();
         let mut textures = Vec::new();
@@ -151,25 +313,41 @@ impl Collada {
             } else {
                 path::PathBuf::from(&image.image_filename)
             };
-            let tex = Texture::from_file(image_path)?;
+            let tex = Texture::from_file(image_path, self.image_color_space(&image.id))?;
             textures.push(tex);
         }
 
+        let mut static_references = HashMap::<&str, usize>::new();
+        for visual_scene in &self.visual_scenes {
+            for node in &visual_scene.nodes {
+                if self.node_motion(node).is_none() {
+                    *static_references.entry(node.id.as_str()).or_default() += 1;
+                }
+            }
+        }
+
         for visual_scene in &self.visual_scenes {
             for node in &visual_scene.nodes {
                 for camera in &self.cameras {
                     if camera.id != node.id {
                         continue;
                     }
-                    cameras.push(Camera::from_orientation_matrix(
+                    let mut cam = Camera::from_orientation_matrix(
                         width,
                         height,
                         &node.matrix.to_vecmath_matrix(),
-                        camera.fov,
-                    ));
+                        camera.projection,
+                    );
+                    cam.set_name(&camera.id);
+                    cam.set_motion(self.node_motion(node));
+                    if let Some(dof_distance) = camera.dof_distance {
+                        cam.set_focus_distance(dof_distance);
+                    }
+                    cameras.push(cam);
                     break;
                 }
 
+                // lights are placed at their rest pose, animated or not
                 for light in &self.lights {
                     if light.id != node.id {
                         continue;
@@ -187,26 +365,36 @@ impl Collada {
                         continue;
                     }
 
-                    let mut geom_vertices = vec![];
-                    for tri_vtx_indices in geometry.triangles.chunks(3) {
-                        geom_vertices.push(Vertex::new(
-                            geometry.vertices[3 * tri_vtx_indices[0] as usize],
-                            geometry.vertices[3 * tri_vtx_indices[0] as usize + 1],
-                            geometry.vertices[3 * tri_vtx_indices[0] as usize + 2],
-                        ));
-                        geom_vertices.push(Vertex::new(
-                            geometry.vertices[3 * tri_vtx_indices[1] as usize],
-                            geometry.vertices[3 * tri_vtx_indices[1] as usize + 1],
-                            geometry.vertices[3 * tri_vtx_indices[1] as usize + 2],
-                        ));
-                        geom_vertices.push(Vertex::new(
-                            geometry.vertices[3 * tri_vtx_indices[2] as usize],
-                            geometry.vertices[3 * tri_vtx_indices[2] as usize + 1],
-                            geometry.vertices[3 * tri_vtx_indices[2] as usize + 2],
-                        ));
+                    // animated geometry keeps its vertices in object space, the motion places it
+                    let motion = self.node_motion(node);
+                    if motion.is_some() {
+                        let mut geom = self.to_geometry(geometry, triangle_vertices(geometry))?;
+                        geom.set_motion(motion);
+                        geometries.push(geom);
+                        break;
+                    }
+
+                    // geometry placed by several static nodes is shared by instances
+                    if static_references[geometry.id.as_str()] > 1 {
+                        let mesh_index = match mesh_indices.get(geometry.id.as_str()) {
+                            Some(mesh_index) => *mesh_index,
+                            None => {
+                                meshes
+                                    .push(self.to_geometry(geometry, triangle_vertices(geometry))?);
+                                mesh_indices.insert(geometry.id.as_str(), meshes.len() - 1);
+                                meshes.len() - 1
+                            }
+                        };
+                        // a singular transform squashes the mesh flat, there is nothing to render
+                        if let Some(instance) =
+                            Instance::new(mesh_index, node.matrix.to_vecmath_matrix())
+                        {
+                            instances.push(instance);
+                        }
+                        break;
                     }
 
-                    let geom_vertices = geom_vertices
+                    let geom_vertices = triangle_vertices(geometry)
                         .iter()
                         .map(|vtx| {
                             crate::vecmath::Vec3::from(
@@ -215,45 +403,7 @@ impl Collada {
                             )
                         })
                         .collect();
-
-                    let material = {
-                        match self.materials.iter().find(|m| m.id == geometry.material_id) {
-                            None => Material::default(),
-                            Some(collada_material) => match self
-                                .effects
-                                .iter()
-                                .find(|eff| eff.id == collada_material.effect_url)
-                            {
-                                None => Material::default(),
-                                Some(collada_effect) => {
-                                    let diffuse = match &collada_effect.diffuse_or_tex {
-                                        ColladaDiffuseOrTexImageId::Diffuse(rgba) => {
-                                            Diffuse::Color((*rgba).into())
-                                        }
-                                        ColladaDiffuseOrTexImageId::TexImageId(image_id) => {
-                                            let pos = self
-                                                .images
-                                                .iter()
-                                                .position(|img| &img.id == image_id)
-                                                .ok_or_else(|| ColladaError::MaterialsConversion(
-                                                    "can't find texture name".to_string(),
-                                                ))?;
-                                            Diffuse::TextureId(pos)
-                                        }
-                                    };
-
-                                    Material {
-                                        diffuse,
-                                        emissive: collada_effect.emission.into(),
-                                        specular: collada_effect.specular,
-                                        index_of_refraction: collada_effect.index_of_refraction,
-                                    }
-                                }
-                            },
-                        }
-                    };
-
-                    geometries.push(Geometry::new(geom_vertices, material));
+                    geometries.push(self.to_geometry(geometry, geom_vertices)?);
                     break;
                 }
             }
@@ -261,54 +411,125 @@ impl Collada {
 
         let tri_count = geometries
             .iter()
+            .chain(
+                instances
+                    .iter()
+                    .map(|instance| &meshes[instance.mesh_index]),
+            )
             .fold(0, |accum, geom| accum + geom.vertices.len() / 3);
         println!("number of triangles: {}", tri_count);
+        if !instances.is_empty() {
+            println!(
+                "number of instances: {} of {} meshes",
+                instances.len(),
+                meshes.len()
+            );
+        }
 
         Ok(Scene {
             geometries,
+            meshes,
+            instances,
+            shapes: vec![],
             lights,
             cameras,
             textures,
+            time_range: ALL_TIME,
         })
     }
 }
 
+// Texture coordinates of the triangle vertices, empty if the geometry has none. t is flipped,
+// it starts at the bottom of the image and textures at the top.
+fn triangle_uvs(geometry: &ColladaGeometry) -> Result<Vec<(f32, f32)>, ColladaError> {
+    geometry
+        .triangle_uvs
+        .iter()
+        .map(|idx| {
+            let idx = 2 * *idx as usize;
+            match (geometry.uvs.get(idx), geometry.uvs.get(idx + 1)) {
+                (Some(s), Some(t)) => Ok((*s, 1.0 - t)),
+                _ => Err(ColladaError::GeometryConversion),
+            }
+        })
+        .collect()
+}
+
+// three vertices per triangle, in object space
+fn triangle_vertices(geometry: &ColladaGeometry) -> Vec<Vertex> {
+    let mut geom_vertices = vec![];
+    for tri_vtx_indices in geometry.triangles.chunks(3) {
+        geom_vertices.push(Vertex::new(
+            geometry.vertices[3 * tri_vtx_indices[0] as usize],
+            geometry.vertices[3 * tri_vtx_indices[0] as usize + 1],
+            geometry.vertices[3 * tri_vtx_indices[0] as usize + 2],
+        ));
+        geom_vertices.push(Vertex::new(
+            geometry.vertices[3 * tri_vtx_indices[1] as usize],
+            geometry.vertices[3 * tri_vtx_indices[1] as usize + 1],
+            geometry.vertices[3 * tri_vtx_indices[1] as usize + 2],
+        ));
+        geom_vertices.push(Vertex::new(
+            geometry.vertices[3 * tri_vtx_indices[2] as usize],
+            geometry.vertices[3 * tri_vtx_indices[2] as usize + 1],
+            geometry.vertices[3 * tri_vtx_indices[2] as usize + 2],
+        ));
+    }
+    geom_vertices
+}
+
 fn to_cameras(elem: &xml::Element) -> Result<Vec<ColladaCamera>, ColladaError> {
     if let xml::DataOrElements::Elements(camera_elements) = &elem.data_or_elements {
         let mut cameras = vec![];
         for camera_elem in camera_elements {
             let id = camera_elem.get_attrib_value("id")?.to_string();
-            let perspective_elem = camera_elem
+            let technique_common_elem = camera_elem
                 .get_child_by_name("optics")?
-                .get_child_by_name("technique_common")?
-                .get_child_by_name("perspective")?;
-
-            let fov_elem = perspective_elem.get_child_by_name("xfov")?;
-            let aspect_ratio_elem = perspective_elem.get_child_by_name("aspect_ratio")?;
-            let fov = match &fov_elem.data_or_elements {
-                xml::DataOrElements::Data(fov_data) => {
-                    let (_, fov) = array_f32().parse(fov_data)?;
-                    fov
+                .get_child_by_name("technique_common")?;
+
+            let projection = if let Ok(perspective_elem) =
+                technique_common_elem.get_child_by_name("perspective")
+            {
+                // any combination of xfov, yfov and aspect_ratio may be present, but at least one fov
+                let xfov_deg = get_optional_f32(perspective_elem, "xfov")?;
+                let yfov_deg = get_optional_f32(perspective_elem, "yfov")?;
+                let aspect_ratio = get_optional_f32(perspective_elem, "aspect_ratio")?;
+                if xfov_deg.is_none() && yfov_deg.is_none() {
+                    return Err(ColladaError::CamerasConversion("cant read fov".to_string()));
                 }
-                _ => return Err(ColladaError::CamerasConversion("cant read fov".to_string())),
-            }[0];
-
-            let _aspect_ratio = match &aspect_ratio_elem.data_or_elements {
-                xml::DataOrElements::Data(aspect_ratio_data) => {
-                    let (_, aspect_ratio) = array_f32().parse(aspect_ratio_data)?;
-                    aspect_ratio
-                }
-                _ => {
-                    return Err(ColladaError::CamerasConversion(
-                        "cant read aspect_ratio".to_string(),
-                    ))
+                Projection::Perspective(FieldOfView {
+                    xfov_deg,
+                    yfov_deg,
+                    aspect_ratio,
+                })
+            } else {
+                let orthographic_elem = technique_common_elem.get_child_by_name("orthographic")?;
+                let xmag = get_optional_f32(orthographic_elem, "xmag")?;
+                let ymag = get_optional_f32(orthographic_elem, "ymag")?;
+                let aspect_ratio = get_optional_f32(orthographic_elem, "aspect_ratio")?;
+                if xmag.is_none() && ymag.is_none() {
+                    return Err(ColladaError::CamerasConversion("cant read mag".to_string()));
                 }
-            }[0];
+                Projection::Orthographic(Magnification {
+                    xmag,
+                    ymag,
+                    aspect_ratio,
+                })
+            };
+
+            // blender exports the focus distance, but not the aperture
+            let dof_distance = match camera_elem
+                .get_child_by_name("extra")
+                .and_then(|extra| extra.get_child_by_name("technique"))
+            {
+                Err(_) => None,
+                Ok(technique_elem) => get_optional_f32(technique_elem, "dof_distance")?,
+            };
 
             cameras.push(ColladaCamera {
                 id,
-                fov,
-                _aspect_ratio,
+                projection,
+                dof_distance,
             });
         }
         return Ok(cameras);
@@ -318,6 +539,20 @@ fn to_cameras(elem: &xml::Element) -> Result<Vec<ColladaCamera>, ColladaError> {
     ))
 }
 
+// value of a child element holding a single float, None if the child is missing
+fn get_optional_f32(elem: &xml::Element, name: &str) -> Result<Option<f32>, ColladaError> {
+    match elem.get_child_by_name(name) {
+        Err(_) => Ok(None),
+        Ok(child) => {
+            let data_str = child
+                .get_as_data()
+                .map_err(|_| ColladaError::CamerasConversion(format!("cant read {}", name)))?;
+            let (_, array) = array_f32().parse(data_str)?;
+            Ok(array.first().copied())
+        }
+    }
+}
+
 fn to_lights(elem: &xml::Element) -> Result<Vec<ColladaLight>, ColladaError> {
     if let xml::DataOrElements::Elements(light_elements) = &elem.data_or_elements {
         let mut lights = vec![];
@@ -353,101 +588,92 @@ fn to_effects(elem: &xml::Element) -> Result<Vec<ColladaEffect>, ColladaError> {
         let mut effects = vec![];
         for effect_elem in effect_elements {
             let id = effect_elem.get_attrib_value("id")?.to_string();
-            let lambert_elem = effect_elem
+            // the common profile shading models, from constant (emission only) up to blinn
+            let technique_elem = effect_elem
                 .get_child_by_name("profile_COMMON")?
-                .get_child_by_name("technique")?
-                .get_child_by_name("lambert")?;
-
-            let emission = {
-                let data_str = lambert_elem
-                    .get_child_by_name("emission")?
-                    .get_child_by_name("color")?
-                    .get_as_data()
-                    .map_err(|_| {
-                        ColladaError::EffectsConversion("Can't get emission color".to_string())
-                    })?;
-
-                let (_, color_array) = array_f32().parse(data_str)?;
-                RGBA::new(
-                    color_array[0],
-                    color_array[1],
-                    color_array[2],
-                    color_array[3],
-                )
-            };
-
-            let diffuse_or_tex = {
-                let diffuse_elem = lambert_elem.get_child_by_name("diffuse")?;
-                if let Ok(color_elem) = diffuse_elem.get_child_by_name("color") {
-                    let color_str = color_elem.get_as_data().map_err(|_| {
-                        ColladaError::EffectsConversion("Cant get diffuse color".to_string())
-                    })?;
-
-                    let (_, color_array) = array_f32().parse(color_str)?;
-                    ColladaDiffuseOrTexImageId::Diffuse(RGBA::new(
-                        color_array[0],
-                        color_array[1],
-                        color_array[2],
-                        color_array[3],
-                    ))
-                } else {
-                    // It's not a color, its a texture;
-                    //  From texture (sampler name) to sampler to surface to image_id.
-                    //  image_id is later used to find filename
-                    let tex_elem = diffuse_elem.get_child_by_name("texture")?;
-                    let _texcoord_source = tex_elem.get_attrib_value("texcoord")?;
-                    let sampler_name = tex_elem.get_attrib_value("texture")?.to_string();
-                    let surface_name = effect_elem
-                        .get_child_by_name("profile_COMMON")?
-                        .get_child_by_attrib(("sid", sampler_name))?
-                        .get_child_by_name("sampler2D")?
-                        .get_child_by_name("source")?
-                        .get_as_data()
-                        .map_err(|_| {
-                            ColladaError::EffectsConversion("Cant get sampler".to_string())
-                        })?
-                        .to_string();
-
-                    let image_id = effect_elem
-                        .get_child_by_name("profile_COMMON")?
-                        .get_child_by_attrib(("sid", surface_name))?
-                        .get_child_by_name("surface")?
-                        .get_child_by_name("init_from")?
-                        .get_as_data()
-                        .map_err(|_| {
-                            ColladaError::EffectsConversion("Cant get surface".to_string())
-                        })?
-                        .to_string();
-
-                    ColladaDiffuseOrTexImageId::TexImageId(image_id)
+                .get_child_by_name("technique")?;
+            let (shading_elem, shading) = ["constant", "lambert", "phong", "blinn"]
+                .iter()
+                .find_map(|name| {
+                    technique_elem
+                        .get_child_by_name(name)
+                        .ok()
+                        .map(|elem| (elem, *name))
+                })
+                .ok_or_else(|| {
+                    ColladaError::EffectsConversion(
+                        "no constant, lambert, phong or blinn technique".to_string(),
+                    )
+                })?;
+
+            let emission = effect_color(shading_elem, "emission")?
+                .unwrap_or_else(|| RGBA::new(0.0, 0.0, 0.0, 1.0));
+
+            let (diffuse_or_tex, sampler) = match shading_elem.get_child_by_name("diffuse") {
+                Err(_) => (
+                    ColladaDiffuseOrTexImageId::Diffuse(RGBA::new(0.0, 0.0, 0.0, 1.0)),
+                    TextureSampler::default(),
+                ),
+                Ok(diffuse_elem) => {
+                    if let Ok(color_elem) = diffuse_elem.get_child_by_name("color") {
+                        (
+                            ColladaDiffuseOrTexImageId::Diffuse(parse_color(color_elem)?),
+                            TextureSampler::default(),
+                        )
+                    } else {
+                        let tex_elem = diffuse_elem.get_child_by_name("texture")?;
+                        let (image_id, sampler) = effect_texture(effect_elem, tex_elem)?;
+                        (ColladaDiffuseOrTexImageId::TexImageId(image_id), sampler)
+                    }
                 }
             };
 
-            let index_of_refraction = {
-                let data_str = lambert_elem
-                    .get_child_by_name("index_of_refraction")?
-                    .get_child_by_attrib(("sid", "ior".to_string()))?
-                    .get_as_data()
-                    .map_err(|_| {
-                        ColladaError::EffectsConversion("Can't get index of refraction".to_string())
-                    })?;
+            // constant and lambert have no highlight
+            let (specular, shininess) = match shading {
+                "phong" | "blinn" => (
+                    effect_color(shading_elem, "specular")?,
+                    effect_float(shading_elem, "shininess")?,
+                ),
+                _ => (Some(RGBA::new(0.0, 0.0, 0.0, 1.0)), None),
+            };
 
-                let (_, ior_array) = array_f32().parse(data_str)?;
-                ior_array[0]
+            let transparency = match effect_color(shading_elem, "transparent")? {
+                None => 0.0,
+                Some(transparent) => {
+                    let opaque = shading_elem
+                        .get_child_by_name("transparent")?
+                        .get_attrib_value("opaque")
+                        .unwrap_or("A_ONE");
+                    let factor = effect_float(shading_elem, "transparency")?.unwrap_or(1.0);
+                    to_transparency(&transparent, opaque, factor)?
+                }
             };
 
-            let specular = match lambert_elem.get_child_by_name("reflectivity") {
-                Err(_) => None,
-                Ok(refl) => {
-                    let data_str = refl
-                        .get_child_by_attrib(("sid", "specular".to_string()))?
-                        .get_as_data()
-                        .map_err(|_| {
-                            ColladaError::EffectsConversion("Can't get specular".to_string())
-                        })?;
-
-                    let (_, specular_array) = array_f32().parse(data_str)?;
-                    Some(specular_array[0])
+            // FCOLLADA puts its bump map in an extra of the technique, some exporters in one of
+            // the shading model
+            let bump_elem = [technique_elem, shading_elem]
+                .iter()
+                .copied()
+                .find_map(|elem| {
+                    elem.get_child_by_name("extra")
+                        .and_then(|extra| {
+                            extra.get_child_by_attrib(("profile", "FCOLLADA".to_string()))
+                        })
+                        .and_then(|technique| technique.get_child_by_name("bump"))
+                        .ok()
+                });
+            let bump = match bump_elem {
+                None => None,
+                Some(bump_elem) => {
+                    let tex_elem = bump_elem.get_child_by_name("texture")?;
+                    let (image_id, sampler) = effect_texture(effect_elem, tex_elem)?;
+                    Some(ColladaBump {
+                        image_id,
+                        sampler,
+                        normal_map: bump_elem
+                            .get_attrib_value("bumptype")
+                            .map_or(false, |bump_type| bump_type == "NORMALMAP"),
+                    })
                 }
             };
 
@@ -455,8 +681,15 @@ fn to_effects(elem: &xml::Element) -> Result<Vec<ColladaEffect>, ColladaError> {
                 id,
                 emission,
                 diffuse_or_tex,
+                sampler,
                 specular,
-                index_of_refraction,
+                shininess,
+                reflective: effect_color(shading_elem, "reflective")?,
+                reflectivity: effect_float(shading_elem, "reflectivity")?,
+                transparency,
+                index_of_refraction: effect_float(shading_elem, "index_of_refraction")?
+                    .unwrap_or(1.0),
+                bump,
             });
         }
         return Ok(effects);
@@ -466,6 +699,143 @@ fn to_effects(elem: &xml::Element) -> Result<Vec<ColladaEffect>, ColladaError> {
     ))
 }
 
+// The image id and sampler of a <texture>. It names a sampler, which names a surface, which
+// names the image.
+fn effect_texture(
+    effect_elem: &xml::Element,
+    tex_elem: &xml::Element,
+) -> Result<(String, TextureSampler), ColladaError> {
+    let _texcoord_source = tex_elem.get_attrib_value("texcoord")?;
+    let sampler_name = tex_elem.get_attrib_value("texture")?.to_string();
+    let sampler_elem = effect_elem
+        .get_child_by_name("profile_COMMON")?
+        .get_child_by_attrib(("sid", sampler_name))?
+        .get_child_by_name("sampler2D")?;
+    let surface_name = sampler_elem
+        .get_child_by_name("source")?
+        .get_as_data()
+        .map_err(|_| ColladaError::EffectsConversion("Cant get sampler".to_string()))?
+        .to_string();
+
+    let image_id = effect_elem
+        .get_child_by_name("profile_COMMON")?
+        .get_child_by_attrib(("sid", surface_name))?
+        .get_child_by_name("surface")?
+        .get_child_by_name("init_from")?
+        .get_as_data()
+        .map_err(|_| ColladaError::EffectsConversion("Cant get surface".to_string()))?
+        .to_string();
+
+    Ok((image_id, to_texture_sampler(sampler_elem)?))
+}
+
+// wrap and filter of a <sampler2D>, the defaults for what it leaves out. Collada 1.4 names the
+// mip-map filter in the minfilter, 1.5 has a mipfilter of its own.
+fn to_texture_sampler(sampler_elem: &xml::Element) -> Result<TextureSampler, ColladaError> {
+    let setting = |name: &str| {
+        sampler_elem
+            .get_child_by_name(name)
+            .ok()
+            .and_then(|elem| elem.get_as_data().ok())
+            .map(str::trim)
+    };
+    let wrap = |name: &str| match setting(name) {
+        None | Some("WRAP") => Ok(WrapMode::Repeat),
+        Some("MIRROR") => Ok(WrapMode::Mirror),
+        Some("CLAMP") | Some("BORDER") | Some("NONE") => Ok(WrapMode::Clamp),
+        Some(mode) => Err(ColladaError::EffectsConversion(format!(
+            "unknown wrap mode {}",
+            mode
+        ))),
+    };
+    let filter = match (setting("minfilter"), setting("mipfilter")) {
+        (Some("ANISOTROPIC"), _) => TextureFilter::Anisotropic,
+        (Some("NEAREST"), None | Some("NONE")) => TextureFilter::Nearest,
+        (Some("LINEAR"), None | Some("NONE")) => TextureFilter::Bilinear,
+        _ => TextureFilter::Trilinear,
+    };
+    Ok(TextureSampler {
+        wrap_u: wrap("wrap_s")?,
+        wrap_v: wrap("wrap_t")?,
+        filter,
+    })
+}
+
+fn parse_color(color_elem: &xml::Element) -> Result<RGBA, ColladaError> {
+    let color_str = color_elem
+        .get_as_data()
+        .map_err(|_| ColladaError::EffectsConversion("Can't get color".to_string()))?;
+
+    let (_, color_array) = array_f32().parse(color_str)?;
+    if color_array.len() < 3 {
+        return Err(ColladaError::EffectsConversion(format!(
+            "color {} has less than three channels",
+            color_str
+        )));
+    }
+    let alpha = color_array.get(3).copied().unwrap_or(1.0);
+    Ok(RGBA::new(
+        color_array[0],
+        color_array[1],
+        color_array[2],
+        alpha,
+    ))
+}
+
+// the color of a parameter like <specular>, None if it's missing or a texture
+fn effect_color(shading_elem: &xml::Element, name: &str) -> Result<Option<RGBA>, ColladaError> {
+    match shading_elem
+        .get_child_by_name(name)
+        .and_then(|param_elem| param_elem.get_child_by_name("color"))
+    {
+        Err(_) => Ok(None),
+        Ok(color_elem) => parse_color(color_elem).map(Some),
+    }
+}
+
+// the value of a parameter like <shininess>, None if it's missing or a param reference
+fn effect_float(shading_elem: &xml::Element, name: &str) -> Result<Option<f32>, ColladaError> {
+    match shading_elem
+        .get_child_by_name(name)
+        .and_then(|param_elem| param_elem.get_child_by_name("float"))
+    {
+        Err(_) => Ok(None),
+        Ok(float_elem) => {
+            let data_str = float_elem
+                .get_as_data()
+                .map_err(|_| ColladaError::EffectsConversion(format!("Can't get {}", name)))?;
+
+            let (_, float_array) = array_f32().parse(data_str)?;
+            float_array
+                .first()
+                .copied()
+                .map(Some)
+                .ok_or_else(|| ColladaError::EffectsConversion(format!("{} has no value", name)))
+        }
+    }
+}
+
+// The fraction of light let through, from <transparent> and <transparency>. Depending on the
+// opaque mode the opacity is the alpha or the luminance of the color, scaled by the transparency
+// factor, or one minus that.
+fn to_transparency(transparent: &RGBA, opaque: &str, factor: f32) -> Result<f32, ColladaError> {
+    let luminance =
+        0.212_671 * transparent.r + 0.715_160 * transparent.g + 0.072_169 * transparent.b;
+    let transparency = match opaque {
+        "A_ONE" => 1.0 - transparent.a * factor,
+        "A_ZERO" => transparent.a * factor,
+        "RGB_ONE" => 1.0 - luminance * factor,
+        "RGB_ZERO" => luminance * factor,
+        _ => {
+            return Err(ColladaError::EffectsConversion(format!(
+                "unknown opaque mode {}",
+                opaque
+            )))
+        }
+    };
+    Ok(transparency.clamp(0.0, 1.0))
+}
+
 fn to_images(elem: &xml::Element) -> Result<Vec<ColladaImage>, ColladaError> {
     if let xml::DataOrElements::Elements(image_elements) = &elem.data_or_elements {
         let mut images = Vec::new();
@@ -524,19 +894,19 @@ fn to_visual_scenes(elem: &xml::Element) -> Result<Vec<ColladaVisualScene>, Coll
                             ))
                         }
                     };
+                    let node_id = node_elem.get_attrib_value("id").unwrap_or(name);
 
-                    let matrix_elem = node_elem.get_child_by_name("matrix")?;
-                    if let xml::DataOrElements::Data(matrix_data) = &matrix_elem.data_or_elements {
-                        let (_, matrix_array) = array_f32().parse(matrix_data)?;
-                        let collada_matrix = ColladaMatrix::from_slice(&matrix_array[..])
-                            .ok_or_else(|| {
-                                ColladaError::VisualSceneConversion("cant create array".to_string())
-                            })?;
-                        nodes.push(ColladaVisualSceneNode::new(
-                            name.to_string(),
-                            collada_matrix,
+                    let transforms = to_transforms(node_elem)?;
+                    if transforms.is_empty() {
+                        return Err(ColladaError::VisualSceneConversion(
+                            "node without transform".to_string(),
                         ));
                     }
+                    nodes.push(ColladaVisualSceneNode::new(
+                        name.to_string(),
+                        node_id.to_string(),
+                        transforms,
+                    ));
                 }
             }
         }
@@ -547,6 +917,167 @@ fn to_visual_scenes(elem: &xml::Element) -> Result<Vec<ColladaVisualScene>, Coll
     ))
 }
 
+// the node's <matrix>, <translate>, <rotate> and <scale> elements, in order
+fn to_transforms(node_elem: &xml::Element) -> Result<Vec<ColladaTransform>, ColladaError> {
+    let mut transforms = vec![];
+    if let xml::DataOrElements::Elements(children) = &node_elem.data_or_elements {
+        for child in children {
+            let kind = match child.name.as_str() {
+                "matrix" => ColladaTransformKind::Matrix,
+                "translate" => ColladaTransformKind::Translate,
+                "rotate" => ColladaTransformKind::Rotate,
+                "scale" => ColladaTransformKind::Scale,
+                _ => continue,
+            };
+            let (_, values) = array_f32().parse(child.get_as_data()?)?;
+            if values.len() < kind.num_values() {
+                return Err(ColladaError::VisualSceneConversion(format!(
+                    "too few values in {}",
+                    child.name
+                )));
+            }
+            transforms.push(ColladaTransform {
+                sid: child
+                    .get_attrib_value("sid")
+                    .ok()
+                    .map(|sid| sid.to_string()),
+                kind,
+                values,
+            });
+        }
+    }
+    Ok(transforms)
+}
+
+// the element if it is next in the input, otherwise None and the input untouched
+fn optional_element<'a>(name: &str, input: &'a str) -> (&'a str, Option<xml::Element>) {
+    match xml::element_with_name(name.to_string()).parse(input) {
+        Ok((remaining, element)) => (remaining, Some(element)),
+        Err(_) => (input, None),
+    }
+}
+
+fn to_animation_channels(
+    elem: &xml::Element,
+) -> Result<Vec<ColladaAnimationChannel>, ColladaError> {
+    let mut channels = vec![];
+    if let xml::DataOrElements::Elements(animation_elements) = &elem.data_or_elements {
+        for animation_elem in animation_elements {
+            convert_animation(animation_elem, &mut channels)?;
+        }
+    }
+    Ok(channels)
+}
+
+fn convert_animation(
+    animation_elem: &xml::Element,
+    channels: &mut Vec<ColladaAnimationChannel>,
+) -> Result<(), ColladaError> {
+    let children = match &animation_elem.data_or_elements {
+        xml::DataOrElements::Elements(children) => children,
+        xml::DataOrElements::Data(_) => return Ok(()),
+    };
+
+    for child in children {
+        match child.name.as_str() {
+            // animations can be grouped, e.g. one per object
+            "animation" => convert_animation(child, channels)?,
+            "channel" => {
+                let target = child.get_attrib_value("target")?;
+                let (node_id, sid, value_index) =
+                    parse_channel_target(target).ok_or_else(|| {
+                        ColladaError::AnimationsConversion(format!("unsupported target {}", target))
+                    })?;
+
+                let sampler_id = &child.get_attrib_value("source")?[1..]; //strip '#'
+                let sampler = animation_elem.get_child_by_attrib(("id", sampler_id.to_string()))?;
+                let input_source = |semantic: &str| -> Result<&xml::Element, ColladaError> {
+                    let input = sampler.get_child_by_attrib(("semantic", semantic.to_string()))?;
+                    let source_id = &input.get_attrib_value("source")?[1..];
+                    Ok(animation_elem.get_child_by_attrib(("id", source_id.to_string()))?)
+                };
+
+                let (_, times) = array_f32().parse(
+                    input_source("INPUT")?
+                        .get_child_by_name("float_array")?
+                        .get_as_data()?,
+                )?;
+                let (_, values) = array_f32().parse(
+                    input_source("OUTPUT")?
+                        .get_child_by_name("float_array")?
+                        .get_as_data()?,
+                )?;
+                if times.iter().any(|time| !time.is_finite()) {
+                    return Err(ColladaError::AnimationsConversion(format!(
+                        "invalid key time for {}",
+                        target
+                    )));
+                }
+                if times.is_empty() || values.len() % times.len() != 0 {
+                    return Err(ColladaError::AnimationsConversion(format!(
+                        "key count mismatch for {}",
+                        target
+                    )));
+                }
+                // bezier and other curves are approximated as linear
+                let interpolations = match input_source("INTERPOLATION") {
+                    Ok(source) => source
+                        .get_child_by_name("Name_array")?
+                        .get_as_data()?
+                        .split_whitespace()
+                        .map(|name| match name {
+                            "STEP" => ColladaInterpolation::Step,
+                            _ => ColladaInterpolation::Linear,
+                        })
+                        .collect(),
+                    Err(_) => vec![],
+                };
+
+                channels.push(ColladaAnimationChannel {
+                    node_id,
+                    sid,
+                    value_index,
+                    times,
+                    values,
+                    interpolations,
+                });
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+// splits "node/sid", "node/sid.MEMBER" and "node/sid(i)" or "node/sid(row)(col)" into
+// node id, sid and the index of the targeted value
+fn parse_channel_target(target: &str) -> Option<(String, String, Option<usize>)> {
+    let (node_id, rest) = target.split_once('/')?;
+    if let Some((sid, member)) = rest.split_once('.') {
+        let value_index = match member {
+            "X" => 0,
+            "Y" => 1,
+            "Z" => 2,
+            "ANGLE" => 3,
+            _ => return None,
+        };
+        return Some((node_id.to_string(), sid.to_string(), Some(value_index)));
+    }
+    if let Some((sid, indices)) = rest.split_once('(') {
+        let indices = indices
+            .trim_end_matches(')')
+            .split(")(")
+            .map(|idx| idx.parse::<usize>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        let value_index = match indices[..] {
+            [idx] => idx,
+            [row, col] => row * 4 + col,
+            _ => return None,
+        };
+        return Some((node_id.to_string(), sid.to_string(), Some(value_index)));
+    }
+    Some((node_id.to_string(), rest.to_string(), None))
+}
+
 fn to_geometries(elem: &xml::Element) -> Result<Vec<ColladaGeometry>, ColladaError> {
     if let xml::DataOrElements::Elements(geometry_elements) = &elem.data_or_elements {
         let mut geometries = vec![];
@@ -572,29 +1103,79 @@ fn convert_geometry(geometry_element: &xml::Element) -> Result<ColladaGeometry,
     let vertices = parsed_vertices;
 
     // get material id
-    let material_id = mesh
-        .get_child_by_name("triangles")?
-        .get_attrib_value("material")?
-        .to_string();
+    let triangles_elem = mesh.get_child_by_name("triangles")?;
+    let material_id = triangles_elem.get_attrib_value("material")?.to_string();
+
+    // the indices of every input are interleaved, each input at its own offset
+    let mut stride = 1;
+    let mut vertex_offset = 0;
+    let mut texcoord_input = None;
+    if let xml::DataOrElements::Elements(children) = &triangles_elem.data_or_elements {
+        for input_elem in children.iter().filter(|child| child.name == "input") {
+            let offset = input_elem
+                .get_attrib_value("offset")?
+                .parse::<usize>()
+                .map_err(|_| ColladaError::GeometryConversion)?;
+            stride = stride.max(offset + 1);
+            match input_elem.get_attrib_value("semantic")? {
+                "VERTEX" => vertex_offset = offset,
+                // only the first set of texture coordinates is used
+                "TEXCOORD" if texcoord_input.is_none() => {
+                    let source = input_elem.get_attrib_value("source")?;
+                    texcoord_input = Some((offset, source.trim_start_matches('#').to_string()));
+                }
+                _ => (),
+            }
+        }
+    }
 
     // get triangle indices
-    let mut triangles = vec![];
-    let triangle_indices_str = mesh
-        .get_child_by_name("triangles")?
-        .get_child_by_name("p")?
-        .get_as_data()?;
-
+    let triangle_indices_str = triangles_elem.get_child_by_name("p")?.get_as_data()?;
     let (_, parsed_index_array) = array_u32().parse(triangle_indices_str)?;
-    for (pos_index, _normal_index, _texcoord_index) in parsed_index_array
-        .chunks(3)
-        .map(|indices| (indices[0], indices[1], indices[2]))
-    {
-        triangles.push(pos_index);
-    }
+    let triangles = parsed_index_array
+        .chunks_exact(stride)
+        .map(|indices| indices[vertex_offset])
+        .collect();
+
+    let (uvs, triangle_uvs) = match texcoord_input {
+        None => (vec![], vec![]),
+        Some((offset, source_id)) => {
+            let source_elem = mesh.get_child_by_attrib(("id", source_id))?;
+            let uvs_str = source_elem
+                .get_child_by_name("float_array")?
+                .get_as_data()?;
+            let (_, parsed_uvs) = array_f32().parse(uvs_str)?;
+            let uv_stride = match source_elem
+                .get_child_by_name("technique_common")
+                .and_then(|elem| elem.get_child_by_name("accessor"))
+                .and_then(|elem| elem.get_attrib_value("stride"))
+            {
+                Ok(stride) => stride
+                    .parse::<usize>()
+                    .map_err(|_| ColladaError::GeometryConversion)?,
+                Err(_) => 2,
+            };
+            if uv_stride < 2 {
+                return Err(ColladaError::GeometryConversion);
+            }
+            // (s, t) pairs, the rest of the stride is unused
+            let uvs = parsed_uvs
+                .chunks_exact(uv_stride)
+                .flat_map(|uv| [uv[0], uv[1]])
+                .collect();
+            let triangle_uvs = parsed_index_array
+                .chunks_exact(stride)
+                .map(|indices| indices[offset])
+                .collect();
+            (uvs, triangle_uvs)
+        }
+    };
 
     Ok(ColladaGeometry {
         vertices,
         triangles,
+        uvs,
+        triangle_uvs,
         id,
         material_id,
     })
@@ -626,6 +1207,7 @@ pub enum ColladaError {
     ImagesConversion(String),
     EffectsConversion(String),
     CamerasConversion(String),
+    AnimationsConversion(String),
 }
 
 impl From<xml::ElementError> for ColladaError {
@@ -684,6 +1266,9 @@ impl fmt::Display for ColladaError {
             ColladaError::ImagesConversion(s) => write!(f, "ImagesConversion error; {}", s),
             ColladaError::EffectsConversion(s) => write!(f, "EffectsConversion error; {}", s),
             ColladaError::CamerasConversion(s) => write!(f, "CamerasConversion error; {}", s),
+            ColladaError::AnimationsConversion(s) => {
+                write!(f, "AnimationsConversion error; {}", s)
+            }
         }
     }
 }
@@ -713,6 +1298,7 @@ impl Error for ColladaError {
             ColladaError::ImagesConversion(_) => None,
             ColladaError::EffectsConversion(_) => None,
             ColladaError::CamerasConversion(_) => None,
+            ColladaError::AnimationsConversion(_) => None,
         }
     }
 }
@@ -731,6 +1317,311 @@ mod tests {
         assert!(parsed.is_ok());
     }
 
+    #[test]
+    fn test_parse_dof_distance() {
+        let collada = Collada::parse(COLLADA_DOC).unwrap();
+        assert_eq!(collada.cameras[0].dof_distance, Some(10.0));
+    }
+
+    #[test]
+    fn test_parse_camera_fov() {
+        let collada = Collada::parse(COLLADA_DOC).unwrap();
+        let expected = Projection::Perspective(FieldOfView {
+            xfov_deg: Some(39.59775),
+            yfov_deg: None,
+            aspect_ratio: Some(1.777778),
+        });
+        assert_eq!(collada.cameras[0].projection, expected);
+    }
+
+    #[test]
+    fn test_parse_orthographic_camera() {
+        let doc = COLLADA_DOC
+            .replace(
+                "<perspective>\n                <xfov sid=\"xfov\">39.59775</xfov>",
+                "<orthographic>\n                <xmag sid=\"xmag\">3.5</xmag>",
+            )
+            .replace("</perspective>", "</orthographic>");
+        let collada = Collada::parse(&doc).unwrap();
+        let expected = Projection::Orthographic(Magnification {
+            xmag: Some(3.5),
+            ymag: None,
+            aspect_ratio: Some(1.777778),
+        });
+        assert_eq!(collada.cameras[0].projection, expected);
+    }
+
+    #[test]
+    fn test_parse_phong_shininess() {
+        let doc = COLLADA_DOC
+            .replace("<lambert>", "<phong>")
+            .replace(
+                "</lambert>",
+                "<shininess>\n                <float sid=\"shininess\">50</float>\n                </shininess>\n            </phong>",
+            );
+        let collada = Collada::parse(&doc).unwrap();
+        assert_eq!(collada.effects[0].shininess, Some(50.0));
+
+        let collada = Collada::parse(COLLADA_DOC).unwrap();
+        assert_eq!(collada.effects[0].shininess, None);
+    }
+
+    #[test]
+    fn test_parse_blinn_effect() {
+        let doc = COLLADA_DOC.replace("<lambert>", "<blinn>").replace(
+            "</lambert>",
+            r##"<specular>
+                <color sid="specular">0.5 0.5 0.5 1</color>
+                </specular>
+                <reflective>
+                <color sid="reflective">1 0.5 0 1</color>
+                </reflective>
+                <reflectivity>
+                <float sid="reflectivity">0.5</float>
+                </reflectivity>
+                <transparent opaque="RGB_ZERO">
+                <color sid="transparent">1 1 1 1</color>
+                </transparent>
+                <transparency>
+                <float sid="transparency">0.25</float>
+                </transparency>
+            </blinn>"##,
+        );
+        let collada = Collada::parse(&doc).unwrap();
+        let effect = &collada.effects[0];
+        assert_eq!(effect.specular, Some(RGBA::new(0.5, 0.5, 0.5, 1.0)));
+        assert_eq!(effect.reflective, Some(RGBA::new(1.0, 0.5, 0.0, 1.0)));
+        assert_eq!(effect.reflectivity, Some(0.5));
+        assert!((effect.transparency - 0.25).abs() < 1e-5);
+        assert_eq!(effect.index_of_refraction, 1.45);
+
+        let material = collada.geometry_material(&collada.geometries[0]).unwrap();
+        assert_eq!(material.specular, RGB::new(0.5, 0.5, 0.5));
+        assert_eq!(material.reflective, Some(RGB::new(0.5, 0.25, 0.0)));
+        assert!((material.transparency - 0.25).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_parse_constant_effect() {
+        let doc = COLLADA_DOC
+            .replace("<lambert>", "<constant>")
+            .replace("</lambert>", "</constant>")
+            .replace("<color sid=\"emission\">0 0 0 1</color>", "<color sid=\"emission\">2 2 2 1</color>")
+            .replace("<diffuse>\n                <color sid=\"diffuse\">0.8 0.8 0.8 1</color>\n                </diffuse>", "")
+            .replace("<float sid=\"ior\">1.45</float>", "<param ref=\"ior\"/>");
+        let collada = Collada::parse(&doc).unwrap();
+        let material = collada.geometry_material(&collada.geometries[0]).unwrap();
+        assert_eq!(material.emissive, RGB::new(2.0, 2.0, 2.0));
+        assert!(matches!(material.diffuse, Diffuse::Color(c) if c == RGB::black()));
+        assert_eq!(material.specular, RGB::black());
+        assert_eq!(material.reflective, None);
+        assert_eq!(material.transparency, 0.0);
+        assert_eq!(material.index_of_refraction, 1.0);
+    }
+
+    #[test]
+    fn test_transparency_opaque_modes() {
+        let transparent = RGBA::new(1.0, 1.0, 1.0, 0.5);
+        assert_eq!(to_transparency(&transparent, "A_ONE", 1.0).unwrap(), 0.5);
+        assert_eq!(to_transparency(&transparent, "A_ZERO", 0.5).unwrap(), 0.25);
+        assert!(to_transparency(&transparent, "RGB_ONE", 1.0).unwrap().abs() < 1e-5);
+        assert!((to_transparency(&transparent, "RGB_ZERO", 1.0).unwrap() - 1.0).abs() < 1e-5);
+        assert!(to_transparency(&transparent, "OPAQUE", 1.0).is_err());
+    }
+
+    #[test]
+    fn test_parse_texcoords() {
+        let collada = Collada::parse(COLLADA_DOC).unwrap();
+        let geometry = &collada.geometries[0];
+        assert_eq!(geometry.triangles.len(), 36);
+        assert_eq!(geometry.triangles[..3], [4, 2, 0]);
+        let uvs = triangle_uvs(geometry).unwrap();
+        assert_eq!(uvs.len(), 36);
+        assert_eq!(uvs[0], (0.625, 1.0));
+        assert_eq!(uvs[1], (0.375, 0.75));
+    }
+
+    #[test]
+    fn test_parse_sampler() {
+        let sampler_doc = r##"<sampler2D>
+            <source>image-surface</source>
+            <wrap_s>MIRROR</wrap_s>
+            <wrap_t>CLAMP</wrap_t>
+            <minfilter>LINEAR</minfilter>
+            </sampler2D>"##;
+        let (_, sampler_elem) = xml::element_with_name("sampler2D".to_string())
+            .parse(sampler_doc)
+            .unwrap();
+        let sampler = to_texture_sampler(&sampler_elem).unwrap();
+        assert_eq!(
+            sampler,
+            TextureSampler {
+                wrap_u: WrapMode::Mirror,
+                wrap_v: WrapMode::Clamp,
+                filter: TextureFilter::Bilinear,
+            }
+        );
+
+        let (_, sampler_elem) = xml::element_with_name("sampler2D".to_string())
+            .parse("<sampler2D><source>image-surface</source></sampler2D>")
+            .unwrap();
+        assert_eq!(
+            to_texture_sampler(&sampler_elem).unwrap(),
+            TextureSampler::default()
+        );
+    }
+
+    #[test]
+    fn test_parse_bump() {
+        let doc = COLLADA_DOC
+            .replace(
+                "<technique sid=\"common\">",
+                r##"<newparam sid="bricks-surface">
+                <surface type="2D"><init_from>bricks-png</init_from></surface>
+                </newparam>
+                <newparam sid="bricks-sampler">
+                <sampler2D><source>bricks-surface</source><wrap_s>CLAMP</wrap_s></sampler2D>
+                </newparam>
+                <technique sid="common">"##,
+            )
+            .replace(
+                "</lambert>",
+                r##"</lambert>
+                <extra><technique profile="FCOLLADA">
+                <bump bumptype="NORMALMAP"><texture texture="bricks-sampler" texcoord="UVMap"/></bump>
+                </technique></extra>"##,
+            )
+            .replace(
+                "<library_images/>",
+                r##"<library_images>
+                <image id="bricks-png" name="bricks"><init_from>bricks.png</init_from></image>
+                </library_images>"##,
+            );
+        let collada = Collada::parse(&doc).unwrap();
+        let bump = collada.effects[0].bump.as_ref().unwrap();
+        assert_eq!(bump.image_id, "bricks-png");
+        assert!(bump.normal_map);
+
+        let material = collada.geometry_material(&collada.geometries[0]).unwrap();
+        assert_eq!(material.bump, Some(Bump::NormalMap(0)));
+        // the diffuse is a color, so the sampler is the bump map's
+        assert_eq!(material.sampler.wrap_u, WrapMode::Clamp);
+        assert_eq!(collada.image_color_space("bricks-png"), ColorSpace::Linear);
+    }
+
+    fn animation_doc(target: &str, stride_values: &str, interpolation: &str) -> String {
+        let animation = format!(
+            r##"<library_animations>
+        <animation id="Cube_anim" name="Cube">
+            <animation id="Cube_anim_channel">
+            <source id="Cube_anim-input">
+                <float_array id="Cube_anim-input-array" count="2">0 1</float_array>
+            </source>
+            <source id="Cube_anim-output">
+                <float_array id="Cube_anim-output-array">{}</float_array>
+            </source>
+            <source id="Cube_anim-interpolation">
+                <Name_array id="Cube_anim-interpolation-array" count="2">{} {}</Name_array>
+            </source>
+            <sampler id="Cube_anim-sampler">
+                <input semantic="INPUT" source="#Cube_anim-input"/>
+                <input semantic="OUTPUT" source="#Cube_anim-output"/>
+                <input semantic="INTERPOLATION" source="#Cube_anim-interpolation"/>
+            </sampler>
+            <channel source="#Cube_anim-sampler" target="{}"/>
+            </animation>
+        </animation>
+    </library_animations>
+    <library_visual_scenes>"##,
+            stride_values, interpolation, interpolation, target
+        );
+        COLLADA_DOC.replace("<library_visual_scenes>", &animation)
+    }
+
+    #[test]
+    fn test_shared_geometry_is_instanced() {
+        let second_cube = r##"<node id="Cube2" name="Cube2" type="NODE">
+            <matrix sid="transform">1 0 0 3 0 1 0 0 0 0 1 0 0 0 0 1</matrix>
+            <instance_geometry url="#Cube-mesh" name="Cube2"/>
+        </node>
+        </visual_scene>"##;
+        let doc = COLLADA_DOC.replace("</visual_scene>", second_cube);
+        let scene = Collada::parse(&doc)
+            .unwrap()
+            .to_scene_flatten(None, 64, 48)
+            .unwrap();
+        assert!(scene.geometries.is_empty());
+        assert_eq!(scene.meshes.len(), 1);
+        assert_eq!(scene.instances.len(), 2);
+        let (min, max) = scene.bounds().unwrap();
+        assert!((min.x + 1.0).abs() < 1e-4 && (max.x - 4.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_parse_channel_target() {
+        assert_eq!(
+            parse_channel_target("Cube/transform"),
+            Some(("Cube".to_string(), "transform".to_string(), None))
+        );
+        assert_eq!(
+            parse_channel_target("Cube/rotationZ.ANGLE"),
+            Some(("Cube".to_string(), "rotationZ".to_string(), Some(3)))
+        );
+        assert_eq!(
+            parse_channel_target("Cube/transform(0)(3)"),
+            Some(("Cube".to_string(), "transform".to_string(), Some(3)))
+        );
+        assert_eq!(parse_channel_target("Cube"), None);
+    }
+
+    #[test]
+    fn test_parse_matrix_animation() {
+        let doc = animation_doc(
+            "Cube/transform",
+            "1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1 1 0 0 2 0 1 0 0 0 0 1 0 0 0 0 1",
+            "LINEAR",
+        );
+        let collada = Collada::parse(&doc).unwrap();
+        assert_eq!(collada.animation_channels.len(), 1);
+
+        let cube = &collada.visual_scenes[0].nodes[2];
+        let motion = collada.node_motion(cube).unwrap();
+        assert!((motion.matrix_at(0.5)[12] - 1.0).abs() < 1e-5);
+        assert!((motion.matrix_at(1.0)[12] - 2.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_parse_stepped_translate_animation() {
+        let doc = animation_doc("Cube/location.X", "0 2", "STEP").replace(
+            "<matrix sid=\"transform\">1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1</matrix>",
+            "<translate sid=\"location\">0 0 0</translate>",
+        );
+        let collada = Collada::parse(&doc).unwrap();
+
+        let cube = &collada.visual_scenes[0].nodes[2];
+        let motion = collada.node_motion(cube).unwrap();
+        assert_eq!(motion.matrix_at(0.9)[12], 0.0);
+        assert_eq!(motion.matrix_at(1.0)[12], 2.0);
+    }
+
+    #[test]
+    fn test_parse_animation_key_times() {
+        let doc = animation_doc(
+            "Cube/transform",
+            "1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1 1 0 0 2 0 1 0 0 0 0 1 0 0 0 0 1",
+            "LINEAR",
+        );
+        // keys far apart are sampled a bounded number of times
+        let far_apart = doc.replace(">0 1</float_array>", ">0 1e9</float_array>");
+        let collada = Collada::parse(&far_apart).unwrap();
+        let cube = &collada.visual_scenes[0].nodes[2];
+        let motion = collada.node_motion(cube).unwrap();
+        assert_eq!(motion.keyframes().len(), MAX_ANIMATION_SAMPLES + 1);
+
+        let overflowing = doc.replace(">0 1</float_array>", ">0 1e39</float_array>");
+        assert!(Collada::parse(&overflowing).is_err());
+    }
+
     const COLLADA_DOC: &str = r##"<?xml version="1.0" encoding="utf-8"?>
     <COLLADA xmlns="http://www.collada.org/2005/11/COLLADASchema" version="1.4.1" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
     <asset>
@@ -1,9 +1,9 @@
-use crate::scene::{color::RGBA, Light};
+use crate::scene::{camera::Projection, color::RGBA, texture::TextureSampler, Light};
 
 pub struct ColladaCamera {
     pub id: String,
-    pub fov: f32,
-    pub _aspect_ratio: f32,
+    pub projection: Projection,
+    pub dof_distance: Option<f32>,
 }
 
 pub struct ColladaLight {
@@ -15,12 +15,29 @@ pub enum ColladaDiffuseOrTexImageId {
     Diffuse(RGBA),
     TexImageId(String),
 }
+// the FCOLLADA <bump> of an effect, a height map unless its bumptype makes it a normal map
+pub struct ColladaBump {
+    pub image_id: String,
+    pub sampler: TextureSampler,
+    pub normal_map: bool,
+}
+
 pub struct ColladaEffect {
     pub id: String,
     pub emission: RGBA,
     pub diffuse_or_tex: ColladaDiffuseOrTexImageId,
-    pub specular: Option<f32>,
+    // of the diffuse texture
+    pub sampler: TextureSampler,
+    // None when it's a texture
+    pub specular: Option<RGBA>,
+    // phong or blinn exponent
+    pub shininess: Option<f32>,
+    pub reflective: Option<RGBA>,
+    pub reflectivity: Option<f32>,
+    // [0..1], from <transparent> and <transparency>
+    pub transparency: f32,
     pub index_of_refraction: f32,
+    pub bump: Option<ColladaBump>,
 }
 
 pub struct ColladaImage {
@@ -36,6 +53,9 @@ pub struct ColladaMaterial {
 pub struct ColladaGeometry {
     pub vertices: Vec<f32>,
     pub triangles: Vec<u32>,
+    // (s, t) pairs, indexed by triangle_uvs in step with triangles. Both empty without them.
+    pub uvs: Vec<f32>,
+    pub triangle_uvs: Vec<u32>,
     pub id: String,
     pub material_id: String,
 }
@@ -45,13 +65,207 @@ pub struct ColladaVisualScene {
 }
 
 pub struct ColladaVisualSceneNode {
-    pub id: String,
+    pub id: String, // id of the instanced camera, light or geometry
+    pub node_id: String,
     pub matrix: ColladaMatrix,
+    pub transforms: Vec<ColladaTransform>,
 }
 
 impl ColladaVisualSceneNode {
-    pub fn new(id: String, matrix: ColladaMatrix) -> Self {
-        ColladaVisualSceneNode { id, matrix }
+    pub fn new(id: String, node_id: String, transforms: Vec<ColladaTransform>) -> Self {
+        let matrix =
+            compose_transforms(transforms.iter().map(|t| t.values.as_slice()), &transforms);
+        ColladaVisualSceneNode {
+            id,
+            node_id,
+            matrix,
+            transforms,
+        }
+    }
+
+    // node matrix at the given time, with the channels targeting this node applied
+    pub fn matrix_at(&self, channels: &[&ColladaAnimationChannel], time: f32) -> ColladaMatrix {
+        let animated_values = self
+            .transforms
+            .iter()
+            .map(|transform| {
+                let mut values = transform.values.clone();
+                for channel in channels {
+                    if transform.sid.as_deref() == Some(channel.sid.as_str()) {
+                        channel.apply(&mut values, time);
+                    }
+                }
+                values
+            })
+            .collect::<Vec<_>>();
+        compose_transforms(
+            animated_values.iter().map(|v| v.as_slice()),
+            &self.transforms,
+        )
+    }
+}
+
+fn compose_transforms<'a>(
+    values: impl Iterator<Item = &'a [f32]>,
+    transforms: &[ColladaTransform],
+) -> ColladaMatrix {
+    values
+        .zip(transforms)
+        .fold(ColladaMatrix::ident(), |matrix, (values, transform)| {
+            matrix.mul(&transform.kind.to_collada_matrix(values))
+        })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColladaTransformKind {
+    Matrix,    // 16 values, row by row
+    Translate, // x, y, z
+    Rotate,    // axis x, y, z and angle in degrees
+    Scale,     // x, y, z
+}
+
+impl ColladaTransformKind {
+    pub fn num_values(&self) -> usize {
+        match self {
+            ColladaTransformKind::Matrix => 16,
+            ColladaTransformKind::Rotate => 4,
+            ColladaTransformKind::Translate | ColladaTransformKind::Scale => 3,
+        }
+    }
+
+    fn to_collada_matrix(self, v: &[f32]) -> ColladaMatrix {
+        let elems = match self {
+            ColladaTransformKind::Matrix => {
+                return ColladaMatrix::from_slice(v).unwrap_or_else(ColladaMatrix::ident)
+            }
+            ColladaTransformKind::Translate => [
+                1.0, 0.0, 0.0, v[0], 0.0, 1.0, 0.0, v[1], 0.0, 0.0, 1.0, v[2], 0.0, 0.0, 0.0, 1.0,
+            ],
+            ColladaTransformKind::Scale => [
+                v[0], 0.0, 0.0, 0.0, 0.0, v[1], 0.0, 0.0, 0.0, 0.0, v[2], 0.0, 0.0, 0.0, 0.0, 1.0,
+            ],
+            ColladaTransformKind::Rotate => {
+                // rotation around an arbitrary axis (Rodrigues)
+                let len = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2])
+                    .sqrt()
+                    .max(f32::EPSILON);
+                let (x, y, z) = (v[0] / len, v[1] / len, v[2] / len);
+                let (sin, cos) = v[3].to_radians().sin_cos();
+                let c = 1.0 - cos;
+                [
+                    cos + x * x * c,
+                    x * y * c - z * sin,
+                    x * z * c + y * sin,
+                    0.0,
+                    y * x * c + z * sin,
+                    cos + y * y * c,
+                    y * z * c - x * sin,
+                    0.0,
+                    z * x * c - y * sin,
+                    z * y * c + x * sin,
+                    cos + z * z * c,
+                    0.0,
+                    0.0,
+                    0.0,
+                    0.0,
+                    1.0,
+                ]
+            }
+        };
+        ColladaMatrix { elems }
+    }
+}
+
+// <matrix>, <translate>, <rotate> or <scale> element of a node
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColladaTransform {
+    pub sid: Option<String>,
+    pub kind: ColladaTransformKind,
+    pub values: Vec<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColladaInterpolation {
+    Linear,
+    Step,
+}
+
+// one animated <channel>, with the keys of its sampler
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColladaAnimationChannel {
+    pub node_id: String,
+    pub sid: String,
+    // which of the target values are animated, None for all of them
+    pub value_index: Option<usize>,
+    pub times: Vec<f32>,
+    pub values: Vec<f32>, // values.len() / times.len() values per key
+    pub interpolations: Vec<ColladaInterpolation>,
+}
+
+impl ColladaAnimationChannel {
+    fn stride(&self) -> usize {
+        self.values.len() / self.times.len().max(1)
+    }
+
+    fn key_values(&self, key_idx: usize) -> &[f32] {
+        let stride = self.stride();
+        &self.values[key_idx * stride..(key_idx + 1) * stride]
+    }
+
+    pub fn interpolation(&self, key_idx: usize) -> ColladaInterpolation {
+        self.interpolations
+            .get(key_idx)
+            .copied()
+            .unwrap_or(ColladaInterpolation::Linear)
+    }
+
+    // true if the values stay the same from the given time until the next key
+    pub fn holds_after(&self, time: f32) -> bool {
+        let next_idx = self.times.partition_point(|key_time| *key_time <= time);
+        next_idx == 0
+            || next_idx == self.times.len()
+            || self.interpolation(next_idx - 1) == ColladaInterpolation::Step
+    }
+
+    // sampled values at the given time, held constant outside of the keys
+    pub fn sample(&self, time: f32) -> Vec<f32> {
+        let next_idx = self.times.partition_point(|key_time| *key_time <= time);
+        match (next_idx, self.times.len()) {
+            (_, 0) => vec![],
+            (0, _) => self.key_values(0).to_vec(),
+            (next_idx, len) if next_idx == len => self.key_values(len - 1).to_vec(),
+            (next_idx, _) => {
+                let prev = self.key_values(next_idx - 1);
+                match self.interpolation(next_idx - 1) {
+                    ColladaInterpolation::Step => prev.to_vec(),
+                    ColladaInterpolation::Linear => {
+                        let next = self.key_values(next_idx);
+                        let t = (time - self.times[next_idx - 1])
+                            / (self.times[next_idx] - self.times[next_idx - 1]);
+                        prev.iter()
+                            .zip(next)
+                            .map(|(p, n)| p + (n - p) * t)
+                            .collect()
+                    }
+                }
+            }
+        }
+    }
+
+    fn apply(&self, target_values: &mut [f32], time: f32) {
+        let sampled = self.sample(time);
+        match self.value_index {
+            Some(idx) => {
+                if let (Some(target), Some(value)) = (target_values.get_mut(idx), sampled.first()) {
+                    *target = *value;
+                }
+            }
+            None => {
+                for (target, value) in target_values.iter_mut().zip(sampled) {
+                    *target = value;
+                }
+            }
+        }
     }
 }
 
@@ -62,6 +276,25 @@ pub struct ColladaMatrix {
 }
 
 impl ColladaMatrix {
+    pub fn ident() -> Self {
+        ColladaMatrix {
+            elems: [
+                1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0,
+            ],
+        }
+    }
+
+    // self applied after other, as in collada's column vector convention
+    pub fn mul(&self, other: &ColladaMatrix) -> Self {
+        let product =
+            crate::vecmath::Matrix::new(&self.elems) * crate::vecmath::Matrix::new(&other.elems);
+        let mut elems = [0.0; 16];
+        for (i, elem) in elems.iter_mut().enumerate() {
+            *elem = product[i];
+        }
+        ColladaMatrix { elems }
+    }
+
     pub fn from_slice(elems: &[f32]) -> Option<Self> {
         if elems.len() < 16 {
             return None;
@@ -1,4 +1,6 @@
 pub mod colladaloader;
+pub mod objloader;
+pub mod tomlloader;
 
 use super::Scene;
 use std::{error, fmt, path};
@@ -20,6 +22,8 @@ pub trait SceneLoader {
 #[derive(Debug)]
 pub enum SceneLoadError {
     ColladaLoader(colladaloader::ColladaError),
+    ObjLoader(objloader::ObjError),
+    TomlLoader(tomlloader::TomlError),
     TextureLoader(super::texture::TextureLoadError),
     Io(std::io::Error),
 }
@@ -36,6 +40,18 @@ impl From<colladaloader::ColladaError> for SceneLoadError {
     }
 }
 
+impl From<objloader::ObjError> for SceneLoadError {
+    fn from(e: objloader::ObjError) -> Self {
+        SceneLoadError::ObjLoader(e)
+    }
+}
+
+impl From<tomlloader::TomlError> for SceneLoadError {
+    fn from(e: tomlloader::TomlError) -> Self {
+        SceneLoadError::TomlLoader(e)
+    }
+}
+
 impl From<std::io::Error> for SceneLoadError {
     fn from(e: std::io::Error) -> Self {
         SceneLoadError::Io(e)
@@ -46,6 +62,8 @@ impl fmt::Display for SceneLoadError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             SceneLoadError::ColladaLoader(e) => write!(f, "{}", e.to_string()),
+            SceneLoadError::ObjLoader(e) => write!(f, "{}", e.to_string()),
+            SceneLoadError::TomlLoader(e) => write!(f, "{}", e.to_string()),
             SceneLoadError::TextureLoader(e) => write!(f, "{}", e.to_string()),
             SceneLoadError::Io(e) => write!(f, "{}", e.to_string()),
         }
@@ -55,6 +73,8 @@ impl error::Error for SceneLoadError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match self {
             SceneLoadError::ColladaLoader(e) => Some(e),
+            SceneLoadError::ObjLoader(e) => Some(e),
+            SceneLoadError::TomlLoader(e) => Some(e),
             SceneLoadError::TextureLoader(e) => Some(e),
             SceneLoadError::Io(e) => Some(e),
         }
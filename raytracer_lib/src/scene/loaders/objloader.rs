@@ -0,0 +1,458 @@
+use super::{SceneLoadError, SceneLoader};
+use crate::scene::{
+    color::{Diffuse, RGB},
+    texture::{ColorSpace, Texture, TextureLoader},
+    Bump, Geometry, Material, Scene, Vec3, Vertex, ALL_TIME,
+};
+
+use std::{collections::HashMap, error, fmt, fs, path};
+
+// Wavefront obj meshes, with a geometry for each material used. Vertex positions, texture
+// coordinates and faces are used, polygons are split into triangle fans. Normals are ignored.
+// Materials come from the mtllib files, relative to data_dir, see parse_mtl, as do their textures.
+pub struct ObjLoader;
+
+impl SceneLoader for ObjLoader {
+    fn from_str(
+        doc: &str,
+        data_dir: Option<&path::Path>,
+        _width: usize,
+        _height: usize,
+    ) -> Result<Scene, SceneLoadError> {
+        let obj = parse_obj(doc)?;
+
+        let resolve = |name: &str| match data_dir {
+            Some(data_dir) => data_dir.join(name),
+            None => path::PathBuf::from(name),
+        };
+        let mut materials = HashMap::new();
+        let mut texture_files = Vec::new();
+        for mtllib in &obj.mtllibs {
+            let mtl = fs::read_to_string(resolve(mtllib))?;
+            materials.extend(parse_mtl(&mtl, &mut texture_files)?);
+        }
+        // only bump and normal maps are read, and they hold data rather than colors
+        let textures = texture_files
+            .iter()
+            .map(|file| Texture::from_file(resolve(file), ColorSpace::Linear))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let geometries = obj
+            .groups
+            .into_iter()
+            .map(|group| {
+                let material = materials
+                    .get(&group.material_name)
+                    .cloned()
+                    .unwrap_or_default();
+                let mut geom = Geometry::new(group.vertices, material);
+                geom.set_uvs(group.uvs);
+                geom
+            })
+            .collect();
+        Ok(Scene {
+            geometries,
+            meshes: vec![],
+            instances: vec![],
+            shapes: vec![],
+            lights: vec![],
+            cameras: vec![],
+            textures,
+            time_range: ALL_TIME,
+        })
+    }
+
+    fn from_file<P: AsRef<path::Path>>(
+        path: P,
+        width: usize,
+        height: usize,
+    ) -> Result<Scene, SceneLoadError> {
+        let contents = fs::read_to_string(&path)?;
+        ObjLoader::from_str(&contents, path.as_ref().parent(), width, height)
+    }
+}
+
+struct Obj {
+    mtllibs: Vec<String>,
+    groups: Vec<ObjGroup>,
+}
+
+// Triangles by the name of their material, three vertices per triangle. Triangles before any
+// usemtl have the material "".
+struct ObjGroup {
+    material_name: String,
+    vertices: Vec<Vertex>,
+    // for each vertex, (0, 0) for corners without any. Empty if the obj has no texture
+    // coordinates.
+    uvs: Vec<(f32, f32)>,
+}
+
+fn parse_obj(doc: &str) -> Result<Obj, ObjError> {
+    let mut positions = Vec::new();
+    let mut texcoords = Vec::new();
+    let mut obj = Obj {
+        mtllibs: vec![],
+        groups: vec![],
+    };
+    let mut group_idx = None;
+    for (line_idx, line) in doc.lines().enumerate() {
+        let mut tokens = line.split_whitespace();
+        match tokens.next() {
+            Some("mtllib") => obj.mtllibs.extend(tokens.map(str::to_string)),
+            Some("usemtl") => {
+                let name = tokens.next().unwrap_or("");
+                group_idx = Some(group_index(&mut obj.groups, name));
+            }
+            Some("v") => {
+                let coords = tokens
+                    .take(3)
+                    .map(|token| token.parse::<f32>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| ObjError::Vertex(format!("line {}: {}", line_idx + 1, e)))?;
+                if coords.len() < 3 {
+                    return Err(ObjError::Vertex(format!(
+                        "line {}: expected three coordinates",
+                        line_idx + 1
+                    )));
+                }
+                positions.push(Vec3::new(coords[0], coords[1], coords[2]));
+            }
+            Some("vt") => {
+                let coords = tokens
+                    .take(2)
+                    .map(|token| token.parse::<f32>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| ObjError::Vertex(format!("line {}: {}", line_idx + 1, e)))?;
+                // v starts at the bottom of the image, textures at the top
+                match coords[..] {
+                    [] => {
+                        return Err(ObjError::Vertex(format!(
+                            "line {}: expected texture coordinates",
+                            line_idx + 1
+                        )))
+                    }
+                    [u] => texcoords.push((u, 1.0)),
+                    [u, v, ..] => texcoords.push((u, 1.0 - v)),
+                }
+            }
+            Some("f") => {
+                let corners = tokens
+                    .map(|token| corner_indices(token, positions.len(), texcoords.len()))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| {
+                        ObjError::Face(format!("line {}: bad vertex index", line_idx + 1))
+                    })?;
+                if corners.len() < 3 {
+                    return Err(ObjError::Face(format!(
+                        "line {}: expected at least three vertices",
+                        line_idx + 1
+                    )));
+                }
+                let group_idx = *group_idx.get_or_insert_with(|| group_index(&mut obj.groups, ""));
+                let group = &mut obj.groups[group_idx];
+                for i in 1..corners.len() - 1 {
+                    for (position, texcoord) in [corners[0], corners[i], corners[i + 1]] {
+                        group.vertices.push(positions[position]);
+                        group
+                            .uvs
+                            .push(texcoord.map_or((0.0, 0.0), |idx| texcoords[idx]));
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    if texcoords.is_empty() {
+        for group in &mut obj.groups {
+            group.uvs.clear();
+        }
+    }
+    Ok(obj)
+}
+
+// index of the group of the material, added if it's new
+fn group_index(groups: &mut Vec<ObjGroup>, material_name: &str) -> usize {
+    match groups
+        .iter()
+        .position(|group| group.material_name == material_name)
+    {
+        Some(idx) => idx,
+        None => {
+            groups.push(ObjGroup {
+                material_name: material_name.to_string(),
+                vertices: vec![],
+                uvs: vec![],
+            });
+            groups.len() - 1
+        }
+    }
+}
+
+// Materials of an mtl file by name. Kd is the base color, Ke the emission and Ni the index of
+// refraction. Roughness comes from the Ns exponent, unless given by the Pr and Pm of the PBR
+// extension, along with metallic. map_Bump (or bump) is a height map scaled by its -bm option,
+// norm a tangent space normal map, other texture maps are ignored. The files of the maps are
+// added to texture_files, which the materials refer to by index.
+fn parse_mtl(
+    doc: &str,
+    texture_files: &mut Vec<String>,
+) -> Result<HashMap<String, Material>, ObjError> {
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, Material)> = None;
+    for (line_idx, line) in doc.lines().enumerate() {
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        if keyword == "newmtl" {
+            materials.extend(current.take());
+            let name = tokens.next().unwrap_or("").to_string();
+            current = Some((name, Material::new(Diffuse::Color(RGB::white()))));
+            continue;
+        }
+        let material = match &mut current {
+            Some((_, material)) => material,
+            None => continue,
+        };
+        if let "map_Bump" | "map_bump" | "bump" | "norm" = keyword {
+            // options come before the file name, which can't have spaces
+            let args = tokens.collect::<Vec<_>>();
+            let file = args.last().ok_or_else(|| {
+                ObjError::Material(format!("line {}: {} without a file", line_idx + 1, keyword))
+            })?;
+            let texture_id = match texture_files.iter().position(|known| known == file) {
+                Some(texture_id) => texture_id,
+                None => {
+                    texture_files.push(file.to_string());
+                    texture_files.len() - 1
+                }
+            };
+            material.bump = Some(match keyword {
+                "norm" => Bump::NormalMap(texture_id),
+                _ => {
+                    let scale = match args.iter().position(|arg| *arg == "-bm") {
+                        None => 1.0,
+                        // the value, which can't be the file name
+                        Some(idx) => args
+                            .get(idx + 1)
+                            .filter(|_| idx + 2 < args.len())
+                            .and_then(|value| value.parse::<f32>().ok())
+                            .ok_or_else(|| {
+                                ObjError::Material(format!("line {}: bad -bm value", line_idx + 1))
+                            })?,
+                    };
+                    Bump::HeightMap { texture_id, scale }
+                }
+            });
+            continue;
+        }
+        let values = tokens
+            .map(|token| token.parse::<f32>())
+            .collect::<Result<Vec<_>, _>>();
+        let values = match (keyword, values) {
+            ("Kd" | "Ke" | "Ni" | "Ns" | "Pr" | "Pm", Ok(values)) if !values.is_empty() => values,
+            ("Kd" | "Ke" | "Ni" | "Ns" | "Pr" | "Pm", _) => {
+                return Err(ObjError::Material(format!(
+                    "line {}: bad {} value",
+                    line_idx + 1,
+                    keyword
+                )))
+            }
+            _ => continue,
+        };
+        let rgb = || match values[..] {
+            [r, g, b, ..] => RGB::new(r, g, b),
+            _ => RGB::new(values[0], values[0], values[0]),
+        };
+        match keyword {
+            "Kd" => material.diffuse = Diffuse::Color(rgb()),
+            "Ke" => material.emissive = rgb(),
+            "Ni" => material.index_of_refraction = values[0],
+            "Ns" => material.roughness = Material::roughness_from_shininess(values[0]),
+            "Pr" => material.roughness = values[0],
+            "Pm" => material.metallic = values[0],
+            _ => (),
+        }
+    }
+    materials.extend(current);
+    Ok(materials)
+}
+
+// indices into the positions and texture coordinates read so far, from a face corner like "7",
+// "7/1/3", "7//3" or "-1". None if an index is out of range.
+fn corner_indices(
+    token: &str,
+    num_positions: usize,
+    num_texcoords: usize,
+) -> Option<(usize, Option<usize>)> {
+    let mut indices = token.split('/');
+    let position = obj_index(indices.next()?, num_positions)?;
+    let texcoord = match indices.next() {
+        None | Some("") => None,
+        Some(index) => Some(obj_index(index, num_texcoords)?),
+    };
+    Some((position, texcoord))
+}
+
+// Indices start at 1, negative ones count back from the last element
+fn obj_index(token: &str, count: usize) -> Option<usize> {
+    let index = match token.parse::<isize>().ok()? {
+        0 => return None,
+        index if index < 0 => count as isize + index,
+        index => index - 1,
+    };
+    if index < 0 || index as usize >= count {
+        return None;
+    }
+    Some(index as usize)
+}
+
+// -- Error Handling ----------------------------------------------------------
+
+#[derive(Debug)]
+pub enum ObjError {
+    Vertex(String),
+    Face(String),
+    Material(String),
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ObjError::Vertex(e) => write!(f, "Vertex error; {}", e),
+            ObjError::Face(e) => write!(f, "Face error; {}", e),
+            ObjError::Material(e) => write!(f, "Material error; {}", e),
+        }
+    }
+}
+
+impl error::Error for ObjError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_triangle_fans() {
+        let doc = "# a quad and a triangle
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+vt 0 0
+vt 1 0
+vt 1 1
+vt 0 1
+vn 0 0 1
+f 1/1/1 2/2/1 3/3/1 4/4/1
+f -4//1 -3//1 -1//1
+";
+        let obj = parse_obj(doc).unwrap();
+        assert_eq!(obj.groups.len(), 1);
+        let vertices = &obj.groups[0].vertices;
+        assert_eq!(vertices.len(), 9);
+        assert_eq!(vertices[3], Vec3::new(0.0, 0.0, 0.0));
+        assert_eq!(vertices[5], Vec3::new(0.0, 1.0, 0.0));
+        assert_eq!(vertices[8], Vec3::new(0.0, 1.0, 0.0));
+        // v flipped, and none for the corners of the last face
+        let uvs = &obj.groups[0].uvs;
+        assert_eq!(uvs.len(), 9);
+        assert_eq!(uvs[2], (1.0, 0.0));
+        assert_eq!(uvs[5], (0.0, 0.0));
+        assert_eq!(uvs[6], (0.0, 0.0));
+
+        assert!(matches!(
+            parse_obj("v 0 0 0\nf 1 2 3"),
+            Err(ObjError::Face(_))
+        ));
+        assert!(matches!(parse_obj("v 0 zero 0"), Err(ObjError::Vertex(_))));
+        assert!(matches!(
+            parse_obj("v 0 0 0\nf 1/1 1/1 1/1"),
+            Err(ObjError::Face(_))
+        ));
+    }
+
+    #[test]
+    fn test_materials() {
+        let doc = "mtllib scene.mtl
+v 0 0 0
+v 1 0 0
+v 0 1 0
+usemtl gold
+f 1 2 3
+usemtl plastic
+f 1 2 3
+usemtl gold
+f 3 2 1
+";
+        let obj = parse_obj(doc).unwrap();
+        assert_eq!(obj.mtllibs, vec!["scene.mtl".to_string()]);
+        assert_eq!(obj.groups.len(), 2);
+        assert_eq!(obj.groups[0].material_name, "gold");
+        assert_eq!(obj.groups[0].vertices.len(), 6);
+        assert!(obj.groups[0].uvs.is_empty());
+
+        let mtl = "newmtl gold
+Kd 1.0 0.8 0.3
+Pm 1.0
+Pr 0.25
+
+newmtl plastic
+Kd 0.5
+Ns 100
+";
+        let materials = parse_mtl(mtl, &mut vec![]).unwrap();
+        let gold = &materials["gold"];
+        assert!(matches!(gold.diffuse, Diffuse::Color(c) if c == RGB::new(1.0, 0.8, 0.3)));
+        assert_eq!((gold.metallic, gold.roughness), (1.0, 0.25));
+        let plastic = &materials["plastic"];
+        assert_eq!(plastic.metallic, 0.0);
+        assert_eq!(plastic.roughness, Material::roughness_from_shininess(100.0));
+
+        assert!(matches!(
+            parse_mtl("newmtl a\nNs shiny", &mut vec![]),
+            Err(ObjError::Material(_))
+        ));
+    }
+
+    #[test]
+    fn test_bump_maps() {
+        let mtl = "newmtl bumpy
+map_Bump -bm 0.5 -clamp on bricks.png
+
+newmtl bent
+norm bricks_normal.png
+
+newmtl plain
+bump bricks.png
+";
+        let mut texture_files = vec![];
+        let materials = parse_mtl(mtl, &mut texture_files).unwrap();
+        assert_eq!(texture_files, vec!["bricks.png", "bricks_normal.png"]);
+        assert_eq!(
+            materials["bumpy"].bump,
+            Some(Bump::HeightMap {
+                texture_id: 0,
+                scale: 0.5
+            })
+        );
+        assert_eq!(materials["bent"].bump, Some(Bump::NormalMap(1)));
+        assert_eq!(
+            materials["plain"].bump,
+            Some(Bump::HeightMap {
+                texture_id: 0,
+                scale: 1.0
+            })
+        );
+
+        assert!(matches!(
+            parse_mtl("newmtl a\nmap_Bump -bm bricks.png", &mut vec![]),
+            Err(ObjError::Material(_))
+        ));
+        assert!(matches!(
+            parse_mtl("newmtl a\nnorm", &mut vec![]),
+            Err(ObjError::Material(_))
+        ));
+    }
+}
@@ -0,0 +1,1013 @@
+use std::{collections::HashMap, error, fmt, fs, path};
+
+use crate::raytracer::settings::{Integrator, RenderSettings};
+use crate::raytracer::tonemap::Tonemapper;
+use crate::scene::{
+    camera::{ApertureShape, Camera, FieldOfView, Magnification, Projection, SensorFit},
+    color::{Diffuse, RGB, RGBA},
+    motion::{Interpolation, Keyframe, Motion},
+    shape::{Shape, ShapeKind},
+    texture::{ColorSpace, Texture, TextureFilter, TextureLoader, TextureSampler, WrapMode},
+    Bump, Geometry, Instance, Light, Material, Matrix, Scene, Vec3, Vec4, ALL_TIME,
+};
+
+mod toml_types;
+use toml_types::{
+    TomlCamera, TomlColorSpace, TomlIntegrator, TomlKeyframe, TomlLight, TomlMaterial, TomlMesh,
+    TomlProjection, TomlRender, TomlScene, TomlSensorFit, TomlShape, TomlShapeKind, TomlTexture,
+    TomlTextureFilter, TomlTonemapper, TomlWrapMode,
+};
+
+use super::{colladaloader::ColladaLoader, objloader::ObjLoader, SceneLoadError, SceneLoader};
+
+// Scene files in toml, laid out as in toml_types. Besides the scene they hold the settings to
+// render it with, so a render is described by the file alone.
+pub struct TomlLoader;
+
+impl SceneLoader for TomlLoader {
+    // cameras get the given resolution instead of the one in the render settings
+    fn from_str(
+        doc: &str,
+        data_dir: Option<&path::Path>,
+        width: usize,
+        height: usize,
+    ) -> Result<Scene, SceneLoadError> {
+        let (mut scene, _) = TomlLoader::from_str_with_settings(doc, data_dir)?;
+        for camera in scene.cameras.iter_mut() {
+            camera.set_resolution(width, height);
+        }
+        Ok(scene)
+    }
+
+    fn from_file<P: AsRef<path::Path>>(
+        path: P,
+        width: usize,
+        height: usize,
+    ) -> Result<Scene, SceneLoadError> {
+        let contents = fs::read_to_string(&path)?;
+        TomlLoader::from_str(&contents, path.as_ref().parent(), width, height)
+    }
+}
+
+impl TomlLoader {
+    // files the scene refers to are relative to data_dir
+    pub fn from_str_with_settings(
+        doc: &str,
+        data_dir: Option<&path::Path>,
+    ) -> Result<(Scene, RenderSettings), SceneLoadError> {
+        let file: TomlScene = toml::from_str(doc).map_err(|e| TomlError::Parse(e.to_string()))?;
+        let settings = render_settings(&file.render);
+        let scene = to_scene(&file, data_dir, &settings)?;
+        Ok((scene, settings))
+    }
+
+    pub fn from_file_with_settings<P: AsRef<path::Path>>(
+        path: P,
+    ) -> Result<(Scene, RenderSettings), SceneLoadError> {
+        let contents = fs::read_to_string(&path)?;
+        TomlLoader::from_str_with_settings(&contents, path.as_ref().parent())
+    }
+
+    // Everything is written inline, except textures loaded from files, which are referred to
+    // relative to data_dir where possible. Meshes that no instance uses are left out.
+    pub fn to_string(
+        scene: &Scene,
+        settings: &RenderSettings,
+        data_dir: Option<&path::Path>,
+    ) -> Result<String, TomlError> {
+        let mut file = TomlScene {
+            render: toml_render(settings),
+            ..TomlScene::default()
+        };
+
+        for (idx, texture) in scene.textures.iter().enumerate() {
+            file.textures.push(match texture.source() {
+                Some(source) => TomlTexture {
+                    name: texture_name(idx),
+                    file: Some(
+                        data_dir
+                            .and_then(|dir| source.strip_prefix(dir).ok())
+                            .unwrap_or(source)
+                            .to_string_lossy()
+                            .into_owned(),
+                    ),
+                    color_space: Some(match texture.color_space() {
+                        ColorSpace::Srgb => TomlColorSpace::Srgb,
+                        ColorSpace::Linear => TomlColorSpace::Linear,
+                    }),
+                    width: 0,
+                    height: 0,
+                    texels: vec![],
+                    alpha: vec![],
+                },
+                None => TomlTexture {
+                    name: texture_name(idx),
+                    file: None,
+                    color_space: None,
+                    width: texture.width(),
+                    height: texture.height(),
+                    texels: texture
+                        .texels()
+                        .iter()
+                        .map(|texel| rgb_array(&RGB::from(*texel)))
+                        .collect(),
+                    alpha: if texture.is_opaque() {
+                        vec![]
+                    } else {
+                        texture.texels().iter().map(|texel| texel.a).collect()
+                    },
+                },
+            });
+        }
+
+        // every object gets a material of its own
+        let mut materials = vec![];
+        let mut add_material = |material: &Material| {
+            let name = format!("material_{}", materials.len());
+            materials.push(toml_material(&name, material));
+            name
+        };
+
+        for geom in &scene.geometries {
+            let (vertices, motion) = match &geom.motion {
+                None => (&geom.transformed_vertices, vec![]),
+                Some(motion) => (&geom.vertices, toml_keyframes(motion, geom.transform())),
+            };
+            file.meshes.push(TomlMesh {
+                file: None,
+                vertices: vertices.iter().map(vec3_array).collect(),
+                uvs: geom.uvs().iter().map(|(u, v)| [*u, *v]).collect(),
+                material: Some(add_material(&geom.material)),
+                transform: None,
+                motion,
+                instances: vec![],
+            });
+        }
+        for (mesh_idx, mesh) in scene.meshes.iter().enumerate() {
+            let instances = scene
+                .instances
+                .iter()
+                .filter(|instance| instance.mesh_index == mesh_idx)
+                .map(|instance| *instance.matrix().elements())
+                .collect::<Vec<_>>();
+            if instances.is_empty() {
+                continue;
+            }
+            file.meshes.push(TomlMesh {
+                file: None,
+                vertices: mesh.transformed_vertices.iter().map(vec3_array).collect(),
+                uvs: mesh.uvs().iter().map(|(u, v)| [*u, *v]).collect(),
+                material: Some(add_material(&mesh.material)),
+                transform: None,
+                motion: vec![],
+                instances,
+            });
+        }
+        for shape in &scene.shapes {
+            let kind = match shape.kind {
+                ShapeKind::Sphere { center, radius } => TomlShapeKind::Sphere {
+                    center: vec3_array(&center),
+                    radius,
+                },
+                ShapeKind::Plane { point, normal } => TomlShapeKind::Plane {
+                    point: vec3_array(&point),
+                    normal: vec3_array(&normal),
+                },
+                ShapeKind::Disk {
+                    center,
+                    normal,
+                    radius,
+                } => TomlShapeKind::Disk {
+                    center: vec3_array(&center),
+                    normal: vec3_array(&normal),
+                    radius,
+                },
+                ShapeKind::Quad {
+                    corner,
+                    edge_u,
+                    edge_v,
+                } => TomlShapeKind::Quad {
+                    corner: vec3_array(&corner),
+                    edge_u: vec3_array(&edge_u),
+                    edge_v: vec3_array(&edge_v),
+                },
+            };
+            file.shapes.push(TomlShape {
+                kind,
+                material: add_material(&shape.material),
+            });
+        }
+        file.materials = materials;
+
+        file.lights = scene
+            .lights
+            .iter()
+            .map(|light| TomlLight {
+                position: vec3_array(&light.pos),
+                color: rgb_array(&light.color),
+            })
+            .collect();
+        file.cameras = scene.cameras.iter().map(toml_camera).collect();
+
+        toml::to_string(&file).map_err(|e| TomlError::Serialize(e.to_string()))
+    }
+}
+
+fn to_scene(
+    file: &TomlScene,
+    data_dir: Option<&path::Path>,
+    settings: &RenderSettings,
+) -> Result<Scene, SceneLoadError> {
+    let resolve = |name: &str| match data_dir {
+        Some(data_dir) => data_dir.join(name),
+        None => path::PathBuf::from(name),
+    };
+    let mut scene = empty_scene();
+
+    // textures the materials only use as normal or bump maps
+    let is_data = |name: &str| {
+        let uses = |reference: &Option<String>| reference.as_deref() == Some(name);
+        let as_bump = file
+            .materials
+            .iter()
+            .any(|material| uses(&material.normal_map) || uses(&material.bump_map));
+        let as_color = file
+            .materials
+            .iter()
+            .any(|material| uses(&material.texture));
+        as_bump && !as_color
+    };
+    let mut texture_ids = HashMap::new();
+    for texture in &file.textures {
+        let loaded = match &texture.file {
+            Some(filename) => {
+                let color_space = match texture.color_space {
+                    Some(TomlColorSpace::Srgb) => ColorSpace::Srgb,
+                    Some(TomlColorSpace::Linear) => ColorSpace::Linear,
+                    None if is_data(&texture.name) => ColorSpace::Linear,
+                    None => ColorSpace::Srgb,
+                };
+                Texture::from_file(resolve(filename), color_space)?
+            }
+            None => {
+                let num_texels = texture.width * texture.height;
+                let size_error = || {
+                    TomlError::Texture(format!(
+                        "texture {} has {} texels, not {} x {}",
+                        texture.name,
+                        texture.texels.len(),
+                        texture.width,
+                        texture.height
+                    ))
+                };
+                if num_texels == 0 {
+                    return Err(size_error().into());
+                }
+                if !texture.alpha.is_empty() && texture.alpha.len() != num_texels {
+                    return Err(TomlError::Texture(format!(
+                        "texture {} has {} alpha values, not one for each of its {} texels",
+                        texture.name,
+                        texture.alpha.len(),
+                        num_texels
+                    ))
+                    .into());
+                }
+                let alpha = |idx: usize| texture.alpha.get(idx).copied().unwrap_or(1.0);
+                Texture::from_rgba(
+                    texture.width,
+                    texture.height,
+                    texture
+                        .texels
+                        .iter()
+                        .enumerate()
+                        .map(|(idx, texel)| RGBA::from_rgb(rgb(texel), alpha(idx)))
+                        .collect(),
+                )
+                .ok_or_else(size_error)?
+            }
+        };
+        texture_ids.insert(texture.name.as_str(), scene.textures.len());
+        scene.textures.push(loaded);
+    }
+
+    let mut materials = HashMap::new();
+    for material in &file.materials {
+        let texture_id = |texture: &str| {
+            texture_ids.get(texture).copied().ok_or_else(|| {
+                TomlError::Reference(format!(
+                    "material {} uses unknown texture {}",
+                    material.name, texture
+                ))
+            })
+        };
+        let diffuse = match (&material.color, &material.texture) {
+            (_, Some(texture)) => Diffuse::TextureId(texture_id(texture)?),
+            (Some(color), None) => Diffuse::Color(rgb(color)),
+            (None, None) => {
+                return Err(TomlError::Material(format!(
+                    "material {} has neither a color nor a texture",
+                    material.name
+                ))
+                .into())
+            }
+        };
+        let bump = match (&material.normal_map, &material.bump_map) {
+            (None, None) => None,
+            (Some(texture), None) => Some(Bump::NormalMap(texture_id(texture)?)),
+            (None, Some(texture)) => Some(Bump::HeightMap {
+                texture_id: texture_id(texture)?,
+                scale: material.bump_scale,
+            }),
+            (Some(_), Some(_)) => {
+                return Err(TomlError::Material(format!(
+                    "material {} has both a normal and a bump map",
+                    material.name
+                ))
+                .into())
+            }
+        };
+        materials.insert(
+            material.name.as_str(),
+            Material {
+                diffuse,
+                emissive: rgb(&material.emissive),
+                specular: rgb(&material.specular),
+                reflective: material.reflective.as_ref().map(rgb),
+                transparency: material.transparency,
+                index_of_refraction: material.index_of_refraction,
+                metallic: material.metallic,
+                roughness: material.roughness,
+                sampler: TextureSampler {
+                    wrap_u: wrap_mode(material.wrap_u),
+                    wrap_v: wrap_mode(material.wrap_v),
+                    filter: match material.filter {
+                        TomlTextureFilter::Nearest => TextureFilter::Nearest,
+                        TomlTextureFilter::Bilinear => TextureFilter::Bilinear,
+                        TomlTextureFilter::Trilinear => TextureFilter::Trilinear,
+                        TomlTextureFilter::Anisotropic => TextureFilter::Anisotropic,
+                    },
+                },
+                bump,
+            },
+        );
+    }
+    let material = |user: &str, name: &str| {
+        materials
+            .get(name)
+            .cloned()
+            .ok_or_else(|| TomlError::Reference(format!("{} uses unknown material {}", user, name)))
+    };
+
+    for (idx, mesh) in file.meshes.iter().enumerate() {
+        let user = format!("mesh {}", idx);
+        let override_material = match &mesh.material {
+            Some(name) => Some(material(&user, name)?),
+            None => None,
+        };
+        let part = match (&mesh.file, override_material.clone()) {
+            (Some(filename), _) if mesh.vertices.is_empty() => {
+                load_mesh_file(&resolve(filename), settings)?
+            }
+            (None, Some(material))
+                if mesh.vertices.len() % 3 == 0
+                    && (mesh.uvs.is_empty() || mesh.uvs.len() == mesh.vertices.len()) =>
+            {
+                let mut geom = Geometry::new(mesh.vertices.iter().map(vec3).collect(), material);
+                geom.set_uvs(mesh.uvs.iter().map(|[u, v]| (*u, *v)).collect());
+                Scene {
+                    geometries: vec![geom],
+                    ..empty_scene()
+                }
+            }
+            _ => {
+                return Err(TomlError::Mesh(format!(
+                    "{} needs either a file, or three vertices per triangle, uvs for all or none \
+                     of them and a material",
+                    user
+                ))
+                .into())
+            }
+        };
+        add_mesh(&mut scene, part, mesh, override_material).map_err(|e| match e {
+            TomlError::Mesh(e) => TomlError::Mesh(format!("{} {}", user, e)),
+            e => e,
+        })?;
+    }
+
+    for (idx, shape) in file.shapes.iter().enumerate() {
+        let material = material(&format!("shape {}", idx), &shape.material)?;
+        scene.shapes.push(match &shape.kind {
+            TomlShapeKind::Sphere { center, radius } => {
+                Shape::sphere(vec3(center), *radius, material)
+            }
+            TomlShapeKind::Plane { point, normal } => {
+                Shape::plane(vec3(point), vec3(normal), material)
+            }
+            TomlShapeKind::Disk {
+                center,
+                normal,
+                radius,
+            } => Shape::disk(vec3(center), vec3(normal), *radius, material),
+            TomlShapeKind::Quad {
+                corner,
+                edge_u,
+                edge_v,
+            } => Shape::quad(vec3(corner), vec3(edge_u), vec3(edge_v), material),
+        });
+    }
+
+    scene.lights = file
+        .lights
+        .iter()
+        .map(|light| Light::new(vec3(&light.position), rgb(&light.color)))
+        .collect();
+    scene.cameras = file
+        .cameras
+        .iter()
+        .map(|camera| to_camera(camera, settings.width, settings.height))
+        .collect::<Result<_, _>>()?;
+    Ok(scene)
+}
+
+fn empty_scene() -> Scene {
+    Scene {
+        geometries: vec![],
+        meshes: vec![],
+        instances: vec![],
+        shapes: vec![],
+        lights: vec![],
+        cameras: vec![],
+        textures: vec![],
+        time_range: ALL_TIME,
+    }
+}
+
+// the triangles of a .dae or .obj file, its lights and cameras are left out
+fn load_mesh_file(path: &path::Path, settings: &RenderSettings) -> Result<Scene, SceneLoadError> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase());
+    let mut part = match extension.as_deref() {
+        Some("dae") => ColladaLoader::from_file(path, settings.width, settings.height)?,
+        Some("obj") => ObjLoader::from_file(path, settings.width, settings.height)?,
+        _ => {
+            return Err(
+                TomlError::Mesh(format!("{} is not a .dae or .obj file", path.display())).into(),
+            )
+        }
+    };
+    part.lights.clear();
+    part.cameras.clear();
+    Ok(part)
+}
+
+// adds the triangles of one mesh entry, given as a scene of their own
+fn add_mesh(
+    scene: &mut Scene,
+    mut part: Scene,
+    mesh: &TomlMesh,
+    material: Option<Material>,
+) -> Result<(), TomlError> {
+    let part_geometries = part.geometries.iter_mut().chain(part.meshes.iter_mut());
+    match material {
+        Some(material) => part_geometries.for_each(|geom| geom.material = material.clone()),
+        None => {
+            let texture_offset = scene.textures.len();
+            for geom in part_geometries {
+                if let Diffuse::TextureId(texture_id) = &mut geom.material.diffuse {
+                    *texture_id += texture_offset;
+                }
+            }
+            scene.textures.append(&mut part.textures);
+        }
+    }
+
+    if !mesh.motion.is_empty() {
+        if !mesh.instances.is_empty() || !part.instances.is_empty() {
+            return Err(TomlError::Mesh(
+                "can't have both motion and instances".to_string(),
+            ));
+        }
+        let motion = motion(&mesh.motion);
+        for geom in part.geometries.iter_mut() {
+            geom.set_motion(Some(motion.clone()));
+        }
+    }
+
+    let transform = mesh.transform.as_ref().map(Matrix::new);
+    let placed = |matrix: Matrix| match &transform {
+        Some(transform) => matrix * transform,
+        None => matrix,
+    };
+    let instance = |mesh_index: usize, matrix: Matrix| {
+        Instance::new(mesh_index, placed(matrix))
+            .ok_or_else(|| TomlError::Mesh("has a placement that can't be inverted".to_string()))
+    };
+
+    if !mesh.instances.is_empty() {
+        // the triangles as loaded are the object space of the instanced meshes
+        if !part.instances.is_empty() || part.geometries.iter().any(|geom| geom.motion.is_some()) {
+            return Err(TomlError::Mesh(
+                "can only instance static triangles".to_string(),
+            ));
+        }
+        for geom in part.geometries {
+            let mesh_index = scene.meshes.len();
+            let mut mesh_geom = Geometry::new(geom.transformed_vertices, geom.material);
+            mesh_geom.set_uvs(geom.uvs);
+            scene.meshes.push(mesh_geom);
+            for placement in &mesh.instances {
+                scene
+                    .instances
+                    .push(instance(mesh_index, Matrix::new(placement))?);
+            }
+        }
+        return Ok(());
+    }
+
+    for geom in part.geometries {
+        scene.geometries.push(match &transform {
+            None => geom,
+            Some(transform) => transformed_geometry(geom, transform),
+        });
+    }
+    let mesh_offset = scene.meshes.len();
+    scene.meshes.append(&mut part.meshes);
+    for part_instance in part.instances {
+        scene.instances.push(instance(
+            part_instance.mesh_index + mesh_offset,
+            *part_instance.matrix(),
+        )?);
+    }
+    Ok(())
+}
+
+// the geometry as placed by its motion or transform, then the given transform
+fn transformed_geometry(geom: Geometry, transform: &Matrix) -> Geometry {
+    match &geom.motion {
+        None => {
+            let vertices = geom
+                .transformed_vertices
+                .iter()
+                .map(|vtx| Vec3::from(transform * Vec4::from_vec3(vtx)))
+                .collect();
+            let mut placed = Geometry::new(vertices, geom.material);
+            placed.set_uvs(geom.uvs);
+            placed
+        }
+        Some(motion) => {
+            let keyframes = motion
+                .keyframes()
+                .iter()
+                .map(|key| Keyframe {
+                    matrix: key.matrix * geom.transform() * transform,
+                    ..key.clone()
+                })
+                .collect();
+            let mut moving = Geometry::new(geom.vertices, geom.material);
+            moving.set_uvs(geom.uvs);
+            moving.set_motion(Some(Motion::new(keyframes)));
+            moving
+        }
+    }
+}
+
+fn to_camera(camera: &TomlCamera, width: usize, height: usize) -> Result<Camera, TomlError> {
+    let projection = match camera.projection {
+        TomlProjection::Perspective {
+            xfov,
+            yfov,
+            aspect_ratio,
+        } => Projection::Perspective(FieldOfView {
+            xfov_deg: xfov,
+            yfov_deg: yfov,
+            aspect_ratio,
+        }),
+        TomlProjection::Orthographic {
+            xmag,
+            ymag,
+            aspect_ratio,
+        } => Projection::Orthographic(Magnification {
+            xmag,
+            ymag,
+            aspect_ratio,
+        }),
+        TomlProjection::Fisheye { fov } => Projection::Fisheye { fov_deg: fov },
+        TomlProjection::Equirectangular => Projection::Equirectangular,
+    };
+    let mut cam = match (&camera.matrix, &camera.position, &camera.target) {
+        (Some(matrix), None, None) => {
+            Camera::from_orientation_matrix(width, height, &Matrix::new(matrix), projection)
+        }
+        (None, Some(position), Some(target)) => {
+            let mut cam = Camera::look_at(
+                width,
+                height,
+                &vec3(position),
+                &vec3(target),
+                &vec3(&camera.up),
+                FieldOfView::horizontal(crate::scene::camera::DEFAULT_FOV_DEG),
+            );
+            cam.set_projection(projection);
+            cam
+        }
+        _ => {
+            return Err(TomlError::Camera(format!(
+                "camera {} needs either a matrix, or a position and a target",
+                camera.name
+            )))
+        }
+    };
+
+    cam.set_name(&camera.name);
+    cam.set_sensor_fit(match camera.sensor_fit {
+        TomlSensorFit::Auto => SensorFit::Auto,
+        TomlSensorFit::Horizontal => SensorFit::Horizontal,
+        TomlSensorFit::Vertical => SensorFit::Vertical,
+    });
+    cam.set_aperture(
+        camera.aperture,
+        camera
+            .aperture_blades
+            .map_or(ApertureShape::Circle, ApertureShape::Polygon),
+    );
+    if let Some(focus_distance) = camera.focus_distance {
+        cam.set_focus_distance(focus_distance);
+    }
+    let (interocular_distance, convergence_distance) = cam.stereo();
+    cam.set_stereo(
+        camera.interocular_distance.unwrap_or(interocular_distance),
+        camera.convergence_distance.unwrap_or(convergence_distance),
+    );
+    if let Some([open, close]) = camera.shutter {
+        cam.set_shutter(open, close);
+    }
+    if !camera.motion.is_empty() {
+        cam.set_motion(Some(motion(&camera.motion)));
+    }
+    Ok(cam)
+}
+
+fn toml_camera(camera: &Camera) -> TomlCamera {
+    let projection = match *camera.projection() {
+        Projection::Perspective(fov) => TomlProjection::Perspective {
+            xfov: fov.xfov_deg,
+            yfov: fov.yfov_deg,
+            aspect_ratio: fov.aspect_ratio,
+        },
+        Projection::Orthographic(mag) => TomlProjection::Orthographic {
+            xmag: mag.xmag,
+            ymag: mag.ymag,
+            aspect_ratio: mag.aspect_ratio,
+        },
+        Projection::Fisheye { fov_deg } => TomlProjection::Fisheye { fov: fov_deg },
+        Projection::Equirectangular => TomlProjection::Equirectangular,
+    };
+    let (aperture, aperture_shape) = camera.aperture();
+    let (interocular_distance, convergence_distance) = camera.stereo();
+    let (open, close) = camera.shutter();
+    TomlCamera {
+        name: camera.name().to_string(),
+        matrix: Some(*camera.base_orientation_matrix().elements()),
+        position: None,
+        target: None,
+        up: [0.0, 1.0, 0.0],
+        projection,
+        sensor_fit: match camera.sensor_fit() {
+            SensorFit::Auto => TomlSensorFit::Auto,
+            SensorFit::Horizontal => TomlSensorFit::Horizontal,
+            SensorFit::Vertical => TomlSensorFit::Vertical,
+        },
+        aperture,
+        aperture_blades: match aperture_shape {
+            ApertureShape::Circle => None,
+            ApertureShape::Polygon(blades) => Some(blades),
+        },
+        focus_distance: Some(camera.focus_distance()),
+        interocular_distance: Some(interocular_distance),
+        convergence_distance: Some(convergence_distance),
+        shutter: if close > open {
+            Some([open, close])
+        } else {
+            None
+        },
+        motion: camera
+            .motion()
+            .map_or_else(Vec::new, |motion| toml_keyframes(motion, &Matrix::ident())),
+    }
+}
+
+fn toml_material(name: &str, material: &Material) -> TomlMaterial {
+    let (color, texture) = match material.diffuse {
+        Diffuse::Color(color) => (Some(rgb_array(&color)), None),
+        Diffuse::TextureId(texture_id) => (None, Some(texture_name(texture_id))),
+    };
+    let (normal_map, bump_map, bump_scale) = match material.bump {
+        None => (None, None, 1.0),
+        Some(Bump::NormalMap(texture_id)) => (Some(texture_name(texture_id)), None, 1.0),
+        Some(Bump::HeightMap { texture_id, scale }) => {
+            (None, Some(texture_name(texture_id)), scale)
+        }
+    };
+    TomlMaterial {
+        name: name.to_string(),
+        color,
+        texture,
+        emissive: rgb_array(&material.emissive),
+        specular: rgb_array(&material.specular),
+        reflective: material.reflective.as_ref().map(rgb_array),
+        transparency: material.transparency,
+        index_of_refraction: material.index_of_refraction,
+        metallic: material.metallic,
+        roughness: material.roughness,
+        wrap_u: toml_wrap_mode(material.sampler.wrap_u),
+        wrap_v: toml_wrap_mode(material.sampler.wrap_v),
+        filter: match material.sampler.filter {
+            TextureFilter::Nearest => TomlTextureFilter::Nearest,
+            TextureFilter::Bilinear => TomlTextureFilter::Bilinear,
+            TextureFilter::Trilinear => TomlTextureFilter::Trilinear,
+            TextureFilter::Anisotropic => TomlTextureFilter::Anisotropic,
+        },
+        normal_map,
+        bump_map,
+        bump_scale,
+    }
+}
+
+fn wrap_mode(wrap: TomlWrapMode) -> WrapMode {
+    match wrap {
+        TomlWrapMode::Repeat => WrapMode::Repeat,
+        TomlWrapMode::Clamp => WrapMode::Clamp,
+        TomlWrapMode::Mirror => WrapMode::Mirror,
+    }
+}
+
+fn toml_wrap_mode(wrap: WrapMode) -> TomlWrapMode {
+    match wrap {
+        WrapMode::Repeat => TomlWrapMode::Repeat,
+        WrapMode::Clamp => TomlWrapMode::Clamp,
+        WrapMode::Mirror => TomlWrapMode::Mirror,
+    }
+}
+
+fn texture_name(texture_id: usize) -> String {
+    format!("texture_{}", texture_id)
+}
+
+fn motion(keyframes: &[TomlKeyframe]) -> Motion {
+    Motion::new(
+        keyframes
+            .iter()
+            .map(|key| match key.step {
+                false => Keyframe::new(key.time, Matrix::new(&key.matrix)),
+                true => Keyframe::step(key.time, Matrix::new(&key.matrix)),
+            })
+            .collect(),
+    )
+}
+
+// keyframes of the motion followed by the transform
+fn toml_keyframes(motion: &Motion, transform: &Matrix) -> Vec<TomlKeyframe> {
+    motion
+        .keyframes()
+        .iter()
+        .map(|key| TomlKeyframe {
+            time: key.time,
+            matrix: *(key.matrix * transform).elements(),
+            step: key.interpolation == Interpolation::Step,
+        })
+        .collect()
+}
+
+fn render_settings(render: &TomlRender) -> RenderSettings {
+    RenderSettings {
+        width: render.width,
+        height: render.height,
+        samples_per_pixel: render.samples_per_pixel,
+        integrator: match render.integrator {
+            TomlIntegrator::Direct => Integrator::Direct,
+            TomlIntegrator::Diffuse => Integrator::Diffuse {
+                bounces: render.bounces,
+            },
+        },
+        tonemapper: match render.tonemapper {
+            TomlTonemapper::Simple => Tonemapper::Simple,
+            TomlTonemapper::Luminance => Tonemapper::Luminance,
+            TomlTonemapper::Gamma => Tonemapper::Gamma,
+        },
+        seed: render.seed,
+    }
+}
+
+fn toml_render(settings: &RenderSettings) -> TomlRender {
+    TomlRender {
+        width: settings.width,
+        height: settings.height,
+        samples_per_pixel: settings.samples_per_pixel,
+        integrator: match settings.integrator {
+            Integrator::Direct => TomlIntegrator::Direct,
+            Integrator::Diffuse { .. } => TomlIntegrator::Diffuse,
+        },
+        bounces: settings.integrator.bounces(),
+        tonemapper: match settings.tonemapper {
+            Tonemapper::Simple => TomlTonemapper::Simple,
+            Tonemapper::Luminance => TomlTonemapper::Luminance,
+            Tonemapper::Gamma => TomlTonemapper::Gamma,
+        },
+        seed: settings.seed,
+    }
+}
+
+impl Default for TomlRender {
+    fn default() -> Self {
+        toml_render(&RenderSettings::default())
+    }
+}
+
+fn vec3(v: &[f32; 3]) -> Vec3 {
+    Vec3::new(v[0], v[1], v[2])
+}
+
+fn vec3_array(v: &Vec3) -> [f32; 3] {
+    [v.x, v.y, v.z]
+}
+
+fn rgb(c: &[f32; 3]) -> RGB {
+    RGB::new(c[0], c[1], c[2])
+}
+
+fn rgb_array(c: &RGB) -> [f32; 3] {
+    [c.r, c.g, c.b]
+}
+
+// -- Error Handling ----------------------------------------------------------
+
+#[derive(Debug)]
+pub enum TomlError {
+    Parse(String),
+    Serialize(String),
+    Reference(String),
+    Texture(String),
+    Material(String),
+    Mesh(String),
+    Camera(String),
+}
+
+impl fmt::Display for TomlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TomlError::Parse(e) => write!(f, "Parse error; {}", e),
+            TomlError::Serialize(e) => write!(f, "Serialize error; {}", e),
+            TomlError::Reference(e) => write!(f, "Reference error; {}", e),
+            TomlError::Texture(e) => write!(f, "Texture error; {}", e),
+            TomlError::Material(e) => write!(f, "Material error; {}", e),
+            TomlError::Mesh(e) => write!(f, "Mesh error; {}", e),
+            TomlError::Camera(e) => write!(f, "Camera error; {}", e),
+        }
+    }
+}
+
+impl error::Error for TomlError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOC: &str = r#"
+[render]
+width = 320
+height = 240
+samples_per_pixel = 4
+integrator = "direct"
+tonemapper = "gamma"
+seed = 7
+
+[[textures]]
+name = "decal"
+width = 2
+height = 1
+texels = [[1.0, 0.0, 0.0], [0.0, 0.0, 1.0]]
+alpha = [1.0, 0.5]
+
+[[materials]]
+name = "decal"
+texture = "decal"
+
+[[materials]]
+name = "red"
+color = [1.0, 0.0, 0.0]
+metallic = 1.0
+roughness = 0.3
+reflective = [0.5, 0.5, 0.5]
+transparency = 0.25
+wrap_u = "mirror"
+filter = "anisotropic"
+bump_map = "decal"
+bump_scale = 2.0
+
+[[meshes]]
+vertices = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]]
+uvs = [[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]]
+material = "red"
+transform = [1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 2.0, 1.0]
+
+[[shapes]]
+kind = "sphere"
+center = [0.0, 0.0, -3.0]
+radius = 0.5
+material = "red"
+
+[[lights]]
+position = [0.0, 5.0, 0.0]
+color = [1.0, 1.0, 1.0]
+
+[[cameras]]
+name = "main"
+position = [0.0, 0.0, 5.0]
+target = [0.0, 0.0, 0.0]
+projection = { kind = "perspective", xfov = 45.0 }
+"#;
+
+    #[test]
+    fn test_load() {
+        let (scene, settings) = TomlLoader::from_str_with_settings(DOC, None).unwrap();
+        assert_eq!(
+            settings,
+            RenderSettings {
+                width: 320,
+                height: 240,
+                samples_per_pixel: 4,
+                integrator: Integrator::Direct,
+                tonemapper: Tonemapper::Gamma,
+                seed: Some(7),
+            }
+        );
+        assert_eq!(scene.geometries.len(), 1);
+        assert_eq!(
+            scene.geometries[0].transformed_vertices[1],
+            Vec3::new(1.0, 0.0, 2.0)
+        );
+        assert_eq!(scene.shapes.len(), 1);
+        assert_eq!(scene.lights.len(), 1);
+        assert_eq!(scene.cameras.len(), 1);
+        assert_eq!(scene.cameras[0].name(), "main");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (scene, settings) = TomlLoader::from_str_with_settings(DOC, None).unwrap();
+        let doc = TomlLoader::to_string(&scene, &settings, None).unwrap();
+        let (reloaded, reloaded_settings) = TomlLoader::from_str_with_settings(&doc, None).unwrap();
+
+        assert_eq!(reloaded_settings, settings);
+        assert_eq!(
+            reloaded.geometries[0].transformed_vertices,
+            scene.geometries[0].transformed_vertices
+        );
+        assert_eq!(reloaded.shapes.len(), 1);
+        assert_eq!(reloaded.shapes[0].material.metallic, 1.0);
+        assert_eq!(reloaded.shapes[0].material.roughness, 0.3);
+        assert_eq!(
+            reloaded.shapes[0].material.reflective,
+            Some(RGB::new(0.5, 0.5, 0.5))
+        );
+        assert_eq!(reloaded.shapes[0].material.transparency, 0.25);
+        assert_eq!(reloaded.shapes[0].material.specular, RGB::white());
+        assert_eq!(
+            reloaded.shapes[0].material.sampler,
+            TextureSampler {
+                wrap_u: WrapMode::Mirror,
+                wrap_v: WrapMode::Repeat,
+                filter: TextureFilter::Anisotropic,
+            }
+        );
+        assert_eq!(
+            reloaded.shapes[0].material.bump,
+            Some(Bump::HeightMap {
+                texture_id: 0,
+                scale: 2.0
+            })
+        );
+        assert_eq!(reloaded.textures.len(), 1);
+        assert_eq!(reloaded.textures[0].texels(), scene.textures[0].texels());
+        assert_eq!(reloaded.textures[0].texels()[1].a, 0.5);
+        assert_eq!(reloaded.geometries[0].uvs(), scene.geometries[0].uvs());
+        assert_eq!(reloaded.geometries[0].uvs()[1], (1.0, 0.0));
+        assert_eq!(reloaded.lights[0].pos, scene.lights[0].pos);
+        assert_eq!(reloaded.cameras[0].name(), "main");
+        assert_eq!(
+            reloaded.cameras[0].base_orientation_matrix(),
+            scene.cameras[0].base_orientation_matrix()
+        );
+    }
+
+    #[test]
+    fn test_unknown_material() {
+        let doc = "[[shapes]]\nkind = \"sphere\"\ncenter = [0.0, 0.0, 0.0]\nradius = 1.0\nmaterial = \"missing\"\n";
+        assert!(matches!(
+            TomlLoader::from_str_with_settings(doc, None),
+            Err(SceneLoadError::TomlLoader(TomlError::Reference(_)))
+        ));
+    }
+}
@@ -0,0 +1,317 @@
+use serde::{Deserialize, Serialize};
+
+// The scene file as written. Vectors and colors are [x, y, z] / [r, g, b] arrays, matrices are
+// 16 numbers column by column, with the translation last. Things refer to each other by name.
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct TomlScene {
+    #[serde(default)]
+    pub render: TomlRender,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub textures: Vec<TomlTexture>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub materials: Vec<TomlMaterial>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub meshes: Vec<TomlMesh>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shapes: Vec<TomlShape>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub lights: Vec<TomlLight>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cameras: Vec<TomlCamera>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TomlRender {
+    pub width: usize,
+    pub height: usize,
+    pub samples_per_pixel: usize,
+    pub integrator: TomlIntegrator,
+    // for the diffuse integrator
+    pub bounces: u8,
+    pub tonemapper: TomlTonemapper,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum TomlIntegrator {
+    Direct,
+    Diffuse,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum TomlTonemapper {
+    Simple,
+    Luminance,
+    Gamma,
+}
+
+// an image file, relative to the scene file, or the texels inline
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TomlTexture {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    // How the file is encoded, inline texels are linear. Without it, srgb unless the texture is
+    // only used as a normal or bump map.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color_space: Option<TomlColorSpace>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub width: usize,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub height: usize,
+    // row by row from the top left
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub texels: Vec<[f32; 3]>,
+    // one for each texel if given, opaque without
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alpha: Vec<f32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum TomlColorSpace {
+    Srgb,
+    Linear,
+}
+
+// a base color or texture, the rest as in Material
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TomlMaterial {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<[f32; 3]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub texture: Option<String>,
+    #[serde(default = "black")]
+    pub emissive: [f32; 3],
+    #[serde(default = "white")]
+    pub specular: [f32; 3],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reflective: Option<[f32; 3]>,
+    #[serde(default)]
+    pub transparency: f32,
+    #[serde(default = "one")]
+    pub index_of_refraction: f32,
+    #[serde(default)]
+    pub metallic: f32,
+    #[serde(default = "one")]
+    pub roughness: f32,
+    #[serde(default = "repeat")]
+    pub wrap_u: TomlWrapMode,
+    #[serde(default = "repeat")]
+    pub wrap_v: TomlWrapMode,
+    #[serde(default = "trilinear")]
+    pub filter: TomlTextureFilter,
+    // a tangent space normal map, or a height map scaled by bump_scale, as in Bump
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub normal_map: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bump_map: Option<String>,
+    #[serde(default = "one")]
+    pub bump_scale: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum TomlWrapMode {
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum TomlTextureFilter {
+    Nearest,
+    Bilinear,
+    Trilinear,
+    Anisotropic,
+}
+
+// Triangles from a .dae or .obj file, relative to the scene file, or given inline with three
+// vertices per triangle. The transform places them in the world, after the instance placements
+// and motion if there are any. Without instances they are geometry of their own.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TomlMesh {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub vertices: Vec<[f32; 3]>,
+    // texture coordinates of the inline vertices, one for each if given
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub uvs: Vec<[f32; 2]>,
+    // required for inline vertices, replaces the materials of a file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub material: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transform: Option<[f32; 16]>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub motion: Vec<TomlKeyframe>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub instances: Vec<[f32; 16]>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TomlKeyframe {
+    pub time: f32,
+    pub matrix: [f32; 16],
+    // hold until the next keyframe instead of blending into it
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub step: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TomlShape {
+    #[serde(flatten)]
+    pub kind: TomlShapeKind,
+    pub material: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum TomlShapeKind {
+    Sphere {
+        center: [f32; 3],
+        radius: f32,
+    },
+    Plane {
+        point: [f32; 3],
+        normal: [f32; 3],
+    },
+    Disk {
+        center: [f32; 3],
+        normal: [f32; 3],
+        radius: f32,
+    },
+    Quad {
+        corner: [f32; 3],
+        edge_u: [f32; 3],
+        edge_v: [f32; 3],
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TomlLight {
+    pub position: [f32; 3],
+    pub color: [f32; 3],
+}
+
+// placed by a matrix, or at a position looking at a target
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TomlCamera {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matrix: Option<[f32; 16]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<[f32; 3]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<[f32; 3]>,
+    #[serde(default = "y_up")]
+    pub up: [f32; 3],
+    pub projection: TomlProjection,
+    #[serde(default = "auto")]
+    pub sensor_fit: TomlSensorFit,
+    #[serde(default, skip_serializing_if = "is_zero_f32")]
+    pub aperture: f32,
+    // polygonal aperture, round without
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aperture_blades: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub focus_distance: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interocular_distance: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub convergence_distance: Option<f32>,
+    // (open, close) relative to the frame time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shutter: Option<[f32; 2]>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub motion: Vec<TomlKeyframe>,
+}
+
+// fields as in collada, see camera::FieldOfView and camera::Magnification
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum TomlProjection {
+    Perspective {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        xfov: Option<f32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        yfov: Option<f32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        aspect_ratio: Option<f32>,
+    },
+    Orthographic {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        xmag: Option<f32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ymag: Option<f32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        aspect_ratio: Option<f32>,
+    },
+    Fisheye {
+        fov: f32,
+    },
+    Equirectangular,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum TomlSensorFit {
+    Auto,
+    Horizontal,
+    Vertical,
+}
+
+fn black() -> [f32; 3] {
+    [0.0; 3]
+}
+
+fn white() -> [f32; 3] {
+    [1.0; 3]
+}
+
+fn one() -> f32 {
+    1.0
+}
+
+fn repeat() -> TomlWrapMode {
+    TomlWrapMode::Repeat
+}
+
+fn trilinear() -> TomlTextureFilter {
+    TomlTextureFilter::Trilinear
+}
+
+fn y_up() -> [f32; 3] {
+    [0.0, 1.0, 0.0]
+}
+
+fn auto() -> TomlSensorFit {
+    TomlSensorFit::Auto
+}
+
+fn is_zero(value: &usize) -> bool {
+    *value == 0
+}
+
+fn is_zero_f32(value: &f32) -> bool {
+    *value == 0.0
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
@@ -1,11 +1,16 @@
+pub mod builder;
 pub mod camera;
 pub mod color;
 pub mod loaders;
+pub mod motion;
+pub mod shape;
 pub mod texture;
 
 pub use crate::vecmath::*;
 use camera::Camera;
 use color::{Diffuse, RGB};
+use motion::Motion;
+use shape::Shape;
 
 pub type Vertex = Vec3;
 
@@ -16,38 +21,149 @@ pub struct Light {
 }
 
 impl Light {
-    fn new(pos: Vec3, color: RGB) -> Self {
+    pub fn new(pos: Vec3, color: RGB) -> Self {
         Light { pos, color }
     }
 }
 
+// time range covering every possible ray time
+pub const ALL_TIME: (f32, f32) = (f32::NEG_INFINITY, f32::INFINITY);
+
 pub struct Scene {
     pub geometries: Vec<Geometry>,
+    // meshes in object space, placed in the world by instances
+    pub meshes: Vec<Geometry>,
+    pub instances: Vec<Instance>,
+    pub shapes: Vec<Shape>,
     pub lights: Vec<Light>,
     pub cameras: Vec<Camera>,
     pub textures: Vec<texture::Texture>,
+    // (start, end) of the ray times that will be traced, moving geometry only needs to be found within it
+    pub time_range: (f32, f32),
 }
 
 impl Scene {
-    #[allow(dead_code)]
-    pub fn apply_transform(&mut self, mat: &Matrix) {
-        for geom in self.geometries.iter_mut() {
-            for (vtx, transformed_vtx) in geom
-                .vertices
+    // axis aligned (min, max) of all geometry over the time range, None for a scene without geometry.
+    // Infinite planes are left out.
+    pub fn bounds(&self) -> Option<(Vec3, Vec3)> {
+        self.instances
+            .iter()
+            .filter_map(|instance| self.instance_bounds(instance))
+            .chain(self.shapes.iter().filter_map(|shape| shape.bounds()))
+            .chain(geometries_bounds(&self.geometries, self.time_range))
+            .reduce(|a, b| union_bounds(&a, &b.0, &b.1))
+    }
+
+    // world space (min, max) of the instanced mesh over the time range
+    pub fn instance_bounds(&self, instance: &Instance) -> Option<(Vec3, Vec3)> {
+        let (min, max) = self.meshes[instance.mesh_index].bounds(self.time_range)?;
+        let corners = [
+            Vec3::new(min.x, min.y, min.z),
+            Vec3::new(max.x, min.y, min.z),
+            Vec3::new(min.x, max.y, min.z),
+            Vec3::new(max.x, max.y, min.z),
+            Vec3::new(min.x, min.y, max.z),
+            Vec3::new(max.x, min.y, max.z),
+            Vec3::new(min.x, max.y, max.z),
+            Vec3::new(max.x, max.y, max.z),
+        ]
+        .map(|corner| instance.to_world(&corner));
+        Some(
+            corners
                 .iter()
-                .zip(geom.transformed_vertices.iter_mut())
-            {
-                *transformed_vtx = Vec3::from(mat * Vec4::from_vec3(vtx));
-            }
+                .fold((corners[0], corners[0]), |bounds, corner| {
+                    union_bounds(&bounds, corner, corner)
+                }),
+        )
+    }
+
+    // moves animated geometry to its pose at start, accelerators need to be rebuilt after this
+    pub fn set_time_range(&mut self, start: f32, end: f32) {
+        self.time_range = (start, end.max(start));
+        for geom in self.geometries.iter_mut().chain(self.meshes.iter_mut()) {
+            geom.update_pose(start);
+        }
+    }
+
+    // index of the camera with the given name, or the given index as a number
+    pub fn find_camera(&self, name_or_index: &str) -> Option<usize> {
+        self.cameras
+            .iter()
+            .position(|cam| cam.name() == name_or_index)
+            .or_else(|| {
+                name_or_index
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|idx| *idx < self.cameras.len())
+            })
+    }
+
+    // every geometry and instance in the scene
+    pub fn objects(&self) -> Vec<SceneObject> {
+        (0..self.geometries.len())
+            .map(SceneObject::Geometry)
+            .chain((0..self.instances.len()).map(SceneObject::Instance))
+            .collect()
+    }
+
+    pub fn object_transform(&self, object: SceneObject) -> Option<&Matrix> {
+        match object {
+            SceneObject::Geometry(idx) => self.geometries.get(idx).map(|geom| geom.transform()),
+            SceneObject::Instance(idx) => self.instances.get(idx).map(|inst| inst.transform()),
+        }
+    }
+
+    // places the object with the transform on top of its pose as loaded, accelerators need to
+    // be rebuilt after this. False if there is no such object or an instance's transform can't
+    // be inverted.
+    pub fn set_object_transform(&mut self, object: SceneObject, transform: &Matrix) -> bool {
+        match object {
+            SceneObject::Geometry(idx) => match self.geometries.get_mut(idx) {
+                Some(geom) => {
+                    geom.set_transform(transform);
+                    true
+                }
+                None => false,
+            },
+            SceneObject::Instance(idx) => match self.instances.get_mut(idx) {
+                Some(instance) => instance.set_transform(transform),
+                None => false,
+            },
+        }
+    }
+
+    // sets the transform of every object
+    pub fn apply_transform(&mut self, mat: &Matrix) {
+        for object in self.objects() {
+            self.set_object_transform(object, mat);
         }
     }
 }
 
+// something in the scene that can be moved on its own
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SceneObject {
+    Geometry(usize),
+    Instance(usize),
+}
+
 #[derive(Debug, Clone)]
 pub struct Geometry {
     pub vertices: Vec<Vertex>,
     pub transformed_vertices: Vec<Vertex>,
+    // texture coordinates of each vertex, empty if there are none and textures are spread over
+    // each triangle by its barycentric coordinates
+    uvs: Vec<(f32, f32)>,
+    // (dp/du, dp/dv) of each triangle, in the space of the vertices, from the uvs. Zero for
+    // triangles whose uvs don't span an area, empty without uvs.
+    tangents: Vec<(Vec3, Vec3)>,
     pub material: Material,
+    // for moving geometry, vertices are in object space and the motion places them in the world
+    pub motion: Option<Motion>,
+    // applied after the motion, moves the geometry away from where it was loaded
+    transform: Matrix,
+    // time the transformed vertices are posed at
+    pose_time: f32,
 }
 impl Geometry {
     pub fn new(vertices: Vec<Vertex>, material: Material) -> Self {
@@ -55,15 +171,433 @@ impl Geometry {
         Geometry {
             vertices,
             transformed_vertices,
+            uvs: vec![],
+            tangents: vec![],
             material,
+            motion: None,
+            transform: Matrix::ident(),
+            pose_time: 0.0,
+        }
+    }
+
+    pub fn transform(&self) -> &Matrix {
+        &self.transform
+    }
+
+    pub fn set_transform(&mut self, transform: &Matrix) {
+        self.transform = *transform;
+        self.transform_vertices();
+    }
+
+    pub fn uvs(&self) -> &[(f32, f32)] {
+        &self.uvs
+    }
+
+    // one for each vertex, or none. Also sets up the tangent frames of the triangles.
+    pub fn set_uvs(&mut self, uvs: Vec<(f32, f32)>) {
+        self.tangents = self
+            .vertices
+            .chunks_exact(3)
+            .zip(uvs.chunks_exact(3))
+            .map(|(corners, corner_uvs)| uv_tangents(corners, corner_uvs))
+            .collect();
+        self.uvs = uvs;
+    }
+
+    // texture coordinates of the corners of the triangle starting at vertex_index, None if the
+    // geometry has none
+    pub fn triangle_uvs(&self, vertex_index: usize) -> Option<[(f32, f32); 3]> {
+        self.uvs
+            .get(vertex_index..vertex_index + 3)
+            .map(|uvs| [uvs[0], uvs[1], uvs[2]])
+    }
+
+    // object to world space at the given time
+    pub fn matrix_at(&self, time: f32) -> Matrix {
+        match &self.motion {
+            None => self.transform,
+            // the product applies the motion first, so the transform acts in world space
+            Some(motion) => motion.matrix_at(time) * self.transform,
+        }
+    }
+
+    // the motion transforms the vertices as given, None makes the geometry static again
+    pub fn set_motion(&mut self, motion: Option<Motion>) {
+        self.motion = motion;
+        self.transform_vertices();
+    }
+
+    // sets transformed vertices to the pose at the given time, only moving geometry changes
+    pub fn update_pose(&mut self, time: f32) {
+        self.pose_time = time;
+        if self.motion.is_some() {
+            self.transform_vertices();
+        }
+    }
+
+    fn transform_vertices(&mut self) {
+        let mat = self.matrix_at(self.pose_time);
+        for (vtx, transformed_vtx) in self
+            .vertices
+            .iter()
+            .zip(self.transformed_vertices.iter_mut())
+        {
+            *transformed_vtx = Vec3::from(mat * Vec4::from_vec3(vtx));
+        }
+    }
+
+    // world space corners of the triangle starting at vertex_index, at the given time
+    pub fn triangle_at(&self, vertex_index: usize, time: f32) -> [Vertex; 3] {
+        match &self.motion {
+            None => [
+                self.transformed_vertices[vertex_index],
+                self.transformed_vertices[vertex_index + 1],
+                self.transformed_vertices[vertex_index + 2],
+            ],
+            Some(_) => {
+                let mat = self.matrix_at(time);
+                [
+                    Vec3::from(mat * Vec4::from_vec3(&self.vertices[vertex_index])),
+                    Vec3::from(mat * Vec4::from_vec3(&self.vertices[vertex_index + 1])),
+                    Vec3::from(mat * Vec4::from_vec3(&self.vertices[vertex_index + 2])),
+                ]
+            }
+        }
+    }
+
+    // world space (dp/du, dp/dv) of the triangle starting at vertex_index, at the given time. None
+    // if the geometry has no uvs.
+    pub fn triangle_tangents_at(&self, vertex_index: usize, time: f32) -> Option<(Vec3, Vec3)> {
+        let (dpdu, dpdv) = self.tangents.get(vertex_index / 3)?;
+        let mat = self.matrix_at(time);
+        let to_world = |v: &Vec3| Vec3::from(mat * Vec4::new(v.x, v.y, v.z, 0.0));
+        Some((to_world(dpdu), to_world(dpdv)))
+    }
+
+    // (min, max) the triangle stays inside during the time range. Between the times of the
+    // motion's sweep a vertex turns by a small angle around the motion's center. It strays from
+    // the straight line between its two positions by at most a quarter of the angle times the sum
+    // of its distances from the center at both times, stretched by at most the size of the
+    // transform, so the corners padded by that bound the whole sweep.
+    pub fn swept_triangle_bounds(
+        &self,
+        vertex_index: usize,
+        time_range: (f32, f32),
+    ) -> (Vec3, Vec3) {
+        let motion = match &self.motion {
+            None => {
+                let corners = self.triangle_at(vertex_index, 0.0);
+                return corners[1..]
+                    .iter()
+                    .fold((corners[0], corners[0]), |bounds, vtx| {
+                        union_bounds(&bounds, vtx, vtx)
+                    });
+            }
+            Some(motion) => motion,
+        };
+        // the corners with their distances from the center the motion turns them around, before
+        // the transform
+        let corners = |time: f32| {
+            let mat = motion.matrix_at(time);
+            let center = Vec3::from(mat * Vec4::new(0.0, 0.0, 0.0, 1.0));
+            let world = self.triangle_at(vertex_index, time);
+            [0, 1, 2].map(|corner| {
+                let offset =
+                    Vec3::from(mat * Vec4::from_vec3(&self.vertices[vertex_index + corner]))
+                        - center;
+                (world[corner], dot(&offset, &offset).sqrt())
+            })
+        };
+        // at least the most the transform lengthens anything by, from its largest column and row
+        let element = |col: usize, row: usize| self.transform[col * 4 + row].abs();
+        let column = |col: usize| (0..3).map(|row| element(col, row)).sum::<f32>();
+        let row = |row: usize| (0..3).map(|col| element(col, row)).sum::<f32>();
+        let stretch =
+            ((0..3).map(column).fold(0.0, f32::max) * (0..3).map(row).fold(0.0, f32::max)).sqrt();
+        let poses = motion
+            .sweep(time_range.0, time_range.1)
+            .into_iter()
+            .map(|(time, turn)| (corners(time), turn))
+            .collect::<Vec<_>>();
+        let first = poses[0].0[0].0;
+        let mut bounds = (first, first);
+        for pair in poses.windows(2) {
+            let ((from, turn), (to, _)) = (&pair[0], &pair[1]);
+            for ((from_vtx, from_radius), (to_vtx, to_radius)) in from.iter().zip(to.iter()) {
+                let pad = 0.25 * turn * (from_radius + to_radius) * stretch;
+                let pad = Vec3::new(pad, pad, pad);
+                bounds = union_bounds(&bounds, &(from_vtx - &pad), &(from_vtx + &pad));
+                bounds = union_bounds(&bounds, &(to_vtx - &pad), &(to_vtx + &pad));
+            }
         }
+        bounds
     }
+
+    // (min, max) of the geometry during the time range, None if it has no vertices
+    pub fn bounds(&self, time_range: (f32, f32)) -> Option<(Vec3, Vec3)> {
+        (0..self.vertices.len() / 3)
+            .map(|tri_idx| self.swept_triangle_bounds(tri_idx * 3, time_range))
+            .reduce(|a, b| union_bounds(&a, &b.0, &b.1))
+    }
+}
+
+// a shared mesh placed in the world with its own transform
+#[derive(Debug, Clone)]
+pub struct Instance {
+    pub mesh_index: usize,
+    placement: Matrix,
+    // applied after the placement, moves the instance away from where it was loaded
+    transform: Matrix,
+    matrix: Matrix,
+    inverse: Matrix,
+}
+impl Instance {
+    // None if the placement can't be inverted, e.g. a zero scale
+    pub fn new(mesh_index: usize, placement: Matrix) -> Option<Self> {
+        let inverse = placement.affine_inverse()?;
+        Some(Instance {
+            mesh_index,
+            placement,
+            transform: Matrix::ident(),
+            matrix: placement,
+            inverse,
+        })
+    }
+
+    // object to world space
+    pub fn matrix(&self) -> &Matrix {
+        &self.matrix
+    }
+
+    pub fn transform(&self) -> &Matrix {
+        &self.transform
+    }
+
+    // false and unchanged if the resulting matrix can't be inverted
+    pub fn set_transform(&mut self, transform: &Matrix) -> bool {
+        // the product applies the placement first, so the transform acts in world space
+        let matrix = self.placement * transform;
+        match matrix.affine_inverse() {
+            Some(inverse) => {
+                self.transform = *transform;
+                self.matrix = matrix;
+                self.inverse = inverse;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn to_world(&self, v: &Vec3) -> Vec3 {
+        Vec3::from(self.matrix * Vec4::from_vec3(v))
+    }
+
+    pub fn direction_to_world(&self, v: &Vec3) -> Vec3 {
+        Vec3::from(self.matrix * Vec4::new(v.x, v.y, v.z, 0.0))
+    }
+
+    // the ray in the mesh's object space. The direction isn't normalized, so hit distances
+    // along the object space ray are the same as along the world space ray.
+    pub fn ray_to_object(&self, ray: &Ray) -> Ray {
+        let pos = Vec3::from(self.inverse * Vec4::from_vec3(&ray.pos));
+        let dir = Vec3::from(self.inverse * Vec4::new(ray.dir.x, ray.dir.y, ray.dir.z, 0.0));
+        Ray::with_time(pos, dir, ray.time)
+    }
+}
+
+// (min, max) of all the geometries during the time range, None if they have no vertices
+pub fn geometries_bounds(geometries: &[Geometry], time_range: (f32, f32)) -> Option<(Vec3, Vec3)> {
+    geometries
+        .iter()
+        .filter_map(|geom| geom.bounds(time_range))
+        .reduce(|a, b| union_bounds(&a, &b.0, &b.1))
+}
+
+// how position changes with the texture coordinates over a triangle, solved from its edges
+fn uv_tangents(corners: &[Vertex], uvs: &[(f32, f32)]) -> (Vec3, Vec3) {
+    let (e1, e2) = (corners[1] - corners[0], corners[2] - corners[0]);
+    let (du1, dv1) = (uvs[1].0 - uvs[0].0, uvs[1].1 - uvs[0].1);
+    let (du2, dv2) = (uvs[2].0 - uvs[0].0, uvs[2].1 - uvs[0].1);
+    let det = du1 * dv2 - du2 * dv1;
+    if det.abs() < 1e-12 {
+        let zero = Vec3::new(0.0, 0.0, 0.0);
+        return (zero, zero);
+    }
+    (
+        (e1 * dv2 - e2 * dv1) * (1.0 / det),
+        (e2 * du1 - e1 * du2) * (1.0 / det),
+    )
+}
+
+fn union_bounds(bounds: &(Vec3, Vec3), min: &Vec3, max: &Vec3) -> (Vec3, Vec3) {
+    (
+        Vec3::new(
+            bounds.0.x.min(min.x),
+            bounds.0.y.min(min.y),
+            bounds.0.z.min(min.z),
+        ),
+        Vec3::new(
+            bounds.1.x.max(max.x),
+            bounds.1.y.max(max.y),
+            bounds.1.z.max(max.z),
+        ),
+    )
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone)]
 pub struct Material {
+    // the base color, of the diffuse for dielectrics and of the specular for metals
     pub diffuse: Diffuse,
     pub emissive: RGB,
-    pub specular: Option<f32>,
+    // tint of the dielectric highlight
+    pub specular: RGB,
+    // reflectance at normal incidence, overriding the dielectric's when set
+    pub reflective: Option<RGB>,
+    // [0..1], the fraction of light refracted through the surface
+    pub transparency: f32,
     pub index_of_refraction: f32,
+    // [0..1], 0 for dielectrics, 1 for metals
+    pub metallic: f32,
+    // [0..1], 0 is a perfect mirror
+    pub roughness: f32,
+    // how the textures of the material are wrapped and filtered
+    pub sampler: texture::TextureSampler,
+    pub bump: Option<Bump>,
+}
+
+// Perturbs the shading normal by a texture, looked up with the material's sampler. Textures
+// with uvs are oriented by them, x along u and y up the image. Without uvs x runs along the
+// first edge of the triangle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bump {
+    // tangent space normals, stored as (n + 1) / 2
+    NormalMap(usize),
+    // Heights, the slope between neighbouring texels tilts the normal. A difference of 1 / scale
+    // between them is a slope of 45 degrees.
+    HeightMap { texture_id: usize, scale: f32 },
+}
+
+impl Material {
+    // a rough opaque dielectric, not emissive
+    pub fn new(diffuse: Diffuse) -> Self {
+        Material {
+            diffuse,
+            emissive: RGB::black(),
+            specular: RGB::white(),
+            reflective: None,
+            transparency: 0.0,
+            index_of_refraction: 1.0,
+            metallic: 0.0,
+            roughness: 1.0,
+            sampler: texture::TextureSampler::default(),
+            bump: None,
+        }
+    }
+
+    // roughness giving about the highlight of a Phong or Blinn-Phong shininess exponent, through
+    // Walter et al.'s alpha = sqrt(2 / (n + 2)), with alpha = roughness^2
+    pub fn roughness_from_shininess(shininess: f32) -> f32 {
+        (2.0 / (shininess.max(0.0) + 2.0)).sqrt().sqrt()
+    }
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Material::new(Diffuse::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn triangle() -> Geometry {
+        Geometry::new(
+            vec![
+                Vec3::new(0.0, 0.0, 0.0),
+                Vec3::new(1.0, 0.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0),
+            ],
+            Material::default(),
+        )
+    }
+
+    #[test]
+    fn test_set_object_transform() {
+        let mut scene = Scene {
+            geometries: vec![triangle()],
+            meshes: vec![triangle()],
+            instances: vec![
+                Instance::new(0, Matrix::translate(&Vec3::new(0.0, 0.0, 5.0))).unwrap(),
+            ],
+            shapes: vec![],
+            lights: vec![],
+            cameras: vec![],
+            textures: vec![],
+            time_range: ALL_TIME,
+        };
+        let offset = Matrix::translate(&Vec3::new(2.0, 0.0, 0.0));
+        assert!(scene.set_object_transform(SceneObject::Geometry(0), &offset));
+        assert!(scene.set_object_transform(SceneObject::Instance(0), &offset));
+        assert!(!scene.set_object_transform(SceneObject::Instance(1), &offset));
+        assert!(!scene.set_object_transform(SceneObject::Instance(0), &Matrix::new(&[0.0; 16])));
+
+        assert_eq!(
+            scene.geometries[0].triangle_at(0, 0.0)[1],
+            Vec3::new(3.0, 0.0, 0.0)
+        );
+        assert_eq!(
+            scene.instances[0].to_world(&Vec3::new(1.0, 0.0, 0.0)),
+            Vec3::new(3.0, 0.0, 5.0)
+        );
+        assert_eq!(
+            scene.bounds(),
+            Some((Vec3::new(2.0, 0.0, 0.0), Vec3::new(3.0, 1.0, 5.0)))
+        );
+
+        // transforms are not cumulative
+        scene.apply_transform(&Matrix::ident());
+        assert_eq!(
+            scene.geometries[0].transformed_vertices[1],
+            Vec3::new(1.0, 0.0, 0.0)
+        );
+
+        // turned a quarter around y, the offset still moves along the world x axis
+        let turn = Matrix::rot_y(std::f32::consts::FRAC_PI_2);
+        scene.instances.push(Instance::new(0, turn).unwrap());
+        let mut turning = triangle();
+        turning.set_motion(Some(Motion::new(vec![motion::Keyframe::new(0.0, turn)])));
+        scene.geometries.push(turning);
+        assert!(scene.set_object_transform(SceneObject::Instance(1), &offset));
+        assert!(scene.set_object_transform(SceneObject::Geometry(1), &offset));
+        let expected = Vec3::new(2.0, 0.0, 1.0);
+        for moved in [
+            scene.instances[1].to_world(&Vec3::new(1.0, 0.0, 0.0)),
+            scene.geometries[1].triangle_at(0, 0.0)[1],
+        ]
+        .iter()
+        {
+            let d = moved - &expected;
+            assert!(dot(&d, &d) < 1e-10, "{:?}", moved);
+        }
+    }
+
+    #[test]
+    fn test_triangle_tangents() {
+        let mut geom = triangle();
+        assert_eq!(geom.triangle_tangents_at(0, 0.0), None);
+
+        // u at twice the rate of x, v down along y as images go
+        geom.set_uvs(vec![(0.0, 1.0), (0.5, 1.0), (0.0, 0.0)]);
+        geom.set_transform(&Matrix::translate(&Vec3::new(0.0, 0.0, 5.0)));
+        assert_eq!(
+            geom.triangle_tangents_at(0, 0.0),
+            Some((Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, -1.0, 0.0)))
+        );
+
+        // the uvs span no area
+        geom.set_uvs(vec![(0.0, 0.0); 3]);
+        let zero = Vec3::new(0.0, 0.0, 0.0);
+        assert_eq!(geom.triangle_tangents_at(0, 0.0), Some((zero, zero)));
+    }
 }
@@ -0,0 +1,189 @@
+use crate::vecmath::Matrix;
+
+// how far a sweep turns at most from one of its times to the next, in radians
+pub const MAX_SWEEP_TURN: f32 = std::f32::consts::PI / 16.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    Linear,
+    // hold the keyframe until the next one
+    Step,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keyframe {
+    pub time: f32,
+    pub matrix: Matrix,
+    // how to get from this keyframe to the next
+    pub interpolation: Interpolation,
+}
+
+impl Keyframe {
+    pub fn new(time: f32, matrix: Matrix) -> Self {
+        Keyframe {
+            time,
+            matrix,
+            interpolation: Interpolation::Linear,
+        }
+    }
+
+    pub fn step(time: f32, matrix: Matrix) -> Self {
+        Keyframe {
+            time,
+            matrix,
+            interpolation: Interpolation::Step,
+        }
+    }
+}
+
+// Transform changing over time, interpolated between keyframes and held constant before the
+// first and after the last one. Keyframes are blended by their translation, rotation and scale,
+// see Matrix::interpolate.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Motion {
+    keyframes: Vec<Keyframe>,
+}
+
+impl Motion {
+    pub fn new(mut keyframes: Vec<Keyframe>) -> Self {
+        keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+        Motion { keyframes }
+    }
+
+    pub fn keyframes(&self) -> &[Keyframe] {
+        &self.keyframes
+    }
+
+    // times in [start, end] where the motion can change; the start, the end and all keyframes
+    // in between. The pose between two of them is the pose at the first (step) or a blend of both (linear).
+    pub fn breakpoints(&self, start: f32, end: f32) -> Vec<f32> {
+        let mut times = vec![start];
+        times.extend(
+            self.keyframes
+                .iter()
+                .map(|key| key.time)
+                .filter(|time| *time > start && *time < end),
+        );
+        times.push(end);
+        times
+    }
+
+    // The breakpoints, with times in between so that the rotation turns by at most
+    // MAX_SWEEP_TURN from one to the next. Each time comes with the angle turned until the next.
+    pub fn sweep(&self, start: f32, end: f32) -> Vec<(f32, f32)> {
+        let breakpoints = self.breakpoints(start, end);
+        let mut sweep = vec![];
+        for (from, to) in breakpoints.iter().zip(breakpoints.iter().skip(1)) {
+            let next_idx = self.keyframes.partition_point(|key| key.time <= *from);
+            let keys = (next_idx.checked_sub(1), self.keyframes.get(next_idx));
+            let turn = match keys {
+                (Some(prev_idx), Some(next)) => {
+                    let prev = &self.keyframes[prev_idx];
+                    match prev.interpolation {
+                        Interpolation::Step => 0.0,
+                        Interpolation::Linear => {
+                            prev.matrix.rotation_angle_to(&next.matrix) * (to - from)
+                                / (next.time - prev.time)
+                        }
+                    }
+                }
+                _ => 0.0,
+            };
+            // keys at the same time
+            let turn = if turn.is_finite() { turn } else { 0.0 };
+            let steps = (turn / MAX_SWEEP_TURN).ceil().max(1.0) as usize;
+            sweep.push((*from, turn / steps as f32));
+            sweep.extend((1..steps).map(|step| {
+                let time = from + (to - from) * step as f32 / steps as f32;
+                (time, turn / steps as f32)
+            }));
+        }
+        sweep.push((end, 0.0));
+        sweep
+    }
+
+    pub fn matrix_at(&self, time: f32) -> Matrix {
+        let next_idx = self.keyframes.partition_point(|key| key.time <= time);
+        match (next_idx, self.keyframes.len()) {
+            (_, 0) => Matrix::ident(),
+            (0, _) => self.keyframes[0].matrix,
+            (next_idx, len) if next_idx == len => self.keyframes[len - 1].matrix,
+            (next_idx, _) => {
+                let prev = &self.keyframes[next_idx - 1];
+                let next = &self.keyframes[next_idx];
+                match prev.interpolation {
+                    Interpolation::Step => prev.matrix,
+                    Interpolation::Linear => {
+                        let t = (time - prev.time) / (next.time - prev.time);
+                        prev.matrix.interpolate(&next.matrix, t)
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vecmath::Vec3;
+
+    #[test]
+    fn test_matrix_at_interpolates_and_clamps() {
+        let motion = Motion::new(vec![
+            Keyframe::new(1.0, Matrix::translate(&Vec3::new(2.0, 0.0, 0.0))),
+            Keyframe::new(0.0, Matrix::ident()),
+        ]);
+        assert_eq!(motion.matrix_at(-1.0), Matrix::ident());
+        assert_eq!(motion.matrix_at(0.25)[12], 0.5);
+        assert_eq!(motion.matrix_at(5.0)[12], 2.0);
+    }
+
+    #[test]
+    fn test_sweep_steps_through_turns() {
+        let motion = Motion::new(vec![
+            Keyframe::new(0.0, Matrix::ident()),
+            Keyframe::step(1.0, Matrix::rot_y(1.0)),
+            Keyframe::new(2.0, Matrix::ident()),
+        ]);
+        let sweep = motion.sweep(-1.0, 3.0);
+        assert_eq!(sweep.first(), Some(&(-1.0, 0.0)));
+        assert_eq!(sweep.last(), Some(&(3.0, 0.0)));
+        // only the turn between 0 and 1 is stepped through, the step holds
+        let turning = sweep.iter().filter(|(_, turn)| *turn > 0.0).count();
+        assert_eq!(turning, 6);
+        let total = sweep.iter().map(|(_, turn)| turn).sum::<f32>();
+        assert!((total - 1.0).abs() < 1e-4);
+        assert!(sweep.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    }
+
+    #[test]
+    fn test_rotation_keeps_scale() {
+        // half a turn, scaled by 2
+        let scale = |m: Matrix| {
+            let mut m = m;
+            for col in 0..3 {
+                for row in 0..3 {
+                    m[col * 4 + row] *= 2.0;
+                }
+            }
+            m
+        };
+        let motion = Motion::new(vec![
+            Keyframe::new(0.0, scale(Matrix::ident())),
+            Keyframe::new(1.0, scale(Matrix::rot_y(std::f32::consts::PI))),
+        ]);
+        let expected = scale(Matrix::rot_y(std::f32::consts::FRAC_PI_2));
+        let mid = motion.matrix_at(0.5);
+        for idx in 0..16 {
+            // the quarter turn either way
+            assert!((mid[idx].abs() - expected[idx].abs()).abs() < 1e-5);
+        }
+
+        // a NaN time doesn't panic
+        Motion::new(vec![
+            Keyframe::new(f32::NAN, Matrix::ident()),
+            Keyframe::new(0.0, Matrix::ident()),
+        ]);
+    }
+}
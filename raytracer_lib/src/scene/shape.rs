@@ -0,0 +1,132 @@
+use super::{cross, Material, Vec3};
+
+// Analytic surfaces, intersected exactly instead of as triangles. Texture coordinates run over
+// [0, 1] across the shape, planes repeat them every unit.
+#[derive(Debug, Clone)]
+pub enum ShapeKind {
+    Sphere {
+        center: Vec3,
+        radius: f32,
+    },
+    // infinite, the normal has unit length
+    Plane {
+        point: Vec3,
+        normal: Vec3,
+    },
+    // the normal has unit length
+    Disk {
+        center: Vec3,
+        normal: Vec3,
+        radius: f32,
+    },
+    // parallelogram spanned by two edges from a corner, facing along edge_u x edge_v
+    Quad {
+        corner: Vec3,
+        edge_u: Vec3,
+        edge_v: Vec3,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Shape {
+    pub kind: ShapeKind,
+    pub material: Material,
+}
+
+impl Shape {
+    pub fn sphere(center: Vec3, radius: f32, material: Material) -> Self {
+        Shape {
+            kind: ShapeKind::Sphere { center, radius },
+            material,
+        }
+    }
+
+    pub fn plane(point: Vec3, normal: Vec3, material: Material) -> Self {
+        Shape {
+            kind: ShapeKind::Plane {
+                point,
+                normal: normal.normalized(),
+            },
+            material,
+        }
+    }
+
+    pub fn disk(center: Vec3, normal: Vec3, radius: f32, material: Material) -> Self {
+        Shape {
+            kind: ShapeKind::Disk {
+                center,
+                normal: normal.normalized(),
+                radius,
+            },
+            material,
+        }
+    }
+
+    pub fn quad(corner: Vec3, edge_u: Vec3, edge_v: Vec3, material: Material) -> Self {
+        Shape {
+            kind: ShapeKind::Quad {
+                corner,
+                edge_u,
+                edge_v,
+            },
+            material,
+        }
+    }
+
+    // (min, max), None for the unbounded plane
+    pub fn bounds(&self) -> Option<(Vec3, Vec3)> {
+        match &self.kind {
+            ShapeKind::Sphere { center, radius } => {
+                let r = Vec3::new(*radius, *radius, *radius);
+                Some((*center - r, *center + r))
+            }
+            ShapeKind::Plane { .. } => None,
+            ShapeKind::Disk {
+                center,
+                normal,
+                radius,
+            } => {
+                // a disk reaches out along an axis by its radius times the sine of the axis to
+                // its normal
+                let extent = |n: f32| radius * (1.0 - n * n).max(0.0).sqrt();
+                let r = Vec3::new(extent(normal.x), extent(normal.y), extent(normal.z));
+                Some((*center - r, *center + r))
+            }
+            ShapeKind::Quad {
+                corner,
+                edge_u,
+                edge_v,
+            } => {
+                let corners = [
+                    *corner + *edge_u,
+                    *corner + *edge_v,
+                    *corner + *edge_u + *edge_v,
+                ];
+                Some(corners.iter().fold((*corner, *corner), |bounds, c| {
+                    super::union_bounds(&bounds, c, c)
+                }))
+            }
+        }
+    }
+
+    // unit normal at a point on the shape, pointing out of spheres
+    pub fn normal_at(&self, pos: &Vec3) -> Vec3 {
+        match &self.kind {
+            ShapeKind::Sphere { center, .. } => (*pos - *center).normalized(),
+            ShapeKind::Plane { normal, .. } | ShapeKind::Disk { normal, .. } => *normal,
+            ShapeKind::Quad { edge_u, edge_v, .. } => cross(edge_u, edge_v).normalized(),
+        }
+    }
+}
+
+// two unit vectors perpendicular to the unit normal and each other, for coordinates on planes
+pub fn tangents(normal: &Vec3) -> (Vec3, Vec3) {
+    // any vector not parallel to the normal will do, the axis it is least aligned with is safest
+    let axis = if normal.x.abs() < 0.5 {
+        Vec3::new(1.0, 0.0, 0.0)
+    } else {
+        Vec3::new(0.0, 1.0, 0.0)
+    };
+    let tangent = cross(&axis, normal).normalized();
+    (tangent, cross(normal, &tangent))
+}
@@ -3,49 +3,383 @@ use image;
 
 use std::{error, fmt, io};
 
+// most samples an anisotropic lookup takes along the footprint
+const MAX_ANISOTROPY: f32 = 16.0;
+
+// An image along with its mip-map pyramid, each level half the size of the one before down to
+// 1x1. Level 0 is the image as given. Texels are linear, with straight (not premultiplied) alpha.
 pub struct Texture {
+    levels: Vec<MipLevel>,
+    // the file it was loaded from, if any, and how its texels were decoded
+    source: Option<std::path::PathBuf>,
+    color_space: ColorSpace,
+}
+
+struct MipLevel {
     width: usize,
     height: usize,
-    data: Vec<color::RGB>,
+    data: Vec<color::RGBA>,
+}
+
+// How the values stored in an image file relate to linear values. Colors, like diffuse maps, are
+// usually sRGB encoded, data, like normal or roughness maps, usually linear. Float images, HDR and
+// EXR, are always linear.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorSpace {
+    Srgb,
+    Linear,
+}
+
+impl ColorSpace {
+    // the linear value of an encoded value in [0, 1]
+    pub fn decode(self, value: f32) -> f32 {
+        match self {
+            ColorSpace::Linear => value,
+            ColorSpace::Srgb if value <= 0.04045 => value / 12.92,
+            ColorSpace::Srgb => ((value + 0.055) / 1.055).powf(2.4),
+        }
+    }
 }
 
 impl Texture {
-    fn new(width: usize, height: usize, data: Vec<color::RGB>) -> Self {
-        Self {
+    // an opaque texture, data is row by row from the top left, width * height texels. None if
+    // there are more or fewer texels.
+    pub fn new(width: usize, height: usize, data: Vec<color::RGB>) -> Option<Self> {
+        let data = data
+            .into_iter()
+            .map(|rgb| color::RGBA::from_rgb(rgb, 1.0))
+            .collect();
+        Texture::from_rgba(width, height, data)
+    }
+
+    pub fn from_rgba(width: usize, height: usize, data: Vec<color::RGBA>) -> Option<Self> {
+        if data.len() != width * height {
+            return None;
+        }
+        Some(Texture::from_level(MipLevel {
             width,
             height,
             data,
+        }))
+    }
+
+    fn from_level(level: MipLevel) -> Self {
+        let mut levels = vec![level];
+        while let Some(level) = levels.last().and_then(MipLevel::downsampled) {
+            levels.push(level);
+        }
+        Self {
+            levels,
+            source: None,
+            color_space: ColorSpace::Linear,
         }
     }
 
-    pub fn get_texel(&self, u: f32, v: f32) -> &color::RGB {
-        // nearest neighbour "filtering"
-        let x = (u * self.width as f32) as usize;
-        let y = (v * self.height as f32) as usize;
+    pub fn width(&self) -> usize {
+        self.levels[0].width
+    }
+
+    pub fn height(&self) -> usize {
+        self.levels[0].height
+    }
 
+    pub fn texels(&self) -> &[color::RGBA] {
+        &self.levels[0].data
+    }
+
+    pub fn num_levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    pub fn source(&self) -> Option<&std::path::Path> {
+        self.source.as_deref()
+    }
+
+    // how the source file was decoded
+    pub fn color_space(&self) -> ColorSpace {
+        self.color_space
+    }
+
+    pub fn is_opaque(&self) -> bool {
+        self.texels().iter().all(|texel| texel.a >= 1.0)
+    }
+
+    // nearest texel, the texture repeats outside [0, 1). Transparent for an empty texture.
+    pub fn get_texel(&self, u: f32, v: f32) -> &color::RGBA {
+        static EMPTY: color::RGBA = color::RGBA {
+            r: 0.0,
+            g: 0.0,
+            b: 0.0,
+            a: 0.0,
+        };
+        if self.levels[0].data.is_empty() {
+            return &EMPTY;
+        }
+        self.levels[0].nearest(u, v, WrapMode::Repeat, WrapMode::Repeat)
+    }
+}
+
+impl MipLevel {
+    fn texel(&self, x: usize, y: usize) -> &color::RGBA {
         &self.data[y * self.width + x]
     }
+
+    // the next level, averaging 2x2 texels into one. The last row or column of odd sizes is
+    // averaged into the texels next to it. None at 1x1, or for an empty texture.
+    fn downsampled(&self) -> Option<MipLevel> {
+        if self.width * self.height <= 1 {
+            return None;
+        }
+        let (width, height) = ((self.width / 2).max(1), (self.height / 2).max(1));
+        // the texels of this level a texel of the next covers along one axis
+        let span = |i: usize, size: usize, new_size: usize| {
+            let end = if i + 1 == new_size { size } else { 2 * i + 2 };
+            (2 * i).min(size - 1)..end
+        };
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = color::RGBA::transparent();
+                let mut count = 0;
+                for src_y in span(y, self.height, height) {
+                    for src_x in span(x, self.width, width) {
+                        sum += *self.texel(src_x, src_y);
+                        count += 1;
+                    }
+                }
+                data.push(sum * (1.0 / count as f32));
+            }
+        }
+        Some(MipLevel {
+            width,
+            height,
+            data,
+        })
+    }
+
+    fn nearest(&self, u: f32, v: f32, wrap_u: WrapMode, wrap_v: WrapMode) -> &color::RGBA {
+        let x = wrap_u.wrap((u * self.width as f32).floor(), self.width);
+        let y = wrap_v.wrap((v * self.height as f32).floor(), self.height);
+        self.texel(x, y)
+    }
+
+    fn bilinear(&self, u: f32, v: f32, sampler: &TextureSampler) -> color::RGBA {
+        // texel centers are at half integers
+        let x = u * self.width as f32 - 0.5;
+        let y = v * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let xs = [
+            sampler.wrap_u.wrap(x0, self.width),
+            sampler.wrap_u.wrap(x0 + 1.0, self.width),
+        ];
+        let ys = [
+            sampler.wrap_v.wrap(y0, self.height),
+            sampler.wrap_v.wrap(y0 + 1.0, self.height),
+        ];
+        let row = |y| *self.texel(xs[0], y) * (1.0 - fx) + *self.texel(xs[1], y) * fx;
+        row(ys[0]) * (1.0 - fy) + row(ys[1]) * fy
+    }
+}
+
+// how texture coordinates outside [0, 1) find a texel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WrapMode {
+    Repeat,
+    // the edge texels stretch out
+    Clamp,
+    // repeats, every other copy flipped
+    Mirror,
+}
+
+impl WrapMode {
+    // texel index of the texel coordinate
+    fn wrap(self, coord: f32, size: usize) -> usize {
+        // saturates for huge coordinates, NaN becomes 0
+        let coord = coord as i64;
+        let size = size as i64;
+        let wrapped = match self {
+            WrapMode::Repeat => coord.rem_euclid(size),
+            WrapMode::Clamp => coord.clamp(0, size - 1),
+            WrapMode::Mirror => {
+                let period_pos = coord.rem_euclid(2 * size);
+                if period_pos < size {
+                    period_pos
+                } else {
+                    2 * size - 1 - period_pos
+                }
+            }
+        };
+        wrapped as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextureFilter {
+    Nearest,
+    Bilinear,
+    // bilinear between the two mip-map levels closest to the footprint
+    Trilinear,
+    // trilinear samples along the long axis of the footprint, at the level of its short axis
+    Anisotropic,
+}
+
+// How far texture coordinates move from one pixel to the next, along the image x and y. Sets
+// the mip-map level and anisotropy of a lookup.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UvDerivatives {
+    pub duv_dx: (f32, f32),
+    pub duv_dy: (f32, f32),
+}
+
+// how a material looks up its textures
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextureSampler {
+    pub wrap_u: WrapMode,
+    pub wrap_v: WrapMode,
+    pub filter: TextureFilter,
+}
+
+impl Default for TextureSampler {
+    fn default() -> Self {
+        TextureSampler {
+            wrap_u: WrapMode::Repeat,
+            wrap_v: WrapMode::Repeat,
+            filter: TextureFilter::Trilinear,
+        }
+    }
+}
+
+impl TextureSampler {
+    // Without derivatives, e.g. for rays that bounced off a rough surface, the mip-mapped filters
+    // fall back to bilinear on the full size image.
+    pub fn sample(
+        &self,
+        texture: &Texture,
+        u: f32,
+        v: f32,
+        derivatives: Option<&UvDerivatives>,
+    ) -> color::RGBA {
+        let level_0 = &texture.levels[0];
+        if level_0.data.is_empty() {
+            return color::RGBA::transparent();
+        }
+        // the footprint's axes in texels of level 0
+        let axes = derivatives.map(|d| {
+            let (w, h) = (level_0.width as f32, level_0.height as f32);
+            (
+                (d.duv_dx.0 * w, d.duv_dx.1 * h),
+                (d.duv_dy.0 * w, d.duv_dy.1 * h),
+            )
+        });
+        let length = |(x, y): (f32, f32)| (x * x + y * y).sqrt();
+
+        match (self.filter, axes) {
+            (TextureFilter::Nearest, _) => *level_0.nearest(u, v, self.wrap_u, self.wrap_v),
+            (TextureFilter::Bilinear, _) | (_, None) => level_0.bilinear(u, v, self),
+            (TextureFilter::Trilinear, Some((axis_x, axis_y))) => {
+                let width = length(axis_x).max(length(axis_y));
+                self.trilinear(texture, u, v, width)
+            }
+            (TextureFilter::Anisotropic, Some((axis_x, axis_y))) => {
+                let (major, minor) = if length(axis_x) >= length(axis_y) {
+                    (axis_x, axis_y)
+                } else {
+                    (axis_y, axis_x)
+                };
+                let (major_length, minor_length) = (length(major), length(minor));
+                if major_length <= 0.0 {
+                    return level_0.bilinear(u, v, self);
+                }
+                // very thin footprints get a blurrier level rather than more samples
+                let num_samples = (major_length / minor_length.max(1e-8))
+                    .min(MAX_ANISOTROPY)
+                    .ceil()
+                    .max(1.0);
+                let width = major_length / num_samples;
+                let (step_u, step_v) = (
+                    major.0 / level_0.width as f32 / num_samples,
+                    major.1 / level_0.height as f32 / num_samples,
+                );
+                let num_samples = num_samples as usize;
+                (0..num_samples)
+                    .map(|i| {
+                        let offset = i as f32 + 0.5 - num_samples as f32 * 0.5;
+                        self.trilinear(texture, u + step_u * offset, v + step_v * offset, width)
+                    })
+                    .fold(color::RGBA::transparent(), |sum, x| sum + x)
+                    * (1.0 / num_samples as f32)
+            }
+        }
+    }
+
+    // width is the footprint in texels of level 0
+    fn trilinear(&self, texture: &Texture, u: f32, v: f32, width: f32) -> color::RGBA {
+        let max_level = (texture.levels.len() - 1) as f32;
+        let lod = width.max(1.0).log2().min(max_level);
+        let level = lod.floor();
+        let t = lod - level;
+        let fine = texture.levels[level as usize].bilinear(u, v, self);
+        if t <= 0.0 {
+            return fine;
+        }
+        let coarse = texture.levels[level as usize + 1].bilinear(u, v, self);
+        fine * (1.0 - t) + coarse * t
+    }
 }
 
 pub trait TextureLoader {
-    fn from_file<P: AsRef<std::path::Path>>(path: P) -> Result<Texture, TextureLoadError>;
+    // color_space is how integer images are encoded, float images are read as linear
+    fn from_file<P: AsRef<std::path::Path>>(
+        path: P,
+        color_space: ColorSpace,
+    ) -> Result<Texture, TextureLoadError>;
 }
 
 impl TextureLoader for Texture {
-    fn from_file<P: AsRef<std::path::Path>>(path: P) -> Result<Texture, TextureLoadError> {
-        let image = image::open(path)?.to_rgb8();
+    fn from_file<P: AsRef<std::path::Path>>(
+        path: P,
+        color_space: ColorSpace,
+    ) -> Result<Texture, TextureLoadError> {
+        let mut texture = Texture::from_image(image::open(&path)?, color_space);
+        texture.source = Some(path.as_ref().to_path_buf());
+        Ok(texture)
+    }
+}
+
+impl Texture {
+    // Keeps the full precision of 8 bit, 16 bit and float images, integers map to [0, 1]. Alpha
+    // is always linear, images without it are opaque.
+    fn from_image(image: image::DynamicImage, color_space: ColorSpace) -> Texture {
+        let is_float = matches!(
+            image.color(),
+            image::ColorType::Rgb32F | image::ColorType::Rgba32F
+        );
+        let color_space = if is_float {
+            ColorSpace::Linear
+        } else {
+            color_space
+        };
+        let image = image.into_rgba32f();
         let (w, h) = image.dimensions();
         let data = image
             .pixels()
             .map(|pix| {
-                color::RGB::new(
-                    pix[0] as f32 / 256.0,
-                    pix[1] as f32 / 256.0,
-                    pix[2] as f32 / 256.0,
+                color::RGBA::new(
+                    color_space.decode(pix[0]),
+                    color_space.decode(pix[1]),
+                    color_space.decode(pix[2]),
+                    pix[3],
                 )
             })
             .collect();
-        Ok(Texture::new(w as usize, h as usize, data))
+        let mut texture = Texture::from_level(MipLevel {
+            width: w as usize,
+            height: h as usize,
+            data,
+        });
+        texture.color_space = color_space;
+        texture
     }
 }
 
@@ -86,3 +420,121 @@ impl error::Error for TextureLoadError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use color::{RGB, RGBA};
+
+    // 2x2, black and white checkers
+    fn checkers() -> Texture {
+        let (b, w) = (RGB::black(), RGB::white());
+        Texture::new(2, 2, vec![b, w, w, b]).unwrap()
+    }
+
+    #[test]
+    fn test_mip_levels() {
+        let texture = Texture::new(5, 3, vec![RGB::white(); 15]).unwrap();
+        let sizes = texture
+            .levels
+            .iter()
+            .map(|level| (level.width, level.height))
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, vec![(5, 3), (2, 1), (1, 1)]);
+        assert_eq!(texture.levels[2].data[0], RGBA::from_rgb(RGB::white(), 1.0));
+
+        let texture = checkers();
+        assert_eq!(texture.num_levels(), 2);
+        assert_eq!(texture.levels[1].data[0], RGBA::new(0.5, 0.5, 0.5, 1.0));
+    }
+
+    #[test]
+    fn test_wrap_modes() {
+        assert_eq!(WrapMode::Repeat.wrap(4.0, 4), 0);
+        assert_eq!(WrapMode::Repeat.wrap(-1.0, 4), 3);
+        assert_eq!(WrapMode::Clamp.wrap(4.0, 4), 3);
+        assert_eq!(WrapMode::Clamp.wrap(-7.0, 4), 0);
+        assert_eq!(WrapMode::Mirror.wrap(4.0, 4), 3);
+        assert_eq!(WrapMode::Mirror.wrap(-1.0, 4), 0);
+        assert_eq!(WrapMode::Mirror.wrap(9.0, 4), 1);
+        assert_eq!(WrapMode::Repeat.wrap(f32::NAN, 4), 0);
+    }
+
+    #[test]
+    fn test_texel_outside_unit_square() {
+        let texture = checkers();
+        assert_eq!(texture.get_texel(1.0, 0.0).g, 0.0);
+        assert_eq!(texture.get_texel(-0.25, 0.25).g, 1.0);
+        assert_eq!(texture.get_texel(0.0, 1.0).g, 0.0);
+
+        let empty = Texture::from_rgba(0, 0, vec![]).unwrap();
+        assert_eq!(empty.get_texel(0.5, 0.5).a, 0.0);
+        assert!(Texture::new(2, 2, vec![RGB::white(); 3]).is_none());
+    }
+
+    #[test]
+    fn test_filters() {
+        let texture = checkers();
+        let mut sampler = TextureSampler {
+            filter: TextureFilter::Bilinear,
+            ..TextureSampler::default()
+        };
+        // between all four texels
+        let c = sampler.sample(&texture, 0.5, 0.5, None);
+        assert!((c.g - 0.5).abs() < 1e-5);
+        // on a texel center
+        assert_eq!(
+            sampler.sample(&texture, 0.25, 0.25, None),
+            RGBA::new(0.0, 0.0, 0.0, 1.0)
+        );
+
+        // a footprint of a whole checker period averages it
+        let derivatives = UvDerivatives {
+            duv_dx: (1.0, 0.0),
+            duv_dy: (0.0, 1.0),
+        };
+        sampler.filter = TextureFilter::Trilinear;
+        let c = sampler.sample(&texture, 0.25, 0.25, Some(&derivatives));
+        assert!((c.g - 0.5).abs() < 1e-5);
+
+        // long along u, thin along v; averages along u without blurring v
+        let derivatives = UvDerivatives {
+            duv_dx: (1.0, 0.0),
+            duv_dy: (0.0, 0.5),
+        };
+        sampler.filter = TextureFilter::Anisotropic;
+        let c = sampler.sample(&texture, 0.25, 0.25, Some(&derivatives));
+        assert!((c.g - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_srgb_decode() {
+        assert_eq!(ColorSpace::Srgb.decode(0.0), 0.0);
+        assert!((ColorSpace::Srgb.decode(1.0) - 1.0).abs() < 1e-6);
+        assert!((ColorSpace::Srgb.decode(0.5) - 0.214).abs() < 1e-3);
+        assert_eq!(ColorSpace::Linear.decode(0.5), 0.5);
+    }
+
+    #[test]
+    fn test_from_image() {
+        // 8 bit full white is 1.0, and alpha is kept and never decoded
+        let image = image::RgbaImage::from_raw(2, 1, vec![255, 255, 255, 255, 128, 0, 0, 128]);
+        let texture = Texture::from_image(image.unwrap().into(), ColorSpace::Srgb);
+        assert_eq!(texture.texels()[0], RGBA::new(1.0, 1.0, 1.0, 1.0));
+        assert!((texture.texels()[1].r - 0.216).abs() < 1e-3);
+        assert!((texture.texels()[1].a - 128.0 / 255.0).abs() < 1e-6);
+        assert!(!texture.is_opaque());
+
+        // 16 bit keeps its precision
+        let image = image::ImageBuffer::<image::Rgb<u16>, _>::from_raw(1, 1, vec![1, 0, 65535]);
+        let texture = Texture::from_image(image.unwrap().into(), ColorSpace::Linear);
+        assert!((texture.texels()[0].r - 1.0 / 65535.0).abs() < 1e-9);
+        assert!(texture.is_opaque());
+
+        // float images are linear whatever they are loaded as, and may exceed 1
+        let image = image::Rgb32FImage::from_raw(1, 1, vec![4.0, 0.5, 0.0]);
+        let texture = Texture::from_image(image.unwrap().into(), ColorSpace::Srgb);
+        assert_eq!(texture.texels()[0], RGBA::new(4.0, 0.5, 0.0, 1.0));
+        assert_eq!(texture.color_space(), ColorSpace::Linear);
+    }
+}
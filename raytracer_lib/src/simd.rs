@@ -0,0 +1,324 @@
+// Four f32 lanes. Uses SSE on x86_64, SIMD128 on wasm32 with the simd128 target feature
+// (enabled in .cargo/config.toml), and plain arrays everywhere else. Masks are lane wise
+// comparison results.
+
+#[cfg(target_arch = "x86_64")]
+mod imp {
+    use std::arch::x86_64::*;
+
+    #[derive(Clone, Copy)]
+    pub struct F32x4(__m128);
+
+    #[derive(Clone, Copy)]
+    pub struct Mask4(__m128);
+
+    // sse is part of the x86_64 baseline, so the intrinsics are always available
+    impl F32x4 {
+        pub fn splat(v: f32) -> Self {
+            F32x4(unsafe { _mm_set1_ps(v) })
+        }
+
+        pub fn from_array(a: [f32; 4]) -> Self {
+            F32x4(unsafe { _mm_loadu_ps(a.as_ptr()) })
+        }
+
+        pub fn to_array(self) -> [f32; 4] {
+            let mut a = [0.0; 4];
+            unsafe { _mm_storeu_ps(a.as_mut_ptr(), self.0) };
+            a
+        }
+
+        pub fn add(self, other: Self) -> Self {
+            F32x4(unsafe { _mm_add_ps(self.0, other.0) })
+        }
+
+        pub fn sub(self, other: Self) -> Self {
+            F32x4(unsafe { _mm_sub_ps(self.0, other.0) })
+        }
+
+        pub fn mul(self, other: Self) -> Self {
+            F32x4(unsafe { _mm_mul_ps(self.0, other.0) })
+        }
+
+        pub fn div(self, other: Self) -> Self {
+            F32x4(unsafe { _mm_div_ps(self.0, other.0) })
+        }
+
+        pub fn min(self, other: Self) -> Self {
+            F32x4(unsafe { _mm_min_ps(self.0, other.0) })
+        }
+
+        pub fn max(self, other: Self) -> Self {
+            F32x4(unsafe { _mm_max_ps(self.0, other.0) })
+        }
+
+        pub fn lt(self, other: Self) -> Mask4 {
+            Mask4(unsafe { _mm_cmplt_ps(self.0, other.0) })
+        }
+
+        pub fn le(self, other: Self) -> Mask4 {
+            Mask4(unsafe { _mm_cmple_ps(self.0, other.0) })
+        }
+    }
+
+    impl Mask4 {
+        pub fn and(self, other: Self) -> Self {
+            Mask4(unsafe { _mm_and_ps(self.0, other.0) })
+        }
+
+        // lane i in bit i
+        pub fn bits(self) -> u32 {
+            (unsafe { _mm_movemask_ps(self.0) }) as u32
+        }
+    }
+}
+
+#[cfg(all(target_arch = "wasm32", target_feature = "simd128"))]
+#[allow(unused_unsafe)]
+mod imp {
+    use std::arch::wasm32::*;
+
+    #[derive(Clone, Copy)]
+    pub struct F32x4(v128);
+
+    #[derive(Clone, Copy)]
+    pub struct Mask4(v128);
+
+    impl F32x4 {
+        pub fn splat(v: f32) -> Self {
+            F32x4(unsafe { f32x4_splat(v) })
+        }
+
+        pub fn from_array(a: [f32; 4]) -> Self {
+            F32x4(unsafe { f32x4(a[0], a[1], a[2], a[3]) })
+        }
+
+        pub fn to_array(self) -> [f32; 4] {
+            unsafe {
+                [
+                    f32x4_extract_lane::<0>(self.0),
+                    f32x4_extract_lane::<1>(self.0),
+                    f32x4_extract_lane::<2>(self.0),
+                    f32x4_extract_lane::<3>(self.0),
+                ]
+            }
+        }
+
+        pub fn add(self, other: Self) -> Self {
+            F32x4(unsafe { f32x4_add(self.0, other.0) })
+        }
+
+        pub fn sub(self, other: Self) -> Self {
+            F32x4(unsafe { f32x4_sub(self.0, other.0) })
+        }
+
+        pub fn mul(self, other: Self) -> Self {
+            F32x4(unsafe { f32x4_mul(self.0, other.0) })
+        }
+
+        pub fn div(self, other: Self) -> Self {
+            F32x4(unsafe { f32x4_div(self.0, other.0) })
+        }
+
+        // swapped, so NaN lanes pick like sse does
+        pub fn min(self, other: Self) -> Self {
+            F32x4(unsafe { f32x4_pmin(other.0, self.0) })
+        }
+
+        pub fn max(self, other: Self) -> Self {
+            F32x4(unsafe { f32x4_pmax(other.0, self.0) })
+        }
+
+        pub fn lt(self, other: Self) -> Mask4 {
+            Mask4(unsafe { f32x4_lt(self.0, other.0) })
+        }
+
+        pub fn le(self, other: Self) -> Mask4 {
+            Mask4(unsafe { f32x4_le(self.0, other.0) })
+        }
+    }
+
+    impl Mask4 {
+        pub fn and(self, other: Self) -> Self {
+            Mask4(unsafe { v128_and(self.0, other.0) })
+        }
+
+        // lane i in bit i
+        pub fn bits(self) -> u32 {
+            (unsafe { i32x4_bitmask(self.0) }) as u32
+        }
+    }
+}
+
+#[cfg(not(any(
+    target_arch = "x86_64",
+    all(target_arch = "wasm32", target_feature = "simd128")
+)))]
+mod imp {
+    #[derive(Clone, Copy)]
+    pub struct F32x4([f32; 4]);
+
+    #[derive(Clone, Copy)]
+    pub struct Mask4([bool; 4]);
+
+    impl F32x4 {
+        pub fn splat(v: f32) -> Self {
+            F32x4([v; 4])
+        }
+
+        pub fn from_array(a: [f32; 4]) -> Self {
+            F32x4(a)
+        }
+
+        pub fn to_array(self) -> [f32; 4] {
+            self.0
+        }
+
+        fn zip(self, other: Self, f: impl Fn(f32, f32) -> f32) -> Self {
+            F32x4([
+                f(self.0[0], other.0[0]),
+                f(self.0[1], other.0[1]),
+                f(self.0[2], other.0[2]),
+                f(self.0[3], other.0[3]),
+            ])
+        }
+
+        fn compare(self, other: Self, f: impl Fn(f32, f32) -> bool) -> Mask4 {
+            Mask4([
+                f(self.0[0], other.0[0]),
+                f(self.0[1], other.0[1]),
+                f(self.0[2], other.0[2]),
+                f(self.0[3], other.0[3]),
+            ])
+        }
+
+        pub fn add(self, other: Self) -> Self {
+            self.zip(other, |a, b| a + b)
+        }
+
+        pub fn sub(self, other: Self) -> Self {
+            self.zip(other, |a, b| a - b)
+        }
+
+        pub fn mul(self, other: Self) -> Self {
+            self.zip(other, |a, b| a * b)
+        }
+
+        pub fn div(self, other: Self) -> Self {
+            self.zip(other, |a, b| a / b)
+        }
+
+        // like sse, the second value when either is NaN
+        pub fn min(self, other: Self) -> Self {
+            self.zip(other, |a, b| if a < b { a } else { b })
+        }
+
+        pub fn max(self, other: Self) -> Self {
+            self.zip(other, |a, b| if a > b { a } else { b })
+        }
+
+        pub fn lt(self, other: Self) -> Mask4 {
+            self.compare(other, |a, b| a < b)
+        }
+
+        pub fn le(self, other: Self) -> Mask4 {
+            self.compare(other, |a, b| a <= b)
+        }
+    }
+
+    impl Mask4 {
+        pub fn and(self, other: Self) -> Self {
+            Mask4([
+                self.0[0] && other.0[0],
+                self.0[1] && other.0[1],
+                self.0[2] && other.0[2],
+                self.0[3] && other.0[3],
+            ])
+        }
+
+        // lane i in bit i
+        pub fn bits(self) -> u32 {
+            self.0
+                .iter()
+                .enumerate()
+                .map(|(i, lane)| (*lane as u32) << i)
+                .sum()
+        }
+    }
+}
+
+pub use imp::F32x4;
+
+impl F32x4 {
+    pub fn abs(self) -> Self {
+        self.max(F32x4::splat(0.0).sub(self))
+    }
+}
+
+// a Vec3 in each lane
+#[derive(Clone, Copy)]
+pub struct Vec3x4 {
+    pub x: F32x4,
+    pub y: F32x4,
+    pub z: F32x4,
+}
+
+impl Vec3x4 {
+    pub fn splat(v: &crate::vecmath::Vec3) -> Self {
+        Vec3x4 {
+            x: F32x4::splat(v.x),
+            y: F32x4::splat(v.y),
+            z: F32x4::splat(v.z),
+        }
+    }
+
+    pub fn from_lanes(lanes: [&crate::vecmath::Vec3; 4]) -> Self {
+        Vec3x4 {
+            x: F32x4::from_array(lanes.map(|v| v.x)),
+            y: F32x4::from_array(lanes.map(|v| v.y)),
+            z: F32x4::from_array(lanes.map(|v| v.z)),
+        }
+    }
+
+    pub fn sub(&self, other: &Self) -> Self {
+        Vec3x4 {
+            x: self.x.sub(other.x),
+            y: self.y.sub(other.y),
+            z: self.z.sub(other.z),
+        }
+    }
+
+    pub fn dot(&self, other: &Self) -> F32x4 {
+        self.x
+            .mul(other.x)
+            .add(self.y.mul(other.y))
+            .add(self.z.mul(other.z))
+    }
+
+    pub fn cross(&self, other: &Self) -> Self {
+        Vec3x4 {
+            x: self.y.mul(other.z).sub(self.z.mul(other.y)),
+            y: self.z.mul(other.x).sub(self.x.mul(other.z)),
+            z: self.x.mul(other.y).sub(self.y.mul(other.x)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lanes() {
+        let a = F32x4::from_array([1.0, -2.0, 3.0, -4.0]);
+        let b = F32x4::splat(2.0);
+        assert_eq!(a.add(b).to_array(), [3.0, 0.0, 5.0, -2.0]);
+        assert_eq!(a.mul(b).to_array(), [2.0, -4.0, 6.0, -8.0]);
+        assert_eq!(a.div(b).to_array(), [0.5, -1.0, 1.5, -2.0]);
+        assert_eq!(a.min(b).to_array(), [1.0, -2.0, 2.0, -4.0]);
+        assert_eq!(a.abs().to_array(), [1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(a.lt(b).bits(), 0b1011);
+        assert_eq!(a.le(F32x4::splat(1.0)).and(a.lt(b)).bits(), 0b1011);
+        assert_eq!(b.le(a).bits(), 0b0100);
+    }
+}
@@ -2,13 +2,32 @@
 pub struct Ray {
     pub pos: Vec3,
     pub dir: Vec3,
+    // scene time the ray is traced at, for motion blur
+    pub time: f32,
 }
 impl Ray {
     pub const fn new(pos: Vec3, dir: Vec3) -> Self {
-        Ray { pos, dir }
+        Ray {
+            pos,
+            dir,
+            time: 0.0,
+        }
+    }
+
+    pub const fn with_time(pos: Vec3, dir: Vec3, time: f32) -> Self {
+        Ray { pos, dir, time }
     }
 }
 
+// how a camera ray changes from one pixel to the next, along the image x and y
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RayDifferential {
+    pub dpos_dx: Vec3,
+    pub ddir_dx: Vec3,
+    pub dpos_dy: Vec3,
+    pub ddir_dy: Vec3,
+}
+
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub struct Vec3 {
     pub x: f32,
@@ -84,6 +103,39 @@ pub fn cross(v0: &Vec3, v1: &Vec3) -> Vec3 {
     )
 }
 
+// Spherical interpolation of unit quaternions (x, y, z, w) along the shorter arc, as a row by
+// row 3x3 rotation matrix.
+fn slerp(a: &[f32; 4], b: &[f32; 4], t: f32) -> [f32; 9] {
+    let mut cos = a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+    // q and -q are the same rotation
+    let sign = if cos < 0.0 { -1.0 } else { 1.0 };
+    cos *= sign;
+    let (wa, wb) = if cos > 0.9995 {
+        (1.0 - t, t)
+    } else {
+        let angle = cos.acos();
+        let sin = angle.sin();
+        (((1.0 - t) * angle).sin() / sin, (t * angle).sin() / sin)
+    };
+    let mut q = [0.0; 4];
+    for (i, q) in q.iter_mut().enumerate() {
+        *q = a[i] * wa + b[i] * wb * sign;
+    }
+    let len = q.iter().map(|c| c * c).sum::<f32>().sqrt();
+    let [x, y, z, w] = q.map(|c| c / len);
+    [
+        1.0 - 2.0 * (y * y + z * z),
+        2.0 * (x * y - z * w),
+        2.0 * (x * z + y * w),
+        2.0 * (x * y + z * w),
+        1.0 - 2.0 * (x * x + z * z),
+        2.0 * (y * z - x * w),
+        2.0 * (x * z - y * w),
+        2.0 * (y * z + x * w),
+        1.0 - 2.0 * (x * x + y * y),
+    ]
+}
+
 #[derive(PartialEq, Debug, Copy, Clone)]
 pub struct Matrix {
     e: [f32; 16],
@@ -104,6 +156,10 @@ impl Matrix {
         Some(Matrix { e: array })
     }
 
+    pub fn elements(&self) -> &[f32; 16] {
+        &self.e
+    }
+
     pub fn ident() -> Self {
         let mut m = Matrix { e: [0.0; 16] };
         m.e[0] = 1.0;
@@ -138,6 +194,145 @@ impl Matrix {
         m
     }
 
+    // Blend between two affine matrices, t = 0.0 gives self, t = 1.0 gives other. Both are taken
+    // apart into translation, rotation and scale; translation and scale are blended linearly and
+    // the rotation along the shortest arc, so objects keep their shape while turning. Matrices
+    // with shear or a zero scale are blended per element.
+    pub fn interpolate(&self, other: &Matrix, t: f32) -> Self {
+        let (a, b) = match (self.decompose(), other.decompose()) {
+            (Some(a), Some(b)) => (a, b),
+            _ => {
+                let mut m = *self;
+                for (elem, other_elem) in m.e.iter_mut().zip(other.e.iter()) {
+                    *elem += (other_elem - *elem) * t;
+                }
+                return m;
+            }
+        };
+        let lerp = |a: f32, b: f32| a + (b - a) * t;
+        let rotation = slerp(&a.1, &b.1, t);
+        let mut m = Matrix::ident();
+        for col in 0..3 {
+            let scale = lerp(a.2[col], b.2[col]);
+            for row in 0..3 {
+                m.e[col * 4 + row] = rotation[row * 3 + col] * scale;
+            }
+            m.e[12 + col] = lerp(a.0[col], b.0[col]);
+        }
+        m
+    }
+
+    // the angle interpolate turns through on the way from self to other, 0 where it blends per
+    // element
+    pub fn rotation_angle_to(&self, other: &Matrix) -> f32 {
+        match (self.decompose(), other.decompose()) {
+            (Some(a), Some(b)) => {
+                let cos = a.1.iter().zip(&b.1).map(|(a, b)| a * b).sum::<f32>();
+                2.0 * cos.abs().min(1.0).acos()
+            }
+            _ => 0.0,
+        }
+    }
+
+    // (translation, quaternion (x, y, z, w), scale per axis), None if an axis has no length or
+    // the axes aren't perpendicular. Mirroring is kept as a negative x scale.
+    fn decompose(&self) -> Option<([f32; 3], [f32; 4], [f32; 3])> {
+        let e = &self.e;
+        let mut scale = [0.0; 3];
+        for (col, scale) in scale.iter_mut().enumerate() {
+            let c = &e[col * 4..col * 4 + 3];
+            *scale = (c[0] * c[0] + c[1] * c[1] + c[2] * c[2]).sqrt();
+            if *scale < f32::MIN_POSITIVE.sqrt() {
+                return None;
+            }
+        }
+        let axis_dot = |a: usize, b: usize| {
+            (0..3)
+                .map(|row| e[a * 4 + row] * e[b * 4 + row])
+                .sum::<f32>()
+                / (scale[a] * scale[b])
+        };
+        if [(0, 1), (1, 2), (2, 0)]
+            .iter()
+            .any(|&(a, b)| axis_dot(a, b).abs() > 1e-4)
+        {
+            return None;
+        }
+        let det = e[0] * (e[5] * e[10] - e[6] * e[9]) - e[4] * (e[1] * e[10] - e[2] * e[9])
+            + e[8] * (e[1] * e[6] - e[2] * e[5]);
+        if det < 0.0 {
+            scale[0] = -scale[0];
+        }
+        // r(row, col) of the rotation
+        let r = |row: usize, col: usize| e[col * 4 + row] / scale[col];
+        let trace = r(0, 0) + r(1, 1) + r(2, 2);
+        let quat = if trace > 0.0 {
+            let s = (trace + 1.0).sqrt() * 2.0;
+            [
+                (r(2, 1) - r(1, 2)) / s,
+                (r(0, 2) - r(2, 0)) / s,
+                (r(1, 0) - r(0, 1)) / s,
+                0.25 * s,
+            ]
+        } else if r(0, 0) > r(1, 1) && r(0, 0) > r(2, 2) {
+            let s = (1.0 + r(0, 0) - r(1, 1) - r(2, 2)).sqrt() * 2.0;
+            [
+                0.25 * s,
+                (r(0, 1) + r(1, 0)) / s,
+                (r(0, 2) + r(2, 0)) / s,
+                (r(2, 1) - r(1, 2)) / s,
+            ]
+        } else if r(1, 1) > r(2, 2) {
+            let s = (1.0 + r(1, 1) - r(0, 0) - r(2, 2)).sqrt() * 2.0;
+            [
+                (r(0, 1) + r(1, 0)) / s,
+                0.25 * s,
+                (r(1, 2) + r(2, 1)) / s,
+                (r(0, 2) - r(2, 0)) / s,
+            ]
+        } else {
+            let s = (1.0 + r(2, 2) - r(0, 0) - r(1, 1)).sqrt() * 2.0;
+            [
+                (r(0, 2) + r(2, 0)) / s,
+                (r(1, 2) + r(2, 1)) / s,
+                0.25 * s,
+                (r(1, 0) - r(0, 1)) / s,
+            ]
+        };
+        Some(([e[12], e[13], e[14]], quat, scale))
+    }
+
+    // inverse of a matrix without projection (last column 0, 0, 0, 1), None if it is singular
+    pub fn affine_inverse(&self) -> Option<Self> {
+        let e = &self.e;
+        let cofactors = [
+            e[5] * e[10] - e[6] * e[9],
+            e[2] * e[9] - e[1] * e[10],
+            e[1] * e[6] - e[2] * e[5],
+            e[6] * e[8] - e[4] * e[10],
+            e[0] * e[10] - e[2] * e[8],
+            e[2] * e[4] - e[0] * e[6],
+            e[4] * e[9] - e[5] * e[8],
+            e[1] * e[8] - e[0] * e[9],
+            e[0] * e[5] - e[1] * e[4],
+        ];
+        let det = e[0] * cofactors[0] + e[1] * cofactors[3] + e[2] * cofactors[6];
+        if det.abs() < f32::MIN_POSITIVE {
+            return None;
+        }
+
+        let mut m = Matrix::ident();
+        for row in 0..3 {
+            for col in 0..3 {
+                m.e[row * 4 + col] = cofactors[row * 3 + col] / det;
+            }
+        }
+        for col in 0..3 {
+            m.e[12 + col] = -(e[12] * m.e[col] + e[13] * m.e[4 + col] + e[14] * m.e[8 + col]);
+        }
+        Some(m)
+    }
+
     pub fn transpose(&self) -> Self {
         let mut m = *self;
         m.e[1] = self.e[4];
@@ -234,6 +429,7 @@ impl std::ops::Mul<&Vec4> for Matrix {
     }
 }
 
+// a * b transforms by a first and then by b, e.g. rot * translate turns before moving
 impl std::ops::Mul<&Matrix> for &Matrix {
     type Output = Matrix;
 
@@ -357,4 +553,17 @@ mod tests {
         let res = m * v;
         assert_eq!(res, Vec4::new(1.0, 2.0, 3.0, 4.0));
     }
+
+    #[test]
+    fn test_affine_inverse() {
+        use super::{Matrix, Vec3, Vec4};
+        let m =
+            Matrix::rot_x(0.3) * Matrix::rot_y(1.2) * Matrix::translate(&Vec3::new(1.0, -2.0, 3.0));
+        let v = Vec4::new(0.5, 2.0, -1.0, 1.0);
+        let res = m.affine_inverse().unwrap() * (m * &v);
+        assert!((res.x - v.x).abs() < 1e-5);
+        assert!((res.y - v.y).abs() < 1e-5);
+        assert!((res.z - v.z).abs() < 1e-5);
+        assert!(Matrix::new(&[0.0; 16]).affine_inverse().is_none());
+    }
 }
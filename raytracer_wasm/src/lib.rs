@@ -5,7 +5,7 @@ use web_sys::ImageData;
 
 mod inline_data;
 
-use raytracer_lib::RayTracer;
+use raytracer_lib::{RayTracer, StereoMode};
 
 #[wasm_bindgen]
 pub struct RaytracerProxy {
@@ -24,6 +24,17 @@ pub fn create_raytracer() -> RaytracerProxy {
     }
 }
 
+// mode is one of "none", "sbs" or "anaglyph"
+#[wasm_bindgen]
+pub fn set_stereo(raytracer_proxy: &mut RaytracerProxy, mode: &str) {
+    let stereo = match mode {
+        "sbs" => Some(StereoMode::SideBySide),
+        "anaglyph" => Some(StereoMode::Anaglyph),
+        _ => None,
+    };
+    raytracer_proxy.raytracer.set_stereo(stereo);
+}
+
 #[wasm_bindgen]
 pub fn draw_traced(raytracer_proxy: &mut RaytracerProxy) {
     let document = web_sys::window().unwrap().document().unwrap();